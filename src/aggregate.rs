@@ -0,0 +1,225 @@
+use super::database::{DailyEntryReader, DatabaseFormat, DatabaseTime, EntryReader, TimeFormat};
+use super::ErrorMessage;
+use chrono;
+use chrono::{Datelike, TimeZone};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time;
+
+/// Time period used to group entries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Period {
+    Day,
+    Week,
+}
+
+impl Period {
+    pub fn from_name(name: &str) -> Result<Self, ErrorMessage> {
+        match name {
+            "day" => Ok(Period::Day),
+            "week" => Ok(Period::Week),
+            _ => Err(ErrorMessage::from(format!("Unknown period '{}'", name))),
+        }
+    }
+
+    /// First day of the period containing date. Weeks start on monday.
+    fn start(self, date: chrono::NaiveDate) -> chrono::NaiveDate {
+        match self {
+            Period::Day => date,
+            Period::Week => {
+                date - chrono::Duration::days(i64::from(date.weekday().num_days_from_monday()))
+            }
+        }
+    }
+
+    /// Start of the next period.
+    fn next(self, start: chrono::NaiveDate) -> chrono::NaiveDate {
+        match self {
+            Period::Day => start + chrono::Duration::days(1),
+            Period::Week => start + chrono::Duration::weeks(1),
+        }
+    }
+
+    /** Name of the period, as shown in the table: ISO 8601 date or week by default.
+     * A custom time format is applied to the local midnight starting the period.
+     */
+    fn label(self, start: chrono::NaiveDate, time_format: &TimeFormat) -> String {
+        if let TimeFormat::Custom(_) = time_format {
+            // Midnight may be skipped by a DST change, then the default label is used.
+            if let Some(midnight) = chrono::Local
+                .from_local_datetime(&start.and_hms(0, 0, 0))
+                .earliest()
+            {
+                return time_format.format(&midnight);
+            }
+        }
+        match self {
+            Period::Day => start.format("%Y-%m-%d").to_string(),
+            Period::Week => start.format("%G-W%V").to_string(),
+        }
+    }
+}
+
+/// Duration in hours and minutes: 2h05.
+fn format_duration(d: time::Duration) -> String {
+    let minutes = d.as_secs() / 60;
+    format!("{}h{:02}", minutes / 60, minutes % 60)
+}
+
+/** Sum durations of entries for each period.
+ * Entries are counted in the period of their time window start, even if they straddle midnight.
+ * If fill_gaps is set, periods without entries are included with zero durations.
+ */
+fn aggregate_entries<I>(
+    entries: I,
+    nb_categories: usize,
+    period: Period,
+    fill_gaps: bool,
+) -> io::Result<BTreeMap<chrono::NaiveDate, Vec<time::Duration>>>
+where
+    I: Iterator<Item = io::Result<(DatabaseTime, Vec<time::Duration>)>>,
+{
+    let zero_durations = vec![time::Duration::new(0, 0); nb_categories];
+    let mut totals = BTreeMap::new();
+    for entry in entries {
+        let (window_start, durations) = entry?;
+        let period_start = period.start(window_start.naive_local().date());
+        let total = totals
+            .entry(period_start)
+            .or_insert_with(|| zero_durations.clone());
+        for (sum, d) in total.iter_mut().zip(durations) {
+            *sum += d
+        }
+    }
+    if fill_gaps {
+        let first_and_last = (
+            totals.keys().next().cloned(),
+            totals.keys().next_back().cloned(),
+        );
+        if let (Some(first), Some(last)) = first_and_last {
+            let mut period_start = first;
+            while period_start < last {
+                totals
+                    .entry(period_start)
+                    .or_insert_with(|| zero_durations.clone());
+                period_start = period.next(period_start);
+            }
+        }
+    }
+    Ok(totals)
+}
+
+/** Sum durations of entries with a time window start between the from and to dates, included.
+ * Entries are ordered by time window start, so reading stops at the first entry after to.
+ */
+pub fn totals_between(
+    entries: EntryReader,
+    from: chrono::NaiveDate,
+    to: chrono::NaiveDate,
+) -> io::Result<Vec<time::Duration>> {
+    let mut totals = vec![time::Duration::new(0, 0); entries.categories().len()];
+    for entry in entries {
+        let (window_start, durations) = entry?;
+        let date = window_start.naive_local().date();
+        if date > to {
+            break;
+        }
+        if date >= from {
+            for (sum, d) in totals.iter_mut().zip(durations) {
+                *sum += d
+            }
+        }
+    }
+    Ok(totals)
+}
+
+/// Write totals as a table with aligned columns: period, then a column per category.
+fn write_table<W: Write>(
+    categories: &[String],
+    totals: &BTreeMap<chrono::NaiveDate, Vec<time::Duration>>,
+    period: Period,
+    time_format: &TimeFormat,
+    out: &mut W,
+) -> io::Result<()> {
+    let rows: Vec<(String, Vec<String>)> = totals
+        .iter()
+        .map(|(start, durations)| {
+            let cells = durations.iter().cloned().map(format_duration).collect();
+            (period.label(*start, time_format), cells)
+        })
+        .collect();
+    let period_header = match period {
+        Period::Day => "day",
+        Period::Week => "week",
+    };
+    let period_width = rows
+        .iter()
+        .map(|(label, _)| label.len())
+        .chain(Some(period_header.len()))
+        .max()
+        .unwrap();
+    let widths: Vec<usize> = categories
+        .iter()
+        .enumerate()
+        .map(|(i, category)| {
+            rows.iter()
+                .map(|(_, cells)| cells[i].len())
+                .chain(Some(category.chars().count()))
+                .max()
+                .unwrap()
+        })
+        .collect();
+    write!(out, "{:<width$}", period_header, width = period_width)?;
+    for (category, width) in categories.iter().zip(&widths) {
+        write!(out, "  {:>width$}", category, width = width)?;
+    }
+    writeln!(out)?;
+    for (label, cells) in &rows {
+        write!(out, "{:<width$}", label, width = period_width)?;
+        for (cell, width) in cells.iter().zip(&widths) {
+            write!(out, "  {:>width$}", cell, width = width)?;
+        }
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+/** Print the total time spent in each category per period, as a table on stdout.
+ * If db_file is a directory, its daily files are read in date order (see --daily-files).
+ */
+pub fn print_aggregate(
+    db_file: &Path,
+    db_format: DatabaseFormat,
+    period: Period,
+    fill_gaps: bool,
+    time_format: &TimeFormat,
+) -> Result<(), ErrorMessage> {
+    let db_filename = db_file.display();
+    let open_error =
+        |e: io::Error| ErrorMessage::new(format!("Unable to open database '{}'", db_filename), e);
+    let (categories, totals) = if db_file.is_dir() {
+        let entries = DailyEntryReader::open(db_file).map_err(open_error)?;
+        let categories = entries.categories().to_vec();
+        let nb_categories = categories.len();
+        (
+            categories,
+            aggregate_entries(entries, nb_categories, period, fill_gaps),
+        )
+    } else {
+        let entries = EntryReader::open(db_file, db_format).map_err(open_error)?;
+        let categories = entries.categories().to_vec();
+        let nb_categories = categories.len();
+        (
+            categories,
+            aggregate_entries(entries, nb_categories, period, fill_gaps),
+        )
+    };
+    let totals = totals
+        .map_err(|e| ErrorMessage::new(format!("Unable to read database '{}'", db_filename), e))?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    write_table(&categories, &totals, period, time_format, &mut stdout)
+        .map_err(|e| ErrorMessage::new("Unable to print aggregate", e))
+}
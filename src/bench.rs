@@ -0,0 +1,175 @@
+use super::classifier::{Classifier, ExcludedWindows, MatchMode};
+use super::daemon::{Daemon, DaemonOptions, WindowedDatabase};
+use super::database::{DatabaseFormat, DurationPrecision};
+use super::{ActiveWindowMetadata, ErrorMessage};
+use serde::Serialize;
+use serde_json;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time;
+
+/// Replay parameters: synthetic window changes, and database writes between them.
+pub struct BenchParameters {
+    /// Number of window changes
+    pub events: usize,
+    /// Number of distinct synthetic windows, changes cycle through them
+    pub windows: usize,
+    /// Simulated time between two window changes, which is counted in durations
+    pub interval: time::Duration,
+    /// Number of window changes between two database writes
+    pub write_every: usize,
+}
+
+/// Latency statistics, in microseconds.
+#[derive(Serialize)]
+struct LatencyStats {
+    count: usize,
+    mean: u64,
+    p50: u64,
+    p99: u64,
+    max: u64,
+}
+
+impl LatencyStats {
+    fn new(mut samples: Vec<time::Duration>) -> Self {
+        samples.sort();
+        let micros = |d: &time::Duration| d.as_micros() as u64;
+        let percentile = |p: usize| {
+            samples
+                .get((samples.len() * p / 100).min(samples.len().saturating_sub(1)))
+                .map_or(0, micros)
+        };
+        let total: u64 = samples.iter().map(micros).sum();
+        LatencyStats {
+            count: samples.len(),
+            mean: total.checked_div(samples.len() as u64).unwrap_or(0),
+            p50: percentile(50),
+            p99: percentile(99),
+            max: samples.last().map_or(0, micros),
+        }
+    }
+}
+
+/// Timing results, printed as JSON.
+#[derive(Serialize)]
+struct BenchResults {
+    events: usize,
+    windows: usize,
+    /// Seconds of real time for the whole replay
+    elapsed: f64,
+    window_changes_per_second: f64,
+    /// Classification and counting of each window change, in microseconds
+    window_change: LatencyStats,
+    /// Database writes, in microseconds
+    write: LatencyStats,
+}
+
+/// Synthetic window: class and title cycle through the given number of windows.
+fn synthetic_window(index: usize) -> ActiveWindowMetadata {
+    ActiveWindowMetadata {
+        title: Some(format!("Document {} - Editor", index)),
+        class: Some(format!("bench-app-{}", index % 7)),
+        instance: Some(format!("bench-app-{}", index % 7)),
+        pid: Some(index as u32 + 1000),
+        process_name: Some(format!("bench-app-{}", index % 7)),
+        window_type: Some(String::from("normal")),
+        ..ActiveWindowMetadata::default()
+    }
+}
+
+/** Replay synthetic window changes through the daemon, without display server, and print timings.
+ * Window changes go through the classifier, excluded windows and duration counters,
+ * as for run_daemon(). Durations are written to a temporary database, removed at the end.
+ * Timestamps are synthetic, spaced by the interval and ending before the replay: it runs as
+ * fast as possible, and counted durations do not depend on its speed.
+ */
+pub fn run_bench(
+    classifier: &mut dyn Classifier,
+    parameters: BenchParameters,
+) -> Result<(), ErrorMessage> {
+    let dir = std::env::temp_dir().join(format!("xstalker-bench-{}", process::id()));
+    fs::DirBuilder::new()
+        .recursive(true)
+        .create(&dir)
+        .map_err(|e| ErrorMessage::new("Unable to create benchmark directory", e))?;
+    let result = replay(classifier, &parameters, &dir);
+    if let Err(e) = fs::remove_dir_all(&dir) {
+        warn!("Unable to remove '{}': {}", dir.display(), e)
+    }
+    let results = result?;
+    println!("{}", serde_json::to_string_pretty(&results).unwrap());
+    Ok(())
+}
+
+fn replay(
+    classifier: &mut dyn Classifier,
+    parameters: &BenchParameters,
+    dir: &Path,
+) -> Result<BenchResults, ErrorMessage> {
+    let database = WindowedDatabase {
+        path: dir.join("bench.log"),
+        format: DatabaseFormat::Plain,
+        precision: DurationPrecision::Milliseconds,
+        window_size: time::Duration::from_secs(24 * 3600),
+        align: false,
+        write_retries: 0,
+        compact_after: None,
+        max_size: None,
+        trim_to_archive: false,
+        dry: false,
+        daily_files: false,
+        monitor: None,
+    };
+    let daemon = Daemon::new(
+        classifier,
+        ExcludedWindows::new(&[], &[], MatchMode::Exact)?,
+        vec![database],
+        DaemonOptions {
+            db_lock: false,
+            allow_empty_categories: true,
+            log_titles: false,
+            ..DaemonOptions::default()
+        },
+    )?;
+    let windows = parameters.windows.max(1);
+    let replayed_time = parameters.interval * parameters.events as u32;
+    let first_timestamp = time::Instant::now()
+        .checked_sub(replayed_time)
+        .ok_or_else(|| ErrorMessage::from("Replayed time is too long, reduce the interval"))?;
+    let start = time::Instant::now();
+    daemon.start(
+        synthetic_window(0),
+        first_timestamp,
+        time::Duration::new(0, 0),
+    )?;
+    let mut window_change_latencies = Vec::with_capacity(parameters.events);
+    let mut write_latencies = Vec::new();
+    for event in 1..=parameters.events {
+        let timestamp = first_timestamp + parameters.interval * event as u32;
+        let metadata = synthetic_window(event % windows);
+        let before = time::Instant::now();
+        daemon.on_window_change(metadata, timestamp)?;
+        window_change_latencies.push(before.elapsed());
+        if parameters.write_every > 0 && event % parameters.write_every == 0 {
+            let before = time::Instant::now();
+            daemon.on_db_write_tick(timestamp)?;
+            write_latencies.push(before.elapsed());
+        }
+    }
+    let elapsed = start.elapsed();
+    daemon.stop()?;
+    let elapsed_secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+    Ok(BenchResults {
+        events: parameters.events,
+        windows: windows,
+        elapsed: elapsed_secs,
+        window_changes_per_second: if elapsed_secs > 0. {
+            parameters.events as f64 / elapsed_secs
+        } else {
+            0.
+        },
+        window_change: LatencyStats::new(window_change_latencies),
+        write: LatencyStats::new(write_latencies),
+    })
+}
@@ -0,0 +1,130 @@
+use super::database::{
+    archive_path, repair_file, Database, DatabaseFormat, DatabaseTime, DurationPrecision,
+    EntryReader, Iso8601,
+};
+use super::{ErrorMessage, UniqueCategories};
+use chrono;
+use std::path::Path;
+use std::time;
+
+/** Check the database file without modifying it, and print problems found.
+ * Entries must be parseable, with a duration for each category.
+ * Time window starts must not decrease, and windows must not overlap.
+ * Returns an error if any problem is found.
+ */
+pub fn check_database(
+    db_file: &Path,
+    db_format: DatabaseFormat,
+    time_window_size: time::Duration,
+) -> Result<(), ErrorMessage> {
+    let db_filename = db_file.display();
+    let mut entries = EntryReader::open(db_file, db_format)
+        .map_err(|e| ErrorMessage::new(format!("Unable to open database '{}'", db_filename), e))?;
+    let window_size = chrono::Duration::from_std(time_window_size).unwrap();
+    let mut nb_entries = 0;
+    let mut nb_problems = 0;
+    let mut previous_start: Option<DatabaseTime> = None;
+    while let Some(entry) = entries.next() {
+        let location = entries.location();
+        nb_entries += 1;
+        let window_start = match entry {
+            Ok((window_start, _durations)) => window_start,
+            Err(e) => {
+                // Field count and format errors, location included
+                println!("{}", e);
+                nb_problems += 1;
+                continue;
+            }
+        };
+        if let Some(previous_start) = previous_start {
+            if window_start < previous_start {
+                println!(
+                    "{}: time window start {} is before the previous one ({})",
+                    location,
+                    window_start.format_iso8601(),
+                    previous_start.format_iso8601()
+                );
+                nb_problems += 1;
+            } else if window_start < previous_start + window_size {
+                println!(
+                    "{}: time window start {} overlaps the previous window ({} + {}s)",
+                    location,
+                    window_start.format_iso8601(),
+                    previous_start.format_iso8601(),
+                    time_window_size.as_secs()
+                );
+                nb_problems += 1;
+            }
+        }
+        previous_start = Some(window_start);
+    }
+    println!(
+        "{}: {} categories, {} entries, {} problems",
+        db_filename,
+        entries.categories().len(),
+        nb_entries,
+        nb_problems
+    );
+    if nb_problems > 0 {
+        return Err(ErrorMessage::from(format!(
+            "Database '{}' has {} problems",
+            db_filename, nb_problems
+        )));
+    }
+    Ok(())
+}
+
+/// Repair the database after an interrupted write, and print what was done.
+pub fn repair_database(db_file: &Path, db_format: DatabaseFormat) -> Result<(), ErrorMessage> {
+    let db_filename = db_file.display();
+    match repair_file(db_file, db_format)
+        .map_err(|e| ErrorMessage::new(format!("Unable to repair database '{}'", db_filename), e))?
+    {
+        Some(line_nb) => println!(
+            "{}: dropped malformed line {}, original saved to '{}.bak'",
+            db_filename, line_nb, db_filename
+        ),
+        None => println!("{}: no repair needed", db_filename),
+    }
+    Ok(())
+}
+
+/** Move entries with a time window start older than older_than to the archive, and print the count.
+ * The database is locked, so this fails if the daemon is running: it compacts by itself.
+ */
+pub fn compact_database(
+    db_file: &Path,
+    db_format: DatabaseFormat,
+    precision: DurationPrecision,
+    older_than: time::Duration,
+) -> Result<(), ErrorMessage> {
+    let db_filename = db_file.display();
+    if !db_file.exists() {
+        return Err(ErrorMessage::from(format!(
+            "Database '{}' does not exist",
+            db_filename
+        )));
+    }
+    // All categories are kept, as none are requested: the migration is irrelevant.
+    let (mut db, _) = Database::open(
+        db_file,
+        db_format,
+        precision,
+        UniqueCategories::default(),
+        true,
+        0,
+    )
+    .map_err(|e| ErrorMessage::new(format!("Unable to open database '{}'", db_filename), e))?;
+    let limit = DatabaseTime::from(time::SystemTime::now())
+        - chrono::Duration::from_std(older_than).unwrap();
+    let nb_archived = db.compact(&limit).map_err(|e| {
+        ErrorMessage::new(format!("Unable to compact database '{}'", db_filename), e)
+    })?;
+    println!(
+        "{}: {} entries moved to '{}'",
+        db_filename,
+        nb_archived,
+        archive_path(db_file).display()
+    );
+    Ok(())
+}
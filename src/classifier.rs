@@ -1,16 +1,495 @@
+use super::database::DatabaseTime;
 use super::{ActiveWindowMetadata, ErrorMessage, UniqueCategories};
+use chrono;
+use globset::{GlobBuilder, GlobMatcher};
+use libc;
+use regex;
+use regex::{Regex, RegexBuilder};
+use rhai;
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::io::{BufRead, BufReader, Write};
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time;
+
+/// Path of a rules or config file meaning that it is read from stdin.
+pub const STDIN_PATH: &str = "-";
+
+/** Read a rules or config file, or stdin if the path is "-".
+ * Stdin is read until its end, so it must not be a terminal.
+ */
+fn read_rules_text(path: &Path) -> io::Result<String> {
+    if path != Path::new(STDIN_PATH) {
+        return fs::read_to_string(path);
+    }
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "stdin is a terminal: rules must be piped into it",
+        ));
+    }
+    let mut text = String::new();
+    io::stdin().read_to_string(&mut text)?;
+    Ok(text)
+}
+
+/// Path to store for reload: stdin is only read once at startup, so it cannot be reloaded.
+fn reload_path(path: &Path) -> Option<PathBuf> {
+    if path == Path::new(STDIN_PATH) {
+        None
+    } else {
+        Some(path.to_path_buf())
+    }
+}
+
+/** Expand environment variables in a rule pattern: $NAME or ${NAME}, and $$ for a literal $.
+ * Other uses of $ are kept, so regex end anchors are not affected.
+ * An undefined variable is an error, instead of silently matching something else.
+ */
+fn expand_env(text: &str) -> Result<String, ErrorMessage> {
+    let is_name_char = |c: char| c == '_' || c.is_ascii_alphanumeric();
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(index) = rest.find('$') {
+        expanded.push_str(&rest[..index]);
+        let after = &rest[index + 1..];
+        let (name, remaining) = if after.starts_with('$') {
+            expanded.push('$');
+            rest = &after[1..];
+            continue;
+        } else if after.starts_with('{') {
+            match after.find('}') {
+                Some(end) => (&after[1..end], &after[end + 1..]),
+                None => {
+                    return Err(ErrorMessage::from(format!(
+                        "Unterminated variable in '{}'",
+                        text
+                    )))
+                }
+            }
+        } else if after.starts_with(|c: char| c == '_' || c.is_ascii_alphabetic()) {
+            let end = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        } else {
+            expanded.push('$');
+            rest = after;
+            continue;
+        };
+        let value = std::env::var(name).map_err(|e| {
+            ErrorMessage::new(format!("Cannot expand environment variable '{}'", name), e)
+        })?;
+        expanded.push_str(&value);
+        rest = remaining;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/** Categories sharing the time of a window, with relative weights.
+ * Time is split proportionally to weights, which must be positive.
+ * The first category is the main one, used for notifications and hooks. Empty if not matched.
+ */
+pub type WeightedCategories = Vec<(String, f32)>;
+
+/// Applies a configuration loaded by Classifier::prepare_reload(). Cannot fail.
+pub type ReloadCommit<'a> = Box<dyn FnOnce() + 'a>;
+
+/// Commit replacing a classifier by its reloaded version, if any.
+fn replace_with<'a, C>(classifier: &'a mut C, reloaded: Option<C>) -> ReloadCommit<'a> {
+    Box::new(move || {
+        if let Some(reloaded) = reloaded {
+            *classifier = reloaded
+        }
+    })
+}
 
 /// Classifier: determines the category based on active window metadata.
 pub trait Classifier {
     /// Returns the set of all categories defined in the classifier.
+    /// The set may grow after classify() calls, for classifiers discovering categories.
     fn categories(&self) -> UniqueCategories;
 
     /// Returns the category name for the metadata, or None if not matched.
-    /// The category must be in the set returned by categories().
+    /// The category must be in the set returned by categories() after the call.
     fn classify(&mut self, metadata: ActiveWindowMetadata) -> Result<Option<String>, ErrorMessage>;
+
+    /// Same as classify(), at the given local time. Only time dependent classifiers use it.
+    fn classify_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        _now: DatabaseTime,
+    ) -> Result<Option<String>, ErrorMessage> {
+        self.classify(metadata)
+    }
+
+    /// Same as classify(), with the time of the window possibly split between several categories.
+    fn classify_weighted(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+    ) -> Result<WeightedCategories, ErrorMessage> {
+        self.classify_weighted_at(metadata, chrono::Local::now())
+    }
+
+    /** Same as classify_at(), with the time of the window possibly split between several categories.
+     * Default adapter for single category classifiers: the category of classify_at(), weight 1.
+     */
+    fn classify_weighted_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<WeightedCategories, ErrorMessage> {
+        Ok(self
+            .classify_at(metadata, now)?
+            .into_iter()
+            .map(|category| (category, 1.))
+            .collect())
+    }
+
+    /// Reload the classifier configuration, if supported. The category set may change.
+    /// On error, the previous configuration must stay in use.
+    fn reload(&mut self) -> Result<(), ErrorMessage> {
+        let commit = self.prepare_reload()?;
+        commit();
+        Ok(())
+    }
+
+    /** First step of reload(): load the new configuration, without using it yet.
+     * Calling the returned commit switches to it. Classifiers combining others load all their
+     * configurations before committing any, so they are all kept if one fails to load.
+     */
+    fn prepare_reload(&mut self) -> Result<ReloadCommit<'_>, ErrorMessage> {
+        Ok(Box::new(|| ()))
+    }
+
+    /// Release resources when the daemon stops, reporting failures. Must be the last call.
+    fn shutdown(&mut self) -> Result<(), ErrorMessage> {
+        Ok(())
+    }
+
+    /// Idle timeout for a category, overriding the global one. None if not specified.
+    fn idle_timeout(&self, _category: &str) -> Option<time::Duration> {
+        None
+    }
+}
+
+/// Allow using a classifier by mutable reference, including trait objects.
+impl<'a, C: Classifier + ?Sized> Classifier for &'a mut C {
+    fn categories(&self) -> UniqueCategories {
+        (**self).categories()
+    }
+    fn classify(&mut self, metadata: ActiveWindowMetadata) -> Result<Option<String>, ErrorMessage> {
+        (**self).classify(metadata)
+    }
+    fn classify_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<Option<String>, ErrorMessage> {
+        (**self).classify_at(metadata, now)
+    }
+    fn classify_weighted_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<WeightedCategories, ErrorMessage> {
+        (**self).classify_weighted_at(metadata, now)
+    }
+    fn reload(&mut self) -> Result<(), ErrorMessage> {
+        (**self).reload()
+    }
+    fn prepare_reload(&mut self) -> Result<ReloadCommit<'_>, ErrorMessage> {
+        (**self).prepare_reload()
+    }
+    fn shutdown(&mut self) -> Result<(), ErrorMessage> {
+        (**self).shutdown()
+    }
+    fn idle_timeout(&self, category: &str) -> Option<time::Duration> {
+        (**self).idle_timeout(category)
+    }
+}
+
+/** Wrap a classifier to check that categories returned by classify() are declared.
+ * Weights returned by classify_weighted() must also be positive and finite.
+ * An undeclared category is an error, instead of being recorded in an unknown column.
+ */
+pub struct CheckedClassifier<C> {
+    inner: C,
+    categories: UniqueCategories,
+}
+
+impl<C: Classifier> CheckedClassifier<C> {
+    pub fn new(inner: C) -> Self {
+        let categories = inner.categories();
+        CheckedClassifier {
+            inner: inner,
+            categories: categories,
+        }
+    }
+
+    fn check_category(&mut self, category: &str) -> Result<(), ErrorMessage> {
+        if !self.categories.contains(category) {
+            // Category may have been discovered by the classifier
+            self.categories = self.inner.categories()
+        }
+        if self.categories.contains(category) {
+            Ok(())
+        } else {
+            Err(ErrorMessage::from(format!(
+                "Classifier returned undeclared category '{}'",
+                category
+            )))
+        }
+    }
+}
+impl<C: Classifier> Classifier for CheckedClassifier<C> {
+    fn categories(&self) -> UniqueCategories {
+        self.categories.clone()
+    }
+    fn classify(&mut self, metadata: ActiveWindowMetadata) -> Result<Option<String>, ErrorMessage> {
+        self.classify_at(metadata, chrono::Local::now())
+    }
+    fn classify_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<Option<String>, ErrorMessage> {
+        let category = self.inner.classify_at(metadata, now)?;
+        if let Some(category) = &category {
+            self.check_category(category)?
+        }
+        Ok(category)
+    }
+    fn classify_weighted_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<WeightedCategories, ErrorMessage> {
+        let categories = self.inner.classify_weighted_at(metadata, now)?;
+        for (category, weight) in &categories {
+            self.check_category(category)?;
+            // Also rejects NaN
+            if !weight.is_finite() || *weight <= 0. {
+                return Err(ErrorMessage::from(format!(
+                    "Classifier returned invalid weight {} for category '{}'",
+                    weight, category
+                )));
+            }
+        }
+        Ok(categories)
+    }
+    fn reload(&mut self) -> Result<(), ErrorMessage> {
+        self.inner.reload()?;
+        self.categories = self.inner.categories();
+        Ok(())
+    }
+    fn prepare_reload(&mut self) -> Result<ReloadCommit<'_>, ErrorMessage> {
+        // Without reload(), new categories are checked against the inner classifier on use
+        self.inner.prepare_reload()
+    }
+    fn shutdown(&mut self) -> Result<(), ErrorMessage> {
+        self.inner.shutdown()
+    }
+    fn idle_timeout(&self, category: &str) -> Option<time::Duration> {
+        self.inner.idle_timeout(category)
+    }
+}
+
+/** Classify using a list of classifiers.
+ * Classifiers are tried in order, and the first defined category is returned.
+ * Categories are the union of all classifier categories.
+ */
+pub struct ChainClassifier {
+    classifiers: Vec<Box<dyn Classifier>>,
+}
+
+impl ChainClassifier {
+    pub fn new(classifiers: Vec<Box<dyn Classifier>>) -> Self {
+        ChainClassifier {
+            classifiers: classifiers,
+        }
+    }
+}
+impl Classifier for ChainClassifier {
+    fn categories(&self) -> UniqueCategories {
+        let mut categories = UniqueCategories::default();
+        for classifier in &self.classifiers {
+            categories.extend(classifier.categories());
+        }
+        categories
+    }
+    fn classify(&mut self, metadata: ActiveWindowMetadata) -> Result<Option<String>, ErrorMessage> {
+        self.classify_at(metadata, chrono::Local::now())
+    }
+    fn classify_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<Option<String>, ErrorMessage> {
+        for classifier in &mut self.classifiers {
+            if let Some(category) = classifier.classify_at(metadata.clone(), now)? {
+                return Ok(Some(category));
+            }
+        }
+        Ok(None)
+    }
+    fn classify_weighted_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<WeightedCategories, ErrorMessage> {
+        for classifier in &mut self.classifiers {
+            let categories = classifier.classify_weighted_at(metadata.clone(), now)?;
+            if !categories.is_empty() {
+                return Ok(categories);
+            }
+        }
+        Ok(Vec::new())
+    }
+    fn prepare_reload(&mut self) -> Result<ReloadCommit<'_>, ErrorMessage> {
+        let commits = self
+            .classifiers
+            .iter_mut()
+            .map(|classifier| classifier.prepare_reload())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Box::new(move || {
+            for commit in commits {
+                commit()
+            }
+        }))
+    }
+    fn shutdown(&mut self) -> Result<(), ErrorMessage> {
+        // Shutdown all classifiers, even if one fails
+        let mut result = Ok(());
+        for classifier in &mut self.classifiers {
+            if let Err(e) = classifier.shutdown() {
+                if result.is_ok() {
+                    result = Err(e)
+                } else {
+                    error!("{}", e)
+                }
+            }
+        }
+        result
+    }
+    fn idle_timeout(&self, category: &str) -> Option<time::Duration> {
+        self.classifiers
+            .iter()
+            .filter_map(|classifier| classifier.idle_timeout(category))
+            .next()
+    }
+}
+
+/** Use a default category for windows without category from the inner classifier.
+ * Time of unclassified windows is then recorded, instead of being dropped.
+ */
+pub struct DefaultCategoryClassifier<C> {
+    inner: C,
+    default: String,
+}
+
+impl<C: Classifier> DefaultCategoryClassifier<C> {
+    pub fn new(inner: C, default: String) -> Self {
+        DefaultCategoryClassifier {
+            inner: inner,
+            default: default,
+        }
+    }
+}
+impl<C: Classifier> Classifier for DefaultCategoryClassifier<C> {
+    fn categories(&self) -> UniqueCategories {
+        let mut categories = self.inner.categories();
+        categories.extend(UniqueCategories::make_unique(vec![self.default.clone()]));
+        categories
+    }
+    fn classify(&mut self, metadata: ActiveWindowMetadata) -> Result<Option<String>, ErrorMessage> {
+        self.classify_at(metadata, chrono::Local::now())
+    }
+    fn classify_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<Option<String>, ErrorMessage> {
+        Ok(Some(
+            self.inner
+                .classify_at(metadata, now)?
+                .unwrap_or_else(|| self.default.clone()),
+        ))
+    }
+    fn classify_weighted_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<WeightedCategories, ErrorMessage> {
+        let categories = self.inner.classify_weighted_at(metadata, now)?;
+        if categories.is_empty() {
+            Ok(vec![(self.default.clone(), 1.)])
+        } else {
+            Ok(categories)
+        }
+    }
+    fn prepare_reload(&mut self) -> Result<ReloadCommit<'_>, ErrorMessage> {
+        self.inner.prepare_reload()
+    }
+    fn shutdown(&mut self) -> Result<(), ErrorMessage> {
+        self.inner.shutdown()
+    }
+    fn idle_timeout(&self, category: &str) -> Option<time::Duration> {
+        self.inner.idle_timeout(category)
+    }
+}
+
+/** Use the window class as category name.
+ * Categories are discovered at runtime, when a new class is seen.
+ * Windows without class use the default category, or no category.
+ */
+pub struct ClassAsCategoryClassifier {
+    default: Option<String>,
+    categories: UniqueCategories,
+}
+
+impl ClassAsCategoryClassifier {
+    pub fn new<S: Into<String>>(default: Option<S>) -> Self {
+        let default = default.map(|s| s.into());
+        ClassAsCategoryClassifier {
+            categories: UniqueCategories::make_unique(default.iter().cloned().collect()),
+            default: default,
+        }
+    }
+
+    pub fn doc() -> &'static str {
+        "Use the window class as category name.\n\
+         Each new class creates a new category in the database.\n\
+         Tabs and newlines in class names are converted to spaces.\n\
+         \n\
+         Windows without class (or an empty one) use the default category if defined.\n\
+         Otherwise the duration will be ignored."
+    }
+}
+impl Classifier for ClassAsCategoryClassifier {
+    fn categories(&self) -> UniqueCategories {
+        self.categories.clone()
+    }
+    fn classify(&mut self, metadata: ActiveWindowMetadata) -> Result<Option<String>, ErrorMessage> {
+        let category = match metadata.class {
+            // Category names must not break the database format
+            Some(ref class) if !class.is_empty() => {
+                Some(class.replace(|c| c == '\t' || c == '\n', " "))
+            }
+            _ => self.default.clone(),
+        };
+        if let Some(category) = &category {
+            if !self.categories.contains(category) {
+                self.categories
+                    .extend(UniqueCategories::make_unique(vec![category.clone()]));
+            }
+        }
+        Ok(category)
+    }
 }
 
 /** Classify using an external process.
@@ -23,16 +502,86 @@ pub trait Classifier {
  * The process must print the list of categories on stdout at startup.
  * Then for each line of metadata, print the category name on stdout.
  * An empty line is considered a None (no category), the time chunk will be ignored.
+ *
+ * Replies can be cached to skip the round trip for windows seen recently, see ReplyCache.
  */
 pub struct Process {
     child: process::Child,
     stdout: BufReader<process::ChildStdout>,
     categories: UniqueCategories,
+    cache: ReplyCache,
+}
+
+/** Least recently used cache of Process replies, by metadata line without pid.
+ * The pid changes for each run of the same program, so it would defeat the cache.
+ * Eviction scans all entries, which is fast enough for the small sizes used.
+ */
+struct ReplyCache {
+    capacity: usize,
+    entries: HashMap<String, (Option<String>, u64)>, // Category, last use
+    clock: u64,                                      // Incremented at each use
+    hits: u64,
+    misses: u64,
+}
+
+impl ReplyCache {
+    fn new(capacity: usize) -> Self {
+        ReplyCache {
+            capacity: capacity,
+            entries: HashMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<Option<String>> {
+        if self.capacity == 0 {
+            return None;
+        }
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some((category, last_use)) => {
+                *last_use = self.clock;
+                self.hits += 1;
+                debug!(
+                    "Process: cache hit ({} hits, {} misses)",
+                    self.hits, self.misses
+                );
+                Some(category.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: String, category: Option<String>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_use))| *last_use)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, (category, self.clock));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear()
+    }
 }
 
 impl Process {
-    /// Start a subprocess
-    pub fn new<C, I, S>(command: C, args: I) -> Result<Self, ErrorMessage>
+    /// Start a subprocess. Up to cache_size replies are cached, 0 disables the cache.
+    pub fn new<C, I, S>(command: C, args: I, cache_size: usize) -> Result<Self, ErrorMessage>
     where
         C: AsRef<OsStr>,
         I: IntoIterator<Item = S>,
@@ -50,7 +599,7 @@ impl Process {
             })?;
         // Send the field names (unbuffered!)
         Process::stdin(&mut child)
-            .write_all(b"title\tclass\n")
+            .write_all(b"title\tclass\tpid\tprocess_name\trole\tdesktop\ticon_name\tfullscreen\tinstance\n")
             .map_err(|e| ErrorMessage::new("Process: cannot write to stdin", e))?;
         // Extract stdout from child instance to wrap it in bufreader.
         let stdout = child.stdout.take().unwrap();
@@ -72,9 +621,80 @@ impl Process {
             child: child,
             stdout: stdout,
             categories: categories,
+            cache: ReplyCache::new(cache_size),
         })
     }
 
+    /// Exit status of the process if it has terminated, for error messages: " (exit code: 1)".
+    fn exit_status_text(&mut self) -> String {
+        match self.child.try_wait() {
+            Ok(Some(status)) => format!(" ({})", status),
+            _ => String::new(),
+        }
+    }
+
+    /// Close stdin to let the process terminate properly with EOF, then wait for it.
+    fn wait(&mut self) -> Result<(), ErrorMessage> {
+        drop(self.child.stdin.take());
+        let status = self
+            .child
+            .wait()
+            .map_err(|e| ErrorMessage::new("Process: wait() failed", e))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(ErrorMessage::from(format!(
+                "Process: terminated with failure ({})",
+                status
+            )))
+        }
+    }
+
+    /// Send a metadata line to the process, and read the category reply.
+    fn request(&mut self, metadata: &str) -> Result<Option<String>, ErrorMessage> {
+        let written = {
+            let stdin = Process::stdin(&mut self.child);
+            stdin
+                .write_all(metadata.as_bytes())
+                .and_then(|()| stdin.flush())
+        };
+        if let Err(e) = written {
+            return Err(match e.kind() {
+                io::ErrorKind::BrokenPipe => ErrorMessage::new(
+                    format!("Process: stdin was closed{}", self.exit_status_text()),
+                    e,
+                ),
+                _ => ErrorMessage::new("Process: cannot write to stdin", e),
+            });
+        }
+        // Receive category
+        let mut line = String::new();
+        let line_len = self
+            .stdout
+            .read_line(&mut line)
+            .map_err(|e| ErrorMessage::new("Process: cannot read reply line", e))?;
+        if line_len == 0 {
+            return Err(ErrorMessage::from(format!(
+                "Process: stdout was closed{}",
+                self.exit_status_text()
+            )));
+        }
+        if line.pop() != Some('\n') {
+            return Err(ErrorMessage::from("Process: unexpected end of output"));
+        }
+        // Filter
+        if line.is_empty() {
+            Ok(None)
+        } else if self.categories.contains(&line) {
+            Ok(Some(line))
+        } else {
+            Err(ErrorMessage::from(format!(
+                "Process: undeclared category '{}'",
+                line
+            )))
+        }
+    }
+
     fn stdin(child: &mut process::Child) -> &mut process::ChildStdin {
         // Stdin must have been piped by spawn, panic if not available.
         child.stdin.as_mut().expect("stdin undefined")
@@ -86,14 +706,22 @@ impl Process {
          On every update, the new window metadata is written to the process stdin.\n\
          Fields of metadata are on one line, tab separated.\n\
          Empty fields are encoded as empty strings (nothing between two tabs).\n\
+         The fullscreen field is 1 for a fullscreen window, else 0.\n\
          Each tab or newline in metadata field are converted to spaces.\n\
-         The initial line sent to the process contains the field names, tab separated.\n\
+         The initial line sent to the process contains the field names, tab separated:\n\
+         title, class, pid, process_name, role, desktop, icon_name, fullscreen, instance.\n\
+         New fields are only added at the end: find fields by position in the initial line,\n\
+         and ignore unknown trailing fields.\n\
          \n\
          The process must answer by writing lines to stdout.\n\
          It must write an initial line with all possible categories, tab separated.\n\
          For each metadata line, it must write a line containing the category name.\n\
          An empty line is interpreted as no category, and the duration will be ignored.\n\
          \n\
+         With --cache-size, replies are cached by metadata except pid, least recently used first out.\n\
+         The process must then reply the same category for the same metadata.\n\
+         The cache is cleared on SIGHUP.\n\
+         \n\
          IMPORTANT:\n\
          The classifier must output lines without buffering, or xstalker will be blocked.\n\
          Category names must not contain tabs or newlines."
@@ -101,8 +729,12 @@ impl Process {
 }
 impl Drop for Process {
     fn drop(&mut self) {
-        // child.wait will close stdin to let the process terminate properly with EOF.
-        self.child.wait().expect("Process: wait() failed");
+        // Fallback if shutdown() was not called (stdin still open): best effort, no panic.
+        if self.child.stdin.is_some() {
+            if let Err(e) = self.wait() {
+                error!("{}", e)
+            }
+        }
     }
 }
 impl Classifier for Process {
@@ -114,34 +746,1290 @@ impl Classifier for Process {
             Some(text) => text.replace(|c| c == '\t' || c == '\n', " "),
             None => String::new(),
         };
-        let metadata = format!(
-            "{}\t{}\n",
+        let pid = escape_field(metadata.pid.map(|pid| pid.to_string()));
+        let fullscreen = if metadata.fullscreen { "1" } else { "0" };
+        let (title, class, process_name, role, desktop, icon_name, instance) = (
             escape_field(metadata.title),
-            escape_field(metadata.class)
+            escape_field(metadata.class),
+            escape_field(metadata.process_name),
+            escape_field(metadata.role),
+            escape_field(metadata.desktop.map(|desktop| desktop.to_string())),
+            escape_field(metadata.icon_name),
+            escape_field(metadata.instance),
         );
-        Process::stdin(&mut self.child)
-            .write_all(metadata.as_bytes())
-            .map_err(|e| ErrorMessage::new("Process: cannot write to stdin", e))?;
-        // Receive category
-        let mut line = String::new();
-        self.stdout
-            .read_line(&mut line)
-            .map_err(|e| ErrorMessage::new("Process: cannot read reply line", e))?;
-        if line.pop() != Some('\n') {
-            return Err(ErrorMessage::from("Process: unexpected end of output"));
+        let cache_key = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            title, class, process_name, role, desktop, icon_name, fullscreen, instance
+        );
+        if let Some(category) = self.cache.get(&cache_key) {
+            return Ok(category);
         }
-        // Filter
-        if line.is_empty() {
-            Ok(None)
-        } else if self.categories.contains(&line) {
-            Ok(Some(line))
+        let metadata = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            title, class, pid, process_name, role, desktop, icon_name, fullscreen, instance
+        );
+        let category = self.request(&metadata)?;
+        self.cache.insert(cache_key, category.clone());
+        Ok(category)
+    }
+    fn prepare_reload(&mut self) -> Result<ReloadCommit<'_>, ErrorMessage> {
+        // The process may classify differently after its own reload
+        Ok(Box::new(move || self.cache.clear()))
+    }
+    fn shutdown(&mut self) -> Result<(), ErrorMessage> {
+        if self.cache.capacity > 0 {
+            info!(
+                "Process: reply cache had {} hits, {} misses",
+                self.cache.hits, self.cache.misses
+            )
+        }
+        self.wait()
+    }
+}
+
+/// Text of a metadata field for matching, undefined fields are empty strings.
+fn field_text(field: &Option<String>) -> &str {
+    field.as_ref().map(String::as_str).unwrap_or("")
+}
+
+/// Regex for a metadata field of a RegexClassifier rule. A negated regex matches if the field does not.
+pub struct FieldRegex {
+    regex: Regex,
+    negated: bool,
+}
+
+impl FieldRegex {
+    /// Parse a regex, negated if it starts with '!'.
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        let (pattern, negated) = if pattern.starts_with('!') {
+            (&pattern[1..], true)
         } else {
-            Err(ErrorMessage::from(format!(
-                "Process: undeclared category '{}'",
-                line
-            )))
+            (pattern, false)
+        };
+        Ok(FieldRegex {
+            regex: Regex::new(pattern)?,
+            negated: negated,
+        })
+    }
+
+    /// Regex matching any field.
+    pub fn any() -> Self {
+        FieldRegex {
+            regex: Regex::new("").unwrap(),
+            negated: false,
         }
     }
+
+    fn is_match(&self, field: &str) -> bool {
+        self.regex.is_match(field) != self.negated
+    }
 }
 
-// TODO classifier with simple text matching rules ?
+/** Rule for RegexClassifier: all regexes must match for the category to be selected.
+ * Fields match anything unless set: RegexRule::new("mail").class(regex).
+ */
+pub struct RegexRule {
+    category: String,
+    title: FieldRegex,
+    class: FieldRegex,
+    process_name: FieldRegex,
+    role: FieldRegex,
+    instance: FieldRegex,
+}
+
+impl RegexRule {
+    pub fn new<S: Into<String>>(category: S) -> Self {
+        RegexRule {
+            category: category.into(),
+            title: FieldRegex::any(),
+            class: FieldRegex::any(),
+            process_name: FieldRegex::any(),
+            role: FieldRegex::any(),
+            instance: FieldRegex::any(),
+        }
+    }
+
+    pub fn title(self, title: FieldRegex) -> Self {
+        RegexRule {
+            title: title,
+            ..self
+        }
+    }
+    pub fn class(self, class: FieldRegex) -> Self {
+        RegexRule {
+            class: class,
+            ..self
+        }
+    }
+    pub fn process_name(self, process_name: FieldRegex) -> Self {
+        RegexRule {
+            process_name: process_name,
+            ..self
+        }
+    }
+    pub fn role(self, role: FieldRegex) -> Self {
+        RegexRule { role: role, ..self }
+    }
+    pub fn instance(self, instance: FieldRegex) -> Self {
+        RegexRule {
+            instance: instance,
+            ..self
+        }
+    }
+
+    fn matches(&self, metadata: &ActiveWindowMetadata) -> bool {
+        self.title.is_match(field_text(&metadata.title))
+            && self.class.is_match(field_text(&metadata.class))
+            && self
+                .process_name
+                .is_match(field_text(&metadata.process_name))
+            && self.role.is_match(field_text(&metadata.role))
+            && self.instance.is_match(field_text(&metadata.instance))
+    }
+}
+
+/** Classify using an ordered list of regex rules.
+ *
+ * Rules are tested in order, and the category of the first matching rule is returned.
+ * A rule matches if all its regexes (title, class, process name, role, instance) match the
+ * metadata.
+ * Negated regexes must not match, which excludes windows from a rule.
+ * Undefined metadata fields are matched as empty strings.
+ * If no rule matches, the window has no category.
+ */
+#[derive(Default)]
+pub struct RegexClassifier {
+    rules: Vec<RegexRule>,
+    categories: UniqueCategories,
+    path: Option<PathBuf>, // Rules file, for reload. None for stdin
+}
+
+impl RegexClassifier {
+    /// Load rules from a file, or stdin if path is "-".
+    pub fn from_file(path: &Path) -> Result<Self, ErrorMessage> {
+        let text = read_rules_text(path).map_err(|e| {
+            ErrorMessage::new(format!("Cannot read rules file '{}'", path.display()), e)
+        })?;
+        let mut classifier = RegexClassifier::new();
+        for (line_index, line) in text.lines().enumerate() {
+            let line_nb = line_index + 1;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            let category = fields.next().unwrap_or("");
+            if category.is_empty() {
+                return Err(ErrorMessage::from(format!(
+                    "Rules line {}: empty category name",
+                    line_nb
+                )));
+            }
+            let mut next_regex = |field_name: &str| {
+                FieldRegex::new(fields.next().unwrap_or("")).map_err(|e| {
+                    ErrorMessage::new(
+                        format!("Rules line {}: invalid {} regex", line_nb, field_name),
+                        e,
+                    )
+                })
+            };
+            let rule = RegexRule::new(category)
+                .title(next_regex("title")?)
+                .class(next_regex("class")?)
+                .process_name(next_regex("process name")?)
+                .role(next_regex("role")?)
+                .instance(next_regex("instance")?);
+            classifier.push_rule(rule);
+        }
+        classifier.path = reload_path(path);
+        Ok(classifier)
+    }
+
+    /// Create a classifier without rules.
+    pub fn new() -> Self {
+        RegexClassifier::default()
+    }
+
+    /// Add a rule, tested after all previously added rules.
+    pub fn push_rule(&mut self, rule: RegexRule) -> &mut Self {
+        self.categories
+            .extend(UniqueCategories::make_unique(vec![rule.category.clone()]));
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn doc() -> &'static str {
+        "Classify using rules from a text file.\n\
+         \n\
+         If multiple files are given, their rules are tested one file after the other.\n\
+         Each line of a file defines a rule, with tab separated fields:\n\
+         category<tab>title_regex<tab>class_regex<tab>process_name_regex<tab>role_regex<tab>instance_regex\n\
+         For X11 windows, instance and class are the two parts of WM_CLASS: Navigator and Firefox.\n\
+         Empty lines and lines starting with '#' are ignored.\n\
+         Missing or empty regexes match anything.\n\
+         A regex starting with '!' is negated: it matches fields not matching the rest.\n\
+         Use [!] to match a leading '!' instead.\n\
+         \n\
+         Rules are tested in order, the first rule where all regexes match gives the category.\n\
+         Regexes are not anchored: use ^ and $ to match the whole field.\n\
+         Undefined metadata fields are matched as empty strings.\n\
+         If no rule matches, the duration will be ignored.\n\
+         \n\
+         A path of '-' reads rules from stdin, once at startup.\n\
+         The rules file is reloaded on SIGHUP, except stdin."
+    }
+}
+impl Classifier for RegexClassifier {
+    fn categories(&self) -> UniqueCategories {
+        self.categories.clone()
+    }
+    fn classify(&mut self, metadata: ActiveWindowMetadata) -> Result<Option<String>, ErrorMessage> {
+        Ok(self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(&metadata))
+            .map(|rule| rule.category.clone()))
+    }
+    fn prepare_reload(&mut self) -> Result<ReloadCommit<'_>, ErrorMessage> {
+        let reloaded = match &self.path {
+            Some(path) => Some(RegexClassifier::from_file(path)?),
+            None => None,
+        };
+        Ok(replace_with(self, reloaded))
+    }
+}
+
+/// How a config rule pattern is compared to a metadata field.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    Exact,
+    Contains,
+    Regex,
+    /// Shell glob on the whole field: * (any text), ? (any character), [a-z] (character class)
+    Glob,
+}
+
+impl MatchMode {
+    pub fn from_name(name: &str) -> Result<Self, ErrorMessage> {
+        match name {
+            "exact" => Ok(MatchMode::Exact),
+            "contains" => Ok(MatchMode::Contains),
+            "regex" => Ok(MatchMode::Regex),
+            "glob" => Ok(MatchMode::Glob),
+            _ => Err(ErrorMessage::from(format!("Unknown match mode '{}'", name))),
+        }
+    }
+}
+
+/// Pattern for a metadata field, as written in the config file: text, or table with settings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FieldPattern {
+    Text(String),
+    Detailed {
+        pattern: String,
+        mode: Option<MatchMode>,
+        case_insensitive: Option<bool>,
+    },
+}
+
+/// Rule for ConfigClassifier, as written in the config file.
+#[derive(Deserialize)]
+struct ConfigRule {
+    category: String,
+    title: Option<FieldPattern>,
+    class: Option<FieldPattern>,
+    instance: Option<FieldPattern>,
+    process_name: Option<FieldPattern>,
+    role: Option<FieldPattern>,
+    /// Icon name, X11 only
+    icon_name: Option<FieldPattern>,
+    /// Virtual desktop index, from 0
+    desktop: Option<u32>,
+    /// Fullscreen state of the window
+    fullscreen: Option<bool>,
+    /// Patterns excluding windows from this rule
+    exclude: Option<ExcludePatterns>,
+    /// Local time of day ("HH:MM") from which the rule matches
+    after: Option<String>,
+    /// Local time of day ("HH:MM") until which the rule matches
+    before: Option<String>,
+    /// Default mode for fields of this rule
+    mode: Option<MatchMode>,
+    /// Default case sensitivity for fields of this rule
+    case_insensitive: Option<bool>,
+    /// Fractions of time given to other categories, the rest goes to category
+    #[serde(default)]
+    split: BTreeMap<String, f32>,
+}
+
+/// Exclusion patterns of a ConfigRule: the rule does not match if any of them matches.
+#[derive(Deserialize)]
+struct ExcludePatterns {
+    title: Option<FieldPattern>,
+    class: Option<FieldPattern>,
+    instance: Option<FieldPattern>,
+    process_name: Option<FieldPattern>,
+    role: Option<FieldPattern>,
+}
+
+/// How ConfigClassifier chooses between matching rules.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum MatchStrategy {
+    /// First matching rule in file order
+    First,
+    /// Most specific matching rule, see CompiledConfigRule::specificity()
+    Best,
+}
+
+impl Default for MatchStrategy {
+    fn default() -> Self {
+        MatchStrategy::First
+    }
+}
+
+/// Layout of the ConfigClassifier config file.
+#[derive(Deserialize)]
+struct Config {
+    default_category: Option<String>,
+    #[serde(default)]
+    match_strategy: MatchStrategy,
+    /// Expand environment variables in patterns, opt-in as it changes the meaning of $
+    #[serde(default)]
+    expand_env: bool,
+    /// Idle timeout durations by category
+    #[serde(default)]
+    idle_timeouts: BTreeMap<String, String>,
+    #[serde(default, rename = "rule")]
+    rules: Vec<ConfigRule>,
+}
+
+/// Compiled pattern for a metadata field. Case insensitive exact and contains texts are lowercase.
+enum FieldMatcher {
+    Exact {
+        text: String,
+        case_insensitive: bool,
+    },
+    Contains {
+        text: String,
+        case_insensitive: bool,
+    },
+    Regex(Regex),
+    Glob(GlobMatcher),
+}
+
+impl FieldMatcher {
+    /// Settings of the field take precedence over settings of the rule.
+    /// Environment variables in the pattern text are expanded if requested.
+    fn new(
+        pattern: &FieldPattern,
+        rule: &ConfigRule,
+        expand_env_vars: bool,
+    ) -> Result<Self, ErrorMessage> {
+        let (text, mode, case_insensitive) = match pattern {
+            FieldPattern::Text(text) => (text, None, None),
+            FieldPattern::Detailed {
+                pattern,
+                mode,
+                case_insensitive,
+            } => (pattern, *mode, *case_insensitive),
+        };
+        let mode = mode.or(rule.mode).unwrap_or(MatchMode::Exact);
+        let case_insensitive = case_insensitive.or(rule.case_insensitive).unwrap_or(false);
+        let text = if expand_env_vars {
+            expand_env(text)?
+        } else {
+            text.clone()
+        };
+        FieldMatcher::compile(text, mode, case_insensitive)
+    }
+
+    fn compile(
+        text: String,
+        mode: MatchMode,
+        case_insensitive: bool,
+    ) -> Result<Self, ErrorMessage> {
+        // Only text patterns are lowercased: in regexes, case changes escapes like \D to \d.
+        let lowercase_if_insensitive = |text: String| {
+            if case_insensitive {
+                text.to_lowercase()
+            } else {
+                text
+            }
+        };
+        Ok(match mode {
+            MatchMode::Exact => FieldMatcher::Exact {
+                text: lowercase_if_insensitive(text),
+                case_insensitive: case_insensitive,
+            },
+            MatchMode::Contains => FieldMatcher::Contains {
+                text: lowercase_if_insensitive(text),
+                case_insensitive: case_insensitive,
+            },
+            MatchMode::Regex => FieldMatcher::Regex(
+                RegexBuilder::new(&text)
+                    .case_insensitive(case_insensitive)
+                    .build()
+                    .map_err(|e| ErrorMessage::new("Invalid regex", e))?,
+            ),
+            MatchMode::Glob => FieldMatcher::Glob(
+                GlobBuilder::new(&text)
+                    .case_insensitive(case_insensitive)
+                    .build()
+                    .map_err(|e| ErrorMessage::new("Invalid glob", e))?
+                    .compile_matcher(),
+            ),
+        })
+    }
+
+    fn matches(&self, field: &str) -> bool {
+        // The field is only copied for case insensitive patterns, which are already lowercase.
+        let lowercase_if = |case_insensitive: bool| -> Cow<str> {
+            if case_insensitive {
+                Cow::Owned(field.to_lowercase())
+            } else {
+                Cow::Borrowed(field)
+            }
+        };
+        match self {
+            FieldMatcher::Exact {
+                text,
+                case_insensitive,
+            } => lowercase_if(*case_insensitive) == text.as_str(),
+            FieldMatcher::Contains {
+                text,
+                case_insensitive,
+            } => lowercase_if(*case_insensitive).contains(text.as_str()),
+            FieldMatcher::Regex(regex) => regex.is_match(field),
+            FieldMatcher::Glob(glob) => glob.is_match(field),
+        }
+    }
+
+    /// Length of the matched text, in bytes: whole field, pattern text, or regex match.
+    fn matched_length(&self, field: &str) -> usize {
+        match self {
+            FieldMatcher::Exact { .. } | FieldMatcher::Glob(_) => field.len(),
+            FieldMatcher::Contains { text, .. } => text.len(),
+            FieldMatcher::Regex(regex) => regex.find(field).map_or(0, |m| m.end() - m.start()),
+        }
+    }
+}
+
+/** Windows never tracked, for privacy: password manager, screen locker.
+ * Unlike a category, nothing is recorded while they are active, not even in logs.
+ * Patterns are case sensitive, and compared with the same modes as ConfigClassifier.
+ */
+pub struct ExcludedWindows {
+    classes: Vec<FieldMatcher>,
+    titles: Vec<FieldMatcher>,
+}
+
+impl ExcludedWindows {
+    pub fn new(
+        classes: &[String],
+        titles: &[String],
+        mode: MatchMode,
+    ) -> Result<Self, ErrorMessage> {
+        let compile = |patterns: &[String], field: &str| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    FieldMatcher::compile(pattern.clone(), mode, false).map_err(|e| {
+                        ErrorMessage::new(format!("Invalid excluded {} '{}'", field, pattern), e)
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(ExcludedWindows {
+            classes: compile(classes, "class")?,
+            titles: compile(titles, "title")?,
+        })
+    }
+
+    /// True if any pattern matches. Windows without class or title are not excluded by them.
+    pub fn matches(&self, metadata: &ActiveWindowMetadata) -> bool {
+        let any_match = |matchers: &[FieldMatcher], field: &Option<String>| match field {
+            Some(text) => matchers.iter().any(|matcher| matcher.matches(text)),
+            None => false,
+        };
+        any_match(&self.classes, &metadata.class) || any_match(&self.titles, &metadata.title)
+    }
+}
+
+/// Compiled rule for ConfigClassifier. Undefined field matchers match anything.
+struct CompiledConfigRule {
+    category: String,
+    title: Option<FieldMatcher>,
+    class: Option<FieldMatcher>,
+    instance: Option<FieldMatcher>,
+    process_name: Option<FieldMatcher>,
+    role: Option<FieldMatcher>,
+    icon_name: Option<FieldMatcher>,
+    desktop: Option<u32>,
+    fullscreen: Option<bool>,
+    /// Exclusion matchers for title, class, instance, process name and role.
+    /// Undefined ones never match.
+    exclude: [Option<FieldMatcher>; 5],
+    after: Option<chrono::NaiveTime>,
+    before: Option<chrono::NaiveTime>,
+    /// Categories sharing the time, category first. Weights sum to 1.
+    weighted_categories: WeightedCategories,
+}
+
+/** Check if the time of day is in the range from after (included) to before (excluded).
+ * If after is later than before, the range wraps around midnight.
+ * An undefined bound is not checked.
+ */
+fn time_of_day_matches(
+    after: Option<chrono::NaiveTime>,
+    before: Option<chrono::NaiveTime>,
+    time: chrono::NaiveTime,
+) -> bool {
+    match (after, before) {
+        (Some(after), Some(before)) if after > before => time >= after || time < before,
+        (after, before) => {
+            after.map_or(true, |after| time >= after) && before.map_or(true, |before| time < before)
+        }
+    }
+}
+
+impl CompiledConfigRule {
+    fn new(rule: ConfigRule, rule_nb: usize, expand_env_vars: bool) -> Result<Self, ErrorMessage> {
+        let compile = |pattern: &Option<FieldPattern>, field_name: &str| match pattern {
+            Some(pattern) => FieldMatcher::new(pattern, &rule, expand_env_vars)
+                .map(Some)
+                .map_err(|e| {
+                    ErrorMessage::new(
+                        format!("Rule {}: invalid {} pattern", rule_nb, field_name),
+                        e,
+                    )
+                }),
+            None => Ok(None),
+        };
+        let exclude = match &rule.exclude {
+            Some(exclude) => [
+                compile(&exclude.title, "exclude title")?,
+                compile(&exclude.class, "exclude class")?,
+                compile(&exclude.instance, "exclude instance")?,
+                compile(&exclude.process_name, "exclude process_name")?,
+                compile(&exclude.role, "exclude role")?,
+            ],
+            None => [None, None, None, None, None],
+        };
+        let parse_time = |text: &Option<String>, bound_name: &str| match text {
+            Some(text) => chrono::NaiveTime::parse_from_str(text, "%H:%M")
+                .map(Some)
+                .map_err(|e| {
+                    ErrorMessage::new(
+                        format!("Rule {}: invalid {} time '{}'", rule_nb, bound_name, text),
+                        e,
+                    )
+                }),
+            None => Ok(None),
+        };
+        let mut weighted_categories = vec![(rule.category.clone(), 1.)];
+        for (category, fraction) in &rule.split {
+            if !fraction.is_finite() || *fraction <= 0. || *category == rule.category {
+                return Err(ErrorMessage::from(format!(
+                    "Rule {}: invalid split fraction for '{}'",
+                    rule_nb, category
+                )));
+            }
+            weighted_categories[0].1 -= fraction;
+            weighted_categories.push((category.clone(), *fraction))
+        }
+        if weighted_categories[0].1 <= 0. {
+            return Err(ErrorMessage::from(format!(
+                "Rule {}: split fractions must sum to less than 1",
+                rule_nb
+            )));
+        }
+        Ok(CompiledConfigRule {
+            weighted_categories: weighted_categories,
+            after: parse_time(&rule.after, "after")?,
+            before: parse_time(&rule.before, "before")?,
+            title: compile(&rule.title, "title")?,
+            class: compile(&rule.class, "class")?,
+            instance: compile(&rule.instance, "instance")?,
+            process_name: compile(&rule.process_name, "process_name")?,
+            role: compile(&rule.role, "role")?,
+            icon_name: compile(&rule.icon_name, "icon_name")?,
+            desktop: rule.desktop,
+            fullscreen: rule.fullscreen,
+            exclude: exclude,
+            category: rule.category.clone(),
+        })
+    }
+
+    /// Undefined metadata fields are empty strings. Time of day bounds are checked against now.
+    fn matches(&self, metadata: &ActiveWindowMetadata, now: chrono::NaiveTime) -> bool {
+        let field_matches = |matcher: &Option<FieldMatcher>, field: &Option<String>| match matcher {
+            Some(matcher) => matcher.matches(field_text(field)),
+            None => true,
+        };
+        let excluded = self
+            .exclude
+            .iter()
+            .zip(&[
+                &metadata.title,
+                &metadata.class,
+                &metadata.instance,
+                &metadata.process_name,
+                &metadata.role,
+            ])
+            .any(|(matcher, field)| match matcher {
+                Some(matcher) => matcher.matches(field_text(field)),
+                None => false,
+            });
+        field_matches(&self.title, &metadata.title)
+            && field_matches(&self.class, &metadata.class)
+            && field_matches(&self.instance, &metadata.instance)
+            && field_matches(&self.process_name, &metadata.process_name)
+            && field_matches(&self.role, &metadata.role)
+            && field_matches(&self.icon_name, &metadata.icon_name)
+            && self
+                .desktop
+                .map_or(true, |desktop| metadata.desktop == Some(desktop))
+            && self
+                .fullscreen
+                .map_or(true, |fullscreen| metadata.fullscreen == fullscreen)
+            && !excluded
+            && time_of_day_matches(self.after, self.before, now)
+    }
+
+    /** Specificity of a matching rule, None if the rule does not match.
+     * Compared as (number of conditions, matched length): the number of defined patterns
+     * and time of day bounds, then the total length of text matched by the patterns.
+     */
+    fn specificity(
+        &self,
+        metadata: &ActiveWindowMetadata,
+        now: chrono::NaiveTime,
+    ) -> Option<(usize, usize)> {
+        if !self.matches(metadata, now) {
+            return None;
+        }
+        let patterns = [
+            (&self.title, &metadata.title),
+            (&self.class, &metadata.class),
+            (&self.instance, &metadata.instance),
+            (&self.process_name, &metadata.process_name),
+            (&self.role, &metadata.role),
+            (&self.icon_name, &metadata.icon_name),
+        ];
+        let mut nb_conditions = self.after.iter().count()
+            + self.before.iter().count()
+            + self.desktop.iter().count()
+            + self.fullscreen.iter().count();
+        let mut matched_length = 0;
+        for (matcher, field) in patterns.iter() {
+            if let Some(matcher) = matcher {
+                nb_conditions += 1;
+                matched_length += matcher.matched_length(field_text(field));
+            }
+        }
+        Some((nb_conditions, matched_length))
+    }
+}
+
+/** Classify using rules from a TOML config file.
+ *
+ * Rules are tested in order, and the category of the first matching rule is returned.
+ * With the best match strategy, the most specific matching rule is used instead.
+ * If no rule matches, the default category is returned if defined, or None otherwise.
+ */
+pub struct ConfigClassifier {
+    default_category: Option<String>,
+    match_strategy: MatchStrategy,
+    idle_timeouts: HashMap<String, time::Duration>,
+    rules: Vec<CompiledConfigRule>,
+    categories: UniqueCategories,
+    path: Option<PathBuf>, // Config file, for reload. None for stdin
+}
+
+impl ConfigClassifier {
+    /// Load config from a file, or stdin if path is "-".
+    pub fn from_file(path: &Path) -> Result<Self, ErrorMessage> {
+        let text = read_rules_text(path).map_err(|e| {
+            ErrorMessage::new(format!("Cannot read config file '{}'", path.display()), e)
+        })?;
+        let mut classifier = ConfigClassifier::from_text(&text, path)?;
+        classifier.path = reload_path(path);
+        Ok(classifier)
+    }
+
+    /// Load config from its text. The path is only used in error messages.
+    fn from_text(text: &str, path: &Path) -> Result<Self, ErrorMessage> {
+        let config: Config = toml::from_str(text).map_err(|e| {
+            ErrorMessage::new(format!("Cannot parse config file '{}'", path.display()), e)
+        })?;
+        let categories = UniqueCategories::make_unique(
+            config
+                .rules
+                .iter()
+                .flat_map(|r| {
+                    Some(r.category.clone())
+                        .into_iter()
+                        .chain(r.split.keys().cloned())
+                })
+                .chain(config.default_category.clone())
+                .collect(),
+        );
+        let mut rules = Vec::with_capacity(config.rules.len());
+        for (index, rule) in config.rules.into_iter().enumerate() {
+            rules.push(
+                CompiledConfigRule::new(rule, index + 1, config.expand_env).map_err(|e| {
+                    ErrorMessage::new(format!("Invalid config file '{}'", path.display()), e)
+                })?,
+            )
+        }
+        let mut idle_timeouts = HashMap::new();
+        for (category, text) in config.idle_timeouts {
+            let timeout = super::parse_duration(&text).map_err(|e| {
+                ErrorMessage::new(
+                    format!(
+                        "Invalid config file '{}': idle timeout of '{}'",
+                        path.display(),
+                        category
+                    ),
+                    e,
+                )
+            })?;
+            idle_timeouts.insert(category, timeout);
+        }
+        Ok(ConfigClassifier {
+            default_category: config.default_category,
+            match_strategy: config.match_strategy,
+            idle_timeouts: idle_timeouts,
+            rules: rules,
+            categories: categories,
+            path: None,
+        })
+    }
+
+    /// Rule giving the category, according to the match strategy.
+    fn matching_rule(
+        &self,
+        metadata: &ActiveWindowMetadata,
+        time: chrono::NaiveTime,
+    ) -> Option<&CompiledConfigRule> {
+        match self.match_strategy {
+            MatchStrategy::First => self.rules.iter().find(|rule| rule.matches(metadata, time)),
+            MatchStrategy::Best => {
+                let mut best: Option<(&CompiledConfigRule, (usize, usize))> = None;
+                for rule in &self.rules {
+                    match (rule.specificity(metadata, time), best) {
+                        // Strictly greater: the first rule wins ties
+                        (Some(specificity), Some((_, best_specificity)))
+                            if specificity <= best_specificity => {}
+                        (Some(specificity), _) => best = Some((rule, specificity)),
+                        (None, _) => {}
+                    }
+                }
+                best.map(|(rule, _)| rule)
+            }
+        }
+    }
+
+    pub fn doc() -> &'static str {
+        "Classify using rules from a TOML config file.\n\
+         \n\
+         Example:\n\
+         default_category = \"other\"\n\
+         [[rule]]\n\
+         category = \"coding\"\n\
+         class = \"konsole\"\n\
+         [[rule]]\n\
+         category = \"mail\"\n\
+         mode = \"contains\"\n\
+         title = { pattern = \"inbox\", case_insensitive = true }\n\
+         role = { pattern = \"^(3pane|Msgcompose)$\", mode = \"regex\" }\n\
+         [[rule]]\n\
+         category = \"research\"\n\
+         class = \"Firefox\"\n\
+         exclude = { title = { pattern = \"YouTube\", mode = \"contains\" } }\n\
+         [[rule]]\n\
+         category = \"overtime\"\n\
+         class = \"konsole\"\n\
+         after = \"18:00\"\n\
+         before = \"06:00\"\n\
+         \n\
+         Each rule may define title, class, instance, process_name and role patterns.\n\
+         For X11 windows, instance and class are the two parts of WM_CLASS: Navigator and Firefox.\n\
+         A pattern is a text, or a table with pattern, and optional mode and case_insensitive.\n\
+         Modes are exact (whole field), contains (substring), regex (not anchored),\n\
+         or glob (whole field, * for any text, ? for any character, [a-z] for a character class).\n\
+         Settings of a pattern table take precedence over mode and case_insensitive of the rule,\n\
+         which take precedence over the defaults: exact and case sensitive.\n\
+         Undefined patterns match anything, undefined metadata fields are empty strings.\n\
+         With expand_env = true at the top level, environment variables are expanded in patterns:\n\
+         $NAME or ${NAME}, and $$ for a literal $. Undefined variables are an error.\n\
+         A $ not followed by a name is kept, like the regex end anchor.\n\
+         The exclude table has patterns with the same syntax and settings:\n\
+         the rule does not match if any of them matches.\n\
+         icon_name is also a pattern, for the X11 WM_ICON_NAME: some terminals show the command there.\n\
+         desktop = 2 restricts the rule to a virtual desktop, numbered from 0 (X11 only).\n\
+         fullscreen = true or false restricts the rule to fullscreen or windowed windows.\n\
+         after and before (HH:MM, local time) restrict the rule to a time of day range,\n\
+         which wraps around midnight if after is later than before.\n\
+         split gives fractions of the time to other categories, the rest goes to category:\n\
+         split = { personal = 0.5 }\n\
+         Fractions must sum to less than 1. Use --precision ms to keep fractions of seconds.\n\
+         \n\
+         Rules are tested in order, the first rule where all patterns match gives the category.\n\
+         With match_strategy = \"best\" at the top level, the most specific matching rule is used:\n\
+         the rule with the most patterns, desktop, fullscreen and after/before bounds,\n\
+         then the longest matched text\n\
+         (whole field for exact and glob, pattern for contains, match for regex), summed over patterns.\n\
+         Remaining ties are broken by rule order, the first rule wins.\n\
+         If no rule matches, default_category is used.\n\
+         Without default_category, the duration will be ignored.\n\
+         \n\
+         Idle timeouts can be set by category, overriding --idle-timeout:\n\
+         [idle_timeouts]\n\
+         documentation = \"15m\"\n\
+         They apply to the category of the active window, which can change while idle:\n\
+         the idle time is compared to the timeout of the new category at the next check.\n\
+         Idle detection must be enabled with --idle-timeout.\n\
+         \n\
+         A path of '-' reads the config from stdin, once at startup.\n\
+         The config file is reloaded on SIGHUP, except stdin."
+    }
+}
+impl Classifier for ConfigClassifier {
+    fn categories(&self) -> UniqueCategories {
+        self.categories.clone()
+    }
+    fn classify(&mut self, metadata: ActiveWindowMetadata) -> Result<Option<String>, ErrorMessage> {
+        self.classify_at(metadata, chrono::Local::now())
+    }
+    fn classify_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<Option<String>, ErrorMessage> {
+        Ok(self
+            .matching_rule(&metadata, now.time())
+            .map(|rule| &rule.category)
+            .or(self.default_category.as_ref())
+            .cloned())
+    }
+    fn classify_weighted_at(
+        &mut self,
+        metadata: ActiveWindowMetadata,
+        now: DatabaseTime,
+    ) -> Result<WeightedCategories, ErrorMessage> {
+        Ok(match self.matching_rule(&metadata, now.time()) {
+            Some(rule) => rule.weighted_categories.clone(),
+            None => self
+                .default_category
+                .iter()
+                .map(|category| (category.clone(), 1.))
+                .collect(),
+        })
+    }
+    fn prepare_reload(&mut self) -> Result<ReloadCommit<'_>, ErrorMessage> {
+        let reloaded = match &self.path {
+            Some(path) => Some(ConfigClassifier::from_file(path)?),
+            None => None,
+        };
+        Ok(replace_with(self, reloaded))
+    }
+    fn idle_timeout(&self, category: &str) -> Option<time::Duration> {
+        self.idle_timeouts.get(category).cloned()
+    }
+}
+
+/** Classify by calling functions of a rhai script.
+ * The script is compiled once, and functions are called on an empty scope for each window.
+ * Script errors are returned with the script error as cause, like other classifier errors.
+ */
+pub struct ScriptClassifier {
+    engine: rhai::Engine,
+    ast: rhai::AST,
+    categories: UniqueCategories,
+    path: Option<PathBuf>, // Script file, for reload. None for stdin
+}
+
+impl ScriptClassifier {
+    /// Load script from a file, or stdin if path is "-".
+    pub fn from_file(path: &Path) -> Result<Self, ErrorMessage> {
+        let text = read_rules_text(path).map_err(|e| {
+            ErrorMessage::new(format!("Cannot read script file '{}'", path.display()), e)
+        })?;
+        let engine = rhai::Engine::new();
+        let ast = engine.compile(&text).map_err(|e| {
+            ErrorMessage::new(format!("Cannot parse script file '{}'", path.display()), e)
+        })?;
+        let categories: rhai::Array = engine
+            .call_fn(&mut rhai::Scope::new(), &ast, "categories", ())
+            .map_err(|e| ErrorMessage::new("Script categories() failed", e))?;
+        let categories = categories
+            .into_iter()
+            .map(|category| {
+                let type_name = category.type_name();
+                category.try_cast::<String>().ok_or_else(|| {
+                    ErrorMessage::from(format!(
+                        "Script categories(): expected strings, got {}",
+                        type_name
+                    ))
+                })
+            })
+            .collect::<Result<Vec<String>, ErrorMessage>>()?;
+        Ok(ScriptClassifier {
+            engine: engine,
+            ast: ast,
+            categories: UniqueCategories::make_unique(categories),
+            path: reload_path(path),
+        })
+    }
+
+    pub fn doc() -> &'static str {
+        "Classify using functions of a rhai script.\n\
+         \n\
+         The script must define two functions:\n\
+         categories(): returns the array of category names, called once at startup.\n\
+         classify(title, class): returns the category name, or () if the window is not matched.\n\
+         Undefined metadata fields are given as empty strings.\n\
+         Returning a category not in categories() is an error.\n\
+         \n\
+         A path of '-' reads the script from stdin, once at startup.\n\
+         The script file is reloaded on SIGHUP, except stdin."
+    }
+}
+impl Classifier for ScriptClassifier {
+    fn categories(&self) -> UniqueCategories {
+        self.categories.clone()
+    }
+    fn classify(&mut self, metadata: ActiveWindowMetadata) -> Result<Option<String>, ErrorMessage> {
+        let title = String::from(field_text(&metadata.title));
+        let class = String::from(field_text(&metadata.class));
+        let category: rhai::Dynamic = self
+            .engine
+            .call_fn(
+                &mut rhai::Scope::new(),
+                &self.ast,
+                "classify",
+                (title, class),
+            )
+            .map_err(|e| ErrorMessage::new("Script classify() failed", e))?;
+        if category.is::<()>() {
+            return Ok(None);
+        }
+        let type_name = category.type_name();
+        match category.try_cast::<String>() {
+            Some(category) => Ok(Some(category)),
+            None => Err(ErrorMessage::from(format!(
+                "Script classify(): expected string or (), got {}",
+                type_name
+            ))),
+        }
+    }
+    fn prepare_reload(&mut self) -> Result<ReloadCommit<'_>, ErrorMessage> {
+        let reloaded = match &self.path {
+            Some(path) => Some(ScriptClassifier::from_file(path)?),
+            None => None,
+        };
+        Ok(replace_with(self, reloaded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(pattern: &str, mode: MatchMode, case_insensitive: bool) -> FieldMatcher {
+        FieldMatcher::compile(String::from(pattern), mode, case_insensitive).unwrap()
+    }
+
+    #[test]
+    fn title_differing_by_case() {
+        let title = "Inbox - Mozilla Thunderbird";
+        let patterns = [
+            (MatchMode::Exact, "inbox - mozilla thunderbird"),
+            (MatchMode::Contains, "thunderbird"),
+            (MatchMode::Regex, "^inbox .*bird$"),
+            (MatchMode::Glob, "inbox - *"),
+        ];
+        for &(mode, pattern) in patterns.iter() {
+            assert!(!matcher(pattern, mode, false).matches(title), "{:?}", mode);
+            assert!(matcher(pattern, mode, true).matches(title), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn case_insensitive_pattern_may_have_capitals() {
+        let title = "Inbox - Mozilla Thunderbird";
+        assert!(matcher("INBOX - Mozilla Thunderbird", MatchMode::Exact, true).matches(title));
+        assert!(matcher("ThunderBird", MatchMode::Contains, true).matches(title));
+        assert!(matcher("Inbox - Mozilla Thunderbird", MatchMode::Exact, false).matches(title));
+        assert!(!matcher("Inbox", MatchMode::Exact, false).matches(title));
+    }
+
+    fn category(classifier: &mut dyn Classifier, title: &str, class: &str) -> Option<String> {
+        classifier
+            .classify(ActiveWindowMetadata::for_test(Some(title), Some(class)))
+            .unwrap()
+    }
+
+    #[test]
+    fn negated_regex_leaves_window_to_later_rule() {
+        let mut classifier = RegexClassifier::new();
+        classifier
+            .push_rule(
+                RegexRule::new("research")
+                    .title(FieldRegex::new("!YouTube").unwrap())
+                    .class(FieldRegex::new("^Firefox$").unwrap()),
+            )
+            .push_rule(RegexRule::new("leisure").title(FieldRegex::new("YouTube").unwrap()));
+        let classifier = &mut classifier;
+        assert_eq!(
+            category(classifier, "Cats - YouTube - Mozilla Firefox", "Firefox"),
+            Some("leisure".into())
+        );
+        assert_eq!(
+            category(classifier, "Rust docs - Mozilla Firefox", "Firefox"),
+            Some("research".into())
+        );
+        assert_eq!(category(classifier, "", "Firefox"), Some("research".into()));
+        assert_eq!(category(classifier, "Rust docs", "Konsole"), None);
+    }
+
+    #[test]
+    fn excluded_window_goes_to_later_rule() {
+        let config = r#"
+            [[rule]]
+            category = "research"
+            class = "Firefox"
+            exclude = { title = { pattern = "YouTube", mode = "contains" } }
+
+            [[rule]]
+            category = "leisure"
+            title = { pattern = "YouTube", mode = "contains" }
+        "#;
+        let mut classifier = ConfigClassifier::from_text(config, Path::new("test.toml")).unwrap();
+        let classifier = &mut classifier;
+        assert_eq!(
+            category(classifier, "Cats - YouTube - Mozilla Firefox", "Firefox"),
+            Some("leisure".into())
+        );
+        assert_eq!(
+            category(classifier, "Rust docs - Mozilla Firefox", "Firefox"),
+            Some("research".into())
+        );
+        assert_eq!(category(classifier, "Rust docs", "Konsole"), None);
+    }
+
+    fn time(hour: u32, minute: u32) -> chrono::NaiveTime {
+        chrono::NaiveTime::from_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn time_of_day_range_wraps_past_midnight() {
+        let (after, before) = (Some(time(18, 0)), Some(time(9, 0)));
+        assert!(time_of_day_matches(after, before, time(18, 0)));
+        assert!(time_of_day_matches(after, before, time(23, 59)));
+        assert!(time_of_day_matches(after, before, time(0, 0)));
+        assert!(time_of_day_matches(after, before, time(8, 59)));
+        assert!(!time_of_day_matches(after, before, time(9, 0)));
+        assert!(!time_of_day_matches(after, before, time(12, 0)));
+        assert!(!time_of_day_matches(after, before, time(17, 59)));
+    }
+
+    #[test]
+    fn time_of_day_range_within_a_day() {
+        let (after, before) = (Some(time(9, 0)), Some(time(12, 0)));
+        assert!(!time_of_day_matches(after, before, time(8, 59)));
+        assert!(time_of_day_matches(after, before, time(9, 0)));
+        assert!(!time_of_day_matches(after, before, time(12, 0)));
+        assert!(time_of_day_matches(None, before, time(0, 0)));
+        assert!(time_of_day_matches(after, None, time(23, 59)));
+        assert!(time_of_day_matches(None, None, time(3, 0)));
+    }
+
+    #[test]
+    fn rule_after_evening_matches_until_morning() {
+        use chrono::TimeZone;
+        let config = r#"
+            default_category = "deep-work"
+
+            [[rule]]
+            category = "overtime"
+            after = "18:00"
+            before = "09:00"
+        "#;
+        let mut classifier = ConfigClassifier::from_text(config, Path::new("test.toml")).unwrap();
+        let mut category_at = |hour: u32, minute: u32| {
+            let now = chrono::Local.ymd(2020, 1, 1).and_hms(hour, minute, 0);
+            let metadata = ActiveWindowMetadata::for_test(Some("main.rs"), Some("Code"));
+            classifier.classify_at(metadata, now).unwrap()
+        };
+        assert_eq!(category_at(8, 30), Some("overtime".into()));
+        assert_eq!(category_at(9, 0), Some("deep-work".into()));
+        assert_eq!(category_at(17, 59), Some("deep-work".into()));
+        assert_eq!(category_at(18, 0), Some("overtime".into()));
+        assert_eq!(category_at(23, 30), Some("overtime".into()));
+        assert_eq!(category_at(0, 15), Some("overtime".into()));
+    }
+
+    #[test]
+    fn regex_rule_matches_instance() {
+        let mut classifier = RegexClassifier::new();
+        classifier.push_rule(RegexRule::new("mail").instance(FieldRegex::new("^Mail$").unwrap()));
+        let window = |instance: &str| ActiveWindowMetadata {
+            instance: Some(instance.into()),
+            ..ActiveWindowMetadata::for_test(Some("Inbox"), Some("Thunderbird"))
+        };
+        assert_eq!(
+            classifier.classify(window("Mail")).unwrap(),
+            Some("mail".into())
+        );
+        assert_eq!(classifier.classify(window("Calendar")).unwrap(), None);
+    }
+
+    #[test]
+    fn env_expansion() {
+        std::env::set_var("XSTALKER_TEST_PROJECT", "xstalker");
+        assert_eq!(
+            expand_env("~/$XSTALKER_TEST_PROJECT/").unwrap(),
+            "~/xstalker/"
+        );
+        assert_eq!(
+            expand_env("${XSTALKER_TEST_PROJECT}_dev").unwrap(),
+            "xstalker_dev"
+        );
+        assert_eq!(
+            expand_env("$$XSTALKER_TEST_PROJECT").unwrap(),
+            "$XSTALKER_TEST_PROJECT"
+        );
+        assert_eq!(expand_env("^vim$").unwrap(), "^vim$");
+        assert!(expand_env("$XSTALKER_TEST_UNDEFINED").is_err());
+        assert!(expand_env("${XSTALKER_TEST_PROJECT").is_err());
+    }
+
+    #[test]
+    fn env_expansion_is_opt_in() {
+        std::env::set_var("XSTALKER_TEST_EDITOR", "kate");
+        let rules = "[[rule]]\n\
+                     category = \"edit\"\n\
+                     class = { pattern = \"^$XSTALKER_TEST_EDITOR$\", mode = \"regex\" }\n";
+        let mut literal = ConfigClassifier::from_text(rules, Path::new("test.toml")).unwrap();
+        assert_eq!(category(&mut literal, "notes", "kate"), None);
+
+        let config = format!("expand_env = true\n{}", rules);
+        let mut expanded = ConfigClassifier::from_text(&config, Path::new("test.toml")).unwrap();
+        assert_eq!(
+            category(&mut expanded, "notes", "kate"),
+            Some("edit".into())
+        );
+        assert_eq!(category(&mut expanded, "notes", "kwrite"), None);
+    }
+
+    #[test]
+    fn class_as_category_discovers_categories() {
+        let mut classifier = ClassAsCategoryClassifier::new(Some("other"));
+        assert_eq!(&classifier.categories()[..], &["other"]);
+        assert_eq!(
+            category(&mut classifier, "Inbox", "Thunderbird"),
+            Some("Thunderbird".into())
+        );
+        assert_eq!(
+            category(&mut classifier, "~", "Tab\tClass"),
+            Some("Tab Class".into())
+        );
+        assert_eq!(category(&mut classifier, "Inbox", ""), Some("other".into()));
+        assert_eq!(
+            category(&mut classifier, "Drafts", "Thunderbird"),
+            Some("Thunderbird".into())
+        );
+        assert_eq!(
+            &classifier.categories()[..],
+            &["other", "Thunderbird", "Tab Class"]
+        );
+
+        let mut without_default = ClassAsCategoryClassifier::new(None::<String>);
+        assert_eq!(
+            without_default
+                .classify(ActiveWindowMetadata::for_test(Some("Inbox"), None))
+                .unwrap(),
+            None
+        );
+        assert!(without_default.categories().is_empty());
+    }
+
+    #[test]
+    fn config_classifier_uses_first_matching_rule_or_default() {
+        let config = "default_category = \"other\"\n\
+                      [[rule]]\n\
+                      category = \"mail\"\n\
+                      class = \"Thunderbird\"\n\
+                      [[rule]]\n\
+                      category = \"code\"\n\
+                      title = { pattern = \".rs\", mode = \"contains\" }\n\
+                      [[rule]]\n\
+                      category = \"unreachable\"\n\
+                      title = { pattern = \"*.rs*\", mode = \"glob\" }\n";
+        let mut classifier = ConfigClassifier::from_text(config, Path::new("test.toml")).unwrap();
+        assert_eq!(
+            category(&mut classifier, "main.rs - Kate", "kate"),
+            Some("code".into())
+        );
+        assert_eq!(
+            category(&mut classifier, "main.rs", "Thunderbird"),
+            Some("mail".into())
+        );
+        assert_eq!(
+            category(&mut classifier, "Inbox", "Firefox"),
+            Some("other".into())
+        );
+        assert_eq!(
+            &classifier.categories()[..],
+            &["code", "mail", "other", "unreachable"]
+        );
+    }
+
+    #[test]
+    fn glob_patterns_match_whole_class() {
+        let jetbrains = matcher("jetbrains-*", MatchMode::Glob, false);
+        assert!(jetbrains.matches("jetbrains-idea"));
+        assert!(jetbrains.matches("jetbrains-"));
+        assert!(!jetbrains.matches("jetbrains"));
+        assert!(!jetbrains.matches("JetBrains-idea"));
+        assert!(!jetbrains.matches("x-jetbrains-idea"));
+        assert!(matcher("jetbrains-*", MatchMode::Glob, true).matches("JetBrains-idea"));
+
+        let gnome = matcher("org.gnome.*", MatchMode::Glob, false);
+        assert!(gnome.matches("org.gnome.Nautilus"));
+        assert!(!gnome.matches("org-gnome-Nautilus"));
+        assert!(!gnome.matches("org.gnome"));
+
+        let gimp = matcher("Gimp-2.?", MatchMode::Glob, false);
+        assert!(gimp.matches("Gimp-2.8"));
+        assert!(!gimp.matches("Gimp-2.10"));
+        assert!(!gimp.matches("Gimp-2."));
+
+        let firefox = matcher("[Ff]irefox*", MatchMode::Glob, false);
+        assert!(firefox.matches("firefox"));
+        assert!(firefox.matches("Firefox-esr"));
+        assert!(!firefox.matches("FIREFOX"));
+        let not_digit = matcher("term[!0-9]", MatchMode::Glob, false);
+        assert!(not_digit.matches("termx"));
+        assert!(!not_digit.matches("term1"));
+        let range = matcher("[a-c]*", MatchMode::Glob, false);
+        assert!(range.matches("chromium"));
+        assert!(!range.matches("dolphin"));
+    }
+
+    #[test]
+    fn case_insensitive_regex_keeps_escapes() {
+        let title = "Inbox (3) - Mozilla Thunderbird";
+        let non_digits = matcher(r"^\D+\(", MatchMode::Regex, true);
+        assert!(non_digits.matches(title));
+        assert!(!non_digits.matches("42 (3)"));
+        let words = matcher(r"\S+ - MOZILLA", MatchMode::Regex, true);
+        assert!(words.matches(title));
+        assert!(!words.matches("  - Mozilla"));
+        assert!(matcher("INBOX*[A-Z]", MatchMode::Glob, true).matches(title));
+    }
+}
@@ -0,0 +1,1508 @@
+use super::classifier::{CheckedClassifier, Classifier, ExcludedWindows, WeightedCategories};
+use super::database::{
+    daily_file_path, json_seconds, CategoryDurationCounter, Database, DatabaseFormat, DatabaseTime,
+    DurationPrecision, EntryExtras, InputActivity, Iso8601, MaxDatabaseSize, SwitchCounter,
+    WindowCounter,
+};
+use super::hook::ChangeHook;
+use super::notification::CategoryNotifier;
+use super::status;
+use super::stretches::StretchLog;
+use super::titles::TitleDurations;
+use super::{
+    display_category, visit_state, ActiveWindowMetadata, ErrorMessage, ShowErrorTraceback,
+    UniqueCategories,
+};
+use chrono;
+use serde::Serialize;
+use serde_json;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time;
+
+/// Time windows ending within this delay in the future are considered elapsed.
+/// Interval ticks use monotonic time, which can slightly differ from the wall clock.
+const WINDOW_CHANGE_TOLERANCE: time::Duration = time::Duration::from_secs(1);
+
+/// Idle detection: time without user input after which the idle category is used.
+pub struct IdleDetection {
+    pub timeout: time::Duration,
+    pub category: String,
+}
+
+/// Daemon settings, besides the classifier, excluded windows and databases.
+pub struct DaemonOptions {
+    /// Fail if a database is already used by another process.
+    pub db_lock: bool,
+    /// Allow a classifier without categories, which only records away categories.
+    pub allow_empty_categories: bool,
+    pub idle_detection: Option<IdleDetection>,
+    /// Category used while the screen is locked
+    pub locked_category: Option<String>,
+    /// Show a desktop notification when the active window category changes.
+    pub notify: bool,
+    /// Command run when the active window category changes
+    pub on_change: Option<String>,
+    /// Write durations at each window change, instead of only at write ticks.
+    pub flush_on_change: bool,
+    /// Track time per window title, keeping at most this number of titles.
+    pub max_titles: Option<usize>,
+    /// Count distinct windows focused in each time window.
+    pub count_windows: bool,
+    /// Wait for begin() to detect the initial category and count time.
+    pub start_paused: bool,
+    /// Adaptive writes: minimum time between two writes of a database. None to write at each tick.
+    pub min_write_interval: Option<time::Duration>,
+    /// Window titles may appear in debug logs.
+    pub log_titles: bool,
+}
+
+impl Default for DaemonOptions {
+    /// Locked databases, titles in logs, and all optional features disabled.
+    fn default() -> Self {
+        DaemonOptions {
+            db_lock: true,
+            allow_empty_categories: false,
+            idle_detection: None,
+            locked_category: None,
+            notify: false,
+            on_change: None,
+            flush_on_change: false,
+            max_titles: None,
+            count_windows: false,
+            start_paused: false,
+            min_write_interval: None,
+            log_titles: true,
+        }
+    }
+}
+
+/// Main category of weighted categories: the first one.
+fn main_category(categories: &WeightedCategories) -> Option<String> {
+    categories.first().map(|(category, _)| category.clone())
+}
+
+/// Log a database write failure before propagating it.
+fn db_write_error(db_filename: &dyn fmt::Display, e: io::Error) -> ErrorMessage {
+    error!("Unable to write to database '{}': {}", db_filename, e);
+    ErrorMessage::new(format!("Unable to write to database '{}'", db_filename), e)
+}
+
+/// Add categories discovered at runtime by the classifier to the database, if new.
+fn add_discovered_categories(
+    db: &mut Database,
+    duration_counter: &mut CategoryDurationCounter,
+    categories: &WeightedCategories,
+) -> io::Result<()> {
+    for (category, _) in categories {
+        if db.add_category(category)? {
+            info!("New category: {}", category);
+            duration_counter.add_category(category);
+        }
+    }
+    Ok(())
+}
+
+fn write_durations_to_disk(
+    db: &mut Database,
+    duration_counter: &mut CategoryDurationCounter,
+    switch_counter: &SwitchCounter,
+    activity: InputActivity,
+    window_counter: &WindowCounter,
+    window_start: &DatabaseTime,
+    timestamp: time::Instant,
+) -> io::Result<()> {
+    duration_counter.record_current_duration(timestamp);
+    db.rewrite_last_entry(
+        window_start,
+        duration_counter.durations(),
+        switch_counter.switches(),
+        activity,
+        window_counter.windows(),
+    )?;
+    duration_counter.mark_written();
+    Ok(())
+}
+
+fn change_time_window(
+    db: &mut Database,
+    duration_counter: &mut CategoryDurationCounter,
+    switch_counter: &mut SwitchCounter,
+    activity: &mut InputActivity,
+    window_counter: &mut WindowCounter,
+    window_start: &mut DatabaseTime,
+    new_window_start: DatabaseTime,
+    timestamp: time::Instant,
+) -> io::Result<()> {
+    // Flush current durations values
+    write_durations_to_disk(
+        db,
+        duration_counter,
+        switch_counter,
+        *activity,
+        window_counter,
+        window_start,
+        timestamp,
+    )?;
+    // Create a new time window
+    db.lock_last_entry();
+    duration_counter.reset_durations();
+    switch_counter.reset_switches();
+    *activity = InputActivity::default();
+    window_counter.reset_windows();
+    *window_start = new_window_start;
+    Ok(())
+}
+
+/** Start of the window containing now, with windows aligned to local midnight.
+ * Hourly windows start at the top of the hour, daily windows at midnight.
+ * Window sizes longer than a day start at midnight.
+ */
+fn aligned_window_start(now: DatabaseTime, window_size: time::Duration) -> DatabaseTime {
+    let midnight = match now.date().and_hms_opt(0, 0, 0) {
+        Some(midnight) => midnight,
+        None => return now, // Midnight skipped by a DST change
+    };
+    let window_size = window_size.as_secs() as i64;
+    let nb_windows = now.signed_duration_since(midnight).num_seconds() / window_size;
+    midnight + chrono::Duration::seconds(nb_windows * window_size)
+}
+
+/// Recording state of a database, for debugging.
+#[derive(Serialize)]
+struct RecorderDump {
+    database: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    monitor: Option<String>,
+    window_start: String,
+    /// Seconds
+    window_size: u64,
+    current_category: Option<String>,
+    /// Seconds in each category, including the current category up to now
+    durations: BTreeMap<String, serde_json::Number>,
+    switches: u32,
+    activity: InputActivity,
+    windows: u32,
+}
+
+/// Internal state of the daemon, for debugging.
+#[derive(Serialize)]
+struct DaemonDump {
+    started: bool,
+    paused: bool,
+    away_category: Option<String>,
+    active_window_categories: WeightedCategories,
+    active_window_excluded: bool,
+    monitor_categories: BTreeMap<String, WeightedCategories>,
+    classifier_categories: Vec<String>,
+    /// Seconds
+    idle_timeout: Option<u64>,
+    idle_category: Option<String>,
+    locked_category: Option<String>,
+    flush_on_change: bool,
+    count_windows: bool,
+    track_titles: bool,
+    recorders: Vec<RecorderDump>,
+}
+
+/// Database file to record to, with its time window size.
+pub struct WindowedDatabase {
+    pub path: PathBuf,
+    pub format: DatabaseFormat,
+    pub precision: DurationPrecision,
+    pub window_size: time::Duration,
+    /// Align new windows to clock boundaries instead of starting them now.
+    pub align: bool,
+    /// Retries of writes failing with a transient error
+    pub write_retries: u32,
+    /// Closed time windows older than this are moved to the archive.
+    pub compact_after: Option<time::Duration>,
+    /// Oldest closed time windows are removed to keep the file under this size.
+    pub max_size: Option<MaxDatabaseSize>,
+    /// Move time windows removed by max_size to the archive instead of dropping them.
+    pub trim_to_archive: bool,
+    /// Start from an empty in memory database, and only log writes.
+    pub dry: bool,
+    /// Path is a directory with a database file per day, see database::daily_file_path().
+    pub daily_files: bool,
+    /// Output name if the database records the window shown on a monitor, instead of the
+    /// active window. See Daemon::on_monitor_windows().
+    pub monitor: Option<String>,
+}
+
+/// Daily database files: parameters to open the file of each new day.
+struct DailyFiles {
+    format: DatabaseFormat,
+    precision: DurationPrecision,
+    db_lock: bool,
+    write_retries: u32,
+    date: Cell<chrono::NaiveDate>, // Date of the open file
+}
+
+/// Open a database file, logging how its categories differ. Dry databases are only in memory.
+fn open_database(
+    path: &Path,
+    format: DatabaseFormat,
+    precision: DurationPrecision,
+    categories: UniqueCategories,
+    db_lock: bool,
+    write_retries: u32,
+    dry: bool,
+) -> Result<Database, ErrorMessage> {
+    let db_filename = path.display();
+    if dry {
+        info!("Dry database '{}': writes are only logged", db_filename);
+        return Ok(Database::open_dry(path, format, precision, categories));
+    }
+    let (db, migration) =
+        Database::open(path, format, precision, categories, db_lock, write_retries).map_err(
+            |e| ErrorMessage::new(format!("Unable to open database '{}'", db_filename), e),
+        )?;
+    if !migration.is_empty() {
+        info!("Categories of database '{}': {}", db_filename, migration)
+    }
+    Ok(db)
+}
+
+/** Recording state for one database: durations for the current time window.
+ * State is wrapped in RefCell, as it is shared by tasks: cannot prove with type that mutations are exclusive.
+ * With daily files, db_file is the directory, and db the file of the current time window start.
+ */
+struct Recorder {
+    db_file: PathBuf,
+    daily: Option<DailyFiles>,
+    monitor: Option<String>,
+    window_size: time::Duration,
+    compact_after: Option<time::Duration>,
+    max_size: Option<MaxDatabaseSize>,
+    trim_to_archive: bool,
+    trim_warned: Cell<bool>, // Trimming is announced once with a warning
+    dry: bool,               // The visit state file is not used either
+    db: RefCell<Database>,
+    duration_counter: RefCell<CategoryDurationCounter>,
+    switch_counter: RefCell<SwitchCounter>,
+    /// Input events of the current time window, if recorded
+    activity: RefCell<InputActivity>,
+    /// Distinct windows of the current time window, if counted
+    window_counter: RefCell<WindowCounter>,
+    window_start: RefCell<DatabaseTime>,
+    /// Time of the last write by write_durations_to_disk(), None before the first one
+    last_write: Cell<Option<time::Instant>>,
+}
+
+impl Recorder {
+    /// Open the database, and resume the time window of the last entry if still current.
+    fn open(
+        database: WindowedDatabase,
+        categories: UniqueCategories,
+        db_lock: bool,
+        now: DatabaseTime,
+    ) -> Result<Self, ErrorMessage> {
+        let db_filename = database.path.display();
+        let daily = if database.daily_files {
+            Some(DailyFiles {
+                format: database.format,
+                precision: database.precision,
+                db_lock: db_lock,
+                write_retries: database.write_retries,
+                date: Cell::new(now.naive_local().date()),
+            })
+        } else {
+            None
+        };
+        let db_path = match &daily {
+            Some(daily) => daily_file_path(&database.path, daily.date.get(), daily.format),
+            None => database.path.clone(),
+        };
+        let mut db = open_database(
+            &db_path,
+            database.format,
+            database.precision,
+            categories,
+            db_lock,
+            database.write_retries,
+            database.dry,
+        )?;
+        let mut duration_counter = CategoryDurationCounter::new(db.categories().clone());
+        let mut switch_counter = SwitchCounter::default();
+        let mut activity = InputActivity::default();
+        let mut window_counter = WindowCounter::default();
+
+        // Start of a new window: the first aligned window is shorter.
+        let new_window_start = if database.align {
+            aligned_window_start(now, database.window_size)
+        } else {
+            now
+        };
+
+        // Determine current time window
+        let window_start = {
+            if let Some((time, durations)) = db.get_last_entry().map_err(|e| {
+                ErrorMessage::new(format!("Unable to read last entry of '{}'", db_filename), e)
+            })? {
+                let window_size = chrono::Duration::from_std(database.window_size).unwrap();
+                if time <= now && now < time + window_size {
+                    // We are still in the time window of the last entry, resume the window.
+                    duration_counter.set_durations(durations);
+                    let extras = db.current_extras();
+                    switch_counter.set_switches(extras.switches);
+                    activity = extras.activity;
+                    window_counter.set_windows(extras.windows);
+                    time
+                } else {
+                    // Outside of last entry time window: create a new window.
+                    // Times include their UTC offset, so timezone changes have no effect here.
+                    if now < time {
+                        // System clock adjustement, the new window will overlap the last one.
+                        warn!(
+                            "Clock went backwards: last time window starts at {}, after now ({})",
+                            time.format_iso8601(),
+                            now.format_iso8601()
+                        )
+                    } else if now >= time + window_size + window_size {
+                        info!(
+                            "No activity recorded since the end of the last time window ({})",
+                            (time + window_size).format_iso8601()
+                        )
+                    }
+                    db.lock_last_entry();
+                    if time <= now && new_window_start < time + window_size {
+                        // Last window was not aligned, do not overlap it.
+                        time + window_size
+                    } else {
+                        new_window_start
+                    }
+                }
+            } else {
+                // No last entry: create new window.
+                new_window_start
+            }
+        };
+        Ok(Recorder {
+            db_file: database.path,
+            daily: daily,
+            monitor: database.monitor,
+            window_size: database.window_size,
+            compact_after: database.compact_after,
+            max_size: database.max_size,
+            trim_to_archive: database.trim_to_archive,
+            trim_warned: Cell::new(false),
+            dry: database.dry,
+            db: RefCell::new(db),
+            duration_counter: RefCell::new(duration_counter),
+            switch_counter: RefCell::new(switch_counter),
+            activity: RefCell::new(activity),
+            window_counter: RefCell::new(window_counter),
+            window_start: RefCell::new(window_start),
+            last_write: Cell::new(None),
+        })
+    }
+
+    fn db_write_error(&self, e: io::Error) -> ErrorMessage {
+        db_write_error(&self.db_file.display(), e)
+    }
+
+    fn dump(&self, timestamp: time::Instant) -> RecorderDump {
+        let duration_counter = self.duration_counter.borrow();
+        RecorderDump {
+            database: self.db_file.display().to_string(),
+            monitor: self.monitor.clone(),
+            window_start: self.window_start.borrow().format_iso8601(),
+            window_size: self.window_size.as_secs(),
+            current_category: duration_counter.current_category().map(String::from),
+            durations: duration_counter
+                .categories()
+                .iter()
+                .cloned()
+                .zip(
+                    duration_counter
+                        .durations_at(timestamp)
+                        .into_iter()
+                        .map(json_seconds),
+                )
+                .collect(),
+            switches: self.switch_counter.borrow().switches(),
+            activity: *self.activity.borrow(),
+            windows: self.window_counter.borrow().windows(),
+        }
+    }
+
+    /// Time until the end of the current window, 0 if already elapsed.
+    fn duration_to_next_window_change(&self, now: DatabaseTime) -> time::Duration {
+        let elapsed = now
+            .signed_duration_since(*self.window_start.borrow())
+            .to_std()
+            .unwrap_or_default();
+        self.window_size.checked_sub(elapsed).unwrap_or_default()
+    }
+
+    fn add_discovered_categories(
+        &self,
+        categories: &WeightedCategories,
+    ) -> Result<(), ErrorMessage> {
+        add_discovered_categories(
+            &mut self.db.borrow_mut(),
+            &mut self.duration_counter.borrow_mut(),
+            categories,
+        )
+        .map_err(|e| self.db_write_error(e))
+    }
+
+    fn add_categories(&self, categories: UniqueCategories) -> Result<Vec<String>, ErrorMessage> {
+        let added = self
+            .db
+            .borrow_mut()
+            .add_categories(categories.clone())
+            .map_err(|e| self.db_write_error(e))?;
+        if !added.is_empty() {
+            self.duration_counter
+                .borrow_mut()
+                .add_categories(categories);
+        }
+        Ok(added)
+    }
+
+    /// Set the note of the current time window, writing durations first so that its entry exists.
+    fn annotate(&self, note: &str, timestamp: time::Instant) -> Result<(), ErrorMessage> {
+        self.write_durations_to_disk(timestamp)?;
+        self.db
+            .borrow_mut()
+            .annotate_current(note)
+            .map_err(|e| self.db_write_error(e))
+    }
+
+    fn categories_changed(&self, categories: &WeightedCategories, timestamp: time::Instant) {
+        self.switch_counter
+            .borrow_mut()
+            .category_changed(categories.first().map(|(category, _)| category));
+        self.duration_counter
+            .borrow_mut()
+            .categories_changed(categories, timestamp)
+    }
+
+    fn skip_duration(&self, start: time::Instant, end: time::Instant) {
+        self.duration_counter.borrow_mut().skip_duration(start, end)
+    }
+
+    /// Write durations, and save the current visit which is not stored in the database.
+    fn write_durations_to_disk(&self, timestamp: time::Instant) -> Result<(), ErrorMessage> {
+        write_durations_to_disk(
+            &mut self.db.borrow_mut(),
+            &mut self.duration_counter.borrow_mut(),
+            &self.switch_counter.borrow(),
+            *self.activity.borrow(),
+            &self.window_counter.borrow(),
+            &self.window_start.borrow(),
+            timestamp,
+        )
+        .map_err(|e| self.db_write_error(e))?;
+        self.last_write.set(Some(timestamp));
+        if self.dry {
+            return Ok(());
+        }
+        let state_file = visit_state::state_path(&self.db_file);
+        if let Err(e) = visit_state::save(
+            &state_file,
+            &self.window_start.borrow(),
+            &self.duration_counter.borrow(),
+            timestamp,
+        ) {
+            warn!(
+                "Unable to save visit state '{}': {}",
+                state_file.display(),
+                e
+            )
+        }
+        Ok(())
+    }
+
+    /** With daily files, switch to the file of the day of window_start if it changed.
+     * Called after a time window change: durations are zero, the previous file is complete.
+     */
+    fn follow_daily_file(&self, window_start: &DatabaseTime) -> Result<(), ErrorMessage> {
+        let daily = match &self.daily {
+            Some(daily) => daily,
+            None => return Ok(()),
+        };
+        let date = window_start.naive_local().date();
+        if date == daily.date.get() {
+            return Ok(());
+        }
+        let path = daily_file_path(&self.db_file, date, daily.format);
+        info!("Recording to daily database file '{}'", path.display());
+        let categories = self.db.borrow().categories().clone();
+        let db = open_database(
+            &path,
+            daily.format,
+            daily.precision,
+            categories,
+            daily.db_lock,
+            daily.write_retries,
+            self.dry,
+        )?;
+        // An existing file of the day may order categories differently
+        self.duration_counter
+            .borrow_mut()
+            .set_categories(db.categories().clone());
+        *self.db.borrow_mut() = db;
+        daily.date.set(date);
+        Ok(())
+    }
+
+    /** Write durations only if they or the extra fields changed since the last write, and at most
+     * once per min_interval. Returns true if written. Skipped writes are logged at trace level.
+     */
+    fn write_changes_to_disk(
+        &self,
+        timestamp: time::Instant,
+        min_interval: time::Duration,
+    ) -> Result<bool, ErrorMessage> {
+        let changed = self
+            .duration_counter
+            .borrow()
+            .changed_since_write(timestamp)
+            || self.db.borrow().has_unwritten_changes()
+            || {
+                let written = self.db.borrow().current_extras();
+                written.switches != self.switch_counter.borrow().switches()
+                    || written.activity != *self.activity.borrow()
+                    || written.windows != self.window_counter.borrow().windows()
+            };
+        if !changed {
+            trace!("Skipped write of '{}': no changes", self.db_file.display());
+            return Ok(false);
+        }
+        if let Some(last_write) = self.last_write.get() {
+            if timestamp < last_write + min_interval {
+                trace!(
+                    "Skipped write of '{}': last write less than {}s ago",
+                    self.db_file.display(),
+                    min_interval.as_secs()
+                );
+                return Ok(false);
+            }
+        }
+        self.write_durations_to_disk(timestamp)?;
+        Ok(true)
+    }
+
+    /// Restore the current visit if interrupted less than max_age ago.
+    fn restore_visit(&self, max_age: time::Duration, timestamp: time::Instant) {
+        if self.dry {
+            return;
+        }
+        let state_file = visit_state::state_path(&self.db_file);
+        if visit_state::restore(
+            &state_file,
+            &self.window_start.borrow(),
+            &mut self.duration_counter.borrow_mut(),
+            max_age,
+            timestamp,
+        ) {
+            info!("Resumed current visit from '{}'", state_file.display())
+        }
+    }
+
+    /** Close the current time window now, and start a new one: the next windows follow from now.
+     * Nothing is done if the current window started less than a second ago, as entries are
+     * identified by their start time.
+     */
+    fn rotate_time_window(&self) -> Result<(), ErrorMessage> {
+        let (now_instant, now) = (
+            time::Instant::now(),
+            DatabaseTime::from(time::SystemTime::now()),
+        );
+        let mut window_start = self.window_start.borrow_mut();
+        if now.signed_duration_since(*window_start) < chrono::Duration::seconds(1) {
+            warn!(
+                "Time window of '{}' just started, not rotated",
+                self.db_file.display()
+            );
+            return Ok(());
+        }
+        change_time_window(
+            &mut self.db.borrow_mut(),
+            &mut self.duration_counter.borrow_mut(),
+            &mut self.switch_counter.borrow_mut(),
+            &mut self.activity.borrow_mut(),
+            &mut self.window_counter.borrow_mut(),
+            &mut window_start,
+            now,
+            now_instant,
+        )
+        .map_err(|e| self.db_write_error(e))?;
+        self.follow_daily_file(&window_start)
+    }
+
+    /** Close all fully elapsed time windows.
+     * Ticks may be late (heavy load, system suspend), so several windows may have elapsed.
+     * Then archive closed windows older than compact_after, if set.
+     * Then remove the oldest closed windows exceeding max_size, if set.
+     */
+    fn change_time_windows(&self) -> Result<(), ErrorMessage> {
+        let (now_instant, now) = (
+            time::Instant::now(),
+            DatabaseTime::from(time::SystemTime::now()),
+        );
+        let window_size = chrono::Duration::from_std(self.window_size).unwrap();
+        let tolerance = chrono::Duration::from_std(WINDOW_CHANGE_TOLERANCE).unwrap();
+        let mut window_start = self.window_start.borrow_mut();
+        let mut nb_closed_windows = 0;
+        loop {
+            // Window start is only moved by whole window sizes, so it does not drift.
+            let window_end = *window_start + window_size;
+            if window_end > now + tolerance {
+                if nb_closed_windows > 1 {
+                    warn!(
+                        "Time window change was late (clock jump or system suspend): closed {} windows",
+                        nb_closed_windows
+                    )
+                }
+                break;
+            }
+            nb_closed_windows += 1;
+            // Record durations up to the window end, not the late tick.
+            let elapsed_since_end = now
+                .signed_duration_since(window_end)
+                .to_std()
+                .unwrap_or_default();
+            let window_end_instant = now_instant
+                .checked_sub(elapsed_since_end)
+                .unwrap_or(now_instant);
+            change_time_window(
+                &mut self.db.borrow_mut(),
+                &mut self.duration_counter.borrow_mut(),
+                &mut self.switch_counter.borrow_mut(),
+                &mut self.activity.borrow_mut(),
+                &mut self.window_counter.borrow_mut(),
+                &mut window_start,
+                window_end,
+                window_end_instant,
+            )
+            .map_err(|e| self.db_write_error(e))?;
+            self.follow_daily_file(&window_start)?
+        }
+        // Closed windows are immutable, old ones can be archived.
+        if let (Some(compact_after), true) = (self.compact_after, nb_closed_windows > 0) {
+            let limit = now - chrono::Duration::from_std(compact_after).unwrap();
+            let nb_archived = self
+                .db
+                .borrow_mut()
+                .compact(&limit)
+                .map_err(|e| self.db_write_error(e))?;
+            if nb_archived > 0 {
+                info!(
+                    "Archived {} time windows of '{}'",
+                    nb_archived,
+                    self.db_file.display()
+                )
+            }
+        }
+        if let (Some(max_size), true) = (self.max_size, nb_closed_windows > 0) {
+            let nb_trimmed = self
+                .db
+                .borrow_mut()
+                .trim(max_size, self.trim_to_archive)
+                .map_err(|e| self.db_write_error(e))?;
+            if nb_trimmed > 0 {
+                let destination = if self.trim_to_archive {
+                    "moved to the archive"
+                } else {
+                    "dropped"
+                };
+                if !self.trim_warned.replace(true) {
+                    warn!(
+                        "Database '{}' reached its maximum size: oldest time windows are {}",
+                        self.db_file.display(),
+                        destination
+                    )
+                }
+                info!(
+                    "Trimmed {} time windows of '{}': {}",
+                    nb_trimmed,
+                    self.db_file.display(),
+                    destination
+                )
+            }
+        }
+        Ok(())
+    }
+}
+
+/** Time tracking state and its transitions, independent of the event loop.
+ *
+ * Each method handles one event: window change, away check, time gap, database write tick,
+ * time window boundary, classifier reload or pause.
+ * The event loop only calls them, so they can be used without a display server or runtime.
+ * Methods take &self, as events are handled by concurrent tasks sharing the daemon.
+ * State is wrapped in RefCell and Cell: borrows never last across events.
+ * Only database errors are returned. Failures of the other outputs (notifications, change hook,
+ * InfluxDB, stretch and title files) are logged by them, as they must not stop time recording.
+ */
+pub struct Daemon<C> {
+    classifier: RefCell<CheckedClassifier<C>>,
+    /// Windows which are not classified: no time is counted while they are active.
+    excluded_windows: ExcludedWindows,
+    active_window_excluded: Cell<bool>,
+    /// Recorders for each database, the first one is the main one.
+    recorders: Vec<Recorder>,
+    /// Categories of the active window, which are not counted while the user is away.
+    active_window_categories: RefCell<WeightedCategories>,
+    /// Categories of the window shown on each monitor, for monitor databases.
+    monitor_categories: RefCell<BTreeMap<String, WeightedCategories>>,
+    /// Category used while the user is away (idle or screen locked), replacing the active window.
+    away_category: RefCell<Option<String>>,
+    /// Time is not counted while paused, but categories are still tracked to resume.
+    paused: Cell<bool>,
+    /// Initial category detected: window changes are ignored before, when started paused.
+    started: Cell<bool>,
+    idle_detection: Option<IdleDetection>,
+    locked_category: Option<String>,
+    notifier: Option<CategoryNotifier>,
+    change_hook: Option<ChangeHook>,
+    flush_on_change: bool,
+    /// Stretches of the counted category, next to the main database. None for a dry database.
+    stretch_log: Option<RefCell<StretchLog>>,
+    /// Time per window title, next to the main database. None unless enabled, or if dry.
+    title_durations: Option<RefCell<TitleDurations>>,
+    /// Title of the active window, only kept if titles are tracked.
+    active_window_title: RefCell<Option<String>>,
+    /// Count distinct windows focused in each time window.
+    count_windows: bool,
+    /// Adaptive writes: minimum time between two writes of a database, which are skipped if
+    /// nothing changed. None to write at each tick.
+    min_write_interval: Option<time::Duration>,
+    /// Window titles may appear in debug logs. Unset with --no-store-titles.
+    log_titles: bool,
+}
+
+impl<C: Classifier> Daemon<C> {
+    /** Open the databases. Away categories are added to the classifier categories.
+     * Fails if the classifier has no categories, unless allow_empty_categories is set.
+     */
+    pub fn new(
+        classifier: C,
+        excluded_windows: ExcludedWindows,
+        databases: Vec<WindowedDatabase>,
+        options: DaemonOptions,
+    ) -> Result<Self, ErrorMessage> {
+        let DaemonOptions {
+            db_lock,
+            allow_empty_categories,
+            idle_detection,
+            locked_category,
+            notify,
+            on_change,
+            flush_on_change,
+            max_titles,
+            count_windows,
+            start_paused,
+            min_write_interval,
+            log_titles,
+        } = options;
+        let classifier = CheckedClassifier::new(classifier);
+        let mut categories = classifier.categories();
+        if categories.is_empty() && !allow_empty_categories {
+            // Nothing would ever be recorded, except away categories.
+            return Err(ErrorMessage::from(
+                "Classifier defines no categories: check the rules, or use --allow-empty-categories",
+            ));
+        }
+        let away_categories = idle_detection
+            .iter()
+            .map(|idle_detection| idle_detection.category.clone())
+            .chain(locked_category.clone())
+            .collect();
+        categories.extend(UniqueCategories::make_unique(away_categories));
+        let stretch_log = match databases.first() {
+            Some(main) if !main.dry => Some(RefCell::new(StretchLog::new(&main.path))),
+            _ => None,
+        };
+        let title_durations = match (databases.first(), max_titles) {
+            (Some(main), Some(max_titles)) if !main.dry => {
+                Some(RefCell::new(TitleDurations::open(&main.path, max_titles)?))
+            }
+            _ => None,
+        };
+        let now = DatabaseTime::from(time::SystemTime::now());
+        let mut recorders = Vec::with_capacity(databases.len());
+        for database in databases {
+            recorders.push(Recorder::open(database, categories.clone(), db_lock, now)?)
+        }
+        Ok(Daemon {
+            classifier: RefCell::new(classifier),
+            excluded_windows: excluded_windows,
+            active_window_excluded: Cell::new(false),
+            recorders: recorders,
+            active_window_categories: RefCell::new(Vec::new()),
+            monitor_categories: RefCell::new(BTreeMap::new()),
+            away_category: RefCell::new(None),
+            paused: Cell::new(start_paused),
+            started: Cell::new(false),
+            idle_detection: idle_detection,
+            locked_category: locked_category,
+            notifier: if notify {
+                Some(CategoryNotifier::new())
+            } else {
+                None
+            },
+            change_hook: on_change.map(ChangeHook::new),
+            flush_on_change: flush_on_change,
+            stretch_log: stretch_log,
+            title_durations: title_durations,
+            active_window_title: RefCell::new(None),
+            count_windows: count_windows,
+            min_write_interval: min_write_interval,
+            log_titles: log_titles,
+        })
+    }
+
+    /// Durations of the main database, for metrics, status and DBus.
+    pub fn main_duration_counter(&self) -> &RefCell<CategoryDurationCounter> {
+        &self.recorders[0].duration_counter
+    }
+
+    /// Extra fields of the current time window of the main database: switches, activity and
+    /// windows since the last write included.
+    pub fn main_extras(&self) -> EntryExtras {
+        let main = &self.recorders[0];
+        EntryExtras {
+            switches: main.switch_counter.borrow().switches(),
+            activity: *main.activity.borrow(),
+            windows: main.window_counter.borrow().windows(),
+            note: main.db.borrow().current_extras().note,
+        }
+    }
+
+    /// Time window start of the main database.
+    pub fn main_window_start(&self) -> DatabaseTime {
+        *self.recorders[0].window_start.borrow()
+    }
+
+    pub fn detects_idle(&self) -> bool {
+        self.idle_detection.is_some()
+    }
+
+    pub fn detects_screen_lock(&self) -> bool {
+        self.locked_category.is_some()
+    }
+
+    pub fn nb_databases(&self) -> usize {
+        self.recorders.len()
+    }
+
+    /// Time until the next window boundary of the database at index, from its current window start.
+    pub fn duration_to_window_boundary(&self, index: usize) -> time::Duration {
+        let now = DatabaseTime::from(time::SystemTime::now());
+        self.recorders[index].duration_to_next_window_change(now)
+    }
+
+    /// Main category of the active window.
+    fn active_window_category(&self) -> Option<String> {
+        main_category(&self.active_window_categories.borrow())
+    }
+
+    /** Categories counted now: none if paused, away category, or window categories.
+     * Window categories are those of the active window, or of the window shown on the monitor.
+     */
+    fn counted_categories(&self, monitor: &Option<String>) -> WeightedCategories {
+        if self.paused.get() {
+            Vec::new()
+        } else {
+            match (&*self.away_category.borrow(), monitor) {
+                (Some(away_category), _) => vec![(away_category.clone(), 1.)],
+                (None, None) => self.active_window_categories.borrow().clone(),
+                (None, Some(monitor)) => self
+                    .monitor_categories
+                    .borrow()
+                    .get(monitor)
+                    .cloned()
+                    .unwrap_or_default(),
+            }
+        }
+    }
+
+    fn change_counted_category(&self, timestamp: time::Instant) {
+        for recorder in &self.recorders {
+            recorder.categories_changed(&self.counted_categories(&recorder.monitor), timestamp);
+        }
+        self.change_stretch(&self.counted_categories(&None), timestamp);
+        self.change_title(timestamp)
+    }
+
+    /// Count the active window title, unless paused or away.
+    fn change_title(&self, timestamp: time::Instant) {
+        if let Some(title_durations) = &self.title_durations {
+            let counted = !self.paused.get() && self.away_category.borrow().is_none();
+            let title = self.active_window_title.borrow();
+            title_durations.borrow_mut().title_changed(
+                title.as_ref().map(String::as_str).filter(|_| counted),
+                timestamp,
+            )
+        }
+    }
+
+    fn change_stretch(&self, categories: &WeightedCategories, timestamp: time::Instant) {
+        if let Some(stretch_log) = &self.stretch_log {
+            stretch_log.borrow_mut().category_changed(
+                categories.first().map(|(category, _)| category.as_str()),
+                timestamp,
+            )
+        }
+    }
+
+    /// Categories of a new active window: none for excluded windows, whose metadata is not logged.
+    fn classify(&self, metadata: ActiveWindowMetadata) -> Result<WeightedCategories, ErrorMessage> {
+        let excluded = self.excluded_windows.matches(&metadata);
+        self.active_window_excluded.set(excluded);
+        if self.title_durations.is_some() {
+            *self.active_window_title.borrow_mut() = if excluded {
+                None
+            } else {
+                metadata.title.clone()
+            };
+        }
+        if excluded {
+            info!("Active window is excluded");
+            return Ok(Vec::new());
+        }
+        if self.count_windows && !self.paused.get() {
+            for recorder in self.recorders.iter().filter(|r| r.monitor.is_none()) {
+                recorder.window_counter.borrow_mut().window_focused(
+                    metadata.title.as_ref().map(String::as_str),
+                    metadata.class.as_ref().map(String::as_str),
+                )
+            }
+        }
+        if self.log_titles {
+            debug!("task_handle_window_change: {:?}", metadata);
+        } else {
+            debug!("task_handle_window_change: {:?}", metadata.without_titles());
+        }
+        let categories = self.classifier.borrow_mut().classify_weighted(metadata)?;
+        if categories.len() > 1 {
+            info!("Active window categories: {:?}", categories);
+        } else {
+            info!(
+                "Active window category: {}",
+                display_category(&main_category(&categories))
+            );
+        }
+        Ok(categories)
+    }
+
+    /** Set the initial category from the current active window.
+     * The visit interrupted by the last stop is resumed if it lasted less than max_interruption.
+     */
+    pub fn start(
+        &self,
+        metadata: ActiveWindowMetadata,
+        timestamp: time::Instant,
+        max_interruption: time::Duration,
+    ) -> Result<(), ErrorMessage> {
+        let categories = self.classify(metadata)?;
+        for recorder in &self.recorders {
+            recorder.add_discovered_categories(&categories)?;
+            // Monitor databases start at the first monitor check
+            if recorder.monitor.is_none() {
+                recorder.categories_changed(&categories, timestamp);
+                recorder.restore_visit(max_interruption, timestamp);
+            }
+        }
+        self.change_stretch(&categories, timestamp);
+        self.change_title(timestamp);
+        *self.active_window_categories.borrow_mut() = categories;
+        self.started.set(true);
+        Ok(())
+    }
+
+    pub fn is_started(&self) -> bool {
+        self.started.get()
+    }
+
+    /** Begin counting time for a daemon started paused: the initial category is only detected now.
+     * The pause is ended before start(), which counts the categories of the active window.
+     */
+    pub fn begin(
+        &self,
+        metadata: ActiveWindowMetadata,
+        timestamp: time::Instant,
+        max_interruption: time::Duration,
+    ) -> Result<(), ErrorMessage> {
+        info!("Tracking begins");
+        self.paused.set(false);
+        self.start(metadata, timestamp, max_interruption)
+    }
+
+    /** Classify the new active window, and count its category if the user is not away.
+     * While an excluded window is active, no time is counted until the next window change.
+     * Category changes are not notified while paused.
+     * Ignored until start(), as the initial category is detected there.
+     */
+    pub fn on_window_change(
+        &self,
+        metadata: ActiveWindowMetadata,
+        timestamp: time::Instant,
+    ) -> Result<(), ErrorMessage> {
+        if !self.started.get() {
+            return Ok(());
+        }
+        let categories = self.classify(metadata)?;
+        let category = main_category(&categories);
+        let previous_category = self.active_window_category();
+        if category != previous_category && !self.paused.get() {
+            if let Some(notifier) = &self.notifier {
+                notifier.category_changed(&category)
+            }
+            if let Some(change_hook) = &self.change_hook {
+                change_hook.category_changed(&category, &previous_category)
+            }
+        }
+        for recorder in &self.recorders {
+            recorder.add_discovered_categories(&categories)?;
+        }
+        *self.active_window_categories.borrow_mut() = categories;
+        if self.away_category.borrow().is_none() {
+            self.change_counted_category(timestamp)
+        }
+        if self.flush_on_change {
+            self.write_changes(time::Instant::now())?
+        }
+        Ok(())
+    }
+
+    /** Classify the window shown on each monitor, for the databases of these monitors.
+     * Monitors without window, or showing an excluded window, count no time.
+     * Outputs without database (connected after start) are ignored.
+     * Ignored until start(), like window changes.
+     */
+    pub fn on_monitor_windows(
+        &self,
+        windows: Vec<(String, Option<ActiveWindowMetadata>)>,
+        timestamp: time::Instant,
+    ) -> Result<(), ErrorMessage> {
+        if !self.started.get() {
+            return Ok(());
+        }
+        for (output, metadata) in windows {
+            let monitor = Some(output);
+            let recorders: Vec<&Recorder> = self
+                .recorders
+                .iter()
+                .filter(|recorder| recorder.monitor == monitor)
+                .collect();
+            if recorders.is_empty() {
+                continue;
+            }
+            let categories = match metadata {
+                Some(ref metadata) if self.excluded_windows.matches(metadata) => Vec::new(),
+                Some(metadata) => self.classifier.borrow_mut().classify_weighted(metadata)?,
+                None => Vec::new(),
+            };
+            let output = monitor.as_ref().unwrap();
+            if self.monitor_categories.borrow().get(output) == Some(&categories) {
+                continue;
+            }
+            debug!(
+                "Monitor {} category: {}",
+                output,
+                display_category(&main_category(&categories))
+            );
+            for recorder in &recorders {
+                recorder.add_discovered_categories(&categories)?;
+            }
+            self.monitor_categories
+                .borrow_mut()
+                .insert(output.clone(), categories);
+            let counted_categories = self.counted_categories(&monitor);
+            for recorder in &recorders {
+                recorder.categories_changed(&counted_categories, timestamp);
+            }
+        }
+        Ok(())
+    }
+
+    /** Switch between away (locked has priority over idle) and active window categories.
+     * The idle timeout of the active window category may override the global one.
+     * If the category changes while idle, the new timeout applies at the next check.
+     */
+    pub fn on_away_check(&self, locked: bool, idle_time: time::Duration, instant: time::Instant) {
+        let locked = locked && self.locked_category.is_some();
+        let idle = match &self.idle_detection {
+            Some(idle_detection) => {
+                let timeout = match &self.active_window_category() {
+                    Some(category) => self.classifier.borrow().idle_timeout(category),
+                    None => None,
+                };
+                idle_time >= timeout.unwrap_or(idle_detection.timeout)
+            }
+            None => false,
+        };
+        let new_away_category = if locked {
+            self.locked_category.clone()
+        } else if idle {
+            self.idle_detection.as_ref().map(|d| d.category.clone())
+        } else {
+            None
+        };
+        if new_away_category != *self.away_category.borrow() {
+            info!("User away: {}", display_category(&new_away_category));
+            *self.away_category.borrow_mut() = new_away_category;
+            self.change_counted_category(instant)
+        }
+    }
+
+    /// Ignore the time between start and end: time not spent on the computer.
+    pub fn on_gap(&self, start: time::Instant, end: time::Instant) {
+        for recorder in &self.recorders {
+            recorder.skip_duration(start, end);
+        }
+        if let Some(stretch_log) = &self.stretch_log {
+            stretch_log.borrow_mut().skip_duration(start, end)
+        }
+        if let Some(title_durations) = &self.title_durations {
+            title_durations.borrow_mut().skip_duration(start, end)
+        }
+    }
+
+    fn write_all(&self, instant: time::Instant) -> Result<(), ErrorMessage> {
+        for recorder in &self.recorders {
+            recorder.write_durations_to_disk(instant)?;
+        }
+        if let Some(title_durations) = &self.title_durations {
+            title_durations.borrow_mut().write(instant)
+        }
+        Ok(())
+    }
+
+    /** Write durations of all databases, or only the changed ones with adaptive writes.
+     * Titles are only written with a database, as they change with its durations.
+     */
+    fn write_changes(&self, instant: time::Instant) -> Result<(), ErrorMessage> {
+        let min_write_interval = match self.min_write_interval {
+            Some(min_write_interval) => min_write_interval,
+            None => return self.write_all(instant),
+        };
+        let mut written = false;
+        for recorder in &self.recorders {
+            written |= recorder.write_changes_to_disk(instant, min_write_interval)?;
+        }
+        if let (Some(title_durations), true) = (&self.title_durations, written) {
+            title_durations.borrow_mut().write(instant)
+        }
+        Ok(())
+    }
+
+    /// Write durations of all databases, skipping unchanged ones with adaptive writes.
+    pub fn on_db_write_tick(&self, instant: time::Instant) -> Result<(), ErrorMessage> {
+        trace!("task_write_db");
+        self.write_changes(instant)
+    }
+
+    /** Write durations of all databases now, on user request (SIGUSR1), then log the state dump.
+     * Tasks run one at a time on the single threaded event loop, so no other write is in progress:
+     * state is never borrowed across tasks, and the RefCell borrows cannot conflict.
+     */
+    pub fn on_flush_request(&self) -> Result<(), ErrorMessage> {
+        info!("Flush requested by SIGUSR1");
+        self.write_all(time::Instant::now())?;
+        info!("State: {}", self.dump());
+        Ok(())
+    }
+
+    /// Internal state and configuration as pretty JSON, for debugging. Nothing is modified.
+    pub fn dump(&self) -> String {
+        let now = time::Instant::now();
+        let dump = DaemonDump {
+            started: self.started.get(),
+            paused: self.paused.get(),
+            away_category: self.away_category.borrow().clone(),
+            active_window_categories: self.active_window_categories.borrow().clone(),
+            active_window_excluded: self.active_window_excluded.get(),
+            monitor_categories: self.monitor_categories.borrow().clone(),
+            classifier_categories: self.classifier.borrow().categories().to_vec(),
+            idle_timeout: self.idle_detection.as_ref().map(|d| d.timeout.as_secs()),
+            idle_category: self.idle_detection.as_ref().map(|d| d.category.clone()),
+            locked_category: self.locked_category.clone(),
+            flush_on_change: self.flush_on_change,
+            count_windows: self.count_windows,
+            track_titles: self.title_durations.is_some(),
+            recorders: self
+                .recorders
+                .iter()
+                .map(|recorder| recorder.dump(now))
+                .collect(),
+        };
+        serde_json::to_string_pretty(&dump).unwrap()
+    }
+
+    /// Close the elapsed time windows of the database at index, from 0 to nb_databases().
+    pub fn on_window_boundary(&self, index: usize) -> Result<(), ErrorMessage> {
+        debug!("task_new_time_window");
+        self.recorders[index].change_time_windows()
+    }
+
+    /** Start new time windows for all databases now, on user request (SIGUSR2).
+     * Current windows are written and closed early. The next boundaries are then one window
+     * size after now: the event loop must use duration_to_window_boundary() to schedule them.
+     */
+    pub fn on_rotate_request(&self) -> Result<(), ErrorMessage> {
+        info!("Time window rotation requested by SIGUSR2");
+        for recorder in &self.recorders {
+            recorder.rotate_time_window()?
+        }
+        Ok(())
+    }
+
+    /// Reload the classifier, adding new categories to the databases.
+    /// Reload errors are logged, and the previous configuration is kept.
+    pub fn on_reload(&self) -> Result<(), ErrorMessage> {
+        info!("Reloading classifier");
+        let mut classifier = self.classifier.borrow_mut();
+        if let Err(e) = classifier.reload() {
+            error!("Unable to reload classifier: {:?}", ShowErrorTraceback(e));
+            return Ok(());
+        }
+        for recorder in &self.recorders {
+            let added = recorder.add_categories(classifier.categories())?;
+            if !added.is_empty() {
+                info!(
+                    "Added categories to the database '{}': {}",
+                    recorder.db_file.display(),
+                    added.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop or restart counting time at timestamp.
+    pub fn set_paused(&self, paused: bool, timestamp: time::Instant) {
+        if self.paused.replace(paused) != paused {
+            self.change_counted_category(timestamp)
+        }
+    }
+
+    /// Add input event counts to the current time windows, unless paused or in an excluded window.
+    pub fn on_input(&self, activity: InputActivity) {
+        if self.paused.get() || self.active_window_excluded.get() {
+            return;
+        }
+        for recorder in &self.recorders {
+            recorder.activity.borrow_mut().add(activity)
+        }
+    }
+
+    /// Set the note of the current time window of all databases. An empty note removes it.
+    pub fn annotate(&self, note: &str) -> Result<(), ErrorMessage> {
+        info!("Annotating current time window: '{}'", note);
+        let now = time::Instant::now();
+        for recorder in &self.recorders {
+            recorder.annotate(note, now)?;
+        }
+        Ok(())
+    }
+
+    /** End the current stretch, flush durations recorded since the last write, and shutdown the classifier.
+     * A summary of the current time window of the main database is logged after the flush.
+     */
+    pub fn stop(self) -> Result<(), ErrorMessage> {
+        let now = time::Instant::now();
+        if let Some(stretch_log) = &self.stretch_log {
+            stretch_log.borrow_mut().end(now)
+        }
+        self.write_all(now)?;
+        info!(
+            "Session summary:\n{}",
+            status::summary(
+                &self.main_duration_counter().borrow(),
+                &self.main_window_start(),
+                &self.main_extras()
+            )
+            .trim_end()
+        );
+        self.classifier.into_inner().shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::classifier::{ClassAsCategoryClassifier, MatchMode};
+    use super::*;
+
+    /// Classifier failing on every window.
+    struct FailingClassifier;
+    impl Classifier for FailingClassifier {
+        fn categories(&self) -> UniqueCategories {
+            UniqueCategories::make_unique(vec!["work".into()])
+        }
+        fn classify(
+            &mut self,
+            _metadata: ActiveWindowMetadata,
+        ) -> Result<Option<String>, ErrorMessage> {
+            Err(ErrorMessage::from("classifier failure"))
+        }
+    }
+
+    /// Daemon recording to a dry database, which is never written to disk.
+    fn test_daemon<C: Classifier>(
+        classifier: C,
+        window_size: time::Duration,
+        options: DaemonOptions,
+    ) -> Daemon<C> {
+        let database = WindowedDatabase {
+            path: PathBuf::from("test.db"),
+            format: DatabaseFormat::Plain,
+            precision: DurationPrecision::Milliseconds,
+            window_size: window_size,
+            align: false,
+            write_retries: 0,
+            compact_after: None,
+            max_size: None,
+            trim_to_archive: false,
+            dry: true,
+            daily_files: false,
+            monitor: None,
+        };
+        Daemon::new(
+            classifier,
+            ExcludedWindows::new(&[], &[], MatchMode::Exact).unwrap(),
+            vec![database],
+            DaemonOptions {
+                db_lock: false,
+                allow_empty_categories: true,
+                ..options
+            },
+        )
+        .unwrap()
+    }
+
+    /// Daemon using the window class as category, with hourly time windows.
+    fn class_daemon(options: DaemonOptions) -> Daemon<ClassAsCategoryClassifier> {
+        test_daemon(
+            ClassAsCategoryClassifier::new(None::<String>),
+            time::Duration::from_secs(3600),
+            options,
+        )
+    }
+
+    fn window(class: &str) -> ActiveWindowMetadata {
+        ActiveWindowMetadata::for_test(None, Some(class))
+    }
+
+    fn secs(n: u64) -> time::Duration {
+        time::Duration::from_secs(n)
+    }
+
+    /// Seconds counted for a category in the main database, up to timestamp.
+    fn counted<C: Classifier>(daemon: &Daemon<C>, category: &str, timestamp: time::Instant) -> u64 {
+        let counter = daemon.main_duration_counter().borrow();
+        counter
+            .categories()
+            .index_of(category)
+            .map_or(0, |index| counter.durations_at(timestamp)[index].as_secs())
+    }
+
+    #[test]
+    fn window_change_switches_counted_category() {
+        let daemon = class_daemon(DaemonOptions::default());
+        let t0 = time::Instant::now();
+        daemon.start(window("editor"), t0, secs(0)).unwrap();
+        daemon
+            .on_window_change(window("browser"), t0 + secs(10))
+            .unwrap();
+        assert_eq!(counted(&daemon, "editor", t0 + secs(15)), 10);
+        assert_eq!(counted(&daemon, "browser", t0 + secs(15)), 5);
+        assert_eq!(daemon.main_extras().switches, 1);
+    }
+
+    #[test]
+    fn idle_replaces_window_category() {
+        let daemon = class_daemon(DaemonOptions {
+            idle_detection: Some(IdleDetection {
+                timeout: secs(60),
+                category: "idle".into(),
+            }),
+            ..DaemonOptions::default()
+        });
+        let t0 = time::Instant::now();
+        daemon.start(window("editor"), t0, secs(0)).unwrap();
+        daemon.on_away_check(false, secs(30), t0 + secs(30));
+        daemon.on_away_check(false, secs(60), t0 + secs(60));
+        // Window changes while idle are only counted when the user is back
+        daemon
+            .on_window_change(window("browser"), t0 + secs(70))
+            .unwrap();
+        daemon.on_away_check(false, secs(0), t0 + secs(100));
+        let t = t0 + secs(110);
+        assert_eq!(counted(&daemon, "editor", t), 60);
+        assert_eq!(counted(&daemon, "idle", t), 40);
+        assert_eq!(counted(&daemon, "browser", t), 10);
+    }
+
+    #[test]
+    fn pause_stops_counting() {
+        let daemon = class_daemon(DaemonOptions::default());
+        let t0 = time::Instant::now();
+        daemon.start(window("editor"), t0, secs(0)).unwrap();
+        daemon.set_paused(true, t0 + secs(10));
+        daemon
+            .on_window_change(window("browser"), t0 + secs(20))
+            .unwrap();
+        daemon.set_paused(false, t0 + secs(30));
+        let t = t0 + secs(40);
+        assert_eq!(counted(&daemon, "editor", t), 10);
+        assert_eq!(counted(&daemon, "browser", t), 10);
+    }
+
+    #[test]
+    fn window_changes_are_ignored_until_begin() {
+        let daemon = class_daemon(DaemonOptions {
+            start_paused: true,
+            ..DaemonOptions::default()
+        });
+        let t0 = time::Instant::now();
+        daemon.on_window_change(window("editor"), t0).unwrap();
+        assert!(!daemon.is_started());
+        daemon
+            .begin(window("browser"), t0 + secs(10), secs(0))
+            .unwrap();
+        assert!(daemon.is_started());
+        let t = t0 + secs(20);
+        assert_eq!(counted(&daemon, "editor", t), 0);
+        assert_eq!(counted(&daemon, "browser", t), 10);
+    }
+
+    #[test]
+    fn failed_start_leaves_daemon_stopped() {
+        let daemon = test_daemon(FailingClassifier, secs(3600), DaemonOptions::default());
+        let t0 = time::Instant::now();
+        assert!(daemon.start(window("editor"), t0, secs(0)).is_err());
+        assert!(!daemon.is_started());
+        // Ignored, instead of failing again
+        assert!(daemon.on_window_change(window("editor"), t0).is_ok());
+    }
+
+    #[test]
+    fn window_boundary_starts_new_time_window() {
+        // Windows ending within WINDOW_CHANGE_TOLERANCE are elapsed: the first one is closed now.
+        let daemon = test_daemon(
+            ClassAsCategoryClassifier::new(None::<String>),
+            secs(1),
+            DaemonOptions::default(),
+        );
+        let t0 = time::Instant::now();
+        daemon.start(window("editor"), t0, secs(0)).unwrap();
+        let window_start = daemon.main_window_start();
+        daemon.on_window_boundary(0).unwrap();
+        assert_eq!(
+            daemon.main_window_start(),
+            window_start + chrono::Duration::seconds(1)
+        );
+        let counter = daemon.main_duration_counter().borrow();
+        assert!(counter.durations().iter().all(|d| *d == secs(0)));
+    }
+}
@@ -1,11 +1,22 @@
-use super::UniqueCategories;
+use super::{ErrorMessage, UniqueCategories};
 use chrono;
+use chrono::TimeZone;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use libc;
+use serde::{Deserialize, Serialize};
+use serde_json;
 use std;
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::time;
 
 // io::Error with InvalidData is used for DB formatting errors. Shorten creation.
@@ -16,42 +27,578 @@ where
     io::Error::new(io::ErrorKind::InvalidData, error)
 }
 
-/// Store line related counts, updated by "processing" lines (read or write).
-/// The goal is to keep track of the offset of the last line of the database file.
-struct LineCounts {
-    last_line_start_offset: usize, // byte offset of last processed line
-    last_line_len: usize,          // size of last processed line
-    line_nb: usize,                // index of last processed line
+/** Version of the plain database format, written in the header: "time_window:v2\tcat1...".
+ * Version 1 headers have no version suffix ("time_window\tcat1..."), and may contain time
+ * windows without UTC offset. They are upgraded when opened by Database::open.
+ * Version 1 covers every unversioned file, including those written with the optional extra
+ * fields (~switches, @activity, %windows, #note) before versioning: readers of all versions
+ * accept them after the durations, and keep them on upgrade.
+ * Increment it for each format change that older binaries cannot read.
+ */
+pub const FORMAT_VERSION: u32 = 2;
+const HEADER_VERSION_SEPARATOR: &str = ":v";
+
+/// Parse header line (without newline), return format version and categories.
+fn parse_versioned_header(header: &str) -> io::Result<(u32, UniqueCategories)> {
+    // Line must contain at least 'time' header.
+    let mut elements = header.split('\t');
+    let time_header = match elements.next() {
+        Some(time_header) => time_header,
+        None => return Err(bad_data("Header has no field")),
+    };
+    let version = match time_header.find(HEADER_VERSION_SEPARATOR) {
+        Some(index) => time_header[index + HEADER_VERSION_SEPARATOR.len()..]
+            .parse()
+            .map_err(|_| bad_data(format!("Invalid format version: {:?}", time_header)))?,
+        None => 1,
+    };
+    if version > FORMAT_VERSION {
+        return Err(bad_data(format!(
+            "Database format version {} is newer than supported version {}, update xstalker",
+            version, FORMAT_VERSION
+        )));
+    }
+    let categories =
+        UniqueCategories::from_unique(elements.map(|s| s.into()).collect()).map_err(bad_data)?;
+    Ok((version, categories))
+}
+
+/// Parse header line (without newline), return categories.
+fn parse_header(header: &str) -> io::Result<UniqueCategories> {
+    parse_versioned_header(header).map(|(_version, categories)| categories)
 }
 
-impl LineCounts {
-    fn new() -> Self {
-        LineCounts {
-            last_line_start_offset: 0,
-            last_line_len: 0,
-            line_nb: 0,
+/// Parse a duration in seconds, with an optional decimal part: "12" or "12.345".
+fn parse_seconds(text: &str) -> Result<time::Duration, String> {
+    let invalid = || format!("Invalid duration '{}'", text);
+    let mut parts = text.splitn(2, '.');
+    let seconds: u64 = parts.next().unwrap().parse().map_err(|_| invalid())?;
+    let nanos = match parts.next() {
+        Some(decimals) => {
+            if decimals.is_empty()
+                || decimals.len() > 9
+                || !decimals.bytes().all(|b| b.is_ascii_digit())
+            {
+                return Err(invalid());
+            }
+            // Pad to nanoseconds
+            format!("{:0<9}", decimals).parse().unwrap()
         }
+        None => 0,
+    };
+    Ok(time::Duration::new(seconds, nanos))
+}
+
+/// Format a duration in seconds, with milliseconds only if not a whole number of seconds.
+pub fn format_seconds(d: time::Duration) -> String {
+    if d.subsec_millis() == 0 {
+        format!("{}", d.as_secs())
+    } else {
+        format!("{}.{:03}", d.as_secs(), d.subsec_millis())
     }
-    /// Update counters for processing the next line (read or write)
-    fn advance(&mut self, line_len: usize) {
-        self.last_line_start_offset += self.last_line_len;
-        self.last_line_len = line_len;
-        self.line_nb += 1;
+}
+
+/// Precision of durations stored in the database.
+/// Durations are counted at full precision, and only rounded when written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DurationPrecision {
+    Seconds,
+    Milliseconds,
+}
+
+impl DurationPrecision {
+    /// Select precision from name: "s" or "ms".
+    pub fn from_name(name: &str) -> Result<Self, ErrorMessage> {
+        match name {
+            "s" => Ok(DurationPrecision::Seconds),
+            "ms" => Ok(DurationPrecision::Milliseconds),
+            _ => Err(ErrorMessage::from(format!(
+                "Unknown duration precision '{}'",
+                name
+            ))),
+        }
+    }
+
+    /// Round to the nearest unit, so rounding errors do not add up to systematic undercounting.
+    fn round(self, d: time::Duration) -> time::Duration {
+        match self {
+            DurationPrecision::Seconds => {
+                time::Duration::from_secs(d.as_secs() + u64::from(d.subsec_nanos() >= 500_000_000))
+            }
+            DurationPrecision::Milliseconds => time::Duration::from_millis(
+                d.as_secs() * 1000 + u64::from((d.subsec_nanos() + 500_000) / 1_000_000),
+            ),
+        }
+    }
+}
+
+/** Optional fields of an entry, after the durations in the plain format:
+ * [~switches][@keys/mouse][%windows][#note].
+ * Fields are only written if set, so files without them keep the previous format.
+ */
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EntryExtras {
+    /// Number of switches between categories during the time window
+    pub switches: u32,
+    /// Input event counts during the time window, if recorded
+    pub activity: InputActivity,
+    /// Number of distinct windows focused during the time window, 0 if not recorded
+    pub windows: u32,
+    /// Free text note, without tabs or newlines
+    pub note: Option<String>,
+}
+
+/** Numbers of user input events, for the activity level: never which keys or where.
+ * Mouse events are button presses and pointer motion events.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct InputActivity {
+    pub keys: u32,
+    pub mouse: u32,
+}
+
+impl InputActivity {
+    pub fn is_zero(&self) -> bool {
+        self.keys == 0 && self.mouse == 0
+    }
+
+    pub fn add(&mut self, other: InputActivity) {
+        self.keys = self.keys.saturating_add(other.keys);
+        self.mouse = self.mouse.saturating_add(other.mouse);
+    }
+
+    /// Input events per minute of the given duration, 0 if empty.
+    pub fn events_per_minute(&self, duration: time::Duration) -> f64 {
+        let minutes = duration.as_secs() as f64 / 60.;
+        if minutes > 0. {
+            (f64::from(self.keys) + f64::from(self.mouse)) / minutes
+        } else {
+            0.
+        }
+    }
+}
+
+const SWITCHES_PREFIX: &str = "~";
+const ACTIVITY_PREFIX: &str = "@";
+const WINDOWS_PREFIX: &str = "%";
+const NOTE_PREFIX: &str = "#";
+
+/// Split the last field of line if it starts with prefix: line without the field, field value.
+fn split_last_field<'a>(line: &'a str, prefix: &str) -> Option<(&'a str, &'a str)> {
+    let index = line.rfind('\t')?;
+    let field = &line[index + 1..];
+    if field.starts_with(prefix) {
+        Some((&line[..index], &field[prefix.len()..]))
+    } else {
+        None
+    }
+}
+
+/// Split an entry line into the line without extra fields, and the extra fields.
+fn split_extras(mut line: &str) -> (&str, EntryExtras) {
+    let mut extras = EntryExtras::default();
+    if let Some((rest, note)) = split_last_field(line, NOTE_PREFIX) {
+        extras.note = Some(note.into());
+        line = rest;
+    }
+    if let Some((rest, windows)) = split_last_field(line, WINDOWS_PREFIX) {
+        // An invalid field is kept, to be reported as an invalid duration
+        if let Ok(windows) = windows.parse() {
+            extras.windows = windows;
+            line = rest;
+        }
+    }
+    if let Some((rest, activity)) = split_last_field(line, ACTIVITY_PREFIX) {
+        let mut counts = activity.splitn(2, '/').map(str::parse);
+        // An invalid field is kept, to be reported as an invalid duration
+        if let (Some(Ok(keys)), Some(Ok(mouse))) = (counts.next(), counts.next()) {
+            extras.activity = InputActivity {
+                keys: keys,
+                mouse: mouse,
+            };
+            line = rest;
+        }
+    }
+    if let Some((rest, switches)) = split_last_field(line, SWITCHES_PREFIX) {
+        // An invalid field is kept, to be reported as an invalid duration
+        if let Ok(switches) = switches.parse() {
+            extras.switches = switches;
+            line = rest;
+        }
+    }
+    (line, extras)
+}
+
+/// Add extra fields to an entry line without them. Tabs and newlines of the note become spaces.
+fn add_extras(mut line: String, extras: &EntryExtras) -> String {
+    if extras.switches > 0 {
+        use std::fmt::Write;
+        write!(&mut line, "\t{}{}", SWITCHES_PREFIX, extras.switches).unwrap();
+    }
+    if !extras.activity.is_zero() {
+        use std::fmt::Write;
+        write!(
+            &mut line,
+            "\t{}{}/{}",
+            ACTIVITY_PREFIX, extras.activity.keys, extras.activity.mouse
+        )
+        .unwrap();
+    }
+    if extras.windows > 0 {
+        use std::fmt::Write;
+        write!(&mut line, "\t{}{}", WINDOWS_PREFIX, extras.windows).unwrap();
+    }
+    if let Some(note) = extras.note.as_ref().filter(|note| !note.is_empty()) {
+        line.push('\t');
+        line.push_str(NOTE_PREFIX);
+        line.extend(note.chars().map(|c| match c {
+            '\t' | '\n' | '\r' => ' ',
+            c => c,
+        }));
+    }
+    line
+}
+
+/// Parse an entry line (without newline): time window start and durations for categories.
+/// Extra fields are ignored.
+fn parse_entry(
+    line: &str,
+    nb_categories: usize,
+) -> io::Result<(DatabaseTime, Vec<time::Duration>)> {
+    let mut elements = split_extras(line).0.split('\t');
+    match elements.next() {
+        Some(time_window_text) => {
+            let time_window = DatabaseTime::parse_iso8601(time_window_text)
+                .map_err(|err| bad_data(format!("Cannot parse time window: {}", err)))?;
+            // Read durations of entry
+            let mut durations = Vec::with_capacity(nb_categories);
+            for s in elements {
+                let duration = parse_seconds(s)
+                    .map_err(|err| bad_data(format!("Cannot parse category duration: {}", err)))?;
+                durations.push(duration)
+            }
+            if durations.len() != nb_categories {
+                return Err(bad_data(format!(
+                    "Durations: expected {} fields, got {}",
+                    nb_categories,
+                    durations.len()
+                )));
+            }
+            Ok((time_window, durations))
+        }
+        None => Err(bad_data("Entry is empty")),
+    }
+}
+
+/// Format an entry line (without newline) from time window start and durations.
+fn format_entry(window_start: &DatabaseTime, durations: &[time::Duration]) -> String {
+    let mut line = window_start.format_iso8601();
+    for d in durations {
+        use std::fmt::Write;
+        write!(&mut line, "\t{}", format_seconds(*d)).unwrap();
     }
-    /// Forget info about last line, do as if there was no last line.
-    fn ignore_last_line(&mut self) {
-        self.last_line_start_offset += self.last_line_len;
-        self.last_line_len = 0;
+    line
+}
+
+/// Entry of a JSON Lines database, one per line.
+#[derive(Serialize, Deserialize)]
+struct JsonEntry {
+    start: String,
+    durations: BTreeMap<String, serde_json::Number>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    switches: u32,
+    #[serde(default, skip_serializing_if = "InputActivity::is_zero")]
+    activity: InputActivity,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    windows: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// Duration in seconds as a JSON number: integer if a whole number of seconds.
+pub fn json_seconds(d: time::Duration) -> serde_json::Number {
+    if d.subsec_millis() == 0 {
+        serde_json::Number::from(d.as_secs())
+    } else {
+        // Finite, so always representable
+        serde_json::Number::from_f64(d.as_secs() as f64 + f64::from(d.subsec_millis()) / 1000.)
+            .unwrap()
+    }
+}
+
+/// Parse a JSON number of seconds, integer or decimal.
+fn parse_json_seconds(n: &serde_json::Number) -> io::Result<time::Duration> {
+    if let Some(seconds) = n.as_u64() {
+        return Ok(time::Duration::from_secs(seconds));
+    }
+    match n.as_f64() {
+        Some(seconds) if seconds >= 0. => {
+            Ok(time::Duration::from_millis((seconds * 1000.).round() as u64))
+        }
+        _ => Err(bad_data(format!("Invalid duration '{}'", n))),
+    }
+}
+
+/// Parse a JSON Lines database line (without newline), categories missing from the entry are 0.
+fn parse_json_entry(
+    line: &str,
+    categories: &UniqueCategories,
+) -> io::Result<(DatabaseTime, Vec<time::Duration>)> {
+    parse_json_entry_with_extras(line, categories)
+        .map(|(window_start, durations, _)| (window_start, durations))
+}
+
+/// Same as parse_json_entry(), also returning the extra fields.
+fn parse_json_entry_with_extras(
+    line: &str,
+    categories: &UniqueCategories,
+) -> io::Result<(DatabaseTime, Vec<time::Duration>, EntryExtras)> {
+    let entry: JsonEntry = serde_json::from_str(line).map_err(bad_data)?;
+    let time_window = DatabaseTime::parse_iso8601(&entry.start)
+        .map_err(|err| bad_data(format!("Cannot parse time window: {}", err)))?;
+    if let Some(category) = entry.durations.keys().find(|c| !categories.contains(c)) {
+        return Err(bad_data(format!("Unknown category '{}'", category)));
+    }
+    let mut durations = Vec::with_capacity(categories.len());
+    for category in categories.iter() {
+        durations.push(match entry.durations.get(category) {
+            Some(seconds) => parse_json_seconds(seconds)?,
+            None => time::Duration::new(0, 0),
+        })
+    }
+    let extras = EntryExtras {
+        switches: entry.switches,
+        activity: entry.activity,
+        windows: entry.windows,
+        note: entry.note,
+    };
+    Ok((time_window, durations, extras))
+}
+
+/// Categories of a JSON Lines database line, sorted by name.
+fn json_entry_categories(line: &str) -> io::Result<UniqueCategories> {
+    let entry: JsonEntry = serde_json::from_str(line).map_err(bad_data)?;
+    Ok(UniqueCategories::make_unique(
+        entry.durations.into_iter().map(|(c, _)| c).collect(),
+    ))
+}
+/// Size limit of a database file, see Database::trim().
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaxDatabaseSize {
+    /// Size of the file in bytes
+    Bytes(u64),
+    /// Number of entries (time windows)
+    Entries(usize),
+}
+
+impl MaxDatabaseSize {
+    /// Parse a size: bytes with an optional K, M or G suffix (powers of 1024), or "<n>entries".
+    pub fn from_arg(text: &str) -> Result<Self, ErrorMessage> {
+        let invalid = || {
+            ErrorMessage::from(format!(
+                "Invalid database size '{}': expected bytes (with K, M or G suffix) or <n>entries",
+                text
+            ))
+        };
+        if text.ends_with("entries") {
+            let number = text.trim_end_matches("entries").trim_end();
+            return number
+                .parse()
+                .map(MaxDatabaseSize::Entries)
+                .map_err(|_| invalid());
+        }
+        let (number, unit) = match text.chars().last() {
+            Some('K') => (&text[..text.len() - 1], 1 << 10),
+            Some('M') => (&text[..text.len() - 1], 1 << 20),
+            Some('G') => (&text[..text.len() - 1], 1 << 30),
+            _ => (text, 1),
+        };
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        number
+            .checked_mul(unit)
+            .map(MaxDatabaseSize::Bytes)
+            .ok_or_else(invalid)
+    }
+}
+
+/// Storage format of the database file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DatabaseFormat {
+    /// Tab-separated text with a header line
+    Plain,
+    /// One JSON object per line
+    JsonLines,
+}
+
+impl DatabaseFormat {
+    /// Select format from name: "plain", "jsonl", or "auto" to detect from the file extension.
+    pub fn from_name(name: &str, path: &Path) -> Result<Self, ErrorMessage> {
+        match name {
+            "auto" => Ok(DatabaseFormat::detect(path)),
+            "plain" => Ok(DatabaseFormat::Plain),
+            "jsonl" => Ok(DatabaseFormat::JsonLines),
+            _ => Err(ErrorMessage::from(format!(
+                "Unknown database format '{}'",
+                name
+            ))),
+        }
+    }
+
+    /// JSON Lines for files with a .jsonl extension, plain otherwise.
+    pub fn detect(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext == "jsonl" => DatabaseFormat::JsonLines,
+            _ => DatabaseFormat::Plain,
+        }
+    }
+}
+
+/** Convert a time window start without UTC offset (old format) to rfc3339 with offset.
+ * The time is assumed to be in the local timezone.
+ * Returns None if the text is not in the old format.
+ */
+fn migrate_naive_time(text: &str) -> Option<String> {
+    if DatabaseTime::parse_iso8601(text).is_ok() {
+        return None;
+    }
+    let naive: chrono::NaiveDateTime = text.parse().ok()?;
+    chrono::Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.format_iso8601())
+}
+
+/// Delay before the first retry of a failed write, doubled for each retry.
+const WRITE_RETRY_INITIAL_BACKOFF: time::Duration = time::Duration::from_millis(100);
+/// Maximum delay before a retry of a failed write.
+const WRITE_RETRY_MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+/// Maximum number of retries of a failed write: about half an hour of retries at most.
+pub const MAX_WRITE_RETRIES: u32 = 32;
+
+/// Delay before the next retry of a failed write, from the delay before the previous one.
+fn next_write_retry_backoff(previous: Option<time::Duration>) -> time::Duration {
+    match previous {
+        Some(previous) => cmp::min(previous * 2, WRITE_RETRY_MAX_BACKOFF),
+        None => WRITE_RETRY_INITIAL_BACKOFF,
+    }
+}
+
+/// Write which failed with a transient error, retried by a later write after the backoff.
+struct FailedWrite {
+    retries: u32,
+    backoff: time::Duration,
+    retry_at: time::Instant,
+}
+
+/// Errors that may disappear by themselves: interruptions, timeouts, full disk or quota.
+fn is_transient_error(e: &io::Error) -> bool {
+    match e.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => true,
+        _ => match e.raw_os_error() {
+            Some(code) => code == libc::ENOSPC || code == libc::EDQUOT,
+            None => false,
+        },
     }
-    /// Current cursor offset after processing last line.
-    fn cursor(&self) -> usize {
-        self.last_line_start_offset + self.last_line_len
+}
+
+/** Replace the file at path with the content written by write, going through a temporary file.
+ * The temporary file is synced before being renamed over path. If write fails, path is untouched.
+ */
+fn write_atomically<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> io::Result<()>,
+{
+    let tmp_path = sibling_path(path, ".tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        write(&mut writer)?;
+        writer.into_inner()?.sync_all()? // May be costly, but we do not call that often...
     }
+    fs::rename(&tmp_path, path)
 }
 
-/// Seek to an offset from start of file.
-fn seek_to_offset<F: Seek>(f: &mut F, offset: usize) -> io::Result<()> {
-    f.seek(io::SeekFrom::Start(offset as u64)).map(|_| ())
+/// Path of a file next to the database: database file name with an added suffix.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/** Path of the archive of compacted entries: <db_file>.archive.gz.
+ * The archive is gzip compressed JSON Lines, whatever the database format.
+ * Each compaction appends a gzip member, so existing archived data is never rewritten.
+ */
+pub fn archive_path(db_file: &Path) -> PathBuf {
+    sibling_path(db_file, ".archive.gz")
+}
+
+/// Path of the database of a monitor with --per-monitor: <db_file>.monitor-<output>.
+pub fn monitor_path(db_file: &Path, output: &str) -> PathBuf {
+    sibling_path(db_file, &format!(".monitor-{}", output))
+}
+
+/// File name prefix of daily database files.
+const DAILY_FILE_PREFIX: &str = "activity-";
+
+/** Path of the daily database file of date in dir: activity-2024-06-01.log for the plain format,
+ * or activity-2024-06-01.jsonl for JSON Lines. Names sort in date order.
+ */
+pub fn daily_file_path(dir: &Path, date: chrono::NaiveDate, format: DatabaseFormat) -> PathBuf {
+    let extension = match format {
+        DatabaseFormat::Plain => "log",
+        DatabaseFormat::JsonLines => "jsonl",
+    };
+    dir.join(format!(
+        "{}{}.{}",
+        DAILY_FILE_PREFIX,
+        date.format("%Y-%m-%d"),
+        extension
+    ))
+}
+
+/// Daily database files of dir, in date order. Other files (archives, locks) are ignored.
+pub fn daily_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_daily_file = match (path.file_stem(), path.extension()) {
+            (Some(stem), Some(extension)) => {
+                let stem = stem.to_string_lossy();
+                stem.starts_with(DAILY_FILE_PREFIX)
+                    && chrono::NaiveDate::parse_from_str(
+                        &stem[DAILY_FILE_PREFIX.len()..],
+                        "%Y-%m-%d",
+                    )
+                    .is_ok()
+                    && (extension == "log" || extension == "jsonl")
+            }
+            _ => false,
+        };
+        if is_daily_file {
+            files.push(path)
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Acquire an exclusive advisory lock on the file. It is released when the file is closed.
+fn lock_file(f: &File) -> io::Result<()> {
+    if unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.kind() {
+        io::ErrorKind::WouldBlock => Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "database already in use by another process",
+        )),
+        _ => Err(err),
+    }
 }
 
 /** Time spent Database.
@@ -59,105 +606,377 @@ fn seek_to_offset<F: Seek>(f: &mut F, offset: usize) -> io::Result<()> {
  *
  * Database is a text file with a header line, and one entry for each subsequent lines.
  * Each line is tab-separated into columns.
- * The first column is the time window start, in rfc3339 format.
- * The next columns represent the time spent in each category, in seconds (integer).
+ * The first column is the time window start, in rfc3339 format with the UTC offset.
+ * Old databases with time window starts without offset are converted at opening.
+ * The next columns represent the time spent in each category, in seconds.
+ * Seconds are integers, or have 3 decimals if the database stores milliseconds.
  * The header line contain the category name for each columns.
  * Each category must be uniquely named.
  *
  * The Database is supposed to be written to disk often, to avoid data loss.
- * This is done by rewriting the last entry, except when the time window changes (new entry).
- * Rewriting the last entry is done using LineCounted, which tracks last line position.
+ * Entries are stored in memory, and only the last entry can be modified.
+ * When the time window changes, the last entry is locked and a new one will be created.
+ * Each write replaces the whole file: content is written to a temporary file,
+ * which is then renamed over the database file.
+ * Thus the database file is always complete, even if a crash happens during a write.
+ *
+ * A lock file (flock) is held for the lifetime of the Database, to prevent concurrent writers.
+ * The database file itself cannot be locked, as it is replaced on each write.
+ *
+ * With the JSON Lines format, there is no header line.
+ * Each line is an object: {"start": <rfc3339 time window start>, "durations": {<category>: <seconds>}}.
+ * Durations are numbers of seconds, with decimals if the database stores milliseconds.
+ * Categories are the union of the keys of all entries.
+ * Entries are kept in memory in the plain format, and only converted when reading or writing.
  */
 pub struct Database {
-    file: File,
-    counts: LineCounts, // After construction, always points to last line of file.
+    path: PathBuf,
+    format: DatabaseFormat,
+    precision: DurationPrecision,
+    /// Number of retries of a write failing with a transient error
+    write_retries: u32,
+    failed_write: Option<FailedWrite>, // Content in memory is newer than the file
+    _lock_file: Option<File>,          // Kept open to hold the lock
     categories: UniqueCategories,
+    locked_entries: Vec<String>, // Entry lines, without newline
+    last_entry: Option<String>,  // Modifiable last entry line, without newline
+    dry: bool,                   // Writes are only logged, the file is never touched
 }
 
 /// Time windows are timezone aware, in system local timezone.
+/// Comparisons and arithmetic are done on absolute time, so they are not affected by offsets.
 pub type DatabaseTime = chrono::DateTime<chrono::Local>;
 
+/** Canonical text representation of DatabaseTime, shared by the database, reports and exports.
+ * Times are formatted as RFC 3339 (ISO 8601) with their UTC offset, so they are unambiguous
+ * around DST changes. Parsed times are converted to the local timezone, keeping the same instant.
+ */
+pub trait Iso8601: Sized {
+    fn format_iso8601(&self) -> String;
+    fn parse_iso8601(text: &str) -> Result<Self, ErrorMessage>;
+}
+
+impl Iso8601 for DatabaseTime {
+    fn format_iso8601(&self) -> String {
+        self.to_rfc3339()
+    }
+    fn parse_iso8601(text: &str) -> Result<Self, ErrorMessage> {
+        chrono::DateTime::parse_from_rfc3339(text)
+            .map(|time| time.with_timezone(&chrono::Local))
+            .map_err(|e| ErrorMessage::from(format!("Invalid time '{}': {}", text, e)))
+    }
+}
+
+/** Presentation format of times in reports and exports, the database always uses Iso8601.
+ * Custom formats are strftime-like strings, interpreted by chrono: "%Y-%m-%d %H:%M", "%s".
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeFormat {
+    Iso8601,
+    Custom(String),
+}
+
+impl TimeFormat {
+    /// ISO 8601 if no format is given. Invalid format strings are rejected here, not when used.
+    pub fn from_arg(format: Option<&str>) -> Result<Self, ErrorMessage> {
+        match format {
+            None => Ok(TimeFormat::Iso8601),
+            Some(format) => {
+                if chrono::format::StrftimeItems::new(format)
+                    .any(|item| item == chrono::format::Item::Error)
+                {
+                    return Err(ErrorMessage::from(format!(
+                        "Invalid time format '{}'",
+                        format
+                    )));
+                }
+                Ok(TimeFormat::Custom(format.into()))
+            }
+        }
+    }
+
+    pub fn format(&self, time: &DatabaseTime) -> String {
+        match self {
+            TimeFormat::Iso8601 => time.format_iso8601(),
+            TimeFormat::Custom(format) => time.format(format).to_string(),
+        }
+    }
+}
+
+/** Differences between the categories of an existing database and the requested ones.
+ * Added categories get zero durations in past entries. Historical categories are only in the
+ * database: they are kept with their past durations, and only get zero durations from now on.
+ */
+#[derive(Debug, Default)]
+pub struct CategoryMigration {
+    pub added: Vec<String>,
+    pub historical: Vec<String>,
+}
+
+impl CategoryMigration {
+    fn new(db_categories: &UniqueCategories, requested: &UniqueCategories) -> Self {
+        CategoryMigration {
+            added: requested
+                .iter()
+                .filter(|c| !db_categories.contains(c))
+                .cloned()
+                .collect(),
+            historical: db_categories
+                .iter()
+                .filter(|c| !requested.contains(c))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.historical.is_empty()
+    }
+}
+
+impl fmt::Display for CategoryMigration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if !self.added.is_empty() {
+            parts.push(format!("added {}", self.added.join(", ")));
+        }
+        if !self.historical.is_empty() {
+            parts.push(format!(
+                "no longer used, kept for past entries: {}",
+                self.historical.join(", ")
+            ));
+        }
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
 impl Database {
     /** Open a database.
      * If the database does not exist, create a new one.
-     * If the database exist and is compatible (contains the requested categories), use it.
-     * If it exists but is not compatible, add the new categories.
+     * Creates parent directories if needed.
+     * If the database exists, new requested categories are added to it.
+     * Categories of the database which are not requested are never removed, as past entries
+     * use them. The differences are returned, see CategoryMigration.
+     * If lock is set, fail if the database is already used by another process.
+     * Writes failing with a transient error are retried write_retries times, by later writes.
      */
-    pub fn open(path: &Path, classifier_categories: UniqueCategories) -> io::Result<Self> {
-        match fs::OpenOptions::new().read(true).write(true).open(path) {
-            Ok(f) => {
-                let mut reader = BufReader::new(f);
-                let mut counts = LineCounts::new();
-                let mut db_categories = Database::parse_categories(&mut reader, &mut counts)?;
-                let nb_missing_categories = db_categories.extend(classifier_categories);
-                if nb_missing_categories == 0 {
-                    // Can reuse the database as it is
-                    counts.ignore_last_line(); // Skip header
-                    Database::scan_entries(&mut reader, &mut counts, db_categories.len())?;
-                    Ok(Database {
-                        file: reader.into_inner(),
-                        counts: counts,
-                        categories: db_categories,
-                    })
-                } else {
-                    // Put file content in memory
-                    let mut entry_lines = String::new();
-                    reader.read_to_string(&mut entry_lines)?;
-                    // Rewrite file TODO scan ? better impl ?
-                    let entry_suffix: String = std::iter::repeat("\t0")
-                        .take(nb_missing_categories)
-                        .collect();
-                    let mut counts = LineCounts::new();
-                    let mut writer = BufWriter::new(reader.into_inner());
-                    seek_to_offset(&mut writer, 0)?;
-                    {
-                        let header = format!("time_window\t{}\n", db_categories.join("\t"));
-                        writer.write_all(header.as_bytes())?;
-                        counts.advance(header.len());
-                    }
-                    for entry in entry_lines.lines() {
-                        let new_entry = format!("{}{}\n", entry, entry_suffix);
-                        writer.write_all(new_entry.as_bytes())?;
-                        counts.advance(new_entry.len());
-                    }
-                    Ok(Database {
-                        file: writer.into_inner()?,
-                        counts: counts,
-                        categories: db_categories,
-                    })
+    pub fn open(
+        path: &Path,
+        format: DatabaseFormat,
+        precision: DurationPrecision,
+        classifier_categories: UniqueCategories,
+        lock: bool,
+        write_retries: u32,
+    ) -> io::Result<(Self, CategoryMigration)> {
+        if let Some(dir) = path.parent() {
+            fs::DirBuilder::new().recursive(true).create(dir)?
+        }
+        let lock_file = if lock {
+            let f = fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(sibling_path(path, ".lock"))?;
+            lock_file(&f)?;
+            Some(f)
+        } else {
+            None
+        };
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let (version, mut db_categories, mut entries) = match format {
+                    DatabaseFormat::Plain => Database::parse_content(&content)?,
+                    DatabaseFormat::JsonLines => Database::parse_json_content(&content)?,
+                };
+                let outdated = version < FORMAT_VERSION;
+                if outdated {
+                    info!(
+                        "Upgrading database '{}' from format version {} to {}",
+                        path.display(),
+                        version,
+                        FORMAT_VERSION
+                    )
                 }
+                let nb_migrated_entries = Database::migrate_naive_times(&mut entries);
+                if nb_migrated_entries > 0 {
+                    info!(
+                        "Converted {} time windows to include the UTC offset",
+                        nb_migrated_entries
+                    )
+                }
+                let migration = CategoryMigration::new(&db_categories, &classifier_categories);
+                let nb_missing_categories = db_categories.extend(classifier_categories).len();
+                let last_entry = entries.pop();
+                let mut db = Database {
+                    path: path.to_path_buf(),
+                    format: format,
+                    precision: precision,
+                    write_retries: write_retries,
+                    failed_write: None,
+                    _lock_file: lock_file,
+                    categories: db_categories,
+                    locked_entries: entries,
+                    last_entry: last_entry,
+                    dry: false,
+                };
+                if nb_missing_categories > 0 {
+                    db.fill_new_categories(nb_missing_categories);
+                }
+                if nb_missing_categories > 0 || nb_migrated_entries > 0 || outdated {
+                    db.write_to_disk()?
+                }
+                Ok((db, migration))
             }
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
-                Database::create_new(path, classifier_categories)
+                let mut db = Database {
+                    path: path.to_path_buf(),
+                    format: format,
+                    precision: precision,
+                    write_retries: write_retries,
+                    failed_write: None,
+                    _lock_file: lock_file,
+                    categories: classifier_categories,
+                    locked_entries: Vec::new(),
+                    last_entry: None,
+                    dry: false,
+                };
+                db.write_to_disk()?;
+                Ok((db, CategoryMigration::default()))
             }
             Err(e) => Err(e),
         }
     }
 
-    /** Create a new empty database with the specified categories.
-     * Creates parent directories if needed.
+    /** Create an empty database in memory, which logs writes at debug level instead of doing them.
+     * The file at path is never read or written, path is only used in messages.
      */
-    pub fn create_new(path: &Path, categories: UniqueCategories) -> io::Result<Self> {
-        if let Some(dir) = path.parent() {
-            fs::DirBuilder::new().recursive(true).create(dir)?
+    pub fn open_dry(
+        path: &Path,
+        format: DatabaseFormat,
+        precision: DurationPrecision,
+        categories: UniqueCategories,
+    ) -> Self {
+        Database {
+            path: path.to_path_buf(),
+            format: format,
+            precision: precision,
+            write_retries: 0,
+            failed_write: None,
+            _lock_file: None,
+            categories: categories,
+            locked_entries: Vec::new(),
+            last_entry: None,
+            dry: true,
         }
-        let mut f = fs::OpenOptions::new()
-            .read(true)
+    }
+
+    /** Create a new database file containing the given entries, which must be ordered.
+     * Fails if the file already exists. Durations are written at full precision.
+     */
+    pub fn create_with_entries(
+        path: &Path,
+        format: DatabaseFormat,
+        categories: UniqueCategories,
+        entries: &[(DatabaseTime, Vec<time::Duration>)],
+    ) -> io::Result<()> {
+        // Claim the path, so that an existing file is never replaced.
+        fs::OpenOptions::new()
             .write(true)
             .create_new(true)
             .open(path)?;
-        let mut counts = LineCounts::new();
-        {
-            let header = format!("time_window\t{}\n", categories.join("\t"));
-            f.write_all(header.as_bytes())?;
-            counts.advance(header.len());
-        }
-        counts.ignore_last_line(); // Skip header
-        Ok(Database {
-            file: f,
-            counts: counts,
+        let mut db = Database {
+            path: path.to_path_buf(),
+            format: format,
+            precision: DurationPrecision::Milliseconds,
+            write_retries: 0,
+            failed_write: None,
+            _lock_file: None,
             categories: categories,
-        })
+            locked_entries: entries
+                .iter()
+                .map(|(window_start, durations)| format_entry(window_start, durations))
+                .collect(),
+            last_entry: None,
+            dry: false,
+        };
+        db.write_to_disk()
+    }
+
+    /// Parse and check the database file content, return format version, categories and entries.
+    fn parse_content(content: &str) -> io::Result<(u32, UniqueCategories, Vec<String>)> {
+        // Every line must be '\n'-terminated
+        if content.is_empty() {
+            return Err(bad_data("No header line"));
+        }
+        let mut lines = content.split('\n');
+        let last_line = lines.next_back().unwrap(); // Text after last newline
+        let (version, categories) = match lines.next() {
+            Some(header) => parse_versioned_header(header)?,
+            None => return Err(bad_data("Header line is not newline terminated")),
+        };
+        let nb_categories = categories.len();
+        let mut entries = Vec::new();
+        for (index, line) in lines.enumerate() {
+            // Check field count, without the optional extra fields
+            let nb_fields = split_extras(line).0.split('\t').count();
+            if nb_fields != nb_categories + 1 {
+                return Err(bad_data(format!(
+                    "Line {}: expected {} fields, got {}: {:?}",
+                    index + 2,
+                    nb_categories + 1,
+                    nb_fields,
+                    line
+                )));
+            }
+            entries.push(String::from(line))
+        }
+        if !last_line.is_empty() {
+            return Err(bad_data(format!(
+                "Line {}: Not newline terminated",
+                entries.len() + 2
+            )));
+        }
+        Ok((version, categories, entries))
+    }
+
+    /// Parse the JSON Lines database file content, return format version, categories and entries.
+    fn parse_json_content(content: &str) -> io::Result<(u32, UniqueCategories, Vec<String>)> {
+        // Every line must be '\n'-terminated
+        let mut lines = content.split('\n');
+        let last_line = lines.next_back().unwrap(); // Text after last newline
+        let lines: Vec<&str> = lines.collect();
+        if !last_line.is_empty() {
+            return Err(bad_data(format!(
+                "Line {}: Not newline terminated",
+                lines.len() + 1
+            )));
+        }
+        let add_line_nb =
+            |index: usize| move |e: io::Error| bad_data(format!("Line {}: {}", index + 1, e));
+        let mut categories = UniqueCategories::default();
+        for (index, line) in lines.iter().enumerate() {
+            categories.extend(json_entry_categories(line).map_err(add_line_nb(index))?);
+        }
+        let mut entries = Vec::with_capacity(lines.len());
+        for (index, line) in lines.iter().enumerate() {
+            let (window_start, durations, extras) =
+                parse_json_entry_with_extras(line, &categories).map_err(add_line_nb(index))?;
+            entries.push(add_extras(format_entry(&window_start, &durations), &extras))
+        }
+        // No header, JSON entries name their fields and are always current
+        Ok((FORMAT_VERSION, categories, entries))
+    }
+
+    /// Convert entries with time windows without UTC offset. Returns the number of converted entries.
+    fn migrate_naive_times(entries: &mut [String]) -> usize {
+        let mut nb_migrated = 0;
+        for entry in entries {
+            let time_end = entry.find('\t').unwrap_or_else(|| entry.len());
+            if let Some(time) = migrate_naive_time(&entry[..time_end]) {
+                entry.replace_range(..time_end, &time);
+                nb_migrated += 1
+            }
+        }
+        nb_migrated
     }
 
     /// Get database categories, ordered by column index.
@@ -165,134 +984,685 @@ impl Database {
         &self.categories
     }
 
-    /// Parse header line, return categories and updating line counts.
-    fn parse_categories(
-        reader: &mut BufReader<File>,
-        counts: &mut LineCounts,
-    ) -> io::Result<UniqueCategories> {
-        let mut header = String::new();
-        counts.advance(reader.read_line(&mut header)?);
-        // Line must exist, must be '\n'-terminated, must contain at least 'time' header.
-        match header.pop() {
-            Some('\n') => {
-                let mut elements = header.split('\t');
-                match elements.next() {
-                    Some(_time_header) => UniqueCategories::from_unique(
-                        elements.map(|s| s.into()).collect(),
-                    ).map_err(|e| bad_data(e)),
-                    None => Err(bad_data("Header has no field")),
+    /** Add new categories to the database, with zero durations in existing entries.
+     * Returns the categories actually added, in column order.
+     */
+    pub fn add_categories(&mut self, categories: UniqueCategories) -> io::Result<Vec<String>> {
+        let added = self.categories.extend(categories).to_vec();
+        if !added.is_empty() {
+            self.fill_new_categories(added.len());
+            self.write_to_disk()?
+        }
+        Ok(added)
+    }
+
+    /// Add a single category, see add_categories. Returns true if the category was new.
+    pub fn add_category(&mut self, name: &str) -> io::Result<bool> {
+        self.add_categories(UniqueCategories::make_unique(vec![name.into()]))
+            .map(|added| !added.is_empty())
+    }
+
+    /// Add zero durations to all entries for the last nb_new_categories categories.
+    fn fill_new_categories(&mut self, nb_new_categories: usize) {
+        let entry_suffix: String = std::iter::repeat("\t0").take(nb_new_categories).collect();
+        for entry in self
+            .locked_entries
+            .iter_mut()
+            .chain(self.last_entry.iter_mut())
+        {
+            // Durations are before the extra fields
+            let (durations, extras) = split_extras(entry);
+            let filled = format!("{}{}", durations, entry_suffix);
+            *entry = add_extras(filled, &extras);
+        }
+    }
+
+    /** Write the database. Transient errors (full disk, network filesystem timeout) are retried by
+     * the following writes, with exponential backoff: writes are skipped until the next retry,
+     * and the unwritten content is kept in memory meanwhile.
+     * Permanent errors, or transient ones after all retries, are returned.
+     */
+    fn write_to_disk(&mut self) -> io::Result<()> {
+        if self.dry {
+            debug!(
+                "Dry database '{}': would write {} entries, last entry: {}",
+                self.path.display(),
+                self.locked_entries.len() + self.last_entry.iter().count(),
+                self.last_entry.as_ref().map_or("none", String::as_str)
+            );
+            return Ok(());
+        }
+        if let Some(failed_write) = &self.failed_write {
+            if time::Instant::now() < failed_write.retry_at {
+                trace!(
+                    "Skipped write of '{}': waiting for retry",
+                    self.path.display()
+                );
+                return Ok(());
+            }
+        }
+        let e = match self.try_write_to_disk() {
+            Ok(()) => {
+                if let Some(failed_write) = self.failed_write.take() {
+                    info!(
+                        "Database '{}' written after {} retries",
+                        self.path.display(),
+                        failed_write.retries
+                    )
                 }
+                return Ok(());
             }
-            None => Err(bad_data("No header line")),
-            _ => Err(bad_data("Header line is not newline terminated")),
+            Err(e) => e,
+        };
+        let failed_write = self.failed_write.take();
+        let retries = failed_write.as_ref().map_or(0, |f| f.retries);
+        if retries >= self.write_retries || !is_transient_error(&e) {
+            return Err(e);
         }
+        let backoff = next_write_retry_backoff(failed_write.map(|f| f.backoff));
+        warn!(
+            "Unable to write to database '{}': {}; retry {}/{} in {}ms",
+            self.path.display(),
+            e,
+            retries + 1,
+            self.write_retries,
+            backoff.as_millis()
+        );
+        self.failed_write = Some(FailedWrite {
+            retries: retries + 1,
+            backoff: backoff,
+            retry_at: time::Instant::now() + backoff,
+        });
+        Ok(())
     }
 
-    /// Check db entries. Assume reader cursor is at first entry line.
-    fn scan_entries(
-        reader: &mut BufReader<File>,
-        counts: &mut LineCounts,
-        nb_categories: usize,
-    ) -> io::Result<()> {
-        let mut line = String::new();
-        loop {
-            let current_line_nb = counts.line_nb + 1;
-            line.clear(); // Reset buf, as read_line appends to it
-            let line_len = reader.read_line(&mut line)?;
-            // Entry line must be either empty, or be '\n'-terminated and have the right fields
-            match line.pop() {
-                Some('\n') => {
-                    // Check field count
-                    let nb_fields = line.split('\t').count();
-                    if nb_fields != nb_categories + 1 {
-                        return Err(bad_data(format!(
-                            "Line {}: expected {} fields, got {}: {:?}",
-                            current_line_nb,
-                            nb_categories + 1,
-                            nb_fields,
-                            line
-                        )));
-                    }
+    /// True if a failed write waits for its retry: the file is older than the content in memory.
+    pub fn has_unwritten_changes(&self) -> bool {
+        self.failed_write.is_some()
+    }
+
+    /** Write the whole database to a temporary file, then rename it over the database file.
+     * The temporary file is synced before renaming, so the database file is always complete.
+     */
+    fn try_write_to_disk(&self) -> io::Result<()> {
+        write_atomically(&self.path, |writer| self.write_content(writer))
+    }
+
+    /// Write the whole database content in its format.
+    fn write_content<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let entries = self.locked_entries.iter().chain(self.last_entry.iter());
+        match self.format {
+            DatabaseFormat::Plain => {
+                writeln!(writer, "{}", self.header())?;
+                for entry in entries {
+                    writeln!(writer, "{}", entry)?;
                 }
-                None => return Ok(()), // Empty last line
-                _ => {
-                    return Err(bad_data(format!(
-                        "Line {}: Not newline terminated",
-                        current_line_nb
-                    )))
+            }
+            DatabaseFormat::JsonLines => {
+                for entry in entries {
+                    serde_json::to_writer(&mut *writer, &self.json_entry(entry)?)?;
+                    writeln!(writer)?;
                 }
             }
-            counts.advance(line_len);
         }
+        Ok(())
     }
 
-    /** Parse the last entry of the database file.
-     * If entry is correct: return time window start and duration for categories.
-     * If entry is empty: return None.
-     * If entry is incorrect: error.
+    /// Header line of the plain format, without newline.
+    fn header(&self) -> String {
+        format!(
+            "time_window{}{}\t{}",
+            HEADER_VERSION_SEPARATOR,
+            FORMAT_VERSION,
+            self.categories.join("\t")
+        )
+    }
+
+    /// Convert an entry line from the in memory plain format to JSON.
+    fn json_entry(&self, entry: &str) -> io::Result<JsonEntry> {
+        let (window_start, durations) = parse_entry(entry, self.categories.len())?;
+        let extras = split_extras(entry).1;
+        Ok(JsonEntry {
+            start: window_start.format_iso8601(),
+            durations: self
+                .categories
+                .iter()
+                .cloned()
+                .zip(durations.into_iter().map(json_seconds))
+                .collect(),
+            switches: extras.switches,
+            activity: extras.activity,
+            windows: extras.windows,
+            note: extras.note,
+        })
+    }
+
+    /** Move locked entries with a time window start before limit to the archive file.
+     * The last entry is never archived, as it may still change.
+     * The archive is synced before the database is rewritten without the archived entries:
+     * if interrupted in between, entries are in both files, but none are lost.
+     * Returns the number of archived entries.
      */
-    pub fn get_last_entry(&mut self) -> io::Result<Option<(DatabaseTime, Vec<time::Duration>)>> {
-        let mut line = String::new();
-        seek_to_offset(&mut self.file, self.counts.last_line_start_offset)?;
-        self.file.read_to_string(&mut line)?;
-        // If line exists, it must be '\n'-terminated, must contain time + categories durations
-        match line.pop() {
-            Some('\n') => {
-                let mut elements = line.split('\t');
-                match elements.next() {
-                    Some(time_window_text) => {
-                        let time_window: DatabaseTime = time_window_text
-                            .parse()
-                            .map_err(|err| bad_data(format!("Cannot parse time window: {}", err)))?;
-                        // Read durations of entry
-                        let mut durations = Vec::with_capacity(self.categories.len());
-                        for s in elements {
-                            let seconds: u64 = s.parse().map_err(|err| {
-                                bad_data(format!("Cannot parse category duration: {}", err))
-                            })?;
-                            durations.push(time::Duration::from_secs(seconds))
-                        }
-                        if durations.len() != self.categories.len() {
-                            return Err(bad_data(format!(
-                                "Durations: expected {} fields, got {}",
-                                self.categories.len(),
-                                durations.len()
-                            )));
-                        }
-                        Ok(Some((time_window, durations)))
+    pub fn compact(&mut self, limit: &DatabaseTime) -> io::Result<usize> {
+        if self.dry {
+            return Ok(0); // Nothing to archive from memory
+        }
+        let mut nb_archived = 0;
+        for entry in &self.locked_entries {
+            let (window_start, _) = parse_entry(entry, self.categories.len())?;
+            if window_start >= *limit {
+                break; // Entries are ordered by time window start
+            }
+            nb_archived += 1;
+        }
+        if nb_archived == 0 {
+            return Ok(0);
+        }
+        self.archive_entries(nb_archived)?;
+        self.locked_entries.drain(..nb_archived);
+        self.write_to_disk()?;
+        Ok(nb_archived)
+    }
+
+    /** Remove the oldest locked entries until the file fits in max_size.
+     * The last entry is never removed, so the limit may still be exceeded by it and the header.
+     * Removed entries are moved to the archive if archive is set (see compact), or dropped.
+     * Returns the number of removed entries.
+     */
+    pub fn trim(&mut self, max_size: MaxDatabaseSize, archive: bool) -> io::Result<usize> {
+        if self.dry {
+            return Ok(0); // Memory is not limited
+        }
+        let nb_entries = self.locked_entries.len() + self.last_entry.iter().count();
+        let nb_removed = match max_size {
+            MaxDatabaseSize::Entries(max_entries) => nb_entries.saturating_sub(max_entries),
+            MaxDatabaseSize::Bytes(max_bytes) => {
+                let header_size = match self.format {
+                    DatabaseFormat::Plain => self.header().len() as u64 + 1,
+                    DatabaseFormat::JsonLines => 0,
+                };
+                let mut entry_sizes = Vec::with_capacity(nb_entries);
+                for entry in self.locked_entries.iter().chain(self.last_entry.iter()) {
+                    entry_sizes.push(self.entry_file_size(entry)?)
+                }
+                let mut size: u64 = header_size + entry_sizes.iter().sum::<u64>();
+                let mut nb_removed = 0;
+                for entry_size in &entry_sizes {
+                    if size <= max_bytes {
+                        break;
                     }
-                    None => Err(bad_data("Entry is empty")),
+                    size -= entry_size;
+                    nb_removed += 1;
                 }
+                nb_removed
             }
-            None => Ok(None), // Empty database
-            _ => Err(bad_data("Entry is not newline terminated")),
         }
+        .min(self.locked_entries.len());
+        if nb_removed == 0 {
+            return Ok(0);
+        }
+        if archive {
+            self.archive_entries(nb_removed)?;
+        }
+        self.locked_entries.drain(..nb_removed);
+        self.write_to_disk()?;
+        Ok(nb_removed)
+    }
+
+    /// Append the first nb_entries locked entries to the archive, and sync it.
+    fn archive_entries(&self, nb_entries: usize) -> io::Result<()> {
+        let archive = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(archive_path(&self.path))?;
+        let mut encoder = GzEncoder::new(BufWriter::new(archive), Compression::default());
+        for entry in &self.locked_entries[..nb_entries] {
+            serde_json::to_writer(&mut encoder, &self.json_entry(entry)?)?;
+            writeln!(encoder)?;
+        }
+        encoder.finish()?.into_inner()?.sync_all()
+    }
+
+    /// Size of an entry line in the database file, newline included.
+    fn entry_file_size(&self, entry: &str) -> io::Result<u64> {
+        let line_size = match self.format {
+            DatabaseFormat::Plain => entry.len(),
+            DatabaseFormat::JsonLines => serde_json::to_vec(&self.json_entry(entry)?)?.len(),
+        };
+        Ok(line_size as u64 + 1)
     }
 
-    /// Rewrite the last entry in the database.
+    /** Parse the last entry of the database.
+     * If entry is correct: return time window start and duration for categories.
+     * If entry is empty: return None.
+     * If entry is incorrect: error.
+     */
+    pub fn get_last_entry(&self) -> io::Result<Option<(DatabaseTime, Vec<time::Duration>)>> {
+        match &self.last_entry {
+            Some(line) => parse_entry(line, self.categories.len()).map(Some),
+            None => Ok(None), // Empty database, or last entry locked
+        }
+    }
+
+    /// Rewrite the last entry in the database, with its number of category switches, input
+    /// activity and number of distinct windows. Its note is kept.
     pub fn rewrite_last_entry(
         &mut self,
         window_start: &DatabaseTime,
         durations: &[time::Duration],
+        switches: u32,
+        activity: InputActivity,
+        windows: u32,
     ) -> io::Result<()> {
-        // Build line text
-        let mut line = window_start.to_rfc3339();
-        for d in durations {
-            use std::fmt::Write;
-            write!(&mut line, "\t{}", d.as_secs()).unwrap();
-        }
-        line.push('\n');
-        // Write to file, trim excess file len, flush to disk.
-        seek_to_offset(&mut self.file, self.counts.last_line_start_offset)?;
-        self.file.write_all(line.as_bytes())?;
-        self.counts.last_line_len = line.len();
-        self.file.set_len(self.counts.cursor() as u64)?;
-        self.file.sync_all() // May be costly, but we do not call that often...
-    }
-
-    /// Move the last line cursor to the next line, locking the current last line content.
+        let durations: Vec<_> = durations.iter().map(|d| self.precision.round(*d)).collect();
+        let extras = EntryExtras {
+            switches: switches,
+            activity: activity,
+            windows: windows,
+            ..self.current_extras()
+        };
+        self.last_entry = Some(add_extras(format_entry(window_start, &durations), &extras));
+        self.write_to_disk()
+    }
+
+    /// Extra fields of the last entry: default if none.
+    pub fn current_extras(&self) -> EntryExtras {
+        match &self.last_entry {
+            Some(entry) => split_extras(entry).1,
+            None => EntryExtras::default(),
+        }
+    }
+
+    /** Set the note of the last entry, replacing any previous note. An empty note removes it.
+     * Fails if there is no last entry: it is created by the first rewrite of a time window.
+     */
+    pub fn annotate_current(&mut self, note: &str) -> io::Result<()> {
+        let entry = match &self.last_entry {
+            Some(entry) => {
+                let (line, extras) = split_extras(entry);
+                let extras = EntryExtras {
+                    note: Some(note.into()),
+                    ..extras
+                };
+                add_extras(line.into(), &extras)
+            }
+            None => return Err(bad_data("No current time window to annotate")),
+        };
+        self.last_entry = Some(entry);
+        self.write_to_disk()
+    }
+
+    /// Lock the current last entry content. The next rewrite will create a new entry.
     pub fn lock_last_entry(&mut self) {
-        self.counts.ignore_last_line()
+        if let Some(entry) = self.last_entry.take() {
+            if self.dry {
+                debug!(
+                    "Dry database '{}': would close entry: {}",
+                    self.path.display(),
+                    entry
+                )
+            }
+            self.locked_entries.push(entry)
+        }
+    }
+}
+
+/// Check a line (without newline) of a database file. Line index starts at 0.
+fn check_line(
+    line: &[u8],
+    index: usize,
+    format: DatabaseFormat,
+    categories: &Option<UniqueCategories>,
+) -> io::Result<()> {
+    let line = std::str::from_utf8(line).map_err(bad_data)?;
+    match (format, categories) {
+        (DatabaseFormat::Plain, None) if index == 0 => parse_header(line).map(|_| ()),
+        (DatabaseFormat::Plain, Some(categories)) => {
+            parse_entry(line, categories.len()).map(|_| ())
+        }
+        (DatabaseFormat::JsonLines, _) => {
+            let categories = json_entry_categories(line)?;
+            parse_json_entry(line, &categories).map(|_| ())
+        }
+        (DatabaseFormat::Plain, None) => Err(bad_data("No header line")),
+    }
+}
+
+/** Repair a database file damaged by an interrupted write (power loss).
+ * A truncated or malformed last line is dropped, other malformed lines are an error.
+ * The original file is copied to <db>.bak, then the file is replaced atomically.
+ * Returns the number of the dropped line, or None if the file was correct and left untouched.
+ * Fails if the database is used by another process.
+ */
+pub fn repair_file(path: &Path, format: DatabaseFormat) -> io::Result<Option<usize>> {
+    let lock = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(sibling_path(path, ".lock"))?;
+    lock_file(&lock)?;
+    let content = fs::read(path)?;
+    let mut lines: Vec<&[u8]> = content.split(|b| *b == b'\n').collect();
+    let after_last_newline = lines.pop().unwrap();
+    if format == DatabaseFormat::Plain && lines.is_empty() {
+        return Err(bad_data("No complete header line, cannot be repaired"));
+    }
+    // Check complete lines, the last one is allowed to be malformed
+    let mut categories = None;
+    let mut dropped_line_index = None;
+    for (index, line) in lines.iter().enumerate() {
+        if let Err(e) = check_line(line, index, format, &categories) {
+            let is_header = format == DatabaseFormat::Plain && index == 0;
+            if index + 1 == lines.len() && after_last_newline.is_empty() && !is_header {
+                dropped_line_index = Some(index);
+                break;
+            }
+            return Err(bad_data(format!(
+                "Line {}: {}, cannot be repaired",
+                index + 1,
+                e
+            )));
+        }
+        if format == DatabaseFormat::Plain && index == 0 {
+            categories = Some(parse_header(std::str::from_utf8(line).unwrap())?)
+        }
+    }
+    if !after_last_newline.is_empty() {
+        // Truncated line, without newline
+        dropped_line_index = Some(lines.len());
+    }
+    let dropped_line_index = match dropped_line_index {
+        Some(index) => index,
+        None => return Ok(None),
+    };
+    lines.truncate(dropped_line_index);
+    fs::copy(path, sibling_path(path, ".bak"))?;
+    let tmp_path = sibling_path(path, ".tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for line in lines {
+            writer.write_all(line)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.into_inner()?.sync_all()?
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(Some(dropped_line_index + 1))
+}
+
+/// Reader of the gzip compressed archive, which may contain several gzip members.
+type ArchiveReader = BufReader<MultiGzDecoder<File>>;
+
+/// Open the archive of the database, if it exists.
+fn open_archive(db_file: &Path) -> io::Result<Option<ArchiveReader>> {
+    match File::open(archive_path(db_file)) {
+        Ok(file) => Ok(Some(BufReader::new(MultiGzDecoder::new(file)))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/** Read-only sequential access to database entries, for reports and exports.
+ * The database file is not locked, so it can be read while the daemon is running.
+ * Archived entries (see archive_path) are read first, then entries of the database file.
+ */
+pub struct EntryReader {
+    archive: Option<ArchiveReader>,
+    archive_line_nb: usize, // index of last read archive line
+    reader: BufReader<File>,
+    format: DatabaseFormat,
+    line_nb: usize, // index of last read line
+    categories: UniqueCategories,
+    extras: EntryExtras, // extra fields of last read entry
+}
+
+impl EntryReader {
+    /** Open a database and parse its header.
+     * For JSON Lines, categories are gathered by reading the whole file once.
+     * The archive is also read once to add its categories.
+     * For the plain format, archived categories must be in the header: categories are never removed.
+     */
+    pub fn open(path: &Path, format: DatabaseFormat) -> io::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let (mut categories, line_nb) = match format {
+            DatabaseFormat::Plain => {
+                let mut header = String::new();
+                reader.read_line(&mut header)?;
+                // Line must exist, must be '\n'-terminated
+                let categories = match header.pop() {
+                    Some('\n') => parse_header(&header)?,
+                    None => return Err(bad_data("No header line")),
+                    _ => return Err(bad_data("Header line is not newline terminated")),
+                };
+                (categories, 1)
+            }
+            DatabaseFormat::JsonLines => {
+                let mut categories = UniqueCategories::default();
+                for (index, line) in reader.by_ref().lines().enumerate() {
+                    let line_categories = json_entry_categories(&line?)
+                        .map_err(|e| bad_data(format!("Line {}: {}", index + 1, e)))?;
+                    categories.extend(line_categories);
+                }
+                reader.seek(SeekFrom::Start(0))?;
+                (categories, 0)
+            }
+        };
+        if format == DatabaseFormat::JsonLines {
+            if let Some(archive) = open_archive(path)? {
+                // Gzip streams cannot seek: the archive is opened again for entries
+                for (index, line) in archive.lines().enumerate() {
+                    let line_categories = json_entry_categories(&line?)
+                        .map_err(|e| bad_data(format!("Archive line {}: {}", index + 1, e)))?;
+                    categories.extend(line_categories);
+                }
+            }
+        }
+        Ok(EntryReader {
+            archive: open_archive(path)?,
+            archive_line_nb: 0,
+            reader: reader,
+            format: format,
+            line_nb: line_nb,
+            categories: categories,
+            extras: EntryExtras::default(),
+        })
+    }
+
+    /// Get database categories, ordered by column index.
+    pub fn categories(&self) -> &UniqueCategories {
+        &self.categories
+    }
+
+    /// Note of the last read entry, if any.
+    pub fn note(&self) -> Option<&str> {
+        self.extras.note.as_ref().map(String::as_str)
+    }
+
+    /// Number of category switches of the last read entry. 0 if not recorded.
+    pub fn switches(&self) -> u32 {
+        self.extras.switches
+    }
+
+    /// Input activity of the last read entry. Zero if not recorded.
+    pub fn activity(&self) -> InputActivity {
+        self.extras.activity
+    }
+
+    /// Number of distinct windows of the last read entry. 0 if not recorded.
+    pub fn windows(&self) -> u32 {
+        self.extras.windows
+    }
+
+    /// Parse an entry line and store its extra fields.
+    fn parse_line(
+        &mut self,
+        line: &str,
+        format: DatabaseFormat,
+    ) -> io::Result<(DatabaseTime, Vec<time::Duration>)> {
+        let (window_start, durations, extras) = match format {
+            DatabaseFormat::Plain => {
+                let (window_start, durations) = parse_entry(line, self.categories.len())?;
+                (window_start, durations, split_extras(line).1)
+            }
+            DatabaseFormat::JsonLines => parse_json_entry_with_extras(line, &self.categories)?,
+        };
+        self.extras = extras;
+        Ok((window_start, durations))
+    }
+
+    /// Location of the last read entry: archive or database line number, starting at 1.
+    pub fn location(&self) -> String {
+        match self.archive {
+            Some(_) => format!("Archive line {}", self.archive_line_nb),
+            None => format!("Line {}", self.line_nb),
+        }
+    }
+
+    /// Read the next archived entry. None at the end of the archive, which is then closed.
+    fn next_archived(&mut self) -> Option<io::Result<(DatabaseTime, Vec<time::Duration>)>> {
+        let mut line = String::new();
+        if let Err(e) = self.archive.as_mut()?.read_line(&mut line) {
+            return Some(Err(e));
+        }
+        self.archive_line_nb += 1;
+        let archive_line_nb = self.archive_line_nb;
+        match line.pop() {
+            Some('\n') => Some(
+                self.parse_line(&line, DatabaseFormat::JsonLines)
+                    .map_err(|e| bad_data(format!("Archive line {}: {}", archive_line_nb, e))),
+            ),
+            None => {
+                self.archive = None; // End of archive
+                None
+            }
+            _ => Some(Err(bad_data(format!(
+                "Archive line {}: Not newline terminated",
+                archive_line_nb
+            )))),
+        }
+    }
+}
+
+/// Iterate on entries: time window start and durations for each category.
+impl Iterator for EntryReader {
+    type Item = io::Result<(DatabaseTime, Vec<time::Duration>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(entry) = self.next_archived() {
+            return Some(entry);
+        }
+        let mut line = String::new();
+        if let Err(e) = self.reader.read_line(&mut line) {
+            return Some(Err(e));
+        }
+        self.line_nb += 1;
+        let line_nb = self.line_nb;
+        let add_line_nb = |e: io::Error| bad_data(format!("Line {}: {}", line_nb, e));
+        match line.pop() {
+            Some('\n') => {
+                let format = self.format;
+                Some(self.parse_line(&line, format).map_err(add_line_nb))
+            }
+            None => None, // End of file
+            _ => Some(Err(bad_data(format!(
+                "Line {}: Not newline terminated",
+                line_nb
+            )))),
+        }
+    }
+}
+
+/// Add elapsed time to durations of categories (index, share), split according to their shares.
+fn add_elapsed(
+    durations: &mut [time::Duration],
+    categories: &[(usize, f32)],
+    elapsed: time::Duration,
+) {
+    for (index, share) in categories {
+        durations[*index] += if categories.len() == 1 {
+            elapsed // Exact for the usual single category
+        } else {
+            elapsed.mul_f64(f64::from(*share))
+        }
+    }
+}
+
+/** Sequential access to the entries of daily database files in a directory, in date order.
+ * Categories are the union of the categories of all files, which may differ as categories are
+ * added over time. Durations of each file are moved to the columns of its categories.
+ * The format of each file is detected from its extension.
+ */
+pub struct DailyEntryReader {
+    files: std::vec::IntoIter<PathBuf>,
+    current: Option<(PathBuf, EntryReader, Vec<usize>)>, // File, column of each of its categories
+    categories: UniqueCategories,
+}
+
+impl DailyEntryReader {
+    /// Open the daily files of dir, reading their categories once.
+    pub fn open(dir: &Path) -> io::Result<Self> {
+        let files = daily_files(dir)?;
+        let mut categories = UniqueCategories::default();
+        for file in &files {
+            let entries = EntryReader::open(file, DatabaseFormat::detect(file))
+                .map_err(|e| bad_data(format!("{}: {}", file.display(), e)))?;
+            categories.extend(entries.categories().clone());
+        }
+        Ok(DailyEntryReader {
+            files: files.into_iter(),
+            current: None,
+            categories: categories,
+        })
+    }
+
+    /// Get categories of all files, ordered by column index.
+    pub fn categories(&self) -> &UniqueCategories {
+        &self.categories
+    }
+
+    /// Open the next file. False if there are no more files.
+    fn open_next(&mut self) -> io::Result<bool> {
+        let file = match self.files.next() {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        let entries = EntryReader::open(&file, DatabaseFormat::detect(&file))
+            .map_err(|e| bad_data(format!("{}: {}", file.display(), e)))?;
+        let columns = entries
+            .categories()
+            .iter()
+            .map(|category| self.categories.index_of(category).unwrap())
+            .collect();
+        self.current = Some((file, entries, columns));
+        Ok(true)
+    }
+}
+
+/// Iterate on entries of all files: time window start and durations for each category.
+impl Iterator for DailyEntryReader {
+    type Item = io::Result<(DatabaseTime, Vec<time::Duration>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((file, entries, columns)) = &mut self.current {
+                match entries.next() {
+                    Some(Ok((window_start, durations))) => {
+                        let mut all_durations =
+                            vec![time::Duration::new(0, 0); self.categories.len()];
+                        for (column, d) in columns.iter().zip(durations) {
+                            all_durations[*column] = d
+                        }
+                        return Some(Ok((window_start, all_durations)));
+                    }
+                    Some(Err(e)) => {
+                        return Some(Err(bad_data(format!("{}: {}", file.display(), e))))
+                    }
+                    None => (),
+                }
+            }
+            match self.open_next() {
+                Ok(true) => (),
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
     }
 }
 
@@ -301,12 +1671,15 @@ impl Database {
  * This is used to store the durations for the current time window.
  * Changes in active window are recorded in this structure.
  * Asynchronously, the accumulated durations are written to the database.
+ * A dirty flag tells if durations changed since the last write, to skip writes without changes.
  */
 pub struct CategoryDurationCounter {
-    current_category_index: Option<usize>, // Index for categories / durations
+    current_categories: Vec<(usize, f32)>, // Indexes for categories / durations, with shares summing to 1
+    current_category_start: time::Instant, // Time of the last category change
     last_recorded: time::Instant,          // Last time where durations were stored in durations vec
     categories: UniqueCategories,
     durations: Vec<time::Duration>,
+    dirty: bool, // Durations or categories changed since the last mark_written()
 }
 
 impl CategoryDurationCounter {
@@ -319,22 +1692,78 @@ impl CategoryDurationCounter {
             .take(categories.len())
             .collect();
         CategoryDurationCounter {
-            current_category_index: None,
+            current_categories: Vec::new(),
+            current_category_start: time::Instant::now(),
             last_recorded: time::Instant::now(),
             categories: categories,
             durations: zeroed_durations,
+            dirty: false,
         }
     }
 
+    /// True if durations changed since the last write, or will change when recorded up to timestamp.
+    pub fn changed_since_write(&self, timestamp: time::Instant) -> bool {
+        self.dirty || (!self.current_categories.is_empty() && timestamp > self.last_recorded)
+    }
+
+    /// Durations have been written: they are unchanged until the next recording.
+    pub fn mark_written(&mut self) {
+        self.dirty = false
+    }
+
+    /// Access categories, in the same order as durations.
+    pub fn categories(&self) -> &UniqueCategories {
+        &self.categories
+    }
+
     /// Access accumulated durations. durations[i] is duration for categories[i].
     pub fn durations(&self) -> &Vec<time::Duration> {
         &self.durations
     }
 
+    /// Name of the current category, None if undefined. The main one if time is split.
+    pub fn current_category(&self) -> Option<&str> {
+        self.current_categories
+            .first()
+            .map(|(index, _)| self.categories[*index].as_str())
+    }
+
+    /// Time elapsed in the current category at timestamp, since the last category change.
+    pub fn current_category_elapsed(&self, timestamp: time::Instant) -> time::Duration {
+        if timestamp > self.current_category_start {
+            timestamp.duration_since(self.current_category_start)
+        } else {
+            time::Duration::new(0, 0)
+        }
+    }
+
+    /// Set the time already spent in the current category at timestamp. For resuming a visit.
+    pub fn restore_current_category_elapsed(
+        &mut self,
+        elapsed: time::Duration,
+        timestamp: time::Instant,
+    ) {
+        self.current_category_start = timestamp.checked_sub(elapsed).unwrap_or(timestamp)
+    }
+
+    /// Accumulated durations including the current category up to timestamp, without recording.
+    pub fn durations_at(&self, timestamp: time::Instant) -> Vec<time::Duration> {
+        let mut durations = self.durations.clone();
+        if timestamp > self.last_recorded {
+            add_elapsed(
+                &mut durations,
+                &self.current_categories,
+                timestamp.duration_since(self.last_recorded),
+            )
+        }
+        durations
+    }
+
     /// Set values for all durations. For resuming a time window from database.
     pub fn set_durations(&mut self, durations: Vec<time::Duration>) {
         assert_eq!(durations.len(), self.categories.len());
-        self.durations = durations
+        self.durations = durations;
+        self.dirty = true
     }
 
     /// Set all durations to 0. For time window change.
@@ -342,14 +1771,67 @@ impl CategoryDurationCounter {
         for d in &mut self.durations {
             *d = time::Duration::new(0, 0)
         }
+        self.dirty = true
+    }
+
+    /// Add new categories with zero durations. Must follow the database category order.
+    pub fn add_categories(&mut self, categories: UniqueCategories) {
+        self.categories.extend(categories);
+        self.durations
+            .resize(self.categories.len(), time::Duration::new(0, 0));
+        self.dirty = true
+    }
+
+    /** Use the categories of a new database file, after reset_durations().
+     * Current categories are kept by name, so they must be in the new categories.
+     */
+    pub fn set_categories(&mut self, categories: UniqueCategories) {
+        self.current_categories = self
+            .current_categories
+            .iter()
+            .map(|(index, share)| {
+                let index = categories
+                    .index_of(&self.categories[*index])
+                    .expect("category name is unknown");
+                (index, *share)
+            })
+            .collect();
+        self.durations = vec![time::Duration::new(0, 0); categories.len()];
+        self.categories = categories;
+        self.dirty = true
+    }
+
+    /// Add a single category with zero duration. Must follow the database category order.
+    pub fn add_category(&mut self, name: &str) {
+        self.add_categories(UniqueCategories::make_unique(vec![name.into()]))
     }
 
-    /// Record duration for current category from last_recorded to timestamp.
+    /** Record duration for current category from last_recorded to timestamp.
+     * Nothing is recorded for a timestamp before last_recorded (delayed window change).
+     */
     pub fn record_current_duration(&mut self, timestamp: time::Instant) {
-        if let Some(index) = self.current_category_index {
-            self.durations[index] += timestamp.duration_since(self.last_recorded)
+        if timestamp > self.last_recorded {
+            add_elapsed(
+                &mut self.durations,
+                &self.current_categories,
+                timestamp.duration_since(self.last_recorded),
+            );
+            self.last_recorded = timestamp;
+            self.dirty |= !self.current_categories.is_empty();
+        }
+    }
+
+    /** Ignore the time between start and end for all categories.
+     * Durations of the current category are recorded up to start.
+     * Used for time not spent on the computer (system suspend).
+     */
+    pub fn skip_duration(&mut self, start: time::Instant, end: time::Instant) {
+        if start > self.last_recorded {
+            self.record_current_duration(start)
+        }
+        if end > self.last_recorded {
+            self.last_recorded = end
         }
-        self.last_recorded = timestamp;
     }
 
     /** Record a change in active window.
@@ -360,15 +1842,245 @@ impl CategoryDurationCounter {
         &mut self,
         category: Option<S>,
         timestamp: time::Instant,
+    ) {
+        let categories: Vec<(S, f32)> = category.map(|s| (s, 1.)).into_iter().collect();
+        self.categories_changed(&categories, timestamp)
+    }
+
+    /** Same as category_changed(), with time split between categories according to weights.
+     * Weights are relative and must be positive. The first category is the main one.
+     */
+    pub fn categories_changed<S: AsRef<str>>(
+        &mut self,
+        categories: &[(S, f32)],
+        timestamp: time::Instant,
     ) {
         self.record_current_duration(timestamp);
-        self.current_category_index = category.map(|s| {
-            self.categories
-                .iter()
-                .enumerate()
-                .find(|(_i, category_name)| category_name.as_str() == s.as_ref())
-                .expect("category name is unknown")
-                .0
+        self.current_category_start = timestamp;
+        self.dirty = true;
+        let total: f32 = categories.iter().map(|(_, weight)| weight).sum();
+        self.current_categories = categories
+            .iter()
+            .map(|(s, weight)| {
+                let index = self
+                    .categories
+                    .index_of(s.as_ref())
+                    .expect("category name is unknown");
+                (index, weight / total)
+            })
+            .collect();
+    }
+}
+
+/** Category switch counter, next to CategoryDurationCounter.
+ * Counts changes from a category to a different one during the current time window.
+ * Times without category (idle, paused) are ignored: coming back to the same category is not a switch.
+ */
+#[derive(Debug, Default)]
+pub struct SwitchCounter {
+    last_category: Option<String>, // Last category counted, kept across time windows
+    switches: u32,
+}
+
+impl SwitchCounter {
+    /// Number of switches in the current time window.
+    pub fn switches(&self) -> u32 {
+        self.switches
+    }
+
+    /// Set the number of switches. For resuming a time window from database.
+    pub fn set_switches(&mut self, switches: u32) {
+        self.switches = switches
+    }
+
+    /// Set the number of switches to 0. For time window change.
+    pub fn reset_switches(&mut self) {
+        self.switches = 0
+    }
+
+    /// Record a change of counted category.
+    pub fn category_changed<S: AsRef<str>>(&mut self, category: Option<S>) {
+        if let Some(category) = category {
+            let category = category.as_ref();
+            match &self.last_category {
+                Some(last) if last == category => return,
+                Some(_) => self.switches = self.switches.saturating_add(1),
+                None => (),
+            }
+            self.last_category = Some(category.into())
+        }
+    }
+}
+
+/** Counter of distinct windows focused during the current time window, next to SwitchCounter.
+ * Windows are identified by their title, or their class if they have no title.
+ * Identifiers are only kept in memory: a resumed time window starts from the recorded count,
+ * so windows focused both before and after a restart are counted twice.
+ */
+#[derive(Debug, Default)]
+pub struct WindowCounter {
+    windows: HashSet<String>,
+    resumed: u32, // Count recorded before the daemon start
+}
+
+impl WindowCounter {
+    /// Number of distinct windows in the current time window.
+    pub fn windows(&self) -> u32 {
+        self.resumed.saturating_add(self.windows.len() as u32)
+    }
+
+    /// Set the number of windows. For resuming a time window from database.
+    pub fn set_windows(&mut self, windows: u32) {
+        self.windows.clear();
+        self.resumed = windows
+    }
+
+    /// Forget all windows. For time window change.
+    pub fn reset_windows(&mut self) {
+        self.windows.clear();
+        self.resumed = 0
+    }
+
+    /// Record a focused window. Windows without title nor class are not counted.
+    pub fn window_focused(&mut self, title: Option<&str>, class: Option<&str>) {
+        let identifier = title
+            .filter(|title| !title.is_empty())
+            .or_else(|| class.filter(|class| !class.is_empty()));
+        if let Some(identifier) = identifier {
+            if !self.windows.contains(identifier) {
+                self.windows.insert(identifier.into());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty directory for a test, removed first if left by a previous run.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("xstalker-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writer accepting a number of bytes, then failing.
+    struct FailingWriter<W> {
+        inner: W,
+        remaining: usize,
+    }
+    impl<W: Write> Write for FailingWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "injected failure"));
+            }
+            let len = buf.len().min(self.remaining);
+            let written = self.inner.write(&buf[..len])?;
+            self.remaining -= written;
+            Ok(written)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn failed_write_keeps_original_file() {
+        let dir = test_dir("failed-write");
+        let path = dir.join("db");
+        let (mut db, _migration) = Database::open(
+            &path,
+            DatabaseFormat::Plain,
+            DurationPrecision::Seconds,
+            UniqueCategories::for_test(&["work", "leisure"]),
+            false,
+            0,
+        )
+        .unwrap();
+        let start = chrono::Local.ymd(2020, 1, 1).and_hms(10, 0, 0);
+        let durations = [time::Duration::from_secs(60), time::Duration::from_secs(30)];
+        db.rewrite_last_entry(&start, &durations, 1, InputActivity::default(), 2)
+            .unwrap();
+        let original = fs::read_to_string(&path).unwrap();
+
+        // Fails halfway through the content: a direct write would truncate the database.
+        let result = write_atomically(&path, |writer| {
+            db.write_content(&mut FailingWriter {
+                inner: writer,
+                remaining: original.len() / 2,
+            })
         });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), original);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn iso8601_round_trip_across_dst_change() {
+        // Central European summer time ends at 2020-10-25T03:00:00+02:00, repeating 02:00-03:00
+        let before = DatabaseTime::parse_iso8601("2020-10-25T02:30:00+02:00").unwrap();
+        let after = DatabaseTime::parse_iso8601("2020-10-25T02:30:00+01:00").unwrap();
+        assert_eq!(after.timestamp() - before.timestamp(), 3600);
+        assert_eq!(before.timestamp(), 1603585800);
+        assert_eq!(after.timestamp(), 1603589400);
+
+        for time in [before, after].iter() {
+            let parsed = DatabaseTime::parse_iso8601(&time.format_iso8601()).unwrap();
+            assert_eq!(&parsed, time);
+        }
+    }
+
+    #[test]
+    fn iso8601_rejects_invalid_times() {
+        assert!(DatabaseTime::parse_iso8601("2020-10-25 02:30").is_err());
+        assert!(DatabaseTime::parse_iso8601("").is_err());
+    }
+
+    #[test]
+    fn unversioned_file_with_extras_is_upgraded() {
+        let dir = test_dir("v1-extras");
+        let path = dir.join("db");
+        fs::write(
+            &path,
+            "time_window\twork\tleisure\n\
+             2020-01-01T10:00:00+01:00\t60\t30\t~3\t@10/5\t%2\t#standup\n\
+             2020-01-01T11:00:00+01:00\t0\t90\t~1\n",
+        )
+        .unwrap();
+        let (db, _migration) = Database::open(
+            &path,
+            DatabaseFormat::Plain,
+            DurationPrecision::Seconds,
+            UniqueCategories::for_test(&["leisure", "mail", "work"]),
+            false,
+            0,
+        )
+        .unwrap();
+        assert_eq!(db.current_extras().switches, 1);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "time_window:v2\twork\tleisure\tmail\n\
+             2020-01-01T10:00:00+01:00\t60\t30\t0\t~3\t@10/5\t%2\t#standup\n\
+             2020-01-01T11:00:00+01:00\t0\t90\t0\t~1\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_retry_backoff_is_capped() {
+        let mut backoff = next_write_retry_backoff(None);
+        assert_eq!(backoff, WRITE_RETRY_INITIAL_BACKOFF);
+        backoff = next_write_retry_backoff(Some(backoff));
+        assert_eq!(backoff, time::Duration::from_millis(200));
+        // More retries than allowed must neither overflow nor exceed the maximum
+        for _ in 0..2 * MAX_WRITE_RETRIES {
+            backoff = next_write_retry_backoff(Some(backoff));
+            assert!(backoff <= WRITE_RETRY_MAX_BACKOFF);
+        }
+        assert_eq!(backoff, WRITE_RETRY_MAX_BACKOFF);
     }
 }
@@ -0,0 +1,266 @@
+use super::database::CategoryDurationCounter;
+use super::ErrorMessage;
+use dbus::arg::{RefArg, Variant};
+use dbus::{BusType, Connection, ConnectionItem, Message, NameFlag, RequestNameReply};
+use mio;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
+use tokio::prelude::*;
+use tokio::reactor::PollEvented2 as PollEvented; // Tokio is changing interfaces, temporary
+
+/// Well known name requested on the session bus.
+const BUS_NAME: &str = "org.xstalker.XStalker";
+/// Object implementing the interface.
+const OBJECT_PATH: &str = "/org/xstalker/XStalker";
+/// Interface with the CurrentCategory and Paused properties, and Pause/Resume/Annotate methods.
+const INTERFACE: &str = "org.xstalker.XStalker";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+/// Timeout for method calls to a running daemon, in milliseconds.
+const CALL_TIMEOUT_MS: i32 = 5000;
+
+/// Session bus connection, registered to the event loop through its file descriptor.
+struct Bus {
+    connection: Connection,
+    fd: RawFd,
+}
+
+impl Bus {
+    fn new() -> Result<Self, ErrorMessage> {
+        let connection = Connection::get_private(BusType::Session)
+            .map_err(|e| ErrorMessage::new("Unable to connect to the session bus", e))?;
+        let reply = connection
+            .register_name(BUS_NAME, NameFlag::DoNotQueue as u32)
+            .map_err(|e| ErrorMessage::new(format!("Unable to request name {}", BUS_NAME), e))?;
+        if reply != RequestNameReply::PrimaryOwner {
+            return Err(ErrorMessage::from(format!(
+                "Name {} is already owned on the session bus",
+                BUS_NAME
+            )));
+        }
+        connection
+            .register_object_path(OBJECT_PATH)
+            .map_err(|e| ErrorMessage::new("Unable to register object path", e))?;
+        let fd = match connection.watch_fds().iter().find(|w| w.readable()) {
+            Some(watch) => watch.fd(),
+            None => {
+                return Err(ErrorMessage::from(
+                    "No file descriptor to watch session bus",
+                ))
+            }
+        };
+        Ok(Bus {
+            connection: connection,
+            fd: fd,
+        })
+    }
+
+    /// Read all available messages, keeping only method calls.
+    fn method_calls(&self) -> Vec<Message> {
+        let mut method_calls = Vec::new();
+        // With a 0 timeout, Nothing is produced once no message is available.
+        for item in self.connection.incoming(0) {
+            match item {
+                ConnectionItem::MethodCall(message) => method_calls.push(message),
+                ConnectionItem::Nothing => break,
+                _ => (),
+            }
+        }
+        method_calls
+    }
+
+    fn send(&self, message: Message) {
+        if self.connection.send(message).is_err() {
+            warn!("Unable to send DBus reply")
+        }
+    }
+}
+
+impl mio::Evented for Bus {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        mio::unix::EventedFd(&self.fd).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        mio::unix::EventedFd(&self.fd).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        mio::unix::EventedFd(&self.fd).deregister(poll)
+    }
+}
+
+/** DBus service, as a future answering method calls until an error occurs.
+ * Properties are read from the duration counter, so they give the category currently counted.
+ * Pause and Resume are forwarded to set_paused, which must stop or restart counting time.
+ * paused is the initial state, true if the daemon starts paused.
+ * Annotate(s) is forwarded to annotate, which sets the note of the current time window.
+ */
+pub struct Service<'a, F, A> {
+    bus: PollEvented<Bus>,
+    duration_counter: &'a RefCell<CategoryDurationCounter>,
+    set_paused: F,
+    annotate: A,
+    paused: bool,
+}
+
+pub fn serve<'a, F, A>(
+    duration_counter: &'a RefCell<CategoryDurationCounter>,
+    set_paused: F,
+    annotate: A,
+    paused: bool,
+) -> Result<Service<'a, F, A>, ErrorMessage>
+where
+    F: Fn(bool) + 'a,
+    A: Fn(&str) -> Result<(), ErrorMessage> + 'a,
+{
+    Ok(Service {
+        bus: PollEvented::new(Bus::new()?),
+        duration_counter: duration_counter,
+        set_paused: set_paused,
+        annotate: annotate,
+        paused: paused,
+    })
+}
+
+impl<'a, F, A> Service<'a, F, A>
+where
+    F: Fn(bool),
+    A: Fn(&str) -> Result<(), ErrorMessage>,
+{
+    /// Current category name, empty if not counting time.
+    fn current_category(&self) -> String {
+        self.duration_counter
+            .borrow()
+            .current_category()
+            .unwrap_or("")
+            .to_string()
+    }
+
+    fn handle(&mut self, call: &Message) -> Message {
+        let interface = call.interface();
+        let member = call.member();
+        match (
+            interface.as_ref().map(|i| &**i),
+            member.as_ref().map(|m| &**m),
+        ) {
+            (Some(INTERFACE), Some(method @ "Pause"))
+            | (Some(INTERFACE), Some(method @ "Resume")) => {
+                let paused = method == "Pause";
+                if paused != self.paused {
+                    info!("{} requested by DBus", method);
+                    self.paused = paused;
+                    (self.set_paused)(paused)
+                }
+                call.method_return()
+            }
+            (Some(INTERFACE), Some("Annotate")) => match call.read1::<&str>() {
+                Ok(note) => match (self.annotate)(note) {
+                    Ok(()) => call.method_return(),
+                    Err(e) => {
+                        error!("Unable to annotate: {}", e);
+                        Message::new_error(
+                            call,
+                            "org.freedesktop.DBus.Error.Failed",
+                            &e.to_string(),
+                        )
+                        .unwrap()
+                    }
+                },
+                Err(_) => Message::new_error(
+                    call,
+                    "org.freedesktop.DBus.Error.InvalidArgs",
+                    "Expected a note string",
+                )
+                .unwrap(),
+            },
+            (Some(PROPERTIES_INTERFACE), Some("Get")) => match call.read2::<&str, &str>() {
+                Ok((INTERFACE, "CurrentCategory")) => call
+                    .method_return()
+                    .append1(Variant(self.current_category())),
+                Ok((INTERFACE, "Paused")) => call.method_return().append1(Variant(self.paused)),
+                _ => Message::new_error(
+                    call,
+                    "org.freedesktop.DBus.Error.InvalidArgs",
+                    "Unknown property",
+                )
+                .unwrap(),
+            },
+            (Some(PROPERTIES_INTERFACE), Some("GetAll")) => {
+                let mut properties: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+                if let Ok(INTERFACE) = call.read1::<&str>() {
+                    properties.insert(
+                        "CurrentCategory",
+                        Variant(Box::new(self.current_category())),
+                    );
+                    properties.insert("Paused", Variant(Box::new(self.paused)));
+                }
+                call.method_return().append1(properties)
+            }
+            _ => Message::new_error(
+                call,
+                "org.freedesktop.DBus.Error.UnknownMethod",
+                "Unknown method",
+            )
+            .unwrap(),
+        }
+    }
+}
+
+impl<'a, F, A> Future for Service<'a, F, A>
+where
+    F: Fn(bool),
+    A: Fn(&str) -> Result<(), ErrorMessage>,
+{
+    type Item = ();
+    type Error = ErrorMessage;
+
+    fn poll(&mut self) -> Poll<(), ErrorMessage> {
+        loop {
+            match self
+                .bus
+                .poll_read_ready(mio::Ready::readable())
+                .map_err(|e| ErrorMessage::new("DBus connection failed", e))?
+            {
+                Async::Ready(_) => (),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+            self.bus
+                .clear_read_ready(mio::Ready::readable())
+                .map_err(|e| ErrorMessage::new("DBus connection failed", e))?;
+            for call in self.bus.get_ref().method_calls() {
+                trace!("DBus method call: {:?}", call);
+                let reply = self.handle(&call);
+                self.bus.get_ref().send(reply)
+            }
+        }
+    }
+}
+
+/** Set the note of the current time window of a running daemon, through its DBus service.
+ * The daemon must have been started with --dbus.
+ */
+pub fn annotate(note: &str) -> Result<(), ErrorMessage> {
+    let connection = Connection::get_private(BusType::Session)
+        .map_err(|e| ErrorMessage::new("Unable to connect to the session bus", e))?;
+    let call = Message::new_method_call(BUS_NAME, OBJECT_PATH, INTERFACE, "Annotate")
+        .map_err(ErrorMessage::from)?
+        .append1(note);
+    connection
+        .send_with_reply_and_block(call, CALL_TIMEOUT_MS)
+        .map_err(|e| ErrorMessage::new("Unable to annotate the running daemon", e))?;
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use super::ErrorMessage;
+use libc;
+use std::time;
+use tokio;
+use tokio::prelude::*;
+use tokio_signal;
+
+/** Async runtime running the daemon tasks, behind a small interface.
+ * Tasks only use timers, unix signals and the run loop from here, so the runtime can be replaced
+ * or upgraded in this module only. Futures and streams follow the futures 0.1 interface.
+ * Timers are created without the runtime instance, as tasks are built before running it.
+ * The event loop runs on the current thread: tasks borrow the daemon, and are not Send.
+ */
+pub trait EventLoop: Sized {
+    type Interval: Stream<Item = time::Instant, Error = ErrorMessage>;
+    type Delay: Future<Item = (), Error = ErrorMessage>;
+    type Signals: Stream<Item = (), Error = ErrorMessage>;
+
+    fn new() -> Result<Self, ErrorMessage>;
+
+    /** Ticks every period, starting at start. Each tick gives its scheduled time.
+     * Late ticks (system suspend, busy loop) are all fired in a burst.
+     */
+    fn interval(start: time::Instant, period: time::Duration) -> Self::Interval;
+
+    /// Completes at deadline, immediately if in the past.
+    fn delay(deadline: time::Instant) -> Self::Delay;
+
+    /// Produces an item each time one of the unix signals is received.
+    fn signals(signals: &[libc::c_int]) -> Self::Signals;
+
+    /// Run tasks until the future completes.
+    fn run<F>(&mut self, future: F) -> Result<(), ErrorMessage>
+    where
+        F: Future<Item = (), Error = ErrorMessage>;
+}
+
+/// Runtime of the daemon.
+pub type Runtime = TokioCurrentThread;
+
+/// Delay of the daemon runtime, for stream adapters which store it.
+pub type Delay = <Runtime as EventLoop>::Delay;
+
+fn timer_error(e: tokio::timer::Error) -> ErrorMessage {
+    ErrorMessage::new("Timer error", e)
+}
+
+/// Single threaded tokio 0.1 runtime.
+pub struct TokioCurrentThread(tokio::runtime::current_thread::Runtime);
+
+impl EventLoop for TokioCurrentThread {
+    type Interval = stream::MapErr<tokio::timer::Interval, fn(tokio::timer::Error) -> ErrorMessage>;
+    type Delay = future::MapErr<tokio::timer::Delay, fn(tokio::timer::Error) -> ErrorMessage>;
+    type Signals = Box<dyn Stream<Item = (), Error = ErrorMessage>>;
+
+    fn new() -> Result<Self, ErrorMessage> {
+        tokio::runtime::current_thread::Runtime::new()
+            .map(TokioCurrentThread)
+            .map_err(|e| ErrorMessage::new("Unable to create tokio runtime", e))
+    }
+
+    fn interval(start: time::Instant, period: time::Duration) -> Self::Interval {
+        tokio::timer::Interval::new(start, period).map_err(timer_error as fn(_) -> _)
+    }
+
+    fn delay(deadline: time::Instant) -> Self::Delay {
+        tokio::timer::Delay::new(deadline).map_err(timer_error as fn(_) -> _)
+    }
+
+    fn signals(signals: &[libc::c_int]) -> Self::Signals {
+        let mut streams = signals.iter().map(|signal| -> Self::Signals {
+            Box::new(
+                tokio_signal::unix::Signal::new(*signal)
+                    .flatten_stream()
+                    .map(|_signal| ())
+                    .map_err(|e| ErrorMessage::new("Signal handler error", e)),
+            )
+        });
+        let first = streams.next().expect("signals: no signal");
+        streams.fold(first, |all, stream| Box::new(all.select(stream)))
+    }
+
+    fn run<F>(&mut self, future: F) -> Result<(), ErrorMessage>
+    where
+        F: Future<Item = (), Error = ErrorMessage>,
+    {
+        self.0.block_on(future)
+    }
+}
@@ -0,0 +1,115 @@
+use super::database::{format_seconds, DatabaseFormat, EntryReader, TimeFormat};
+use super::influx;
+use super::ErrorMessage;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Quote a CSV field if needed (RFC 4180).
+fn csv_field(text: &str) -> String {
+    if text.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.into()
+    }
+}
+
+/** Write all entries as CSV: a timestamp column, then a duration column (seconds) per category.
+ * The last column is the note of the entry, empty if none.
+ */
+fn write_csv<W: Write>(
+    mut entries: EntryReader,
+    time_format: &TimeFormat,
+    out: &mut W,
+) -> io::Result<()> {
+    write!(out, "timestamp")?;
+    for category in entries.categories().iter() {
+        write!(out, ",{}", csv_field(category))?;
+    }
+    writeln!(out, ",note")?;
+    while let Some(entry) = entries.next() {
+        let (window_start, durations) = entry?;
+        write!(out, "{}", csv_field(&time_format.format(&window_start)))?;
+        for d in durations {
+            write!(out, ",{}", format_seconds(d))?;
+        }
+        writeln!(out, ",{}", csv_field(entries.note().unwrap_or("")))?;
+    }
+    out.flush()
+}
+
+/// Write all entries as InfluxDB line protocol, one line per category and entry, plus notes.
+fn write_influx<W: Write>(mut entries: EntryReader, out: &mut W) -> io::Result<()> {
+    let categories = entries.categories().clone();
+    while let Some(entry) = entries.next() {
+        let (window_start, durations) = entry?;
+        out.write_all(influx::format_entry(&window_start, &categories, &durations).as_bytes())?;
+        if let Some(note) = entries.note() {
+            out.write_all(influx::format_note(&window_start, note).as_bytes())?;
+        }
+    }
+    out.flush()
+}
+
+/// Output format of export.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExportFormat {
+    Csv(TimeFormat),
+    Influx,
+}
+
+impl ExportFormat {
+    /// The time format only applies to CSV: InfluxDB timestamps are always in nanoseconds.
+    pub fn from_name(name: &str, time_format: TimeFormat) -> Result<Self, ErrorMessage> {
+        match name {
+            "csv" => Ok(ExportFormat::Csv(time_format)),
+            "influx" if time_format == TimeFormat::Iso8601 => Ok(ExportFormat::Influx),
+            "influx" => Err(ErrorMessage::from(
+                "InfluxDB export does not support a time format",
+            )),
+            _ => Err(ErrorMessage::from(format!(
+                "Unknown export format '{}'",
+                name
+            ))),
+        }
+    }
+
+    fn write<W: Write>(&self, entries: EntryReader, out: &mut W) -> io::Result<()> {
+        match self {
+            ExportFormat::Csv(time_format) => write_csv(entries, time_format, out),
+            ExportFormat::Influx => write_influx(entries, out),
+        }
+    }
+}
+
+/// Export the database in the given format, to the output file or stdout.
+pub fn export(
+    db_file: &Path,
+    db_format: DatabaseFormat,
+    format: ExportFormat,
+    output: Option<&Path>,
+) -> Result<(), ErrorMessage> {
+    let db_filename = db_file.display();
+    let entries = EntryReader::open(db_file, db_format)
+        .map_err(|e| ErrorMessage::new(format!("Unable to open database '{}'", db_filename), e))?;
+    let read_or_write_error =
+        |e: io::Error| ErrorMessage::new(format!("Unable to export database '{}'", db_filename), e);
+    match output {
+        Some(path) => {
+            let file = File::create(path).map_err(|e| {
+                ErrorMessage::new(format!("Unable to create '{}'", path.display()), e)
+            })?;
+            format
+                .write(entries, &mut BufWriter::new(file))
+                .map_err(read_or_write_error)
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            format
+                .write(entries, &mut stdout)
+                .map_err(read_or_write_error)
+        }
+    }
+}
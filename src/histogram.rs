@@ -0,0 +1,95 @@
+use super::stretches::{read_stretches, stretches_path};
+use super::ErrorMessage;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time;
+
+/// Upper bounds of stretch length buckets, in minutes. The last bucket has no upper bound.
+const BUCKET_BOUNDS: [u64; 3] = [5, 15, 30];
+
+/// Width of the longest bar, in characters.
+const BAR_WIDTH: usize = 40;
+
+fn bucket_labels() -> Vec<String> {
+    let mut labels = Vec::with_capacity(BUCKET_BOUNDS.len() + 1);
+    let mut lower = 0;
+    for upper in BUCKET_BOUNDS.iter() {
+        labels.push(format!("{}-{}m", lower, upper));
+        lower = *upper;
+    }
+    labels.push(format!("{}m+", lower));
+    labels
+}
+
+fn bucket_index(length: time::Duration) -> usize {
+    let minutes = length.as_secs() / 60;
+    BUCKET_BOUNDS
+        .iter()
+        .position(|upper| minutes < *upper)
+        .unwrap_or(BUCKET_BOUNDS.len())
+}
+
+/// Write a bar chart of stretch counts by length bucket, for each category.
+fn write_histogram<W: Write>(counts: &BTreeMap<String, Vec<usize>>, out: &mut W) -> io::Result<()> {
+    let labels = bucket_labels();
+    let label_width = labels.iter().map(String::len).max().unwrap();
+    // Bars share the same scale for all categories, so they can be compared.
+    let max_count = counts
+        .values()
+        .flat_map(|buckets| buckets.iter().cloned())
+        .max()
+        .unwrap_or(0);
+    for (index, (category, buckets)) in counts.iter().enumerate() {
+        if index > 0 {
+            writeln!(out)?;
+        }
+        writeln!(
+            out,
+            "{} ({} stretches)",
+            category,
+            buckets.iter().sum::<usize>()
+        )?;
+        for (label, count) in labels.iter().zip(buckets) {
+            let bar_length = if max_count > 0 {
+                (count * BAR_WIDTH + max_count - 1) / max_count
+            } else {
+                0
+            };
+            writeln!(
+                out,
+                "  {:>width$} | {:<bar_width$} {}",
+                label,
+                "#".repeat(bar_length),
+                count,
+                width = label_width,
+                bar_width = BAR_WIDTH
+            )?;
+        }
+    }
+    out.flush()
+}
+
+/** Print the number of stretches of continuous time in the same category, by length.
+ * Stretches are recorded by the daemon next to the database, see stretches::StretchLog.
+ * If category is given, only this category is printed.
+ */
+pub fn print_histogram(db_file: &Path, category: Option<&str>) -> Result<(), ErrorMessage> {
+    let path = stretches_path(db_file);
+    let stretches = read_stretches(&path).map_err(|e| {
+        ErrorMessage::new(format!("Unable to read stretches '{}'", path.display()), e)
+    })?;
+    let mut counts: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (_start, length, stretch_category) in stretches {
+        if category.map_or(true, |category| category == stretch_category) {
+            counts
+                .entry(stretch_category)
+                .or_insert_with(|| vec![0; BUCKET_BOUNDS.len() + 1])[bucket_index(length)] += 1
+        }
+    }
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    write_histogram(&counts, &mut stdout)
+        .map_err(|e| ErrorMessage::new("Unable to print histogram", e))
+}
@@ -0,0 +1,50 @@
+use std::process::Command;
+use std::thread;
+
+/** Runs a shell command when the category of the active window changes.
+ * The command is run with sh -c, with the new and previous categories as $1 and $2,
+ * also given as XSTALKER_CATEGORY and XSTALKER_PREVIOUS_CATEGORY. Undefined categories are empty.
+ * Commands run in the background: a thread waits for each of them, so the event loop never blocks.
+ * Commands failing to start or exiting with an error status are logged as warnings.
+ */
+pub struct ChangeHook {
+    command: String,
+}
+
+impl ChangeHook {
+    pub fn new(command: String) -> Self {
+        ChangeHook { command: command }
+    }
+
+    pub fn category_changed(&self, category: &Option<String>, previous: &Option<String>) {
+        let category = category.as_ref().map_or("", String::as_str);
+        let previous = previous.as_ref().map_or("", String::as_str);
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .arg("xstalker") // $0
+            .arg(category)
+            .arg(previous)
+            .env("XSTALKER_CATEGORY", category)
+            .env("XSTALKER_PREVIOUS_CATEGORY", previous)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Unable to run category change command: {}", e);
+                return;
+            }
+        };
+        let command = self.command.clone();
+        thread::spawn(move || match child.wait() {
+            Ok(status) if !status.success() => {
+                warn!("Category change command '{}' failed: {}", command, status)
+            }
+            Ok(_) => (),
+            Err(e) => warn!(
+                "Unable to wait for category change command '{}': {}",
+                command, e
+            ),
+        });
+    }
+}
@@ -0,0 +1,88 @@
+use super::database::{format_seconds, DatabaseTime};
+use super::UniqueCategories;
+use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time;
+
+/// Escape a tag value for the line protocol: commas, equal signs and spaces.
+fn escape_tag(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == ',' || c == '=' || c == ' ' {
+            escaped.push('\\');
+        }
+        escaped.push(c)
+    }
+    escaped
+}
+
+/** Format an entry as InfluxDB line protocol, one line per category:
+ * activity,category=<category> duration=<seconds> <time window start, in ns>
+ * Writing the same entry again replaces the points, as they have the same series and time.
+ */
+pub fn format_entry(
+    window_start: &DatabaseTime,
+    categories: &UniqueCategories,
+    durations: &[time::Duration],
+) -> String {
+    let mut lines = String::new();
+    for (category, d) in categories.iter().zip(durations) {
+        writeln!(
+            &mut lines,
+            "activity,category={} duration={} {}",
+            escape_tag(category),
+            format_seconds(*d),
+            window_start.timestamp_nanos()
+        )
+        .unwrap();
+    }
+    lines
+}
+
+/** Format the note of an entry as InfluxDB line protocol:
+ * annotation note="<note>" <time window start, in ns>
+ */
+pub fn format_note(window_start: &DatabaseTime, note: &str) -> String {
+    format!(
+        "annotation note=\"{}\" {}\n",
+        note.replace('\\', "\\\\").replace('"', "\\\""),
+        window_start.timestamp_nanos()
+    )
+}
+
+/** Send the current entry to InfluxDB over UDP.
+ * A failed send is logged at warn level, and the datagram is dropped.
+ */
+pub struct UdpPusher {
+    socket: UdpSocket,
+    addr: SocketAddr,
+}
+
+impl UdpPusher {
+    pub fn new(addr: SocketAddr) -> io::Result<Self> {
+        let local_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let socket = UdpSocket::bind(local_addr)?;
+        // Never block the event loop
+        socket.set_nonblocking(true)?;
+        Ok(UdpPusher {
+            socket: socket,
+            addr: addr,
+        })
+    }
+
+    pub fn push(
+        &self,
+        window_start: &DatabaseTime,
+        categories: &UniqueCategories,
+        durations: &[time::Duration],
+    ) {
+        let lines = format_entry(window_start, categories, durations);
+        if let Err(e) = self.socket.send_to(lines.as_bytes(), &self.addr) {
+            warn!("Unable to push to InfluxDB at {}: {}", self.addr, e)
+        }
+    }
+}
@@ -2,12 +2,23 @@
 extern crate chrono;
 #[macro_use]
 extern crate clap;
+extern crate dbus;
+extern crate env_logger;
+extern crate flate2;
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate notify_rust;
+extern crate regex;
+extern crate serde;
 extern crate tokio;
-use std::cell::RefCell;
+extern crate tokio_signal;
+extern crate toml;
+use std::cell::Cell;
 use std::error::Error;
 use std::fmt;
-use std::io;
-use std::path::Path;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::time;
 use tokio::prelude::*;
 
@@ -52,7 +63,7 @@ impl Error for ErrorMessage {
 }
 
 /// Store a set of unique category names, in a specific order.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct UniqueCategories(Vec<String>);
 impl UniqueCategories {
     /// Check if given vec has unique elements
@@ -73,8 +84,11 @@ impl UniqueCategories {
         categories.dedup();
         UniqueCategories(categories)
     }
-    /// Extend current vec with new categories only. Return slice to inserted elements.
-    pub fn extend(&mut self, categories: UniqueCategories) -> usize {
+    /** Extend current vec with new categories only. Return slice to inserted elements.
+     * Existing categories keep their index, as it is their database column. New categories are
+     * appended in the order of the given set, so extending again with the same set does nothing.
+     */
+    pub fn extend(&mut self, categories: UniqueCategories) -> &[String] {
         let v = &mut self.0;
         let initial_len = v.len();
         for c in categories.0 {
@@ -82,7 +96,21 @@ impl UniqueCategories {
                 v.push(c);
             }
         }
-        v.len() - initial_len
+        &v[initial_len..]
+    }
+    /// Check if the category name is in the set.
+    pub fn contains(&self, name: &str) -> bool {
+        self.index_of(name).is_some()
+    }
+    /// Index of the category name in the set, if present.
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.0.iter().position(|c| c == name)
+    }
+    /// Categories from unique names, in column order. Panics on duplicates, for tests.
+    #[cfg(test)]
+    pub fn for_test(names: &[&str]) -> Self {
+        UniqueCategories::from_unique(names.iter().map(|name| String::from(*name)).collect())
+            .unwrap()
     }
 }
 impl std::ops::Deref for UniqueCategories {
@@ -93,192 +121,994 @@ impl std::ops::Deref for UniqueCategories {
 }
 
 /// Metadata for the current active window
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct ActiveWindowMetadata {
     title: Option<String>,
+    /// Class name (X11 WM_CLASS class part, or Wayland app_id)
     class: Option<String>,
+    /// Instance name (X11 WM_CLASS instance part)
+    instance: Option<String>,
+    pid: Option<u32>,
+    process_name: Option<String>,
+    role: Option<String>,
+    /// Window type (X11 _NET_WM_WINDOW_TYPE without prefix, lowercase): normal, dialog, menu...
+    window_type: Option<String>,
+    /// Current virtual desktop index, from 0 (X11 _NET_CURRENT_DESKTOP)
+    desktop: Option<u32>,
+    /// Icon name (X11 WM_ICON_NAME), some terminals put the running command there
+    icon_name: Option<String>,
+    /// Fullscreen state (X11 _NET_WM_STATE_FULLSCREEN, sway fullscreen mode), false if unknown
+    fullscreen: bool,
+}
+
+impl ActiveWindowMetadata {
+    /// Metadata with only a title and class, to test classifiers without a display server.
+    #[cfg(test)]
+    pub fn for_test(title: Option<&str>, class: Option<&str>) -> Self {
+        ActiveWindowMetadata {
+            title: title.map(String::from),
+            class: class.map(String::from),
+            ..ActiveWindowMetadata::default()
+        }
+    }
+
+    /// Copy without the fields which may contain sensitive text (title, icon name), for logs.
+    fn without_titles(&self) -> Self {
+        ActiveWindowMetadata {
+            title: None,
+            icon_name: None,
+            ..self.clone()
+        }
+    }
+}
+
+/// Get the process name from /proc/<pid>/comm, None if not available.
+fn process_name(pid: u32) -> Option<String> {
+    std::fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|name| name.trim_end_matches('\n').into())
 }
 
 /// Classifier trait and impls.
 mod classifier;
-use classifier::Classifier;
+use classifier::{CheckedClassifier, Classifier, ExcludedWindows, MatchMode};
 
 /// Database time recording
 mod database;
-use database::{CategoryDurationCounter, Database, DatabaseTime};
+use database::{monitor_path, DatabaseFormat, DurationPrecision, MaxDatabaseSize, TimeFormat};
+
+/// Database export to other formats
+mod export;
+
+/// Database summaries by day or week
+mod aggregate;
+
+/// Synthetic window change replay, to measure throughput
+mod bench;
+/// Category switch frequency
+mod stats;
+
+/// Stretches of continuous time in the same category
+mod stretches;
+
+/// Stretch length histograms
+mod histogram;
+
+/// Time per window title
+mod titles;
+
+/// Current visit saved across restarts
+mod visit_state;
+
+/// Database integrity check, repair and compaction
+mod check;
+
+/// Merge of several databases
+mod merge;
+
+/// Default option values from a TOML file
+mod options_file;
+
+/// InfluxDB line protocol export and push
+mod influx;
+
+/// Prometheus metrics and JSON query HTTP endpoint
+mod metrics;
+
+/// Live status over a unix socket
+mod status;
+
+/// Pause, resume and annotations over the session bus
+mod dbus_service;
+
+/// Desktop notifications on category change
+mod notification;
+
+/// Shell command run on category change
+mod hook;
+
+/// Systemd service notifications
+mod systemd;
+
+/// Time tracking state and event handling
+mod daemon;
+use daemon::{Daemon, DaemonOptions, IdleDetection, WindowedDatabase};
 
 /// Xcb interface
 mod xcb_stalker;
-use xcb_stalker::ActiveWindowChanges;
-
-fn write_durations_to_disk(
-    db: &mut Database,
-    duration_counter: &mut CategoryDurationCounter,
-    window_start: &DatabaseTime,
-    timestamp: time::Instant,
-) -> io::Result<()> {
-    duration_counter.record_current_duration(timestamp);
-    db.rewrite_last_entry(window_start, duration_counter.durations())
+use xcb_stalker::{IdleTime, InputEvents, MonitorWindows};
+
+/// Wayland interface (sway IPC)
+mod wayland_stalker;
+
+/// Async runtime of the daemon tasks
+mod event_loop;
+use event_loop::{EventLoop, Runtime};
+
+/// Display server backend selection
+mod stalker;
+use stalker::{ActiveWindowChanges, Backend, Debounce, FocusMode, Reconnect, TitleThrottle};
+
+/// Interval between two checks of the user idle time and screen lock.
+const IDLE_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Interval between two checks of the window shown on each monitor, with --per-monitor.
+const MONITOR_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(5);
+
+/// Interval between two checks for gaps in time (system suspend).
+const GAP_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+/// Category name for logging, "none" if undefined.
+fn display_category(category: &Option<String>) -> &str {
+    category.as_ref().map(String::as_str).unwrap_or("none")
+}
+
+/// Begin tracking of a daemon started paused, from the active window given by a new connection.
+fn begin_tracking<C: Classifier>(
+    daemon: &Daemon<C>,
+    backend: Backend,
+    focus_mode: FocusMode,
+    max_interruption: time::Duration,
+) -> Result<(), ErrorMessage> {
+    let (metadata, timestamp) = ActiveWindowChanges::new(backend, focus_mode)
+        .and_then(|active_window_changes| active_window_changes.get_current_metadata())
+        .map_err(|e| ErrorMessage::new("Unable to get window metadata", e))?;
+    daemon.begin(metadata, timestamp, max_interruption)
 }
 
-fn change_time_window(
-    db: &mut Database,
-    duration_counter: &mut CategoryDurationCounter,
-    window_start: &mut DatabaseTime,
-    time_window_size: time::Duration,
-    timestamp: time::Instant,
-) -> io::Result<()> {
-    // Flush current durations values
-    write_durations_to_disk(db, duration_counter, window_start, timestamp)?;
-    // Create a new time window
-    db.lock_last_entry();
-    duration_counter.reset_durations();
-    *window_start = *window_start + chrono::Duration::from_std(time_window_size).unwrap();
-    Ok(())
+/// Event loop settings of run_daemon(): window events, timers and servers.
+struct RunOptions<'a> {
+    db_write_interval: time::Duration,
+    backend: Backend,
+    focus_mode: FocusMode,
+    /// Time jumps longer than this are not counted (system suspend)
+    gap_threshold: time::Duration,
+    metrics_addr: Option<SocketAddr>,
+    min_dwell: time::Duration,
+    title_interval: time::Duration,
+    ignored_window_types: Vec<String>,
+    max_reconnect_attempts: u32,
+    status_socket: Option<&'a Path>,
+    dbus: bool,
+    influx_udp_addr: Option<SocketAddr>,
+    record_activity: bool,
+    per_monitor: bool,
 }
 
 fn run_daemon(
     classifier: &mut dyn Classifier,
-    db_file: &Path,
-    db_write_interval: time::Duration,
-    time_window_size: time::Duration,
+    excluded_windows: ExcludedWindows,
+    mut databases: Vec<WindowedDatabase>,
+    daemon_options: DaemonOptions,
+    options: RunOptions<'_>,
 ) -> Result<(), ErrorMessage> {
-    let db_filename = db_file.display();
-    // Setup state
-    let classifier_categories = classifier.categories();
-    let mut db = Database::open(db_file, classifier_categories)
-        .map_err(|e| ErrorMessage::new(format!("Unable to open database '{}'", db_filename), e))?;
-    let mut duration_counter = CategoryDurationCounter::new(db.categories().clone());
-    let active_window_changes = ActiveWindowChanges::new()
-        .map_err(|e| ErrorMessage::new("Unable to start window event listener", e))?;
-
-    // Determine current time window
-    let now = DatabaseTime::from(time::SystemTime::now());
-    let window_start = {
-        if let Some((time, durations)) = db.get_last_entry().map_err(|e| {
-            ErrorMessage::new(format!("Unable to read last entry of '{}'", db_filename), e)
-        })? {
-            if time <= now && now < time + chrono::Duration::from_std(time_window_size).unwrap() {
-                // We are still in the time window of the last entry, resume the window.
-                duration_counter.set_durations(durations);
-                time
-            } else {
-                // Outside of last entry time window: create a new window.
-                // This includes the case where now < time (timezone change, system clock adjustement).
-                db.lock_last_entry();
-                now
-            }
-        } else {
-            // No last entry: create new window.
-            now
+    let RunOptions {
+        db_write_interval,
+        backend,
+        focus_mode,
+        gap_threshold,
+        metrics_addr,
+        min_dwell,
+        title_interval,
+        ignored_window_types,
+        max_reconnect_attempts,
+        status_socket,
+        dbus,
+        influx_udp_addr,
+        record_activity,
+        per_monitor,
+    } = options;
+    let start_paused = daemon_options.start_paused;
+    if (daemon_options.idle_detection.is_some() || daemon_options.locked_category.is_some())
+        && backend != Backend::Xcb
+    {
+        return Err(ErrorMessage::from(
+            "Idle and screen lock detection are only supported by the xcb backend",
+        ));
+    }
+    if record_activity && backend != Backend::Xcb {
+        return Err(ErrorMessage::from(
+            "Input activity recording is only supported by the xcb backend",
+        ));
+    }
+    if focus_mode == FocusMode::Mouse && backend != Backend::Xcb {
+        return Err(ErrorMessage::from(
+            "Mouse focus mode is only supported by the xcb backend",
+        ));
+    }
+    if per_monitor && backend != Backend::Xcb {
+        return Err(ErrorMessage::from(
+            "Per monitor tracking is only supported by the xcb backend",
+        ));
+    }
+    // Each monitor records the window shown on it in its own database, like the main one.
+    let monitor_windows = if per_monitor {
+        let monitor_windows = MonitorWindows::new()
+            .map_err(|e| ErrorMessage::new("Unable to start monitor listener", e))?;
+        let outputs = monitor_windows
+            .outputs()
+            .map_err(|e| ErrorMessage::new("Unable to list monitors", e))?;
+        for output in outputs {
+            let main = &databases[0];
+            let path = monitor_path(&main.path, &output);
+            info!("Monitor {}: recording to '{}'", output, path.display());
+            let database = WindowedDatabase {
+                path: path,
+                format: main.format,
+                precision: main.precision,
+                window_size: main.window_size,
+                align: main.align,
+                write_retries: main.write_retries,
+                compact_after: main.compact_after,
+                max_size: main.max_size,
+                trim_to_archive: main.trim_to_archive,
+                dry: main.dry,
+                daily_files: false,
+                monitor: Some(output),
+            };
+            databases.push(database)
         }
+        Some(monitor_windows)
+    } else {
+        None
     };
-    let duration_to_next_window_change = time_window_size
-        - chrono::Duration::to_std(&now.signed_duration_since(window_start)).unwrap();
+    // Queries of the HTTP server read the main database file
+    let (main_db_file, main_db_format) = (databases[0].path.clone(), databases[0].format);
+    let daemon = Daemon::new(classifier, excluded_windows, databases, daemon_options)?;
+    let active_window_changes = ActiveWindowChanges::new(backend, focus_mode)
+        .map_err(|e| ErrorMessage::new("Unable to start window event listener", e))?;
 
-    // Set initial category
-    {
+    // Set initial category, or wait for the first resume if started paused.
+    if start_paused {
+        info!("Started paused: tracking begins at the first resume");
+    } else {
         let (initial_metadata, timestamp) = active_window_changes
             .get_current_metadata()
             .map_err(|e| ErrorMessage::new("Unable to get window metadata", e))?;
-        let initial_category = classifier.classify(initial_metadata)?;
-        duration_counter.category_changed(initial_category, timestamp);
-    }
-
-    // Wrap shared state in RefCell: cannot prove with type that mutations are exclusive.
-    let db = RefCell::new(db);
-    let duration_counter = RefCell::new(duration_counter);
-    let window_start = RefCell::new(window_start);
-
-    // Listen to active window changes.
-    let all_category_changes = active_window_changes
-        .map_err(|e| ErrorMessage::new("Window metadata listener failed", e))
-        .for_each(|(active_window_metadata, timestamp)| {
-            println!("task_handle_window_change");
-            let category = classifier.classify(active_window_metadata)?;
-            duration_counter
-                .borrow_mut()
-                .category_changed(category, timestamp);
-            Ok(())
+        // Interruptions longer than the gap threshold end the visit, as for suspend.
+        daemon.start(initial_metadata, timestamp, gap_threshold)?;
+    }
+
+    // Tasks only translate events to daemon calls, and borrow it until the event loop stops.
+    {
+        let daemon = &daemon;
+
+        // Reconnect to the display server if the listener fails.
+        let active_window_changes = Reconnect::new(
+            active_window_changes,
+            backend,
+            focus_mode,
+            max_reconnect_attempts,
+        );
+
+        // Time in windows of ignored types (popups) stays with the previous window.
+        let active_window_changes = active_window_changes.filter(move |(metadata, _timestamp)| {
+            match &metadata.window_type {
+                Some(window_type) => !ignored_window_types.contains(window_type),
+                None => true,
+            }
         });
 
-    // Periodically write database to file
-    let all_db_writes =
-        tokio::timer::Interval::new(time::Instant::now() + db_write_interval, db_write_interval)
-            .map_err(|e| ErrorMessage::new("Timer error", e))
-            .for_each(|instant| {
-                println!("task_write_db");
-                write_durations_to_disk(
-                    &mut db.borrow_mut(),
-                    &mut duration_counter.borrow_mut(),
-                    &window_start.borrow(),
-                    instant,
+        // Title updates of the same window are classified at most once per title_interval.
+        let active_window_changes = TitleThrottle::new(active_window_changes, title_interval);
+
+        // Listen to active window changes, ignoring windows focused for less than min_dwell.
+        let all_category_changes = Debounce::new(active_window_changes, min_dwell)
+            .map_err(|e| ErrorMessage::new("Window metadata listener failed", e))
+            .for_each(move |(active_window_metadata, timestamp)| {
+                daemon.on_window_change(active_window_metadata, timestamp)
+            });
+
+        // Periodically check user idle time and screen lock.
+        let all_away_checks = if daemon.detects_idle() || daemon.detects_screen_lock() {
+            let idle_time = IdleTime::new()
+                .map_err(|e| ErrorMessage::new("Unable to start idle time listener", e))?;
+            future::Either::A(
+                Runtime::interval(
+                    time::Instant::now() + IDLE_CHECK_INTERVAL,
+                    IDLE_CHECK_INTERVAL,
                 )
-                .map_err(|e| {
-                    ErrorMessage::new(format!("Unable to write to database '{}'", db_filename), e)
+                .for_each(move |instant| {
+                    let locked = if daemon.detects_screen_lock() {
+                        idle_time
+                            .screensaver_active()
+                            .map_err(|e| ErrorMessage::new("Unable to get screen lock state", e))?
+                    } else {
+                        false
+                    };
+                    let idle_time = if daemon.detects_idle() {
+                        idle_time
+                            .get()
+                            .map_err(|e| ErrorMessage::new("Unable to get idle time", e))?
+                    } else {
+                        time::Duration::new(0, 0)
+                    };
+                    daemon.on_away_check(locked, idle_time, instant);
+                    Ok(())
+                }),
+            )
+        } else {
+            future::Either::B(future::empty())
+        };
+
+        // Periodically classify the window shown on each monitor.
+        let all_monitor_checks = match monitor_windows {
+            Some(monitor_windows) => future::Either::A(
+                Runtime::interval(
+                    time::Instant::now() + MONITOR_CHECK_INTERVAL,
+                    MONITOR_CHECK_INTERVAL,
+                )
+                .for_each(move |instant| {
+                    let windows = monitor_windows
+                        .get()
+                        .map_err(|e| ErrorMessage::new("Unable to get monitor windows", e))?;
+                    daemon.on_monitor_windows(windows, instant)
+                }),
+            ),
+            None => future::Either::B(future::empty()),
+        };
+
+        // Count input events for the activity level.
+        let all_input_events = if record_activity {
+            let input_events = InputEvents::new()
+                .map_err(|e| ErrorMessage::new("Unable to start input event listener", e))?;
+            future::Either::A(
+                input_events
+                    .map_err(|e| ErrorMessage::new("Input event listener failed", e))
+                    .for_each(move |activity| {
+                        daemon.on_input(activity);
+                        Ok(())
+                    }),
+            )
+        } else {
+            future::Either::B(future::empty())
+        };
+
+        // Periodically check for gaps in time, and ignore them.
+        // Monotonic time may not count system suspend, so wall clock time is also compared.
+        let all_gap_checks = {
+            let last_check = Cell::new((time::Instant::now(), time::SystemTime::now()));
+            Runtime::interval(
+                time::Instant::now() + GAP_CHECK_INTERVAL,
+                GAP_CHECK_INTERVAL,
+            )
+            .for_each(move |_| {
+                // Interval gives the expected tick time, use the actual time instead.
+                let (instant, system_time) = (time::Instant::now(), time::SystemTime::now());
+                let (last_instant, last_system_time) = last_check.replace((instant, system_time));
+                let monotonic_elapsed = instant.duration_since(last_instant);
+                let wall_clock_elapsed = match system_time.duration_since(last_system_time) {
+                    Ok(elapsed) => elapsed,
+                    Err(e) => {
+                        warn!("Clock went backwards by {}s", e.duration().as_secs());
+                        time::Duration::new(0, 0)
+                    }
+                };
+                let gap = std::cmp::max(monotonic_elapsed, wall_clock_elapsed)
+                    .checked_sub(GAP_CHECK_INTERVAL)
+                    .unwrap_or_default();
+                if gap > gap_threshold {
+                    warn!("Time gap of {}s detected, ignoring it", gap.as_secs());
+                    daemon.on_gap(last_instant, instant)
+                }
+                Ok(())
+            })
+        };
+
+        // Ping the systemd watchdog at each database write, if enabled.
+        let watchdog_timeout = systemd::watchdog_timeout();
+        if let Some(timeout) = watchdog_timeout {
+            if db_write_interval >= timeout {
+                warn!(
+                    "Database write interval is longer than the systemd watchdog timeout ({}s)",
+                    timeout.as_secs()
+                )
+            }
+        }
+
+        // Push durations of the main database to InfluxDB at each database write, if requested.
+        let influx_pusher = match influx_udp_addr {
+            Some(addr) => Some(influx::UdpPusher::new(addr).map_err(|e| {
+                ErrorMessage::new(format!("Unable to create InfluxDB socket for {}", addr), e)
+            })?),
+            None => None,
+        };
+
+        // Periodically write databases to file
+        let all_db_writes =
+            Runtime::interval(time::Instant::now() + db_write_interval, db_write_interval)
+                .for_each(move |instant| {
+                    daemon.on_db_write_tick(instant)?;
+                    if let Some(pusher) = &influx_pusher {
+                        let duration_counter = daemon.main_duration_counter().borrow();
+                        pusher.push(
+                            &daemon.main_window_start(),
+                            duration_counter.categories(),
+                            duration_counter.durations(),
+                        )
+                    }
+                    if watchdog_timeout.is_some() {
+                        if let Err(e) = systemd::notify("WATCHDOG=1") {
+                            warn!("Unable to notify systemd watchdog: {}", e)
+                        }
+                    }
+                    Ok(())
+                });
+
+        // Periodically change time window, for each database.
+        // The next boundary is computed from the window start after each one, so that manual
+        // rotations move the schedule: a boundary scheduled before a rotation closes nothing.
+        let all_time_window_changes =
+            future::join_all((0..daemon.nb_databases()).map(move |index| {
+                future::loop_fn((), move |()| {
+                    let boundary = time::Instant::now() + daemon.duration_to_window_boundary(index);
+                    Runtime::delay(boundary)
+                        .and_then(move |()| daemon.on_window_boundary(index))
+                        .map(|()| future::Loop::<(), ()>::Continue(()))
                 })
-            });
+            }));
 
-    // Periodically change time window
-    let all_time_window_changes = tokio::timer::Interval::new(
-        time::Instant::now() + duration_to_next_window_change,
-        time_window_size,
-    )
-    .map_err(|e| ErrorMessage::new("Timer error", e))
-    .for_each(|instant| {
-        println!("task_new_time_window");
-        change_time_window(
-            &mut db.borrow_mut(),
-            &mut duration_counter.borrow_mut(),
-            &mut window_start.borrow_mut(),
-            time_window_size,
-            instant,
-        )
-        .map_err(|e| ErrorMessage::new(format!("Unable to write to database '{}'", db_filename), e))
-    });
-
-    // Create a tokio runtime to implement an event loop.
-    // Single threaded is enough.
-    // TODO support signals using tokio_signal crate ?
-    let mut runtime = tokio::runtime::current_thread::Runtime::new()
-        .map_err(|e| ErrorMessage::new("Unable to create tokio runtime", e))?;
-    runtime.block_on(
-        Future::join3(all_category_changes, all_db_writes, all_time_window_changes)
-            .map(|(_, _, _)| ()),
-    )
+        // Reload classifier on SIGHUP.
+        let all_reloads = Runtime::signals(&[libc::SIGHUP]).for_each(move |_| daemon.on_reload());
+
+        // Write all databases on SIGUSR1, without waiting for the next write tick, and log the state.
+        let all_flush_requests =
+            Runtime::signals(&[libc::SIGUSR1]).for_each(move |_| daemon.on_flush_request());
+
+        // Start new time windows now on SIGUSR2, without waiting for the window boundaries.
+        let all_rotate_requests =
+            Runtime::signals(&[libc::SIGUSR2]).for_each(move |_| daemon.on_rotate_request());
+
+        // Metrics, status and DBus give durations of the main database
+        let main_duration_counter = daemon.main_duration_counter();
+
+        // Serve current durations as Prometheus metrics, if requested.
+        let all_metrics_requests = match metrics_addr {
+            Some(addr) => future::Either::A(
+                metrics::serve(&addr, main_duration_counter, &main_db_file, main_db_format)
+                    .map_err(|e| {
+                        ErrorMessage::new(format!("Unable to start metrics server on {}", addr), e)
+                    })?,
+            ),
+            None => future::Either::B(future::empty()),
+        };
+
+        // Serve the current category on a unix socket, if requested.
+        // The socket file is removed when _status_socket_file is dropped, on any return path.
+        let (_status_socket_file, all_status_requests) = match status_socket {
+            Some(path) => {
+                let (socket_file, all_status_requests) = status::serve(
+                    path,
+                    main_duration_counter,
+                    move || daemon.main_window_start(),
+                    move || daemon.main_extras(),
+                )
+                .map_err(|e| {
+                    ErrorMessage::new(
+                        format!("Unable to create status socket '{}'", path.display()),
+                        e,
+                    )
+                })?;
+                (Some(socket_file), future::Either::A(all_status_requests))
+            }
+            None => (None, future::Either::B(future::empty())),
+        };
+
+        // Pause and resume time counting over DBus, if requested.
+        let all_dbus_requests = if dbus {
+            future::Either::A(dbus_service::serve(
+                main_duration_counter,
+                move |paused| {
+                    if paused || daemon.is_started() {
+                        daemon.set_paused(paused, time::Instant::now())
+                    } else if let Err(e) =
+                        begin_tracking(daemon, backend, focus_mode, gap_threshold)
+                    {
+                        error!("Unable to begin tracking: {:?}", ShowErrorTraceback(e))
+                    }
+                },
+                move |note| daemon.annotate(note),
+                start_paused,
+            )?)
+        } else {
+            future::Either::B(future::empty())
+        };
+
+        // Stop at the first SIGTERM or SIGINT.
+        let shutdown = Runtime::signals(&[libc::SIGTERM, libc::SIGINT])
+            .into_future()
+            .map(|(_signal, _stream)| ())
+            .map_err(|(e, _stream)| e);
+
+        // Single threaded event loop, see event_loop::EventLoop.
+        let mut runtime = Runtime::new()?;
+        let tasks: Vec<Box<dyn Future<Item = (), Error = ErrorMessage> + '_>> = vec![
+            Box::new(all_category_changes),
+            Box::new(all_db_writes),
+            Box::new(all_time_window_changes.map(|_| ())),
+            Box::new(all_away_checks),
+            Box::new(all_monitor_checks),
+            Box::new(all_input_events),
+            Box::new(all_gap_checks),
+            Box::new(all_reloads),
+            Box::new(all_flush_requests),
+            Box::new(all_rotate_requests),
+            Box::new(all_metrics_requests),
+            Box::new(all_status_requests),
+            Box::new(all_dbus_requests),
+        ];
+        // Setup is complete: database opened, listener started, initial category set.
+        if let Err(e) = systemd::notify("READY=1") {
+            warn!("Unable to notify systemd readiness: {}", e)
+        }
+        runtime.run(
+            future::join_all(tasks)
+                .map(|_| ())
+                .select(shutdown)
+                .map(|(_, _next)| ())
+                .map_err(|(e, _next)| e),
+        )?;
+    }
+
+    // Tasks have been dropped with the event loop, so the daemon is not borrowed anymore.
+    daemon.stop()
 }
 
-fn do_main() -> Result<(), ErrorMessage> {
-    let matches = app_from_crate!()
+/// Classify the current active window and print the result, without using the database.
+fn classify_once(classifier: &mut dyn Classifier, backend: Backend) -> Result<(), ErrorMessage> {
+    let mut classifier = CheckedClassifier::new(classifier);
+    let active_window_changes = ActiveWindowChanges::new(backend, FocusMode::Click)
+        .map_err(|e| ErrorMessage::new("Unable to start window event listener", e))?;
+    let (metadata, _timestamp) = active_window_changes
+        .get_current_metadata()
+        .map_err(|e| ErrorMessage::new("Unable to get window metadata", e))?;
+    debug!("Active window: {:?}", metadata);
+    let title = metadata.title.clone();
+    let class = metadata.class.clone();
+    let instance = metadata.instance.clone();
+    let window_type = metadata.window_type.clone();
+    let fullscreen = metadata.fullscreen;
+    let category = classifier.classify(metadata)?;
+    println!("title: {}", display_category(&title));
+    println!("class: {}", display_category(&class));
+    println!("instance: {}", display_category(&instance));
+    println!("window type: {}", display_category(&window_type));
+    println!("fullscreen: {}", fullscreen);
+    println!("category: {}", display_category(&category));
+    classifier.shutdown()
+}
+
+/** Parse a duration from text.
+ * Accepts a number of seconds, or a sequence of numbers with units (d, h, m, s): "1h30m".
+ */
+fn parse_duration(text: &str) -> Result<time::Duration, ErrorMessage> {
+    let invalid = || {
+        ErrorMessage::from(format!(
+            "Invalid duration '{}': expected seconds, or numbers with units d, h, m, s (1h30m)",
+            text
+        ))
+    };
+    if let Ok(secs) = text.parse() {
+        return Ok(time::Duration::from_secs(secs));
+    }
+    let mut total_secs: u64 = 0;
+    let mut number = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit_secs = match c {
+            'd' => 24 * 3600,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        total_secs = value
+            .checked_mul(unit_secs)
+            .and_then(|secs| total_secs.checked_add(secs))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() || text.is_empty() {
+        return Err(invalid());
+    }
+    Ok(time::Duration::from_secs(total_secs))
+}
+
+/// Command line interface definition.
+fn cli_app() -> clap::App<'static, 'static> {
+    app_from_crate!()
         .setting(clap::AppSettings::VersionlessSubcommands)
         .setting(clap::AppSettings::SubcommandRequired)
+        // Options from the --config file come first, so the command line overrides them.
+        .setting(clap::AppSettings::AllArgsOverrideSelf)
+        .arg(
+            clap::Arg::with_name("config")
+                .long("config")
+                .help("TOML file with default values for options, database and classifier")
+                .takes_value(true)
+                .value_name("file"),
+        )
+        .arg(
+            clap::Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Increase log verbosity, can be repeated")
+                .multiple(true),
+        )
         .arg(
             clap::Arg::with_name("db_file")
                 .help("Path to database file used to store activity")
-                .required(true)
+                .required_unless("once")
                 .index(1),
         )
+        .arg(
+            clap::Arg::with_name("once")
+                .long("once")
+                .help("Print the category of the active window and exit, without database"),
+        )
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .help("Database file format, auto selects jsonl for files with a .jsonl extension")
+                .takes_value(true)
+                .possible_values(&["auto", "plain", "jsonl"])
+                .default_value("auto"),
+        )
+        .arg(
+            clap::Arg::with_name("precision")
+                .long("precision")
+                .help("Precision of durations stored in the database: seconds or milliseconds")
+                .takes_value(true)
+                .possible_values(&["s", "ms"])
+                .default_value("s"),
+        )
         .arg(
             clap::Arg::with_name("time-window")
                 .long("time-window")
                 .help("Maximum time window covered by a database entry")
                 .takes_value(true)
-                .value_name("time_secs")
+                .value_name("duration")
                 .default_value("3600"),
         )
+        .arg(
+            clap::Arg::with_name("align-windows")
+                .long("align-windows")
+                .help("Align time windows to clock boundaries (top of the hour, midnight)"),
+        )
+        .arg(
+            clap::Arg::with_name("dry-db")
+                .long("dry-db")
+                .help("Start from empty in memory databases, and log writes instead of doing them (with -vv)"),
+        )
+        .arg(
+            clap::Arg::with_name("daily-files")
+                .long("daily-files")
+                .help("db_file is a directory with a database file per day (activity-2024-06-01.log), read by the aggregate subcommand"),
+        )
         .arg(
             clap::Arg::with_name("db-write")
                 .long("db-write")
                 .help("Interval at which the database is written to disk")
                 .takes_value(true)
-                .value_name("time_secs")
+                .value_name("duration")
+                .default_value("60"),
+        )
+        .arg(
+            clap::Arg::with_name("adaptive-writes")
+                .long("adaptive-writes")
+                .help("Skip database writes when durations did not change since the last write (idle, paused)"),
+        )
+        .arg(
+            clap::Arg::with_name("min-write-interval")
+                .long("min-write-interval")
+                .help("With adaptive writes, minimum time between two writes of a database, also for --flush-on-change")
+                .takes_value(true)
+                .value_name("duration")
+                .requires("adaptive-writes"),
+        )
+        .arg(
+            clap::Arg::with_name("db-write-retries")
+                .long("db-write-retries")
+                .help("Retries of a database write failing with a transient error (full disk), by later writes, at most 32")
+                .takes_value(true)
+                .value_name("n")
+                .default_value("3"),
+        )
+        .arg(
+            clap::Arg::with_name("compact-after")
+                .long("compact-after")
+                .help("Move closed time windows older than this to a compressed archive (<db_file>.archive.gz)")
+                .takes_value(true)
+                .value_name("duration"),
+        )
+        .arg(
+            clap::Arg::with_name("max-db-size")
+                .long("max-db-size")
+                .help("Remove the oldest closed time windows when the database exceeds this size: bytes (K, M, G suffixes) or <n>entries")
+                .takes_value(true)
+                .value_name("size"),
+        )
+        .arg(
+            clap::Arg::with_name("trim-to-archive")
+                .long("trim-to-archive")
+                .help("Move time windows removed by --max-db-size to the compressed archive instead of dropping them")
+                .requires("max-db-size"),
+        )
+        .arg(
+            clap::Arg::with_name("no-lock")
+                .long("no-lock")
+                .help("Do not lock the database file (for filesystems where flock is unreliable)"),
+        )
+        .arg(
+            clap::Arg::with_name("backend")
+                .long("backend")
+                .help("Display server interface, auto selects from environment")
+                .takes_value(true)
+                .possible_values(&["auto", "xcb", "wayland"])
+                .default_value("auto"),
+        )
+        .arg(
+            clap::Arg::with_name("display")
+                .long("display")
+                .help("X11 display to connect to (:0), instead of $DISPLAY")
+                .takes_value(true)
+                .value_name("display"),
+        )
+        .arg(
+            clap::Arg::with_name("xauthority")
+                .long("xauthority")
+                .help("X11 authority file of the display, instead of $XAUTHORITY")
+                .takes_value(true)
+                .value_name("path"),
+        )
+        .arg(
+            clap::Arg::with_name("focus-mode")
+                .long("focus-mode")
+                .help("Window manager focus policy: with mouse, focus changes only count after a key or button press")
+                .takes_value(true)
+                .possible_values(&["click", "mouse"])
+                .default_value("click"),
+        )
+        .arg(
+            clap::Arg::with_name("gap-threshold")
+                .long("gap-threshold")
+                .help("Gaps in time longer than this are ignored (system suspend)")
+                .takes_value(true)
+                .value_name("duration")
                 .default_value("60"),
         )
+        .arg(
+            clap::Arg::with_name("idle-timeout")
+                .long("idle-timeout")
+                .help("Time without user input after which time is counted as idle")
+                .takes_value(true)
+                .value_name("duration"),
+        )
+        .arg(
+            clap::Arg::with_name("idle-category")
+                .long("idle-category")
+                .help("Category name used for idle time")
+                .takes_value(true)
+                .value_name("name")
+                .default_value("idle"),
+        )
+        .arg(
+            clap::Arg::with_name("locked-category")
+                .long("locked-category")
+                .help("Category name used while the screen is locked (screensaver active)")
+                .takes_value(true)
+                .value_name("name"),
+        )
+        .arg(
+            clap::Arg::with_name("metrics-addr")
+                .long("metrics-addr")
+                .help("Serve Prometheus metrics (/metrics) and JSON queries (/query?from=<date>&to=<date>) over HTTP at this address (127.0.0.1:9100)")
+                .takes_value(true)
+                .value_name("address"),
+        )
+        .arg(
+            clap::Arg::with_name("min-dwell")
+                .long("min-dwell")
+                .help("Ignore windows focused for less than this time (fast window switching)")
+                .takes_value(true)
+                .value_name("milliseconds")
+                .default_value("0"),
+        )
+        .arg(
+            clap::Arg::with_name("title-interval")
+                .long("title-interval")
+                .help("Classify title changes of the active window at most once per interval (terminals updating their title)")
+                .takes_value(true)
+                .value_name("milliseconds")
+                .default_value("1000"),
+        )
+        .arg(
+            clap::Arg::with_name("ignore-window-types")
+                .long("ignore-window-types")
+                .help("Ignore focus changes to windows of these types (dialog,menu,tooltip): time stays with the previous window")
+                .takes_value(true)
+                .use_delimiter(true)
+                .value_name("types"),
+        )
+        .arg(
+            clap::Arg::with_name("exclude-class")
+                .long("exclude-class")
+                .help("Never track windows of this class (password manager): no time is counted while active, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("pattern"),
+        )
+        .arg(
+            clap::Arg::with_name("exclude-title")
+                .long("exclude-title")
+                .help("Never track windows with this title, can be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("pattern"),
+        )
+        .arg(
+            clap::Arg::with_name("exclude-mode")
+                .long("exclude-mode")
+                .help("How excluded class and title patterns are compared, as for the config classifier")
+                .takes_value(true)
+                .possible_values(&["exact", "contains", "regex", "glob"])
+                .default_value("exact"),
+        )
+        .arg(
+            clap::Arg::with_name("max-reconnect-attempts")
+                .long("max-reconnect-attempts")
+                .help("Reconnection attempts to the display server before giving up, 0 to disable")
+                .takes_value(true)
+                .value_name("n")
+                .default_value("10"),
+        )
+        .arg(
+            clap::Arg::with_name("status-socket")
+                .long("status-socket")
+                .help("Unix socket giving the current category, elapsed time and durations as JSON")
+                .takes_value(true)
+                .value_name("path"),
+        )
+        .arg(
+            clap::Arg::with_name("dbus")
+                .long("dbus")
+                .help("Expose the current category and Pause/Resume/Annotate methods on the session bus"),
+        )
+        .arg(
+            clap::Arg::with_name("start-paused")
+                .long("start-paused")
+                .requires("dbus")
+                .help("Do not count time until the DBus Resume method is called, which also detects the initial category"),
+        )
+        .arg(
+            clap::Arg::with_name("influx-udp")
+                .long("influx-udp")
+                .help("Push durations to InfluxDB over UDP, at each database write")
+                .takes_value(true)
+                .value_name("host:port"),
+        )
+        .arg(
+            clap::Arg::with_name("allow-empty-categories")
+                .long("allow-empty-categories")
+                .help("Start even if the classifier defines no categories"),
+        )
+        .arg(
+            clap::Arg::with_name("flush-on-change")
+                .long("flush-on-change")
+                .help("Also write the database at each active window category change"),
+        )
+        .arg(
+            clap::Arg::with_name("record-activity")
+                .long("record-activity")
+                .help("Also record numbers of key presses and mouse events per time window, never which keys (xcb only)"),
+        )
+        .arg(
+            clap::Arg::with_name("count-windows")
+                .long("count-windows")
+                .help("Also record the number of distinct windows (by title, or class) focused per time window"),
+        )
+        .arg(
+            clap::Arg::with_name("per-monitor")
+                .long("per-monitor")
+                .help("Also record the window shown on each monitor in <db_file>.monitor-<output>, in parallel with the active window (xcb only)"),
+        )
+        .arg(
+            clap::Arg::with_name("no-store-titles")
+                .long("no-store-titles")
+                .help("Only use window titles in memory for classification, never write or log them")
+                .long_help(
+                    "Only use window titles in memory for classification, never write or log them.\n\
+                     Titles and icon names are then removed from window metadata in logs (-vv).\n\
+                     Written files contain no titles: databases and archives (durations, switches,\n\
+                     input event counts, number of distinct windows, notes), stretches and visit state\n\
+                     (categories), <db_file>.titles is not used (conflicts with --track-titles).\n\
+                     Status socket, metrics, DBus, notifications and --on-change only give categories.\n\
+                     Titles stay in memory: the current one, and the distinct ones of the current time\n\
+                     window with --count-windows. The process classifier receives them on its stdin,\n\
+                     and must not store them either.",
+                )
+                .conflicts_with("track-titles"),
+        )
+        .arg(
+            clap::Arg::with_name("track-titles")
+                .long("track-titles")
+                .help("Also record time per window title in <db_file>.titles, see the windows subcommand. Titles may be sensitive"),
+        )
+        .arg(
+            clap::Arg::with_name("max-titles")
+                .long("max-titles")
+                .help("Number of titles recorded by --track-titles, time of later titles is counted as other")
+                .takes_value(true)
+                .value_name("n")
+                .default_value("1000"),
+        )
+        .arg(
+            clap::Arg::with_name("notify")
+                .long("notify")
+                .help("Show a desktop notification when the active window category changes"),
+        )
+        .arg(
+            clap::Arg::with_name("default-category")
+                .long("default-category")
+                .help("Category of windows not classified, instead of not recording them (empty to disable)")
+                .takes_value(true)
+                .value_name("category"),
+        )
+        .arg(
+            clap::Arg::with_name("on-change")
+                .long("on-change")
+                .help("Shell command run when the active window category changes, with the new and previous categories as $1 and $2")
+                .takes_value(true)
+                .value_name("command"),
+        )
+        .arg(
+            clap::Arg::with_name("window")
+                .long("window")
+                .help("Also record to another database, with its own time window size")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("file:duration"),
+        )
         .subcommand(
             clap::SubCommand::with_name("process")
                 .about("Classify by using an external subprocess")
                 .after_help(classifier::Process::doc())
                 .setting(clap::AppSettings::TrailingVarArg)
+                .arg(
+                    clap::Arg::with_name("cache-size")
+                        .long("cache-size")
+                        .help("Number of replies to cache, to skip asking again for the same window")
+                        .takes_value(true)
+                        .value_name("n")
+                        .default_value("0"),
+                )
                 .arg(
                     clap::Arg::with_name("command")
                         .help("Subprocess command")
@@ -292,44 +1122,733 @@ fn do_main() -> Result<(), ErrorMessage> {
                         .multiple(true),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            clap::SubCommand::with_name("regex")
+                .about("Classify by using regex rules from files")
+                .after_help(classifier::RegexClassifier::doc())
+                .arg(
+                    clap::Arg::with_name("rules_file")
+                        .help("Path to rules files, tried in order, '-' for stdin")
+                        .required(true)
+                        .index(1)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("class")
+                .about("Use the window class as category")
+                .after_help(classifier::ClassAsCategoryClassifier::doc())
+                .arg(
+                    clap::Arg::with_name("default")
+                        .long("default")
+                        .help("Category for windows without class")
+                        .takes_value(true)
+                        .value_name("category"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("config")
+                .about("Classify by using rules from a TOML config file")
+                .after_help(classifier::ConfigClassifier::doc())
+                .arg(
+                    clap::Arg::with_name("config_file")
+                        .help("Path to the TOML config file, '-' for stdin")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("script")
+                .about("Classify by using functions of a rhai script")
+                .after_help(classifier::ScriptClassifier::doc())
+                .arg(
+                    clap::Arg::with_name("script_file")
+                        .help("Path to the script file, '-' for stdin")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("export")
+                .about(
+                    "Export the database as CSV or InfluxDB lines, instead of running the daemon",
+                )
+                .arg(
+                    clap::Arg::with_name("to")
+                        .long("to")
+                        .help("Export format")
+                        .takes_value(true)
+                        .possible_values(&["csv", "influx"])
+                        .default_value("csv"),
+                )
+                .arg(
+                    clap::Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Output file, stdout if not specified")
+                        .takes_value(true)
+                        .value_name("file"),
+                )
+                .arg(
+                    clap::Arg::with_name("time-format")
+                        .long("time-format")
+                        .help("Format of CSV timestamps, as a strftime format: \"%Y-%m-%d %H:%M\", \"%s\" for epoch seconds. ISO 8601 by default")
+                        .takes_value(true)
+                        .value_name("format"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("aggregate")
+                .about(
+                    "Print time spent per category by day or week, instead of running the daemon",
+                )
+                .arg(
+                    clap::Arg::with_name("by")
+                        .long("by")
+                        .help("Period used to group entries, by their time window start")
+                        .takes_value(true)
+                        .possible_values(&["day", "week"])
+                        .default_value("day"),
+                )
+                .arg(
+                    clap::Arg::with_name("fill-gaps")
+                        .long("fill-gaps")
+                        .help("Also print periods without activity"),
+                )
+                .arg(
+                    clap::Arg::with_name("time-format")
+                        .long("time-format")
+                        .help("Format of period starts, instead of dates and weeks, as a strftime format: \"%Y-%m-%d %H:%M\", \"%s\" for epoch seconds")
+                        .takes_value(true)
+                        .value_name("format"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("stats")
+                .about("Print how often the category changed per hour of activity, instead of running the daemon")
+                .after_help(
+                    "Switches are counted by the daemon for each time window, and stored with durations.\n\
+                     Time windows recorded before switches were counted have no switches.",
+                )
+                .arg(
+                    clap::Arg::with_name("top")
+                        .long("top")
+                        .help("Number of most fragmented time windows to print")
+                        .takes_value(true)
+                        .value_name("n")
+                        .default_value("5"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("histogram")
+                .about("Print how long stretches of continuous time in a category last, instead of running the daemon")
+                .after_help(
+                    "Stretches are recorded by the daemon in <db_file>.stretches, when the category changes.\n\
+                     Pauses, away time without away category and system suspend also end a stretch.",
+                )
+                .arg(
+                    clap::Arg::with_name("category")
+                        .long("category")
+                        .help("Only print stretches of this category")
+                        .takes_value(true)
+                        .value_name("name"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("windows")
+                .about("Print the window titles with the most time, instead of running the daemon")
+                .after_help(
+                    "Titles are recorded by the daemon with --track-titles, in <db_file>.titles.\n\
+                     Time is counted as for categories: not while paused, away, or in excluded windows.",
+                )
+                .arg(
+                    clap::Arg::with_name("top")
+                        .long("top")
+                        .help("Number of titles to print, the others are summed")
+                        .takes_value(true)
+                        .value_name("n")
+                        .default_value("10"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("report")
+                .about("Print durations of the current time window from the daemon status socket, instead of running the daemon")
+                .after_help("The daemon must be running with the same --status-socket.")
+                .arg(
+                    clap::Arg::with_name("watch")
+                        .long("watch")
+                        .help("Redraw the report every second, until interrupted"),
+                )
+                .arg(
+                    clap::Arg::with_name("time-format")
+                        .long("time-format")
+                        .help("Format of the time window start, as a strftime format: \"%Y-%m-%d %H:%M\", \"%s\" for epoch seconds. ISO 8601 by default")
+                        .takes_value(true)
+                        .value_name("format"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("annotate")
+                .about("Set the note of the current time window of the running daemon, instead of running the daemon")
+                .after_help(
+                    "The daemon must be running with --dbus. An empty note removes the current note.\n\
+                     Notes are stored in the database, and included in reports and exports.",
+                )
+                .arg(
+                    clap::Arg::with_name("note")
+                        .help("Note text, tabs and newlines are replaced by spaces")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("check")
+                .about("Check the database for problems, instead of running the daemon")
+                .after_help(
+                    "Entries must have a duration for each category.\n\
+                     Time window starts must not decrease, and windows must not overlap.\n\
+                     The window size is given by --time-window.\n\
+                     Exits with an error if any problem is found. The database is not modified.",
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("compact")
+                .about("Archive time windows older than --compact-after, instead of running the daemon")
+                .after_help(
+                    "Entries are moved to <db_file>.archive.gz, as gzip compressed JSON Lines.\n\
+                     Reports, aggregates and exports read both the archive and the database.\n\
+                     The running daemon compacts by itself when --compact-after is given.",
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("merge")
+                .about("Merge databases into a new database file, instead of running the daemon")
+                .after_help(
+                    "The new database is db_file, in the format given by --format.\n\
+                     Categories are the union of input categories, missing ones count as zero.\n\
+                     Entries with the same time window start are summed.",
+                )
+                .arg(
+                    clap::Arg::with_name("inputs")
+                        .help("Databases to merge, formats are detected from file extensions")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("repair")
+                .about(
+                    "Repair the database after an interrupted write, instead of running the daemon",
+                )
+                .after_help(
+                    "A truncated or malformed last line is dropped.\n\
+                     Other malformed lines are reported, and the database is not modified.\n\
+                     The original database is saved to <db_file>.bak before repair.",
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("bench")
+                .setting(clap::AppSettings::Hidden)
+                .about("Replay synthetic window changes and print timings as JSON")
+                .after_help(
+                    "Window changes go through classification and duration counting as in the daemon,\n\
+                     without display server. Durations are written to a temporary database.",
+                )
+                .arg(
+                    clap::Arg::with_name("events")
+                        .long("events")
+                        .help("Number of window changes")
+                        .takes_value(true)
+                        .value_name("n")
+                        .default_value("10000"),
+                )
+                .arg(
+                    clap::Arg::with_name("windows")
+                        .long("windows")
+                        .help("Number of distinct synthetic windows")
+                        .takes_value(true)
+                        .value_name("n")
+                        .default_value("50"),
+                )
+                .arg(
+                    clap::Arg::with_name("interval")
+                        .long("interval")
+                        .help("Simulated time between window changes, in milliseconds")
+                        .takes_value(true)
+                        .value_name("ms")
+                        .default_value("100"),
+                )
+                .arg(
+                    clap::Arg::with_name("write-every")
+                        .long("write-every")
+                        .help("Number of window changes between database writes, 0 for none")
+                        .takes_value(true)
+                        .value_name("n")
+                        .default_value("100"),
+                )
+                .arg(
+                    clap::Arg::with_name("config")
+                        .long("config")
+                        .help("Classify with a TOML config file, instead of using the class")
+                        .takes_value(true)
+                        .value_name("file"),
+                ),
+        )
+}
 
-    let time_window_size_secs = matches
-        .value_of("time-window")
-        .unwrap()
-        .parse()
+fn do_main() -> Result<(), ErrorMessage> {
+    let matches = options_file::get_matches(cli_app)?;
+
+    env_logger::Builder::new()
+        .filter_level(match matches.occurrences_of("verbose") {
+            0 => log::LevelFilter::Warn,
+            1 => log::LevelFilter::Info,
+            2 => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        })
+        .init();
+
+    // Only optional with --once
+    let db_file = matches.value_of_os("db_file").map(Path::new);
+
+    if let ("report", Some(report_args)) = matches.subcommand() {
+        let status_socket = matches
+            .value_of_os("status-socket")
+            .ok_or_else(|| ErrorMessage::from("Report requires --status-socket"))?;
+        return status::print_report(
+            Path::new(status_socket),
+            report_args.is_present("watch"),
+            &TimeFormat::from_arg(report_args.value_of("time-format"))?,
+        );
+    }
+
+    if let ("annotate", Some(annotate_args)) = matches.subcommand() {
+        return dbus_service::annotate(annotate_args.value_of("note").unwrap());
+    }
+
+    // Commands working on the database only
+    if let ("export", Some(export_args)) = matches.subcommand() {
+        let db_file =
+            db_file.ok_or_else(|| ErrorMessage::from("Export requires a database file"))?;
+        let db_format = DatabaseFormat::from_name(matches.value_of("format").unwrap(), db_file)?;
+        return export::export(
+            db_file,
+            db_format,
+            export::ExportFormat::from_name(
+                export_args.value_of("to").unwrap(),
+                TimeFormat::from_arg(export_args.value_of("time-format"))?,
+            )?,
+            export_args.value_of_os("output").map(Path::new),
+        );
+    }
+    if let ("merge", Some(merge_args)) = matches.subcommand() {
+        let db_file =
+            db_file.ok_or_else(|| ErrorMessage::from("Merge requires a database file"))?;
+        let db_format = DatabaseFormat::from_name(matches.value_of("format").unwrap(), db_file)?;
+        return merge::merge_databases(
+            db_file,
+            db_format,
+            merge_args.values_of_os("inputs").unwrap().map(Path::new),
+        );
+    }
+    if let ("repair", Some(_)) = matches.subcommand() {
+        let db_file =
+            db_file.ok_or_else(|| ErrorMessage::from("Repair requires a database file"))?;
+        let db_format = DatabaseFormat::from_name(matches.value_of("format").unwrap(), db_file)?;
+        return check::repair_database(db_file, db_format);
+    }
+    let compact_after = match matches.value_of("compact-after") {
+        Some(text) => Some(
+            parse_duration(text)
+                .map_err(|e| ErrorMessage::new("Unable to parse compaction delay", e))?,
+        ),
+        None => None,
+    };
+    if let ("compact", Some(_)) = matches.subcommand() {
+        let db_file =
+            db_file.ok_or_else(|| ErrorMessage::from("Compact requires a database file"))?;
+        let db_format = DatabaseFormat::from_name(matches.value_of("format").unwrap(), db_file)?;
+        let compact_after =
+            compact_after.ok_or_else(|| ErrorMessage::from("Compact requires --compact-after"))?;
+        return check::compact_database(
+            db_file,
+            db_format,
+            DurationPrecision::from_name(matches.value_of("precision").unwrap())?,
+            compact_after,
+        );
+    }
+    if let ("aggregate", Some(aggregate_args)) = matches.subcommand() {
+        let db_file =
+            db_file.ok_or_else(|| ErrorMessage::from("Aggregate requires a database file"))?;
+        let db_format = DatabaseFormat::from_name(matches.value_of("format").unwrap(), db_file)?;
+        return aggregate::print_aggregate(
+            db_file,
+            db_format,
+            aggregate::Period::from_name(aggregate_args.value_of("by").unwrap())?,
+            aggregate_args.is_present("fill-gaps"),
+            &TimeFormat::from_arg(aggregate_args.value_of("time-format"))?,
+        );
+    }
+
+    if let ("stats", Some(stats_args)) = matches.subcommand() {
+        let db_file =
+            db_file.ok_or_else(|| ErrorMessage::from("Stats requires a database file"))?;
+        let db_format = DatabaseFormat::from_name(matches.value_of("format").unwrap(), db_file)?;
+        let top = stats_args
+            .value_of("top")
+            .unwrap()
+            .parse()
+            .map_err(|e| ErrorMessage::new("Unable to parse stats top count", e))?;
+        return stats::print_stats(db_file, db_format, top);
+    }
+
+    if let ("histogram", Some(histogram_args)) = matches.subcommand() {
+        let db_file =
+            db_file.ok_or_else(|| ErrorMessage::from("Histogram requires a database file"))?;
+        return histogram::print_histogram(db_file, histogram_args.value_of("category"));
+    }
+
+    if let ("windows", Some(windows_args)) = matches.subcommand() {
+        let db_file =
+            db_file.ok_or_else(|| ErrorMessage::from("Windows requires a database file"))?;
+        let top = windows_args
+            .value_of("top")
+            .unwrap()
+            .parse()
+            .map_err(|e| ErrorMessage::new("Unable to parse windows top count", e))?;
+        return titles::print_top_titles(db_file, top);
+    }
+
+    if let ("bench", Some(bench_args)) = matches.subcommand() {
+        let count = |name: &str| -> Result<usize, ErrorMessage> {
+            bench_args
+                .value_of(name)
+                .unwrap()
+                .parse()
+                .map_err(|e| ErrorMessage::new(format!("Unable to parse bench {}", name), e))
+        };
+        let parameters = bench::BenchParameters {
+            events: count("events")?,
+            windows: count("windows")?,
+            interval: time::Duration::from_millis(count("interval")? as u64),
+            write_every: count("write-every")?,
+        };
+        return match bench_args.value_of_os("config") {
+            Some(config_file) => {
+                let mut classifier =
+                    classifier::ConfigClassifier::from_file(Path::new(config_file))
+                        .map_err(|e| ErrorMessage::new("Cannot create config classifier", e))?;
+                bench::run_bench(&mut classifier, parameters)
+            }
+            None => {
+                let mut classifier = classifier::ClassAsCategoryClassifier::new(None::<&str>);
+                bench::run_bench(&mut classifier, parameters)
+            }
+        };
+    }
+
+    let time_window_size = parse_duration(matches.value_of("time-window").unwrap())
         .map_err(|e| ErrorMessage::new("Unable to parse time window", e))?;
-    let db_write_interval_secs = matches
-        .value_of("db-write")
+    if let ("check", Some(_)) = matches.subcommand() {
+        let db_file =
+            db_file.ok_or_else(|| ErrorMessage::from("Check requires a database file"))?;
+        let db_format = DatabaseFormat::from_name(matches.value_of("format").unwrap(), db_file)?;
+        return check::check_database(db_file, db_format, time_window_size);
+    }
+    let mut extra_windows = Vec::new();
+    for text in matches.values_of("window").unwrap_or_default() {
+        extra_windows.push(parse_window(text)?)
+    }
+    let db_write_interval = parse_duration(matches.value_of("db-write").unwrap())
+        .map_err(|e| ErrorMessage::new("Unable to parse db write interval", e))?;
+    let min_write_interval = if matches.is_present("adaptive-writes") {
+        Some(match matches.value_of("min-write-interval") {
+            Some(text) => parse_duration(text)
+                .map_err(|e| ErrorMessage::new("Unable to parse min write interval", e))?,
+            None => time::Duration::new(0, 0),
+        })
+    } else {
+        None
+    };
+    let db_write_retries = matches
+        .value_of("db-write-retries")
         .unwrap()
         .parse()
-        .map_err(|e| ErrorMessage::new("Unable to parse time window", e))?;
-    if !(0 < db_write_interval_secs && db_write_interval_secs < time_window_size_secs) {
+        .map_err(|e| ErrorMessage::new("Unable to parse database write retries", e))?;
+    if db_write_retries > database::MAX_WRITE_RETRIES {
+        return Err(ErrorMessage::from(format!(
+            "Database write retries must be at most {}",
+            database::MAX_WRITE_RETRIES
+        )));
+    }
+    let min_time_window_size = extra_windows
+        .iter()
+        .map(|(_path, window_size)| *window_size)
+        .fold(time_window_size, std::cmp::min);
+    if !(time::Duration::from_secs(0) < db_write_interval
+        && db_write_interval < min_time_window_size)
+    {
         return Err(ErrorMessage::from(
-            "Wrong time intervals: must follow 0 < db_write < time_window",
+            "Wrong time intervals: must follow 0 < db_write < time_window for all windows",
         ));
     }
 
+    let idle_detection = match matches.value_of("idle-timeout") {
+        Some(text) => Some(IdleDetection {
+            timeout: parse_duration(text)
+                .map_err(|e| ErrorMessage::new("Unable to parse idle timeout", e))?,
+            category: matches.value_of("idle-category").unwrap().into(),
+        }),
+        None => None,
+    };
+
+    let gap_threshold = parse_duration(matches.value_of("gap-threshold").unwrap())
+        .map_err(|e| ErrorMessage::new("Unable to parse gap threshold", e))?;
+
+    // Explicit X11 session, for daemons started outside of it (system services).
+    // Set in the environment, so all xcb connections and reconnections use it.
+    if let Some(display) = matches.value_of_os("display") {
+        std::env::set_var("DISPLAY", display)
+    }
+    if let Some(xauthority) = matches.value_of_os("xauthority") {
+        std::env::set_var("XAUTHORITY", xauthority)
+    }
+    let backend = Backend::from_name(matches.value_of("backend").unwrap())?;
+
+    let min_dwell = time::Duration::from_millis(
+        matches
+            .value_of("min-dwell")
+            .unwrap()
+            .parse()
+            .map_err(|e| ErrorMessage::new("Unable to parse minimum dwell time", e))?,
+    );
+    let title_interval = time::Duration::from_millis(
+        matches
+            .value_of("title-interval")
+            .unwrap()
+            .parse()
+            .map_err(|e| ErrorMessage::new("Unable to parse title interval", e))?,
+    );
+
+    let max_reconnect_attempts = matches
+        .value_of("max-reconnect-attempts")
+        .unwrap()
+        .parse()
+        .map_err(|e| ErrorMessage::new("Unable to parse maximum reconnection attempts", e))?;
+
+    let metrics_addr = match matches.value_of("metrics-addr") {
+        Some(text) => Some(
+            text.parse::<SocketAddr>()
+                .map_err(|e| ErrorMessage::new("Unable to parse metrics address", e))?,
+        ),
+        None => None,
+    };
+
+    let influx_udp_addr = match matches.value_of("influx-udp") {
+        Some(text) => Some(
+            text.to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .ok_or_else(|| {
+                    ErrorMessage::from(format!("Unable to resolve InfluxDB address '{}'", text))
+                })?,
+        ),
+        None => None,
+    };
+
     let mut process_classifier;
+    let mut regex_classifier;
+    let mut config_classifier;
+    let mut script_classifier;
+    let mut class_classifier;
     let classifier: &mut dyn Classifier = match matches.subcommand() {
         ("process", Some(process_args)) => {
             let command_name = process_args.value_of_os("command").unwrap();
             let command_args = process_args.values_of_os("args").unwrap_or_default();
-            process_classifier = classifier::Process::new(command_name, command_args)
-                .map_err(|e| ErrorMessage::new("Cannot create subprocess classifier", e))?;
+            let cache_size = process_args
+                .value_of("cache-size")
+                .unwrap()
+                .parse()
+                .map_err(|e| ErrorMessage::new("Unable to parse process cache size", e))?;
+            process_classifier =
+                classifier::Process::new(command_name, command_args, cache_size)
+                    .map_err(|e| ErrorMessage::new("Cannot create subprocess classifier", e))?;
             &mut process_classifier
         }
+        ("regex", Some(regex_args)) => {
+            let rules_files = regex_args.values_of_os("rules_file").unwrap();
+            if rules_files
+                .clone()
+                .filter(|f| Path::new(f) == Path::new(classifier::STDIN_PATH))
+                .count()
+                > 1
+            {
+                return Err(ErrorMessage::from(
+                    "Stdin can only be used for one rules file",
+                ));
+            }
+            let mut classifiers: Vec<Box<dyn Classifier>> = Vec::new();
+            for rules_file in rules_files {
+                let classifier = classifier::RegexClassifier::from_file(Path::new(rules_file))
+                    .map_err(|e| ErrorMessage::new("Cannot create regex classifier", e))?;
+                classifiers.push(Box::new(classifier))
+            }
+            regex_classifier = classifier::ChainClassifier::new(classifiers);
+            &mut regex_classifier
+        }
+        ("config", Some(config_args)) => {
+            let config_file = Path::new(config_args.value_of_os("config_file").unwrap());
+            config_classifier = classifier::ConfigClassifier::from_file(config_file)
+                .map_err(|e| ErrorMessage::new("Cannot create config classifier", e))?;
+            &mut config_classifier
+        }
+        ("script", Some(script_args)) => {
+            let script_file = Path::new(script_args.value_of_os("script_file").unwrap());
+            script_classifier = classifier::ScriptClassifier::from_file(script_file)
+                .map_err(|e| ErrorMessage::new("Cannot create script classifier", e))?;
+            &mut script_classifier
+        }
+        ("class", Some(class_args)) => {
+            class_classifier =
+                classifier::ClassAsCategoryClassifier::new(class_args.value_of("default"));
+            &mut class_classifier
+        }
         _ => panic!("Argument parsing: subcommand is mandatory"),
     };
 
+    let excluded_values = |name: &str| -> Vec<String> {
+        matches
+            .values_of(name)
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default()
+    };
+    let excluded_windows = ExcludedWindows::new(
+        &excluded_values("exclude-class"),
+        &excluded_values("exclude-title"),
+        MatchMode::from_name(matches.value_of("exclude-mode").unwrap())?,
+    )?;
+
+    // Unclassified windows are not recorded, unless a default category is given.
+    let mut default_category_classifier;
+    let classifier: &mut dyn Classifier = match matches.value_of("default-category") {
+        Some(category) if !category.is_empty() => {
+            default_category_classifier =
+                classifier::DefaultCategoryClassifier::new(classifier, String::from(category));
+            &mut default_category_classifier
+        }
+        _ => classifier,
+    };
+
+    // The class classifier discovers categories at runtime, so it starts without any.
+    let allow_empty_categories =
+        matches.subcommand_name() == Some("class") || matches.is_present("allow-empty-categories");
+
+    if matches.is_present("once") {
+        return classify_once(classifier, backend);
+    }
+
+    let max_titles = if matches.is_present("track-titles") {
+        Some(
+            matches
+                .value_of("max-titles")
+                .unwrap()
+                .parse()
+                .map_err(|e| ErrorMessage::new("Unable to parse max titles", e))?,
+        )
+    } else {
+        None
+    };
+
+    let max_db_size = match matches.value_of("max-db-size") {
+        Some(text) => Some(MaxDatabaseSize::from_arg(text)?),
+        None => None,
+    };
+
+    // First database is the main one, used by metrics and status. Only it may use daily files.
+    let format_name = matches.value_of("format").unwrap();
+    let precision = DurationPrecision::from_name(matches.value_of("precision").unwrap())?;
+    let mut databases = Vec::new();
+    for (path, window_size) in
+        std::iter::once((db_file.unwrap().to_path_buf(), time_window_size)).chain(extra_windows)
+    {
+        databases.push(WindowedDatabase {
+            format: DatabaseFormat::from_name(format_name, &path)?,
+            precision: precision,
+            align: matches.is_present("align-windows"),
+            write_retries: db_write_retries,
+            compact_after: compact_after,
+            max_size: max_db_size,
+            trim_to_archive: matches.is_present("trim-to-archive"),
+            dry: matches.is_present("dry-db"),
+            daily_files: matches.is_present("daily-files") && databases.is_empty(),
+            monitor: None,
+            path: path,
+            window_size: window_size,
+        })
+    }
+
+    let daemon_options = DaemonOptions {
+        db_lock: !matches.is_present("no-lock"),
+        allow_empty_categories: allow_empty_categories,
+        idle_detection: idle_detection,
+        locked_category: matches.value_of("locked-category").map(String::from),
+        notify: matches.is_present("notify"),
+        on_change: matches.value_of("on-change").map(String::from),
+        flush_on_change: matches.is_present("flush-on-change"),
+        max_titles: max_titles,
+        count_windows: matches.is_present("count-windows"),
+        start_paused: matches.is_present("start-paused"),
+        min_write_interval: min_write_interval,
+        log_titles: !matches.is_present("no-store-titles"),
+    };
+    let options = RunOptions {
+        db_write_interval: db_write_interval,
+        backend: backend,
+        focus_mode: FocusMode::from_name(matches.value_of("focus-mode").unwrap())?,
+        gap_threshold: gap_threshold,
+        metrics_addr: metrics_addr,
+        min_dwell: min_dwell,
+        title_interval: title_interval,
+        ignored_window_types: matches
+            .values_of("ignore-window-types")
+            .map(|types| types.map(String::from).collect())
+            .unwrap_or_default(),
+        max_reconnect_attempts: max_reconnect_attempts,
+        status_socket: matches.value_of_os("status-socket").map(Path::new),
+        dbus: matches.is_present("dbus"),
+        influx_udp_addr: influx_udp_addr,
+        record_activity: matches.is_present("record-activity"),
+        per_monitor: matches.is_present("per-monitor"),
+    };
     run_daemon(
         classifier,
-        Path::new(matches.value_of_os("db_file").unwrap()),
-        time::Duration::from_secs(db_write_interval_secs),
-        time::Duration::from_secs(time_window_size_secs),
+        excluded_windows,
+        databases,
+        daemon_options,
+        options,
     )
 }
 
+/// Parse a "file:duration" database with its time window size.
+fn parse_window(text: &str) -> Result<(PathBuf, time::Duration), ErrorMessage> {
+    // Split at the last ':' as the file name may contain some.
+    let mut parts = text.rsplitn(2, ':');
+    let window_size = parts.next().unwrap();
+    let path = match parts.next() {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => {
+            return Err(ErrorMessage::from(format!(
+                "Window '{}' must be formatted as file:duration",
+                text
+            )))
+        }
+    };
+    let window_size = parse_duration(window_size)
+        .map_err(|e| ErrorMessage::new(format!("Unable to parse time window of '{}'", text), e))?;
+    Ok((path, window_size))
+}
+
 /** If main returns Result<_, E>, E will be printed with fmt::Debug.
  * Wrap an Error in this to print a newline delimited error message.
  */
@@ -362,3 +1881,62 @@ impl<'a> Iterator for Traceback<'a> {
         current
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_categories_contain_nothing() {
+        let empty = UniqueCategories::default();
+        assert!(!empty.contains("work"));
+        assert!(!empty.contains(""));
+        assert_eq!(empty.index_of("work"), None);
+    }
+
+    #[test]
+    fn categories_index_is_column_order() {
+        let set = UniqueCategories::for_test(&["work", "leisure", "mail"]);
+        assert!(set.contains("leisure"));
+        assert!(!set.contains("Leisure"));
+        assert_eq!(set.index_of("work"), Some(0));
+        assert_eq!(set.index_of("mail"), Some(2));
+        assert_eq!(set.index_of("unknown"), None);
+    }
+
+    #[test]
+    fn duplicate_categories_are_rejected() {
+        assert!(UniqueCategories::from_unique(vec!["a".into(), "b".into(), "a".into()]).is_err());
+        let set = UniqueCategories::make_unique(vec!["b".into(), "a".into(), "b".into()]);
+        assert_eq!(&set[..], &["a", "b"]);
+        assert_eq!(set.index_of("b"), Some(1));
+    }
+
+    #[test]
+    fn extend_keeps_existing_columns() {
+        let mut set = UniqueCategories::for_test(&["work", "mail"]);
+        let added = set
+            .extend(UniqueCategories::for_test(&["leisure", "mail", "chat"]))
+            .to_vec();
+        assert_eq!(added, ["leisure", "chat"]);
+        assert_eq!(&set[..], &["work", "mail", "leisure", "chat"]);
+
+        // Overlapping sets, in any order, do not add or move columns
+        assert!(set
+            .extend(UniqueCategories::for_test(&["chat", "work"]))
+            .is_empty());
+        assert!(set
+            .extend(UniqueCategories::for_test(&[
+                "work", "mail", "leisure", "chat"
+            ]))
+            .is_empty());
+        assert_eq!(&set[..], &["work", "mail", "leisure", "chat"]);
+
+        let added = set
+            .extend(UniqueCategories::for_test(&["mail", "music"]))
+            .to_vec();
+        assert_eq!(added, ["music"]);
+        assert_eq!(set.index_of("work"), Some(0));
+        assert_eq!(set.index_of("music"), Some(4));
+    }
+}
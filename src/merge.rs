@@ -0,0 +1,73 @@
+use super::database::{Database, DatabaseFormat, DatabaseTime, EntryReader};
+use super::{ErrorMessage, UniqueCategories};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time;
+
+/** Merge entries of several databases into a new database, ordered by time window start.
+ * Categories are the union of input categories; categories missing from an input count as zero.
+ * Entries with the same time window start are summed. Other overlapping windows are kept as is.
+ * Input formats are detected from file extensions. The output must not exist.
+ */
+pub fn merge_databases<'a, I>(
+    output: &Path,
+    output_format: DatabaseFormat,
+    inputs: I,
+) -> Result<(), ErrorMessage>
+where
+    I: IntoIterator<Item = &'a Path>,
+{
+    let mut categories = UniqueCategories::default();
+    let mut windows: BTreeMap<DatabaseTime, BTreeMap<String, time::Duration>> = BTreeMap::new();
+    let mut nb_entries = 0;
+    for input in inputs {
+        let input_filename = input.display();
+        let entries = EntryReader::open(input, DatabaseFormat::detect(input)).map_err(|e| {
+            ErrorMessage::new(format!("Unable to open database '{}'", input_filename), e)
+        })?;
+        let input_categories = entries.categories().clone();
+        categories.extend(input_categories.clone());
+        for entry in entries {
+            let (window_start, durations) = entry.map_err(|e| {
+                ErrorMessage::new(format!("Unable to read database '{}'", input_filename), e)
+            })?;
+            let window = windows.entry(window_start).or_insert_with(BTreeMap::new);
+            for (category, d) in input_categories.iter().zip(durations) {
+                *window
+                    .entry(category.clone())
+                    .or_insert_with(|| time::Duration::new(0, 0)) += d
+            }
+            nb_entries += 1;
+        }
+    }
+    let entries: Vec<(DatabaseTime, Vec<time::Duration>)> = windows
+        .into_iter()
+        .map(|(window_start, window)| {
+            let durations = categories
+                .iter()
+                .map(|category| {
+                    window
+                        .get(category)
+                        .cloned()
+                        .unwrap_or_else(|| time::Duration::new(0, 0))
+                })
+                .collect();
+            (window_start, durations)
+        })
+        .collect();
+    let nb_categories = categories.len();
+    Database::create_with_entries(output, output_format, categories, &entries).map_err(|e| {
+        ErrorMessage::new(
+            format!("Unable to create database '{}'", output.display()),
+            e,
+        )
+    })?;
+    println!(
+        "{}: {} categories, {} entries merged into {} time windows",
+        output.display(),
+        nb_categories,
+        nb_entries,
+        entries.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use super::aggregate;
+use super::database::{json_seconds, CategoryDurationCounter, DatabaseFormat, EntryReader};
+use super::ErrorMessage;
+use chrono;
+use serde::Serialize;
+use serde_json;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time;
+use tokio;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+
+/// Time allowed for a client to send its request and read the response.
+const CLIENT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Only the request line is used, the rest of the request is ignored.
+const REQUEST_BUFFER_SIZE: usize = 1024;
+
+/// Longest date range of a query, in days: the database is read on the event loop thread.
+const MAX_QUERY_DAYS: i64 = 366;
+
+/// Escape a label value for the Prometheus text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Format durations of the current time window in the Prometheus text format.
+pub fn format_metrics(duration_counter: &CategoryDurationCounter) -> String {
+    let mut text = String::from(
+        "# HELP xstalker_category_seconds Time spent in category during the current time window.\n\
+         # TYPE xstalker_category_seconds gauge\n",
+    );
+    let durations = duration_counter.durations_at(time::Instant::now());
+    for (category, duration) in duration_counter.categories().iter().zip(durations) {
+        writeln!(
+            text,
+            "xstalker_category_seconds{{category=\"{}\"}} {}",
+            escape_label(category),
+            duration.as_secs()
+        )
+        .unwrap();
+    }
+    text
+}
+
+/// Total durations for a date range, sent as JSON.
+#[derive(Serialize)]
+struct QueryResult {
+    /// First day of the range, included
+    from: String,
+    /// Last day of the range, included
+    to: String,
+    /// Seconds spent in each category
+    durations: BTreeMap<String, serde_json::Number>,
+}
+
+/// Error sent as JSON for invalid queries.
+#[derive(Serialize)]
+struct QueryError {
+    error: String,
+}
+
+/** Parse query parameters: from and to dates (YYYY-MM-DD, included).
+ * Unknown parameters are ignored. The range must not exceed MAX_QUERY_DAYS.
+ */
+fn parse_query_range(query: &str) -> Result<(chrono::NaiveDate, chrono::NaiveDate), String> {
+    let parse_date = |name: &str, text: &str| {
+        chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d")
+            .map_err(|e| format!("Invalid {} date '{}': {}", name, text, e))
+    };
+    let (mut from, mut to) = (None, None);
+    for param in query.split('&') {
+        let mut name_value = param.splitn(2, '=');
+        match (name_value.next(), name_value.next()) {
+            (Some("from"), Some(text)) => from = Some(parse_date("from", text)?),
+            (Some("to"), Some(text)) => to = Some(parse_date("to", text)?),
+            _ => (),
+        }
+    }
+    let from = from.ok_or_else(|| String::from("Missing from date"))?;
+    let to = to.ok_or_else(|| String::from("Missing to date"))?;
+    if to < from {
+        return Err(String::from("Date range is empty: to is before from"));
+    }
+    if to.signed_duration_since(from).num_days() >= MAX_QUERY_DAYS {
+        return Err(format!("Date range is longer than {} days", MAX_QUERY_DAYS));
+    }
+    Ok((from, to))
+}
+
+/// Compute total durations for the query from the database file: status and JSON body.
+fn query_response(
+    query: &str,
+    db_file: &Path,
+    db_format: DatabaseFormat,
+) -> (&'static str, String) {
+    let error_body = |error: String| serde_json::to_string(&QueryError { error: error }).unwrap();
+    let (from, to) = match parse_query_range(query) {
+        Ok(range) => range,
+        Err(error) => return ("400 Bad Request", error_body(error)),
+    };
+    let totals = EntryReader::open(db_file, db_format).and_then(|entries| {
+        let categories = entries.categories().to_vec();
+        let totals = aggregate::totals_between(entries, from, to)?;
+        Ok(categories.into_iter().zip(totals).collect::<Vec<_>>())
+    });
+    match totals {
+        Ok(totals) => {
+            let result = QueryResult {
+                from: from.format("%Y-%m-%d").to_string(),
+                to: to.format("%Y-%m-%d").to_string(),
+                durations: totals
+                    .into_iter()
+                    .map(|(category, d)| (category, json_seconds(d)))
+                    .collect(),
+            };
+            ("200 OK", serde_json::to_string(&result).unwrap())
+        }
+        Err(e) => {
+            warn!("Unable to read database '{}': {}", db_file.display(), e);
+            (
+                "500 Internal Server Error",
+                error_body(String::from("Unable to read database")),
+            )
+        }
+    }
+}
+
+/// Target of a GET request line, or an empty string if the request is not a GET.
+fn request_target(request: &[u8]) -> &str {
+    let request_line = request.split(|b| *b == b'\r' || *b == b'\n').next();
+    match request_line.map(std::str::from_utf8) {
+        Some(Ok(line)) if line.starts_with("GET ") => line[4..].split(' ').next().unwrap(),
+        _ => "",
+    }
+}
+
+/** Build an HTTP response for the request.
+ * /metrics gives current durations, /query?from=<date>&to=<date> totals from the database.
+ * Anything else is not found.
+ */
+fn http_response(
+    request: &[u8],
+    duration_counter: &RefCell<CategoryDurationCounter>,
+    db_file: &Path,
+    db_format: DatabaseFormat,
+) -> Vec<u8> {
+    let target = request_target(request);
+    let (path, query) = match target.find('?') {
+        Some(index) => (&target[..index], &target[index + 1..]),
+        None => (target, ""),
+    };
+    let (status, content_type, body) = match path {
+        "/metrics" => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            format_metrics(&duration_counter.borrow()),
+        ),
+        "/query" => {
+            let (status, body) = query_response(query, db_file, db_format);
+            (status, "application/json", body)
+        }
+        _ => ("404 Not Found", "text/plain", String::from("Not found\n")),
+    };
+    format!(
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+/** Serve metrics over HTTP at addr/metrics, and database queries at addr/query.
+ * Clients are handled one at a time, which is enough for a scraper or a dashboard.
+ * Client errors are logged and do not stop the server.
+ */
+pub fn serve<'a>(
+    addr: &SocketAddr,
+    duration_counter: &'a RefCell<CategoryDurationCounter>,
+    db_file: &'a Path,
+    db_format: DatabaseFormat,
+) -> io::Result<impl Future<Item = (), Error = ErrorMessage> + 'a> {
+    let listener = TcpListener::bind(addr)?;
+    Ok(listener
+        .incoming()
+        .map_err(|e| ErrorMessage::new("Metrics server failed", e))
+        .for_each(move |socket| {
+            tokio::io::read(socket, vec![0; REQUEST_BUFFER_SIZE])
+                .and_then(move |(socket, buffer, len)| {
+                    let response =
+                        http_response(&buffer[..len], duration_counter, db_file, db_format);
+                    tokio::io::write_all(socket, response)
+                })
+                .timeout(CLIENT_TIMEOUT)
+                .then(|result| -> Result<(), ErrorMessage> {
+                    if let Err(e) = result {
+                        warn!("Metrics client error: {}", e)
+                    }
+                    Ok(())
+                })
+        }))
+}
@@ -0,0 +1,45 @@
+use notify_rust::Notification;
+use std::cell::Cell;
+use std::time;
+
+/// Minimum time between two notifications, to avoid spamming on fast category changes.
+const MIN_INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+/** Shows a desktop notification when the category of the active window changes.
+ * Changes happening less than MIN_INTERVAL after the last notification are not shown.
+ * If it cannot be shown (no notification daemon), a warning is logged and it is lost.
+ */
+pub struct CategoryNotifier {
+    last_notification: Cell<Option<time::Instant>>,
+}
+
+impl CategoryNotifier {
+    pub fn new() -> Self {
+        CategoryNotifier {
+            last_notification: Cell::new(None),
+        }
+    }
+
+    pub fn category_changed(&self, category: &Option<String>) {
+        let now = time::Instant::now();
+        if let Some(last) = self.last_notification.get() {
+            if now < last + MIN_INTERVAL {
+                debug!("Category change notification skipped: rate limited");
+                return;
+            }
+        }
+        self.last_notification.set(Some(now));
+        let body = match category {
+            Some(category) => format!("Now: {}", category),
+            None => String::from("Now: no category"),
+        };
+        if let Err(e) = Notification::new()
+            .appname("xstalker")
+            .summary("Category changed")
+            .body(&body)
+            .show()
+        {
+            warn!("Unable to show notification: {}", e)
+        }
+    }
+}
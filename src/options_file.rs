@@ -0,0 +1,191 @@
+use super::ErrorMessage;
+use clap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use toml;
+
+/** Arguments built from the options file.
+ * db_file and the classifier are positional, so they are only used if missing from the command line.
+ */
+struct OptionsFile {
+    /// Options, in command line syntax
+    options: Vec<OsString>,
+    /// Option names, to report unknown ones
+    names: Vec<String>,
+    db_file: Option<OsString>,
+    /// Classifier subcommand and its arguments
+    classifier: Option<Vec<OsString>>,
+}
+
+/// Add command line arguments for a TOML value of the option name.
+fn push_option(
+    args: &mut Vec<OsString>,
+    name: &str,
+    value: &toml::Value,
+) -> Result<(), ErrorMessage> {
+    let flag = OsString::from(format!("--{}", name));
+    match value {
+        toml::Value::String(text) => args.extend(vec![flag, OsString::from(text)]),
+        toml::Value::Integer(n) => args.extend(vec![flag, OsString::from(n.to_string())]),
+        toml::Value::Float(n) => args.extend(vec![flag, OsString::from(n.to_string())]),
+        toml::Value::Boolean(true) => args.push(flag),
+        toml::Value::Boolean(false) => (),
+        // Repeated option: each value is an occurrence
+        toml::Value::Array(values) if values.iter().all(|value| !value.is_array()) => {
+            for value in values {
+                push_option(args, name, value)?
+            }
+        }
+        _ => {
+            return Err(ErrorMessage::from(format!(
+                "Option '{}': value must be a string, number, boolean or array",
+                name
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Array of strings as arguments.
+fn string_array(name: &str, value: &toml::Value) -> Result<Vec<OsString>, ErrorMessage> {
+    let invalid = || ErrorMessage::from(format!("'{}' must be an array of strings", name));
+    value
+        .as_array()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(|value| value.as_str().map(OsString::from).ok_or_else(invalid))
+        .collect()
+}
+
+impl OptionsFile {
+    fn load(path: &Path) -> Result<Self, ErrorMessage> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            ErrorMessage::new(
+                format!("Unable to read options file '{}'", path.display()),
+                e,
+            )
+        })?;
+        let table: toml::value::Table = toml::from_str(&content).map_err(|e| {
+            ErrorMessage::new(
+                format!("Unable to parse options file '{}'", path.display()),
+                e,
+            )
+        })?;
+        let mut options_file = OptionsFile {
+            options: Vec::new(),
+            names: Vec::new(),
+            db_file: None,
+            classifier: None,
+        };
+        for (name, value) in &table {
+            match name.as_str() {
+                "db_file" => {
+                    let path = value
+                        .as_str()
+                        .ok_or_else(|| ErrorMessage::from("'db_file' must be a string"))?;
+                    options_file.db_file = Some(OsString::from(path))
+                }
+                "classifier" => options_file.classifier = Some(string_array(name, value)?),
+                "config" => {
+                    return Err(ErrorMessage::from(
+                        "Options file cannot include another options file",
+                    ))
+                }
+                _ => {
+                    push_option(&mut options_file.options, name, value)?;
+                    options_file.names.push(name.clone())
+                }
+            }
+        }
+        Ok(options_file)
+    }
+}
+
+/// Path given by --config, found without parsing the command line, as it depends on the file.
+fn find_config_path(args: &[OsString]) -> Option<OsString> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().cloned();
+        }
+        match arg.to_str() {
+            Some(arg) if arg.starts_with("--config=") => {
+                return Some(OsString::from(&arg["--config=".len()..]))
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/** Parse the command line, with default values from the options file given by --config.
+ *
+ * Keys of the TOML file are long option names, with values:
+ * a string or number for options with a value, a boolean for flags, and an array for repeated options.
+ * db_file is the database path, and classifier the classifier with its arguments:
+ * classifier = ["config", "rules.toml"].
+ * Options of the file are placed before the command line arguments: the last occurrence wins,
+ * so the command line overrides them. Unknown options are errors.
+ * On parsing errors, or for help and version, clap prints a message and exits.
+ */
+pub fn get_matches<F>(app: F) -> Result<clap::ArgMatches<'static>, ErrorMessage>
+where
+    F: Fn() -> clap::App<'static, 'static>,
+{
+    let args: Vec<OsString> = env::args_os().collect();
+    let config_path = match find_config_path(args.get(1..).unwrap_or(&[])) {
+        Some(path) => path,
+        None => return Ok(app().get_matches_from(args)),
+    };
+    let mut options_file = OptionsFile::load(Path::new(&config_path))?;
+    let mut db_file = None;
+    let mut classifier = None;
+    loop {
+        let full_args = args[..1]
+            .iter()
+            .chain(&options_file.options)
+            .chain(&db_file)
+            .chain(&args[1..])
+            .chain(classifier.iter().flatten())
+            .cloned();
+        match app().get_matches_from_safe(full_args) {
+            Ok(matches) => return Ok(matches),
+            Err(ref e)
+                if e.kind == clap::ErrorKind::MissingRequiredArgument
+                    && options_file.db_file.is_some() =>
+            {
+                db_file = options_file.db_file.take()
+            }
+            Err(ref e)
+                if e.kind == clap::ErrorKind::MissingSubcommand
+                    && options_file.classifier.is_some() =>
+            {
+                classifier = options_file.classifier.take()
+            }
+            Err(ref e) if e.kind == clap::ErrorKind::UnknownArgument => {
+                let unknown = e.info.iter().flatten().find_map(|arg| {
+                    options_file
+                        .names
+                        .iter()
+                        .find(|name| *arg == format!("--{}", name))
+                });
+                match unknown {
+                    Some(name) => {
+                        return Err(ErrorMessage::from(format!(
+                            "Unknown option '{}' in options file '{}'",
+                            name,
+                            Path::new(&config_path).display()
+                        )))
+                    }
+                    None => e.exit(),
+                }
+            }
+            Err(e) => e.exit(),
+        }
+    }
+}
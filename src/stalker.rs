@@ -0,0 +1,388 @@
+use super::event_loop::{Delay, EventLoop, Runtime};
+use super::{wayland_stalker, xcb_stalker, ActiveWindowMetadata, ErrorMessage};
+use std::cmp;
+use std::env;
+use std::io;
+use std::time;
+use tokio::prelude::*;
+
+/// Display server interface used to listen to active window changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Xcb,
+    Wayland,
+}
+
+impl Backend {
+    /// Select backend from name: "xcb", "wayland", or "auto" to detect from environment.
+    pub fn from_name(name: &str) -> Result<Self, ErrorMessage> {
+        match name {
+            "auto" => Backend::detect(),
+            "xcb" => Ok(Backend::Xcb),
+            "wayland" => Ok(Backend::Wayland),
+            _ => Err(ErrorMessage::from(format!("Unknown backend '{}'", name))),
+        }
+    }
+
+    /// Select backend from display server environment variables.
+    /// Wayland is preferred, as DISPLAY may also be defined for Xwayland.
+    pub fn detect() -> Result<Self, ErrorMessage> {
+        if env::var_os("WAYLAND_DISPLAY").is_some() {
+            Ok(Backend::Wayland)
+        } else if env::var_os("DISPLAY").is_some() {
+            Ok(Backend::Xcb)
+        } else {
+            Err(ErrorMessage::from(
+                "No display server found: neither WAYLAND_DISPLAY nor DISPLAY are defined",
+            ))
+        }
+    }
+}
+
+/// Window manager focus policy, which decides which focus changes are intentional.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FocusMode {
+    /// Focus changes are intentional (click to focus): all of them are counted.
+    Click,
+    /// Focus follows the mouse: a focus change is only counted after a key or button press.
+    Mouse,
+}
+
+impl FocusMode {
+    pub fn from_name(name: &str) -> Result<Self, ErrorMessage> {
+        match name {
+            "click" => Ok(FocusMode::Click),
+            "mouse" => Ok(FocusMode::Mouse),
+            _ => Err(ErrorMessage::from(format!("Unknown focus mode '{}'", name))),
+        }
+    }
+}
+
+/// Asynchronous stream producing ActiveWindowMetadata when active window changes.
+/// Dispatches to the selected backend.
+pub enum ActiveWindowChanges {
+    Xcb(xcb_stalker::ActiveWindowChanges),
+    Wayland(wayland_stalker::ActiveWindowChanges),
+}
+
+impl ActiveWindowChanges {
+    /// Create a new stream, connecting to the display server.
+    /// Only the xcb backend supports the mouse focus mode.
+    pub fn new(backend: Backend, focus_mode: FocusMode) -> io::Result<Self> {
+        Ok(match backend {
+            Backend::Xcb => ActiveWindowChanges::Xcb(xcb_stalker::ActiveWindowChanges::new(
+                focus_mode == FocusMode::Mouse,
+            )?),
+            Backend::Wayland => {
+                ActiveWindowChanges::Wayland(wayland_stalker::ActiveWindowChanges::new()?)
+            }
+        })
+    }
+
+    /// Request the current metadata, irrespective of the stream state.
+    pub fn get_current_metadata(&self) -> io::Result<(ActiveWindowMetadata, time::Instant)> {
+        match self {
+            ActiveWindowChanges::Xcb(inner) => inner.get_current_metadata(),
+            ActiveWindowChanges::Wayland(inner) => inner.get_current_metadata(),
+        }
+    }
+}
+
+impl Stream for ActiveWindowChanges {
+    type Item = (ActiveWindowMetadata, time::Instant);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match self {
+            ActiveWindowChanges::Xcb(inner) => inner.poll(),
+            ActiveWindowChanges::Wayland(inner) => inner.poll(),
+        }
+    }
+}
+
+/** Stream adapter which only produces active window changes that lasted at least min_dwell.
+ * Windows focused for a shorter time are dropped, so their time stays with the previous window.
+ * Produced changes keep their original timestamp, and are delayed by min_dwell.
+ */
+pub struct Debounce<S> {
+    inner: S,
+    inner_done: bool,
+    min_dwell: time::Duration,
+    pending: Option<((ActiveWindowMetadata, time::Instant), Delay)>,
+}
+
+impl<S> Debounce<S> {
+    pub fn new(inner: S, min_dwell: time::Duration) -> Self {
+        Debounce {
+            inner: inner,
+            inner_done: false,
+            min_dwell: min_dwell,
+            pending: None,
+        }
+    }
+}
+
+impl<S> Stream for Debounce<S>
+where
+    S: Stream<Item = (ActiveWindowMetadata, time::Instant), Error = io::Error>,
+{
+    type Item = (ActiveWindowMetadata, time::Instant);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        // Only keep the latest change
+        while !self.inner_done {
+            match self.inner.poll()? {
+                Async::Ready(Some(change)) => {
+                    let dwell_end = Runtime::delay(change.1 + self.min_dwell);
+                    self.pending = Some((change, dwell_end))
+                }
+                Async::Ready(None) => self.inner_done = true,
+                Async::NotReady => break,
+            }
+        }
+        let dwelled = match &mut self.pending {
+            Some((_change, dwell_end)) => {
+                let elapsed = dwell_end
+                    .poll()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                    .is_ready();
+                elapsed || self.inner_done
+            }
+            None if self.inner_done => return Ok(Async::Ready(None)),
+            None => return Ok(Async::NotReady),
+        };
+        if dwelled {
+            Ok(Async::Ready(self.pending.take().map(|(change, _)| change)))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// True if both metadata are from the same window and desktop: all fields but the names are equal.
+fn same_window(a: &ActiveWindowMetadata, b: &ActiveWindowMetadata) -> bool {
+    a.class == b.class
+        && a.instance == b.instance
+        && a.pid == b.pid
+        && a.process_name == b.process_name
+        && a.role == b.role
+        && a.window_type == b.window_type
+        && a.desktop == b.desktop
+        && a.fullscreen == b.fullscreen
+}
+
+/** Stream adapter which limits title changes of the same window to one per interval.
+ * Some applications (terminals, editors) update their title continuously, and each update would
+ * classify the window again. A title change is delayed until interval after the last produced
+ * change, and replaced by later title changes meanwhile: only the latest title is produced.
+ * Changes to another window are produced immediately, dropping any pending title change.
+ */
+pub struct TitleThrottle<S> {
+    inner: S,
+    inner_done: bool,
+    interval: time::Duration,
+    last: Option<(ActiveWindowMetadata, time::Instant)>, // Last produced metadata, and when
+    pending: Option<((ActiveWindowMetadata, time::Instant), Delay)>,
+}
+
+impl<S> TitleThrottle<S> {
+    pub fn new(inner: S, interval: time::Duration) -> Self {
+        TitleThrottle {
+            inner: inner,
+            inner_done: false,
+            interval: interval,
+            last: None,
+            pending: None,
+        }
+    }
+
+    fn produce(
+        &mut self,
+        change: (ActiveWindowMetadata, time::Instant),
+    ) -> (ActiveWindowMetadata, time::Instant) {
+        self.last = Some((change.0.clone(), time::Instant::now()));
+        change
+    }
+}
+
+impl<S> Stream for TitleThrottle<S>
+where
+    S: Stream<Item = (ActiveWindowMetadata, time::Instant), Error = io::Error>,
+{
+    type Item = (ActiveWindowMetadata, time::Instant);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        while !self.inner_done {
+            match self.inner.poll()? {
+                Async::Ready(Some(change)) => {
+                    let last_produced = self
+                        .last
+                        .as_ref()
+                        .filter(|(metadata, _)| same_window(metadata, &change.0))
+                        .map(|(_, produced)| *produced);
+                    let last_produced = match last_produced {
+                        Some(last_produced) => last_produced,
+                        None => {
+                            self.pending = None;
+                            return Ok(Async::Ready(Some(self.produce(change))));
+                        }
+                    };
+                    // Keep the deadline of the first pending title change
+                    let deadline = match self.pending.take() {
+                        Some((_change, deadline)) => deadline,
+                        None => Runtime::delay(last_produced + self.interval),
+                    };
+                    self.pending = Some((change, deadline))
+                }
+                Async::Ready(None) => self.inner_done = true,
+                Async::NotReady => break,
+            }
+        }
+        let ready = match &mut self.pending {
+            Some((_change, deadline)) => {
+                let elapsed = deadline
+                    .poll()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                    .is_ready();
+                elapsed || self.inner_done
+            }
+            None if self.inner_done => return Ok(Async::Ready(None)),
+            None => return Ok(Async::NotReady),
+        };
+        if ready {
+            let (change, _deadline) = self.pending.take().unwrap();
+            Ok(Async::Ready(Some(self.produce(change))))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Delay before the first reconnection attempt, doubled for each failed attempt.
+const RECONNECT_INITIAL_DELAY: time::Duration = time::Duration::from_secs(1);
+/// Maximum delay between two reconnection attempts.
+const RECONNECT_MAX_DELAY: time::Duration = time::Duration::from_secs(60);
+/// A listener failing sooner after reconnection continues the backoff instead of restarting it.
+const RECONNECT_STABLE_DELAY: time::Duration = time::Duration::from_secs(30);
+
+/** Stream adapter which reconnects to the display server when the listener fails.
+ * Attempts are spaced with exponential backoff, and the error is returned after max_attempts.
+ * After reconnection, the current metadata is produced to resynchronize the active window.
+ * If the new listener fails again within RECONNECT_STABLE_DELAY, the failure counts as another
+ * attempt: a display server which accepts connections but fails each time is not retried forever.
+ */
+pub struct Reconnect {
+    backend: Backend,
+    focus_mode: FocusMode,
+    max_attempts: u32,
+    state: ReconnectState,
+    /// Time and attempt number of the last successful reconnection
+    last_reconnection: Option<(time::Instant, u32)>,
+}
+
+enum ReconnectState {
+    Connected(ActiveWindowChanges),
+    Waiting { attempt: u32, retry: Delay },
+}
+
+impl ReconnectState {
+    /// Wait before the given attempt (starting at 1).
+    fn waiting(attempt: u32) -> Self {
+        let delay = RECONNECT_INITIAL_DELAY * 2u32.pow(cmp::min(attempt - 1, 6));
+        ReconnectState::Waiting {
+            attempt: attempt,
+            retry: Runtime::delay(time::Instant::now() + cmp::min(delay, RECONNECT_MAX_DELAY)),
+        }
+    }
+}
+
+impl Reconnect {
+    pub fn new(
+        inner: ActiveWindowChanges,
+        backend: Backend,
+        focus_mode: FocusMode,
+        max_attempts: u32,
+    ) -> Self {
+        Reconnect {
+            backend: backend,
+            focus_mode: focus_mode,
+            max_attempts: max_attempts,
+            state: ReconnectState::Connected(inner),
+            last_reconnection: None,
+        }
+    }
+}
+
+impl Stream for Reconnect {
+    type Item = (ActiveWindowMetadata, time::Instant);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        loop {
+            let next_state = match &mut self.state {
+                ReconnectState::Connected(inner) => match inner.poll() {
+                    Err(e) if self.max_attempts > 0 => {
+                        let attempt = match self.last_reconnection.take() {
+                            Some((time, attempt)) if time.elapsed() < RECONNECT_STABLE_DELAY => {
+                                attempt + 1
+                            }
+                            _ => 1,
+                        };
+                        if attempt > self.max_attempts {
+                            return Err(io::Error::new(
+                                e.kind(),
+                                format!(
+                                    "Listener keeps failing after {} reconnections: {}",
+                                    attempt - 1,
+                                    e
+                                ),
+                            ));
+                        }
+                        warn!("Window metadata listener failed, reconnecting: {}", e);
+                        ReconnectState::waiting(attempt)
+                    }
+                    result => return result,
+                },
+                ReconnectState::Waiting { attempt, retry } => {
+                    let attempt = *attempt;
+                    match retry
+                        .poll()
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+                    {
+                        Async::Ready(()) => (),
+                        Async::NotReady => return Ok(Async::NotReady),
+                    }
+                    let reconnected = ActiveWindowChanges::new(self.backend, self.focus_mode)
+                        .and_then(|inner| {
+                            let current_metadata = inner.get_current_metadata()?;
+                            Ok((inner, current_metadata))
+                        });
+                    match reconnected {
+                        Ok((inner, current_metadata)) => {
+                            info!("Reconnected to display server");
+                            self.last_reconnection = Some((time::Instant::now(), attempt));
+                            self.state = ReconnectState::Connected(inner);
+                            return Ok(Async::Ready(Some(current_metadata)));
+                        }
+                        Err(e) => {
+                            if attempt >= self.max_attempts {
+                                return Err(io::Error::new(
+                                    e.kind(),
+                                    format!(
+                                        "Reconnection failed after {} attempts: {}",
+                                        attempt, e
+                                    ),
+                                ));
+                            }
+                            warn!("Reconnection attempt {} failed: {}", attempt, e);
+                            ReconnectState::waiting(attempt + 1)
+                        }
+                    }
+                }
+            };
+            self.state = next_state
+        }
+    }
+}
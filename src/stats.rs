@@ -0,0 +1,118 @@
+use super::database::{DatabaseFormat, DatabaseTime, EntryReader, Iso8601};
+use super::ErrorMessage;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::time;
+
+/// Time windows with less activity are not ranked, as a few switches would give a high rate.
+const MIN_RANKED_ACTIVITY: time::Duration = time::Duration::from_secs(10 * 60);
+
+/// Category switches and activity of a time window.
+struct WindowSwitches {
+    start: DatabaseTime,
+    switches: u32,
+    activity: time::Duration,
+}
+
+impl WindowSwitches {
+    fn switches_per_hour(&self) -> f64 {
+        switches_per_hour(u64::from(self.switches), self.activity)
+    }
+}
+
+fn switches_per_hour(switches: u64, activity: time::Duration) -> f64 {
+    let hours = activity.as_secs() as f64 / 3600.;
+    if hours > 0. {
+        switches as f64 / hours
+    } else {
+        0.
+    }
+}
+
+/// Duration in hours and minutes: 2h05.
+fn format_duration(d: time::Duration) -> String {
+    let minutes = d.as_secs() / 60;
+    format!("{}h{:02}", minutes / 60, minutes % 60)
+}
+
+/// Read switches and total durations of all time windows with activity.
+fn read_windows(mut entries: EntryReader) -> io::Result<Vec<WindowSwitches>> {
+    let mut windows = Vec::new();
+    while let Some(entry) = entries.next() {
+        let (start, durations) = entry?;
+        let activity = durations.iter().sum();
+        if activity > time::Duration::new(0, 0) {
+            windows.push(WindowSwitches {
+                start: start,
+                switches: entries.switches(),
+                activity: activity,
+            })
+        }
+    }
+    Ok(windows)
+}
+
+/// Write the average switch rate, then the top most fragmented time windows.
+fn write_stats<W: Write>(
+    mut windows: Vec<WindowSwitches>,
+    top: usize,
+    out: &mut W,
+) -> io::Result<()> {
+    let total_switches: u64 = windows.iter().map(|w| u64::from(w.switches)).sum();
+    let total_activity: time::Duration = windows.iter().map(|w| w.activity).sum();
+    writeln!(
+        out,
+        "{} switches in {} of activity, over {} time windows",
+        total_switches,
+        format_duration(total_activity),
+        windows.len()
+    )?;
+    writeln!(
+        out,
+        "Average: {:.1} switches per hour",
+        switches_per_hour(total_switches, total_activity)
+    )?;
+
+    windows.retain(|w| w.activity >= MIN_RANKED_ACTIVITY && w.switches > 0);
+    windows.sort_by(|a, b| {
+        b.switches_per_hour()
+            .partial_cmp(&a.switches_per_hour())
+            .unwrap()
+            .then(a.start.cmp(&b.start))
+    });
+    windows.truncate(top);
+    if !windows.is_empty() {
+        writeln!(out, "\nMost fragmented time windows:")?;
+        for w in &windows {
+            writeln!(
+                out,
+                "  {}  {:>5.1}/h  {:>4} switches in {}",
+                w.start.format_iso8601(),
+                w.switches_per_hour(),
+                w.switches,
+                format_duration(w.activity)
+            )?;
+        }
+    }
+    out.flush()
+}
+
+/** Print category switch frequency: the average number of switches per hour of activity,
+ * and the top time windows with the highest rate.
+ */
+pub fn print_stats(
+    db_file: &Path,
+    db_format: DatabaseFormat,
+    top: usize,
+) -> Result<(), ErrorMessage> {
+    let db_filename = db_file.display();
+    let entries = EntryReader::open(db_file, db_format)
+        .map_err(|e| ErrorMessage::new(format!("Unable to open database '{}'", db_filename), e))?;
+    let windows = read_windows(entries)
+        .map_err(|e| ErrorMessage::new(format!("Unable to read database '{}'", db_filename), e))?;
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    write_stats(windows, top, &mut stdout)
+        .map_err(|e| ErrorMessage::new("Unable to print stats", e))
+}
@@ -0,0 +1,301 @@
+use super::database::{
+    CategoryDurationCounter, DatabaseTime, EntryExtras, InputActivity, Iso8601, TimeFormat,
+};
+use super::ErrorMessage;
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time;
+use tokio;
+use tokio::net::UnixListener;
+use tokio::prelude::*;
+
+/// Time allowed for a client to read the status.
+const CLIENT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+/// Time between two redraws of the live report.
+const WATCH_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// Status sent to clients, as JSON.
+#[derive(Serialize)]
+struct Status<'a> {
+    /// Category currently counted, null if undefined
+    category: Option<&'a str>,
+    /// Seconds since the last category change
+    elapsed: u64,
+    /// Seconds spent in each category during the current time window
+    durations: BTreeMap<&'a str, u64>,
+    /// Start of the current time window, rfc3339
+    window_start: String,
+    /// Input events of the current time window, absent if not recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    activity: Option<InputActivity>,
+    /// Number of distinct windows of the current time window, absent if not counted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    windows: Option<u32>,
+    /// Note of the current time window, absent if none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<&'a str>,
+}
+
+/// Status received by the report client.
+#[derive(Deserialize)]
+struct ReceivedStatus {
+    category: Option<String>,
+    elapsed: u64,
+    durations: BTreeMap<String, u64>,
+    #[serde(default)]
+    window_start: Option<String>,
+    #[serde(default)]
+    activity: Option<InputActivity>,
+    #[serde(default)]
+    windows: Option<u32>,
+    #[serde(default)]
+    note: Option<String>,
+}
+
+/// Current status as a JSON line.
+fn status_line(
+    duration_counter: &CategoryDurationCounter,
+    window_start: &DatabaseTime,
+    extras: &EntryExtras,
+) -> Vec<u8> {
+    let now = time::Instant::now();
+    let status = Status {
+        category: duration_counter.current_category(),
+        elapsed: duration_counter.current_category_elapsed(now).as_secs(),
+        durations: duration_counter
+            .categories()
+            .iter()
+            .map(String::as_str)
+            .zip(
+                duration_counter
+                    .durations_at(now)
+                    .iter()
+                    .map(|d| d.as_secs()),
+            )
+            .collect(),
+        window_start: window_start.format_iso8601(),
+        activity: Some(extras.activity).filter(|activity| !activity.is_zero()),
+        windows: Some(extras.windows).filter(|windows| *windows > 0),
+        note: extras.note.as_ref().map(String::as_str),
+    };
+    let mut line = serde_json::to_vec(&status).unwrap();
+    line.push(b'\n');
+    line
+}
+
+/// Removes the socket file when dropped.
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            warn!(
+                "Unable to remove status socket '{}': {}",
+                self.0.display(),
+                e
+            )
+        }
+    }
+}
+
+/** Serve the current status on a unix socket at path.
+ * Each client receives a JSON line with the current category, elapsed time, durations, start,
+ * input activity, distinct windows and note of the current time window, then is closed.
+ * current_window_start and current_extras give the start, activity, windows and note.
+ * A stale socket file from a previous run is replaced. Other existing files are an error.
+ * The socket file is removed when the returned SocketFile is dropped.
+ */
+pub fn serve<'a, S, E>(
+    path: &Path,
+    duration_counter: &'a RefCell<CategoryDurationCounter>,
+    current_window_start: S,
+    current_extras: E,
+) -> io::Result<(
+    SocketFile,
+    impl Future<Item = (), Error = ErrorMessage> + 'a,
+)>
+where
+    S: Fn() -> DatabaseTime + 'a,
+    E: Fn() -> EntryExtras + 'a,
+{
+    match fs::symlink_metadata(path) {
+        Ok(ref metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        _ => (),
+    }
+    let listener = UnixListener::bind(path)?;
+    let socket_file = SocketFile(path.to_path_buf());
+    let all_clients = listener
+        .incoming()
+        .map_err(|e| ErrorMessage::new("Status socket failed", e))
+        .for_each(move |socket| {
+            let line = status_line(
+                &duration_counter.borrow(),
+                &current_window_start(),
+                &current_extras(),
+            );
+            tokio::io::write_all(socket, line)
+                .timeout(CLIENT_TIMEOUT)
+                .then(|result| -> Result<(), ErrorMessage> {
+                    if let Err(e) = result {
+                        warn!("Status client error: {}", e)
+                    }
+                    Ok(())
+                })
+        });
+    Ok((socket_file, all_clients))
+}
+
+/// Request the status from a daemon serving it at path.
+fn request_status(path: &Path) -> io::Result<ReceivedStatus> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut line = Vec::new();
+    stream.read_to_end(&mut line)?;
+    serde_json::from_slice(&line).map_err(io::Error::from)
+}
+
+/// Duration in hours, minutes and seconds: 1:02:03.
+fn format_seconds(seconds: u64) -> String {
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        (seconds / 60) % 60,
+        seconds % 60
+    )
+}
+
+/** Write the status as a table of categories, sorted by decreasing duration, with the total.
+ * The time window start is shown if the daemon sends it, in time_format.
+ */
+fn write_report<W: Write>(
+    status: &ReceivedStatus,
+    time_format: &TimeFormat,
+    out: &mut W,
+) -> io::Result<()> {
+    let mut durations: Vec<(&str, u64)> = status
+        .durations
+        .iter()
+        .map(|(category, seconds)| (category.as_str(), *seconds))
+        .collect();
+    durations.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let width = durations
+        .iter()
+        .map(|(category, _)| category.chars().count())
+        .chain(Some("total".len()))
+        .max()
+        .unwrap();
+    for (category, seconds) in &durations {
+        // Mark the current category
+        let marker = if Some(*category) == status.category.as_ref().map(String::as_str) {
+            '*'
+        } else {
+            ' '
+        };
+        writeln!(
+            out,
+            "{} {:<width$}  {:>9}",
+            marker,
+            category,
+            format_seconds(*seconds),
+            width = width
+        )?;
+    }
+    let total = durations.iter().map(|(_, seconds)| seconds).sum();
+    writeln!(
+        out,
+        "  {:<width$}  {:>9}",
+        "total",
+        format_seconds(total),
+        width = width
+    )?;
+    if let Some(category) = &status.category {
+        writeln!(
+            out,
+            "\nIn {} for {}",
+            category,
+            format_seconds(status.elapsed)
+        )?;
+    }
+    let window_start = status
+        .window_start
+        .as_ref()
+        .and_then(|text| DatabaseTime::parse_iso8601(text).ok());
+    if let Some(window_start) = window_start {
+        writeln!(
+            out,
+            "Time window since {}",
+            time_format.format(&window_start)
+        )?;
+    }
+    if let Some(activity) = &status.activity {
+        writeln!(
+            out,
+            "Activity: {:.1} input events per minute ({} key presses, {} mouse events)",
+            activity.events_per_minute(time::Duration::from_secs(total)),
+            activity.keys,
+            activity.mouse
+        )?;
+    }
+    if let Some(windows) = status.windows {
+        writeln!(out, "Windows touched: {}", windows)?;
+    }
+    if let Some(note) = &status.note {
+        writeln!(out, "Note: {}", note)?;
+    }
+    out.flush()
+}
+
+/** Report of the current time window from the live counters, formatted as the report subcommand.
+ * Used by the daemon to summarize the session on shutdown.
+ */
+pub fn summary(
+    duration_counter: &CategoryDurationCounter,
+    window_start: &DatabaseTime,
+    extras: &EntryExtras,
+) -> String {
+    // Go through the status sent to clients, so that both reports always match
+    let status: ReceivedStatus =
+        serde_json::from_slice(&status_line(duration_counter, window_start, extras)).unwrap();
+    let mut out = Vec::new();
+    write_report(&status, &TimeFormat::Iso8601, &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+/** Print durations of the current time window, from a daemon serving its status at path.
+ * With watch, the report is redrawn every second until interrupted (Ctrl-C).
+ * The screen is only cleared before each redraw, so the last report stays visible on exit.
+ */
+pub fn print_report(
+    path: &Path,
+    watch: bool,
+    time_format: &TimeFormat,
+) -> Result<(), ErrorMessage> {
+    let stdout = io::stdout();
+    loop {
+        let status = request_status(path).map_err(|e| {
+            ErrorMessage::new(format!("Unable to get status from '{}'", path.display()), e)
+        })?;
+        let mut out = stdout.lock();
+        if watch {
+            // Clear screen and move cursor to the top left
+            write!(out, "\x1b[2J\x1b[H")
+                .map_err(|e| ErrorMessage::new("Unable to print report", e))?;
+        }
+        write_report(&status, time_format, &mut out)
+            .map_err(|e| ErrorMessage::new("Unable to print report", e))?;
+        if !watch {
+            return Ok(());
+        }
+        thread::sleep(WATCH_INTERVAL)
+    }
+}
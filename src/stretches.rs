@@ -0,0 +1,125 @@
+use super::database::{DatabaseTime, Iso8601};
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time;
+
+/// Stretches are appended next to the database: <db_file>.stretches.
+pub fn stretches_path(db_file: &Path) -> PathBuf {
+    let mut name = db_file.as_os_str().to_owned();
+    name.push(".stretches");
+    PathBuf::from(name)
+}
+
+/// Stretch in progress.
+struct Stretch {
+    category: String,
+    start: DatabaseTime,
+    start_instant: time::Instant,
+}
+
+/** Record stretches of continuous time in the counted category.
+ * The database only stores durations per time window, which loses how long each visit lasted.
+ * Each line of the stretch file is: start (rfc3339) \t seconds \t category.
+ * A stretch ends when the counted category changes, including to no category (paused, idle
+ * without idle category), and at time not spent on the computer (system suspend).
+ * A stretch which cannot be appended is logged as a warning, and lost.
+ */
+pub struct StretchLog {
+    path: PathBuf,
+    current: Option<Stretch>,
+}
+
+impl StretchLog {
+    pub fn new(db_file: &Path) -> Self {
+        StretchLog {
+            path: stretches_path(db_file),
+            current: None,
+        }
+    }
+
+    /// Change the counted category: ends the current stretch if the category is different.
+    pub fn category_changed(&mut self, category: Option<&str>, timestamp: time::Instant) {
+        if self.current.as_ref().map(|s| s.category.as_str()) == category {
+            return;
+        }
+        self.end(timestamp);
+        self.current = category.map(|category| Stretch {
+            category: category.into(),
+            start: start_time(timestamp),
+            start_instant: timestamp,
+        })
+    }
+
+    /// Time between start and end is not counted: end the stretch, and start a new one after.
+    pub fn skip_duration(&mut self, start: time::Instant, end: time::Instant) {
+        let category = self.current.as_ref().map(|s| s.category.clone());
+        self.end(start);
+        self.category_changed(category.as_ref().map(String::as_str), end)
+    }
+
+    /// End the current stretch at timestamp, and append it to the file.
+    pub fn end(&mut self, timestamp: time::Instant) {
+        if let Some(stretch) = self.current.take() {
+            if timestamp <= stretch.start_instant {
+                return;
+            }
+            let length = timestamp.duration_since(stretch.start_instant);
+            if let Err(e) = self.append(&stretch, length) {
+                warn!(
+                    "Unable to record stretch to '{}': {}",
+                    self.path.display(),
+                    e
+                )
+            }
+        }
+    }
+
+    fn append(&self, stretch: &Stretch, length: time::Duration) -> io::Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(
+            file,
+            "{}\t{}\t{}",
+            stretch.start.format_iso8601(),
+            length.as_secs(),
+            stretch.category
+        )
+    }
+}
+
+/// Wall clock time of an instant in the recent past.
+fn start_time(timestamp: time::Instant) -> DatabaseTime {
+    let (now, now_instant) = (time::SystemTime::now(), time::Instant::now());
+    if now_instant <= timestamp {
+        return DatabaseTime::from(now);
+    }
+    let elapsed = now_instant.duration_since(timestamp);
+    DatabaseTime::from(now.checked_sub(elapsed).unwrap_or(now))
+}
+
+/// Read all recorded stretches: start, length and category.
+pub fn read_stretches(path: &Path) -> io::Result<Vec<(DatabaseTime, time::Duration, String)>> {
+    let invalid_line = |line_nb: usize, e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Line {}: {}", line_nb, e),
+        )
+    };
+    let mut stretches = Vec::new();
+    for (index, line) in BufReader::new(fs::File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let mut fields = line.splitn(3, '\t');
+        let (start, seconds, category) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(start), Some(seconds), Some(category)) => (start, seconds, category),
+            _ => return Err(invalid_line(index + 1, &"expected 3 fields")),
+        };
+        let start = DatabaseTime::parse_iso8601(start).map_err(|e| invalid_line(index + 1, &e))?;
+        let seconds: u64 = seconds.parse().map_err(|e| invalid_line(index + 1, &e))?;
+        stretches.push((start, time::Duration::from_secs(seconds), category.into()))
+    }
+    Ok(stretches)
+}
@@ -0,0 +1,66 @@
+use libc;
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::time;
+
+/** Send a state notification to systemd (sd_notify protocol): "READY=1", "WATCHDOG=1".
+ * Does nothing if not started by systemd with notify support (NOTIFY_SOCKET undefined).
+ */
+pub fn notify(state: &str) -> io::Result<()> {
+    let socket_path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket_path = socket_path.as_bytes();
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if socket_path.is_empty() || socket_path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "NOTIFY_SOCKET: invalid socket path",
+        ));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(socket_path) {
+        *dst = *src as libc::c_char
+    }
+    if socket_path[0] == b'@' {
+        // Abstract namespace socket, not supported by std::os::unix::net addresses
+        addr.sun_path[0] = 0
+    }
+    let addr_len = mem::size_of::<libc::sa_family_t>() + socket_path.len();
+    let socket = UnixDatagram::unbound()?;
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            addr_len as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/** Watchdog timeout requested by systemd (WATCHDOG_USEC), None if the watchdog is disabled.
+ * WATCHDOG_PID restricts the watchdog to a specific process, if defined.
+ */
+pub fn watchdog_timeout() -> Option<time::Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_str().and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(time::Duration::from_micros(usec))
+}
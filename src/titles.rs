@@ -0,0 +1,224 @@
+use super::ErrorMessage;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time;
+
+/// Titles are recorded next to the database: <db_file>.titles.
+pub fn titles_path(db_file: &Path) -> PathBuf {
+    let mut name = db_file.as_os_str().to_owned();
+    name.push(".titles");
+    PathBuf::from(name)
+}
+
+/** Total time spent in each window title, independent of categories.
+ * Each line of the titles file is: milliseconds \t title. An empty title is the "other" bucket,
+ * which receives the time of new titles once max_titles are recorded. Windows without title are
+ * not counted. Totals accumulate over daemon runs, as the file is read at start.
+ * Titles may be sensitive: the file is only readable by its owner.
+ */
+pub struct TitleDurations {
+    path: PathBuf,
+    max_titles: usize,
+    durations: HashMap<String, time::Duration>,
+    other: time::Duration,
+    /// Counted title, and start of the time not yet added to durations
+    current: Option<(String, time::Instant)>,
+}
+
+impl TitleDurations {
+    /// Load the totals of previous runs, if the file exists.
+    pub fn open(db_file: &Path, max_titles: usize) -> Result<Self, ErrorMessage> {
+        let path = titles_path(db_file);
+        let mut durations = HashMap::new();
+        let mut other = time::Duration::new(0, 0);
+        match read_titles(&path) {
+            Ok(titles) => {
+                for (title, duration) in titles {
+                    match title {
+                        Some(title) => *durations.entry(title).or_default() += duration,
+                        None => other += duration,
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(ErrorMessage::new(
+                    format!("Unable to read titles file '{}'", path.display()),
+                    e,
+                ))
+            }
+        }
+        Ok(TitleDurations {
+            path: path,
+            max_titles: max_titles,
+            durations: durations,
+            other: other,
+            current: None,
+        })
+    }
+
+    /// Change the counted title, None if no time is counted. Empty titles are not counted.
+    pub fn title_changed(&mut self, title: Option<&str>, timestamp: time::Instant) {
+        let title = title
+            .filter(|title| !title.is_empty())
+            .map(|title| title.replace('\n', " "));
+        if self.current.as_ref().map(|(title, _)| title) == title.as_ref() {
+            return;
+        }
+        self.record_current(timestamp);
+        self.current = title.map(|title| (title, timestamp))
+    }
+
+    /// Time between start and end is not counted.
+    pub fn skip_duration(&mut self, start: time::Instant, end: time::Instant) {
+        self.record_current(start);
+        if let Some((_, current_start)) = &mut self.current {
+            *current_start = end
+        }
+    }
+
+    /// Add the time of the current title up to timestamp.
+    fn record_current(&mut self, timestamp: time::Instant) {
+        if let Some((title, start)) = &mut self.current {
+            if timestamp <= *start {
+                return;
+            }
+            let elapsed = timestamp.duration_since(*start);
+            *start = timestamp;
+            if let Some(duration) = self.durations.get_mut(title.as_str()) {
+                *duration += elapsed
+            } else if self.durations.len() < self.max_titles {
+                self.durations.insert(title.clone(), elapsed);
+            } else {
+                self.other += elapsed
+            }
+        }
+    }
+
+    /// Record the current title up to timestamp, and replace the file atomically.
+    /// On failure, a warning is logged: titles stay in memory for the next write.
+    pub fn write(&mut self, timestamp: time::Instant) {
+        self.record_current(timestamp);
+        if let Err(e) = self.write_file() {
+            warn!("Unable to write titles to '{}': {}", self.path.display(), e)
+        }
+    }
+
+    fn write_file(&self) -> io::Result<()> {
+        let mut tmp_path = self.path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let mut file = io::BufWriter::new(
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(&tmp_path)?,
+        );
+        for (title, duration) in &self.durations {
+            writeln!(file, "{}\t{}", as_millis(*duration), title)?;
+        }
+        if self.other > time::Duration::new(0, 0) {
+            writeln!(file, "{}\t", as_millis(self.other))?;
+        }
+        file.flush()?;
+        drop(file);
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+fn as_millis(d: time::Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+/// Read recorded titles with their duration. The "other" bucket has no title.
+fn read_titles(path: &Path) -> io::Result<Vec<(Option<String>, time::Duration)>> {
+    let invalid_line = |line_nb: usize, e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Line {}: {}", line_nb, e),
+        )
+    };
+    let mut titles = Vec::new();
+    for (index, line) in BufReader::new(fs::File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let mut fields = line.splitn(2, '\t');
+        let (millis, title) = match (fields.next(), fields.next()) {
+            (Some(millis), Some(title)) => (millis, title),
+            _ => return Err(invalid_line(index + 1, &"expected 2 fields")),
+        };
+        let millis: u64 = millis.parse().map_err(|e| invalid_line(index + 1, &e))?;
+        let title = if title.is_empty() {
+            None
+        } else {
+            Some(title.into())
+        };
+        titles.push((title, time::Duration::from_millis(millis)))
+    }
+    Ok(titles)
+}
+
+/// Print the top window titles by total time, then the time of other titles.
+pub fn print_top_titles(db_file: &Path, top: usize) -> Result<(), ErrorMessage> {
+    let path = titles_path(db_file);
+    let mut titles = read_titles(&path).map_err(|e| {
+        ErrorMessage::new(
+            format!(
+                "Unable to read titles file '{}' (see --track-titles)",
+                path.display()
+            ),
+            e,
+        )
+    })?;
+    let total: time::Duration = titles.iter().map(|(_, duration)| *duration).sum();
+    let mut other: time::Duration = titles
+        .iter()
+        .filter(|(title, _)| title.is_none())
+        .map(|(_, duration)| *duration)
+        .sum();
+    titles.retain(|(title, _)| title.is_some());
+    titles.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    for (_, duration) in titles.iter().skip(top) {
+        other += *duration
+    }
+    titles.truncate(top);
+
+    let format_duration = |d: time::Duration| {
+        let minutes = d.as_secs() / 60;
+        format!("{:>4}h{:02}", minutes / 60, minutes % 60)
+    };
+    let percent = |d: time::Duration| {
+        if total.as_secs() > 0 {
+            100. * d.as_secs() as f64 / total.as_secs() as f64
+        } else {
+            0.
+        }
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut print = || -> io::Result<()> {
+        for (title, duration) in &titles {
+            writeln!(
+                out,
+                "{} {:>5.1}%  {}",
+                format_duration(*duration),
+                percent(*duration),
+                title.as_ref().unwrap()
+            )?;
+        }
+        if other > time::Duration::new(0, 0) {
+            writeln!(
+                out,
+                "{} {:>5.1}%  (other)",
+                format_duration(other),
+                percent(other)
+            )?;
+        }
+        out.flush()
+    };
+    print().map_err(|e| ErrorMessage::new("Unable to print titles", e))
+}
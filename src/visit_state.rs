@@ -0,0 +1,93 @@
+use super::database::{CategoryDurationCounter, DatabaseTime, Iso8601};
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time;
+
+/** In-progress visit of the current category, saved next to the database (<db_file>.state).
+ * The database only stores durations, so the time already spent in the current category is lost
+ * on restart. It is restored if the daemon restarts in the same time window and category.
+ */
+#[derive(Serialize, Deserialize)]
+struct VisitState {
+    /// Time window start, rfc3339
+    window_start: String,
+    category: Option<String>,
+    /// Milliseconds spent in the category when saved
+    elapsed: u64,
+    /// Wall clock time of the save, rfc3339
+    saved_at: String,
+}
+
+pub fn state_path(db_file: &Path) -> PathBuf {
+    let mut name = db_file.as_os_str().to_owned();
+    name.push(".state");
+    PathBuf::from(name)
+}
+
+/// Save the current visit, replacing the state file atomically.
+pub fn save(
+    path: &Path,
+    window_start: &DatabaseTime,
+    duration_counter: &CategoryDurationCounter,
+    timestamp: time::Instant,
+) -> io::Result<()> {
+    let elapsed = duration_counter.current_category_elapsed(timestamp);
+    let state = VisitState {
+        window_start: window_start.format_iso8601(),
+        category: duration_counter.current_category().map(String::from),
+        elapsed: elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis()),
+        saved_at: DatabaseTime::from(time::SystemTime::now()).format_iso8601(),
+    };
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&state)?)?;
+    fs::rename(&tmp_path, path)
+}
+
+/** Restore the visit elapsed time from the state file, if it is still valid.
+ * It must be for the same time window and the same current category, and saved less than
+ * max_age ago: a longer interruption ends the visit.
+ * Returns true if restored. Missing or invalid state files are ignored, they are replaced later.
+ */
+pub fn restore(
+    path: &Path,
+    window_start: &DatabaseTime,
+    duration_counter: &mut CategoryDurationCounter,
+    max_age: time::Duration,
+    timestamp: time::Instant,
+) -> bool {
+    let state: VisitState = match fs::read(path)
+        .and_then(|content| serde_json::from_slice(&content).map_err(io::Error::from))
+    {
+        Ok(state) => state,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return false,
+        Err(e) => {
+            warn!("Ignoring invalid visit state '{}': {}", path.display(), e);
+            return false;
+        }
+    };
+    let saved_at = match DatabaseTime::parse_iso8601(&state.saved_at) {
+        Ok(saved_at) => saved_at,
+        Err(_) => return false,
+    };
+    let age = DatabaseTime::from(time::SystemTime::now()).signed_duration_since(saved_at);
+    let same_window = DatabaseTime::parse_iso8601(&state.window_start).ok() == Some(*window_start);
+    let same_category =
+        state.category.as_ref().map(String::as_str) == duration_counter.current_category();
+    match age.to_std() {
+        Ok(age) if age <= max_age && same_window && same_category => {
+            duration_counter.restore_current_category_elapsed(
+                time::Duration::from_millis(state.elapsed),
+                timestamp,
+            );
+            true
+        }
+        _ => {
+            debug!("Visit state '{}' is stale, ignored", path.display());
+            false
+        }
+    }
+}
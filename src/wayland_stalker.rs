@@ -0,0 +1,294 @@
+#![deny(deprecated)]
+extern crate mio;
+extern crate serde_json; // for wayland_stalker
+
+use std;
+use std::env;
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::time;
+use tokio::prelude::*;
+use tokio::reactor::PollEvented2 as PollEvented; // Tokio is changing interfaces, temporary
+
+use self::serde_json::Value;
+
+use super::process_name;
+/// This is the type used to output information about the active window.
+/// Defined in main.
+pub use super::ActiveWindowMetadata;
+
+/// Sway IPC protocol constants.
+/// A message is: magic string, payload length (u32), message type (u32), json payload.
+/// Integers are in native byte order.
+const IPC_MAGIC: &[u8] = b"i3-ipc";
+const IPC_HEADER_LEN: usize = 14;
+const IPC_SUBSCRIBE: u32 = 2;
+const IPC_GET_TREE: u32 = 4;
+const IPC_EVENT_WINDOW: u32 = 0x8000_0003;
+
+fn bad_data<E>(error: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Connect to the sway IPC socket, given by the SWAYSOCK environment variable.
+fn connect() -> io::Result<UnixStream> {
+    match env::var_os("SWAYSOCK") {
+        Some(path) => UnixStream::connect(path),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "SWAYSOCK is not defined: only sway is supported by the wayland backend",
+        )),
+    }
+}
+
+fn send_message(mut stream: &UnixStream, message_type: u32, payload: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(IPC_HEADER_LEN + payload.len());
+    message.extend_from_slice(IPC_MAGIC);
+    message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&message_type.to_ne_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+/// Parse a message header, returning (payload length, message type).
+fn parse_header(header: &[u8]) -> io::Result<(usize, u32)> {
+    if &header[..IPC_MAGIC.len()] != IPC_MAGIC {
+        return Err(bad_data("Sway IPC: bad magic string"));
+    }
+    let u32_at = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&header[offset..offset + 4]);
+        u32::from_ne_bytes(bytes)
+    };
+    let len = u32_at(IPC_MAGIC.len()) as usize;
+    let message_type = u32_at(IPC_MAGIC.len() + 4);
+    Ok((len, message_type))
+}
+
+/// Read a complete message from a blocking stream.
+fn receive_message(mut stream: &UnixStream) -> io::Result<(u32, Value)> {
+    let mut header = [0u8; IPC_HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    let (len, message_type) = parse_header(&header)?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok((
+        message_type,
+        serde_json::from_slice(&payload).map_err(bad_data)?,
+    ))
+}
+
+/// Extract a complete message from the start of the buffer, if available.
+fn take_message(buffer: &mut Vec<u8>) -> io::Result<Option<(u32, Value)>> {
+    if buffer.len() < IPC_HEADER_LEN {
+        return Ok(None);
+    }
+    let (len, message_type) = parse_header(&buffer[..IPC_HEADER_LEN])?;
+    if buffer.len() < IPC_HEADER_LEN + len {
+        return Ok(None);
+    }
+    let payload: Vec<u8> = buffer
+        .drain(..IPC_HEADER_LEN + len)
+        .skip(IPC_HEADER_LEN)
+        .collect();
+    Ok(Some((
+        message_type,
+        serde_json::from_slice(&payload).map_err(bad_data)?,
+    )))
+}
+
+/// Metadata from a sway container: title is the name, class is the app_id.
+/// Xwayland windows have no app_id, but have a X11 class and instance.
+fn container_metadata(container: &Value) -> ActiveWindowMetadata {
+    let is_window = match container["type"].as_str() {
+        Some("con") | Some("floating_con") => true,
+        _ => false,
+    };
+    if !is_window {
+        // Focus is on an empty workspace or output
+        return ActiveWindowMetadata::default();
+    }
+    let pid = container["pid"].as_u64().map(|pid| pid as u32);
+    ActiveWindowMetadata {
+        title: container["name"].as_str().map(String::from),
+        class: container["app_id"]
+            .as_str()
+            .or_else(|| container["window_properties"]["class"].as_str())
+            .map(String::from),
+        instance: container["window_properties"]["instance"]
+            .as_str()
+            .map(String::from),
+        pid: pid,
+        process_name: pid.and_then(process_name),
+        // Role and window type are only defined for Xwayland windows
+        role: container["window_properties"]["window_role"]
+            .as_str()
+            .map(String::from),
+        window_type: container["window_properties"]["window_type"]
+            .as_str()
+            .map(String::from),
+        // Sway workspaces are named, not indexed desktops
+        desktop: None,
+        icon_name: None,
+        // 0 is not fullscreen, 1 fullscreen on the workspace, 2 on all outputs
+        fullscreen: container["fullscreen_mode"]
+            .as_u64()
+            .map_or(false, |mode| mode != 0),
+    }
+}
+
+/// Find the focused node in the sway tree.
+fn find_focused(node: &Value) -> Option<&Value> {
+    if node["focused"] == Value::Bool(true) {
+        return Some(node);
+    }
+    let children = |key: &str| node[key].as_array().into_iter().flatten();
+    children("nodes")
+        .chain(children("floating_nodes"))
+        .filter_map(find_focused)
+        .next()
+}
+
+/// Event connection to sway, subscribed to window events (impl detail of ActiveWindowChanges).
+struct EventSocket {
+    stream: UnixStream,
+    buffer: Vec<u8>,
+}
+
+/// Polling support for the event connection: just use the underlying file descriptor.
+impl mio::Evented for EventSocket {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        mio::unix::EventedFd(&self.stream.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        mio::unix::EventedFd(&self.stream.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        mio::unix::EventedFd(&self.stream.as_raw_fd()).deregister(poll)
+    }
+}
+
+/** Asynchronous stream producing ActiveWindowMetadata when active window changes.
+ * Uses the sway IPC protocol, with two connections:
+ * one subscribed to window events, one for synchronous queries.
+ */
+pub struct ActiveWindowChanges {
+    events: PollEvented<EventSocket>,
+    queries: UnixStream,
+}
+
+impl ActiveWindowChanges {
+    /// Create a new stream.
+    /// No tokio reactor is specified, so the socket will be registered lazily at first use.
+    pub fn new() -> io::Result<Self> {
+        let queries = connect()?;
+        let events = connect()?;
+        send_message(&events, IPC_SUBSCRIBE, b"[\"window\"]")?;
+        match receive_message(&events)? {
+            (IPC_SUBSCRIBE, ref reply) if reply["success"] == Value::Bool(true) => (),
+            _ => return Err(bad_data("Sway IPC: subscription to window events failed")),
+        }
+        events.set_nonblocking(true)?;
+        Ok(ActiveWindowChanges {
+            events: PollEvented::new(EventSocket {
+                stream: events,
+                buffer: Vec::new(),
+            }),
+            queries: queries,
+        })
+    }
+
+    /// Request the current metadata, irrespective of the stream state.
+    /// This can be used for initialisation, before the first change.
+    pub fn get_current_metadata(&self) -> io::Result<(ActiveWindowMetadata, time::Instant)> {
+        let timestamp = time::Instant::now();
+        send_message(&self.queries, IPC_GET_TREE, b"")?;
+        match receive_message(&self.queries)? {
+            (IPC_GET_TREE, tree) => {
+                let metadata = match find_focused(&tree) {
+                    Some(container) => container_metadata(container),
+                    None => ActiveWindowMetadata::default(),
+                };
+                Ok((metadata, timestamp))
+            }
+            _ => Err(bad_data("Sway IPC: unexpected reply to get_tree")),
+        }
+    }
+}
+
+/// Asynchronous Stream implementation.
+impl Stream for ActiveWindowChanges {
+    type Item = (ActiveWindowMetadata, time::Instant);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        // Check if there is inbound data
+        match self.events.poll_read_ready(mio::Ready::readable()) {
+            Ok(Async::Ready(_)) => (),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+        }
+        // Read all available data
+        {
+            let socket = self.events.get_mut();
+            let mut chunk = [0u8; 4096];
+            loop {
+                match socket.stream.read(&mut chunk) {
+                    Ok(0) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "Sway IPC: connection closed",
+                        ))
+                    }
+                    Ok(n) => socket.buffer.extend_from_slice(&chunk[..n]),
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        // Reset read flag, will be set again if data arrives on socket
+        self.events.clear_read_ready(mio::Ready::readable())?;
+
+        // Process all complete events, only the last change matters.
+        let timestamp = time::Instant::now();
+        let mut new_metadata = None;
+        while let Some((message_type, event)) = take_message(&mut self.events.get_mut().buffer)? {
+            if message_type != IPC_EVENT_WINDOW {
+                continue;
+            }
+            let container = &event["container"];
+            match event["change"].as_str() {
+                Some("focus") => new_metadata = Some(container_metadata(container)),
+                Some("title") | Some("fullscreen_mode")
+                    if container["focused"] == Value::Bool(true) =>
+                {
+                    new_metadata = Some(container_metadata(container))
+                }
+                _ => (),
+            }
+        }
+        match new_metadata {
+            Some(metadata) => Ok(Async::Ready(Some((metadata, timestamp)))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
@@ -3,12 +3,17 @@ extern crate mio;
 extern crate xcb; // for xcb_stalker
 
 use std;
+use std::cmp;
+use std::env;
 use std::io;
 use std::os::unix::io::AsRawFd;
 use std::time;
 use tokio::prelude::*;
 use tokio::reactor::PollEvented2 as PollEvented; // Tokio is changing interfaces, temporary
 
+use super::database::InputActivity;
+use super::event_loop::{Delay, EventLoop, Runtime};
+use super::process_name;
 /// This is the type used to output information about the active window.
 /// Defined in main.
 pub use super::ActiveWindowMetadata;
@@ -20,6 +25,13 @@ struct Stalker {
     root_window: xcb::Window,
     non_static_atoms: NonStaticAtoms,
     current_active_window: xcb::Window,
+    /// XInput2 major opcode if focus changes require user input to be confirmed.
+    xinput_opcode: Option<u8>,
+    /// Last active window that was reported.
+    confirmed_active_window: xcb::Window,
+    /// Fullscreen state of current_active_window, to only report its changes.
+    /// _NET_WM_STATE also changes for unrelated states (maximized, focused, urgent...).
+    active_window_fullscreen: bool,
 }
 
 /// Store non static useful atoms (impl detail of Stalker).
@@ -27,6 +39,14 @@ struct NonStaticAtoms {
     active_window: xcb::Atom,
     utf8_string: xcb::Atom,
     compound_text: xcb::Atom,
+    wm_pid: xcb::Atom,
+    wm_window_role: xcb::Atom,
+    net_current_desktop: xcb::Atom,
+    net_client_list_stacking: xcb::Atom,
+    net_wm_name: xcb::Atom,
+    net_wm_window_type: xcb::Atom,
+    net_wm_state: xcb::Atom,
+    net_wm_state_fullscreen: xcb::Atom,
 }
 
 fn conn_to_io_error(err: xcb::ConnError) -> io::Error {
@@ -44,6 +64,21 @@ fn conn_to_io_error(err: xcb::ConnError) -> io::Error {
     }
 }
 
+/// Connect to the X server of $DISPLAY. Errors name the display, which may come from the environment.
+fn connect() -> io::Result<(xcb::Connection, i32)> {
+    xcb::Connection::connect(None).map_err(|e| {
+        let e = conn_to_io_error(e);
+        let display = match env::var("DISPLAY") {
+            Ok(display) => format!("'{}'", display),
+            Err(_) => String::from("(DISPLAY is not set)"),
+        };
+        io::Error::new(
+            e.kind(),
+            format!("Unable to connect to X display {}: {}", display, e),
+        )
+    })
+}
+
 /// Get active window id. Error if not found.
 fn get_active_window(
     connection: &xcb::Connection,
@@ -92,11 +127,42 @@ fn disable_property_change_notifications(connection: &xcb::Connection, w: xcb::W
     xcb::change_window_attributes(connection, w, &values);
 }
 
+/** Select raw input events of the mask using the XInput2 extension.
+ * Raw events are received whatever the focused window.
+ * Returns the extension major opcode, used to recognize its events.
+ */
+fn enable_raw_input_notifications(
+    connection: &xcb::Connection,
+    root_window: xcb::Window,
+    mask: u32,
+) -> io::Result<u8> {
+    let unsupported = || io::Error::new(io::ErrorKind::NotFound, "XInput2 extension unsupported");
+    let extension = connection
+        .get_extension_data(xcb::xinput::id())
+        .ok_or_else(unsupported)?;
+    if !extension.present() {
+        return Err(unsupported());
+    }
+    // The client must announce XInput2 support before selecting XInput2 events
+    xcb::xinput::xi_query_version(connection, 2, 0)
+        .get_reply()
+        .map_err(|_| unsupported())?;
+    let mask = xcb::xinput::EventMask::new(
+        xcb::xinput::DEVICE_ALL_MASTER as xcb::xinput::DeviceId,
+        &[mask],
+    );
+    xcb::xinput::xi_select_events(connection, root_window, &[mask]);
+    Ok(extension.major_opcode())
+}
+
 impl Stalker {
-    /// Create and configure a new listener.
-    fn new() -> io::Result<Self> {
+    /** Create and configure a new listener.
+     * If require_input is set, focus changes are only reported after a key or button press,
+     * so windows crossed by the pointer with focus follows mouse are ignored.
+     */
+    fn new(require_input: bool) -> io::Result<Self> {
         // Xcb Boilerplate
-        let (conn, screen_num) = xcb::Connection::connect(None).map_err(conn_to_io_error)?;
+        let (conn, screen_num) = connect()?;
         let root_window = {
             let setup = conn.get_setup();
             let screen = setup.roots().nth(screen_num as usize).unwrap();
@@ -107,6 +173,10 @@ impl Stalker {
         let non_static_atoms = NonStaticAtoms::read_from_conn(&conn)?;
 
         let active_window = get_active_window(&conn, root_window, non_static_atoms.active_window)?;
+        let active_window_fullscreen = is_fullscreen(
+            &non_static_atoms,
+            get_wm_state(&conn, &non_static_atoms, active_window).get_reply(),
+        );
 
         // Listen to its title changes
         enable_property_change_notifications(&conn, active_window);
@@ -115,6 +185,18 @@ impl Stalker {
         // This is where the active window property is maintained.
         enable_property_change_notifications(&conn, root_window);
 
+        // Pointer motion is not selected: crossing windows is not using them.
+        let xinput_opcode = if require_input {
+            Some(enable_raw_input_notifications(
+                &conn,
+                root_window,
+                xcb::xinput::XI_EVENT_MASK_RAW_KEY_PRESS
+                    | xcb::xinput::XI_EVENT_MASK_RAW_BUTTON_PRESS,
+            )?)
+        } else {
+            None
+        };
+
         conn.flush();
         conn.has_error().map_err(conn_to_io_error)?;
 
@@ -123,6 +205,9 @@ impl Stalker {
             root_window: root_window,
             non_static_atoms: non_static_atoms,
             current_active_window: active_window,
+            xinput_opcode: xinput_opcode,
+            confirmed_active_window: active_window,
+            active_window_fullscreen: active_window_fullscreen,
         })
     }
 
@@ -130,32 +215,24 @@ impl Stalker {
     fn get_active_window_metadata(&self) -> io::Result<(ActiveWindowMetadata, time::Instant)> {
         // Timestamp from xcb is unusable
         let timestamp = time::Instant::now();
-        // Requests
-        let title = self.get_text_property(self.current_active_window, xcb::ATOM_WM_NAME);
-        let class = self.get_text_property(self.current_active_window, xcb::ATOM_WM_CLASS);
-        // Process replies
-        let title = title.get_reply();
-        let class = class.get_reply().map(|mut text| match text.find('\0') {
-            Some(offset) => {
-                text.truncate(offset);
-                text
-            }
-            None => text,
-        });
-        Ok((
-            ActiveWindowMetadata {
-                title: title,
-                class: class,
-            },
-            timestamp,
-        ))
+        let metadata = get_window_metadata(
+            &self.connection,
+            self.root_window,
+            &self.non_static_atoms,
+            self.current_active_window,
+        );
+        Ok((metadata, timestamp))
     }
 
     /// Process all pending events, update cached data (active_window).
     /// Return true if the active window metadata has changed, and must be queried again.
+    /// Changes of an active window not confirmed by user input are not reported.
     fn process_events(&mut self) -> io::Result<bool> {
         let mut active_window_changed = false;
         let mut active_window_title_changed = false;
+        let mut active_window_state_changed = false;
+        let mut desktop_changed = false;
+        let mut user_input = false;
         // Process all events, gather changes.
         while let Some(event) = self.connection.poll_for_event() {
             let rt = event.response_type();
@@ -165,18 +242,40 @@ impl Stalker {
                     && event.atom() == self.non_static_atoms.active_window
                     && event.state() == xcb::PROPERTY_NEW_VALUE as u8
                 {
-                    println!("DEBUG: prop change active_window on root");
+                    debug!("prop change active_window on root");
                     active_window_changed = true;
                 }
+                if event.window() == self.root_window
+                    && event.atom() == self.non_static_atoms.net_current_desktop
+                    && event.state() == xcb::PROPERTY_NEW_VALUE as u8
+                {
+                    debug!("prop change current_desktop on root");
+                    desktop_changed = true;
+                }
                 if event.window() == self.current_active_window
-                    && event.atom() == xcb::ATOM_WM_NAME
+                    && (event.atom() == xcb::ATOM_WM_NAME
+                        || event.atom() == self.non_static_atoms.net_wm_name
+                        || event.atom() == xcb::ATOM_WM_ICON_NAME)
                     && event.state() == xcb::PROPERTY_NEW_VALUE as u8
                 {
-                    println!("DEBUG: prop change title on active_window");
+                    debug!("prop change title or icon name on active_window");
                     active_window_title_changed = true;
                 }
+                if event.window() == self.current_active_window
+                    && event.atom() == self.non_static_atoms.net_wm_state
+                {
+                    debug!("prop change wm_state on active_window");
+                    active_window_state_changed = true;
+                }
+            } else if rt == xcb::GE_GENERIC {
+                let event: &xcb::GeGenericEvent = unsafe { xcb::cast_event(&event) };
+                if Some(event.extension()) == self.xinput_opcode {
+                    user_input = true;
+                }
             }
         }
+        // No more events may also mean that the connection was lost (X server restart).
+        self.connection.has_error().map_err(conn_to_io_error)?;
         // Get new active window
         if active_window_changed {
             let new_active_window = self.get_active_window()?;
@@ -190,14 +289,55 @@ impl Stalker {
                 }
                 enable_property_change_notifications(&self.connection, new_active_window);
                 self.current_active_window = new_active_window;
+                self.active_window_fullscreen = self.get_active_window_fullscreen();
+                active_window_title_changed = false;
+                active_window_state_changed = false;
+                if self.xinput_opcode.is_none() {
+                    self.confirmed_active_window = new_active_window;
+                    return Ok(true);
+                }
+                debug!("active_window change waiting for user input");
+            }
+        }
+        if self.confirmed_active_window != self.current_active_window {
+            // Focus changed without input yet: the window is only reported when used.
+            if user_input {
+                debug!("active_window change confirmed by user input");
+                self.confirmed_active_window = self.current_active_window;
                 return Ok(true);
             }
+            return Ok(false);
         }
-        // Active window did not actually change. Check if active window title changed.
-        Ok(active_window_title_changed)
+        // Active window did not actually change. Check if its title, fullscreen or desktop changed.
+        let mut fullscreen_changed = false;
+        if active_window_state_changed {
+            let reply = self.get_active_window_state();
+            let change = fullscreen_change(
+                self.active_window_fullscreen,
+                wm_states(&reply),
+                self.non_static_atoms.net_wm_state_fullscreen,
+            );
+            if let Some(fullscreen) = change {
+                debug!("active_window fullscreen changed to {}", fullscreen);
+                self.active_window_fullscreen = fullscreen;
+                fullscreen_changed = true;
+            }
+        }
+        Ok(active_window_title_changed || fullscreen_changed || desktop_changed)
     }
 
     // Short wrappers
+    fn get_active_window_state(&self) -> Result<xcb::GetPropertyReply, xcb::GenericError> {
+        get_wm_state(
+            &self.connection,
+            &self.non_static_atoms,
+            self.current_active_window,
+        )
+        .get_reply()
+    }
+    fn get_active_window_fullscreen(&self) -> bool {
+        is_fullscreen(&self.non_static_atoms, self.get_active_window_state())
+    }
     fn get_active_window(&self) -> io::Result<xcb::Window> {
         get_active_window(
             &self.connection,
@@ -205,13 +345,6 @@ impl Stalker {
             self.non_static_atoms.active_window,
         )
     }
-    fn get_text_property<'a>(
-        &'a self,
-        w: xcb::Window,
-        atom: xcb::Atom,
-    ) -> GetTextPropertyCookie<'a> {
-        get_text_property(&self.connection, &self.non_static_atoms, w, atom)
-    }
 }
 
 impl NonStaticAtoms {
@@ -221,14 +354,218 @@ impl NonStaticAtoms {
         let active_window_cookie = xcb::intern_atom(&conn, true, "_NET_ACTIVE_WINDOW");
         let utf8_string_cookie = xcb::intern_atom(&conn, true, "UTF8_STRING");
         let compound_text_cookie = xcb::intern_atom(&conn, true, "COMPOUND_TEXT");
+        let wm_pid_cookie = xcb::intern_atom(&conn, true, "_NET_WM_PID");
+        let wm_window_role_cookie = xcb::intern_atom(&conn, true, "WM_WINDOW_ROLE");
+        let net_wm_name_cookie = xcb::intern_atom(&conn, true, "_NET_WM_NAME");
+        let net_wm_window_type_cookie = xcb::intern_atom(&conn, true, "_NET_WM_WINDOW_TYPE");
+        let net_current_desktop_cookie = xcb::intern_atom(&conn, true, "_NET_CURRENT_DESKTOP");
+        let net_client_list_stacking_cookie =
+            xcb::intern_atom(&conn, true, "_NET_CLIENT_LIST_STACKING");
+        let net_wm_state_cookie = xcb::intern_atom(&conn, true, "_NET_WM_STATE");
+        let net_wm_state_fullscreen_cookie =
+            xcb::intern_atom(&conn, true, "_NET_WM_STATE_FULLSCREEN");
         Ok(NonStaticAtoms {
             active_window: active_window_cookie.get_reply().map_err(to_error)?.atom(),
             utf8_string: utf8_string_cookie.get_reply().map_err(to_error)?.atom(),
             compound_text: compound_text_cookie.get_reply().map_err(to_error)?.atom(),
+            wm_pid: wm_pid_cookie.get_reply().map_err(to_error)?.atom(),
+            wm_window_role: wm_window_role_cookie.get_reply().map_err(to_error)?.atom(),
+            net_wm_name: net_wm_name_cookie.get_reply().map_err(to_error)?.atom(),
+            net_wm_window_type: net_wm_window_type_cookie
+                .get_reply()
+                .map_err(to_error)?
+                .atom(),
+            net_current_desktop: net_current_desktop_cookie
+                .get_reply()
+                .map_err(to_error)?
+                .atom(),
+            net_client_list_stacking: net_client_list_stacking_cookie
+                .get_reply()
+                .map_err(to_error)?
+                .atom(),
+            net_wm_state: net_wm_state_cookie.get_reply().map_err(to_error)?.atom(),
+            net_wm_state_fullscreen: net_wm_state_fullscreen_cookie
+                .get_reply()
+                .map_err(to_error)?
+                .atom(),
         })
     }
 }
 
+/// Get the metadata of a window. Missing properties are None.
+fn get_window_metadata(
+    connection: &xcb::Connection,
+    root_window: xcb::Window,
+    non_static_atoms: &NonStaticAtoms,
+    window: xcb::Window,
+) -> ActiveWindowMetadata {
+    // Requests
+    let net_wm_name = get_text_property(
+        connection,
+        non_static_atoms,
+        window,
+        non_static_atoms.net_wm_name,
+    );
+    let title = get_text_property(connection, non_static_atoms, window, xcb::ATOM_WM_NAME);
+    let icon_name = get_text_property(connection, non_static_atoms, window, xcb::ATOM_WM_ICON_NAME);
+    let role = get_text_property(
+        connection,
+        non_static_atoms,
+        window,
+        non_static_atoms.wm_window_role,
+    );
+    let class = get_text_property(connection, non_static_atoms, window, xcb::ATOM_WM_CLASS);
+    let pid = xcb::get_property(
+        connection,
+        false,
+        window,
+        non_static_atoms.wm_pid,
+        xcb::ATOM_CARDINAL,
+        0,
+        1,
+    );
+    // Only the first, preferred, window type is used
+    let window_type = xcb::get_property(
+        connection,
+        false,
+        window,
+        non_static_atoms.net_wm_window_type,
+        xcb::ATOM_ATOM,
+        0,
+        1,
+    );
+    let wm_state = get_wm_state(connection, non_static_atoms, window);
+    // Desktop is a property of the root window, set by EWMH window managers
+    let desktop = xcb::get_property(
+        connection,
+        false,
+        root_window,
+        non_static_atoms.net_current_desktop,
+        xcb::ATOM_CARDINAL,
+        0,
+        1,
+    );
+    // Process replies. UTF-8 _NET_WM_NAME is preferred over legacy WM_NAME.
+    let net_wm_name = net_wm_name.get_reply();
+    let title = title.get_reply();
+    let title = net_wm_name.or(title);
+    let icon_name = icon_name.get_reply();
+    let role = role.get_reply();
+    // WM_CLASS is "instance\0class\0". Without class part, the instance is also the class.
+    let (instance, class) = match class.get_reply() {
+        Some(text) => {
+            let mut parts = text.split('\0').filter(|part| !part.is_empty());
+            let instance = parts.next().map(String::from);
+            let class = parts.next().map(String::from).or_else(|| instance.clone());
+            (instance, class)
+        }
+        None => (None, None),
+    };
+    let cardinal = |reply: Result<xcb::GetPropertyReply, _>| match reply {
+        Ok(ref reply)
+            if reply.type_() == xcb::ATOM_CARDINAL
+                && reply.format() == 32
+                && reply.value_len() == 1 =>
+        {
+            let buf: &[u32] = reply.value();
+            Some(buf[0])
+        }
+        _ => None,
+    };
+    let pid = cardinal(pid.get_reply());
+    let desktop = cardinal(desktop.get_reply());
+    let fullscreen = is_fullscreen(non_static_atoms, wm_state.get_reply());
+    let window_type = match window_type.get_reply() {
+        Ok(ref reply)
+            if reply.type_() == xcb::ATOM_ATOM
+                && reply.format() == 32
+                && reply.value_len() >= 1 =>
+        {
+            let buf: &[xcb::Atom] = reply.value();
+            get_window_type_name(connection, buf[0])
+        }
+        _ => None,
+    };
+    ActiveWindowMetadata {
+        title: title,
+        class: class,
+        instance: instance,
+        pid: pid,
+        process_name: pid.and_then(process_name),
+        role: role,
+        window_type: window_type,
+        desktop: desktop,
+        icon_name: icon_name,
+        fullscreen: fullscreen,
+    }
+}
+
+/// Request the _NET_WM_STATE atoms of a window, set by EWMH window managers.
+fn get_wm_state<'a>(
+    connection: &'a xcb::Connection,
+    non_static_atoms: &NonStaticAtoms,
+    window: xcb::Window,
+) -> xcb::GetPropertyCookie<'a> {
+    xcb::get_property(
+        connection,
+        false,
+        window,
+        non_static_atoms.net_wm_state,
+        xcb::ATOM_ATOM,
+        0,
+        64,
+    )
+}
+
+/// States of a _NET_WM_STATE reply. Empty if unavailable.
+fn wm_states(reply: &Result<xcb::GetPropertyReply, xcb::GenericError>) -> &[xcb::Atom] {
+    match reply {
+        Ok(reply) if reply.type_() == xcb::ATOM_ATOM && reply.format() == 32 => reply.value(),
+        _ => &[],
+    }
+}
+
+/// True if the states contain fullscreen. Always false if the atom is not supported (none).
+fn contains_fullscreen(states: &[xcb::Atom], fullscreen_atom: xcb::Atom) -> bool {
+    fullscreen_atom != xcb::ATOM_NONE && states.contains(&fullscreen_atom)
+}
+
+/// True if a _NET_WM_STATE reply contains the fullscreen state. False if unavailable.
+fn is_fullscreen(
+    non_static_atoms: &NonStaticAtoms,
+    reply: Result<xcb::GetPropertyReply, xcb::GenericError>,
+) -> bool {
+    contains_fullscreen(wm_states(&reply), non_static_atoms.net_wm_state_fullscreen)
+}
+
+/** New fullscreen state of a window after a _NET_WM_STATE change, None if unchanged.
+ * The property also changes for unrelated states (maximized, focused, urgent...), ignored here.
+ */
+fn fullscreen_change(
+    was_fullscreen: bool,
+    states: &[xcb::Atom],
+    fullscreen_atom: xcb::Atom,
+) -> Option<bool> {
+    let fullscreen = contains_fullscreen(states, fullscreen_atom);
+    if fullscreen != was_fullscreen {
+        Some(fullscreen)
+    } else {
+        None
+    }
+}
+
+/// Short name of a window type atom: "dialog" for _NET_WM_WINDOW_TYPE_DIALOG.
+fn get_window_type_name(connection: &xcb::Connection, atom: xcb::Atom) -> Option<String> {
+    let reply = xcb::get_atom_name(connection, atom).get_reply().ok()?;
+    let name = reply.name();
+    let prefix = "_NET_WM_WINDOW_TYPE_";
+    if name.starts_with(prefix) {
+        Some(name[prefix.len()..].to_lowercase())
+    } else {
+        Some(name.to_lowercase())
+    }
+}
+
 /// Request a text property, returning a handle on the request.
 fn get_text_property<'a>(
     connection: &'a xcb::Connection,
@@ -265,7 +602,7 @@ impl<'a> GetTextPropertyCookie<'a> {
                             .ok()
                             .map(|text| String::from(text))
                     }
-                    atom => eprintln!("get_text_property: unsupported atom reply: {}", atom),
+                    atom => warn!("get_text_property: unsupported atom reply: {}", atom),
                 }
             }
         }
@@ -300,17 +637,436 @@ impl mio::Evented for Stalker {
     }
 }
 
+/** Query user idle time and screensaver state using the XScreenSaver extension.
+ * Uses its own connection to the X server, independent from the ActiveWindowChanges stream.
+ */
+pub struct IdleTime {
+    connection: xcb::Connection,
+    root_window: xcb::Window,
+}
+
+impl IdleTime {
+    pub fn new() -> io::Result<Self> {
+        let (conn, screen_num) = connect()?;
+        let root_window = {
+            let setup = conn.get_setup();
+            let screen = setup.roots().nth(screen_num as usize).unwrap();
+            screen.root()
+        };
+        Ok(IdleTime {
+            connection: conn,
+            root_window: root_window,
+        })
+    }
+
+    /// Time since the last user input (keyboard or mouse).
+    pub fn get(&self) -> io::Result<time::Duration> {
+        let cookie = xcb::screensaver::query_info(&self.connection, self.root_window);
+        match cookie.get_reply() {
+            Ok(reply) => Ok(time::Duration::from_millis(
+                reply.ms_since_user_input() as u64
+            )),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "xcb_screensaver_query_info: failure",
+            )),
+        }
+    }
+
+    /// Whether the screensaver is active. Screen lockers (xss-lock) are started with it.
+    pub fn screensaver_active(&self) -> io::Result<bool> {
+        let cookie = xcb::screensaver::query_info(&self.connection, self.root_window);
+        match cookie.get_reply() {
+            Ok(reply) => Ok(reply.state() == xcb::screensaver::STATE_ON as u8),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "xcb_screensaver_query_info: failure",
+            )),
+        }
+    }
+}
+
+/// Enabled RandR output with the area of its CRTC, in root window coordinates.
+struct Monitor {
+    name: String,
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl Monitor {
+    fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.y && y < self.y + self.height
+    }
+}
+
+/** Query the window shown on each monitor, using the RandR extension for monitor areas.
+ * The window of a monitor is the active window if its center is on the monitor,
+ * otherwise the topmost viewable window centered on it (EWMH _NET_CLIENT_LIST_STACKING).
+ * Uses its own connection to the X server, independent from the ActiveWindowChanges stream.
+ */
+pub struct MonitorWindows {
+    connection: xcb::Connection,
+    root_window: xcb::Window,
+    non_static_atoms: NonStaticAtoms,
+}
+
+impl MonitorWindows {
+    pub fn new() -> io::Result<Self> {
+        let (conn, screen_num) = connect()?;
+        let root_window = {
+            let setup = conn.get_setup();
+            let screen = setup.roots().nth(screen_num as usize).unwrap();
+            screen.root()
+        };
+        let non_static_atoms = NonStaticAtoms::read_from_conn(&conn)?;
+        let randr_present = conn
+            .get_extension_data(xcb::randr::id())
+            .map_or(false, |extension| extension.present());
+        if !randr_present {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "RandR extension unsupported",
+            ));
+        }
+        Ok(MonitorWindows {
+            connection: conn,
+            root_window: root_window,
+            non_static_atoms: non_static_atoms,
+        })
+    }
+
+    /// Names of the enabled outputs ("HDMI-1"), in CRTC order.
+    pub fn outputs(&self) -> io::Result<Vec<String>> {
+        Ok(self
+            .monitors()?
+            .into_iter()
+            .map(|monitor| monitor.name)
+            .collect())
+    }
+
+    /// Window metadata for each enabled output, None if no window is shown on it.
+    pub fn get(&self) -> io::Result<Vec<(String, Option<ActiveWindowMetadata>)>> {
+        let monitors = self.monitors()?;
+        let active_window = get_active_window(
+            &self.connection,
+            self.root_window,
+            self.non_static_atoms.active_window,
+        )
+        .ok();
+        // Bottom to top, the active window is tested first
+        let stacking = self.client_list_stacking()?;
+        let candidates = active_window.iter().chain(stacking.iter().rev());
+        let mut windows: Vec<Option<xcb::Window>> = vec![None; monitors.len()];
+        for window in candidates {
+            if windows.iter().all(Option::is_some) {
+                break;
+            }
+            if let Some((x, y)) = self.viewable_window_center(*window) {
+                for (monitor, monitor_window) in monitors.iter().zip(windows.iter_mut()) {
+                    if monitor_window.is_none() && monitor.contains(x, y) {
+                        *monitor_window = Some(*window)
+                    }
+                }
+            }
+        }
+        Ok(monitors
+            .into_iter()
+            .zip(windows)
+            .map(|(monitor, window)| {
+                let metadata = window.map(|window| {
+                    get_window_metadata(
+                        &self.connection,
+                        self.root_window,
+                        &self.non_static_atoms,
+                        window,
+                    )
+                });
+                (monitor.name, metadata)
+            })
+            .collect())
+    }
+
+    /// Enabled outputs: CRTCs with a mode, named after their first output.
+    fn monitors(&self) -> io::Result<Vec<Monitor>> {
+        let to_error =
+            |request: &str| io::Error::new(io::ErrorKind::Other, format!("{}: failure", request));
+        let resources =
+            xcb::randr::get_screen_resources_current(&self.connection, self.root_window)
+                .get_reply()
+                .map_err(|_| to_error("xcb_randr_get_screen_resources_current"))?;
+        let timestamp = resources.config_timestamp();
+        let mut monitors = Vec::new();
+        for crtc in resources.crtcs() {
+            let crtc_info = xcb::randr::get_crtc_info(&self.connection, *crtc, timestamp)
+                .get_reply()
+                .map_err(|_| to_error("xcb_randr_get_crtc_info"))?;
+            let output = match crtc_info.outputs().first() {
+                Some(output) if crtc_info.mode() != xcb::NONE => *output,
+                _ => continue, // Disabled CRTC
+            };
+            let output_info = xcb::randr::get_output_info(&self.connection, output, timestamp)
+                .get_reply()
+                .map_err(|_| to_error("xcb_randr_get_output_info"))?;
+            monitors.push(Monitor {
+                name: String::from_utf8_lossy(output_info.name()).into_owned(),
+                x: i32::from(crtc_info.x()),
+                y: i32::from(crtc_info.y()),
+                width: i32::from(crtc_info.width()),
+                height: i32::from(crtc_info.height()),
+            })
+        }
+        Ok(monitors)
+    }
+
+    /// Managed windows from bottom to top. Empty if the window manager does not support EWMH.
+    fn client_list_stacking(&self) -> io::Result<Vec<xcb::Window>> {
+        let reply = xcb::get_property(
+            &self.connection,
+            false,
+            self.root_window,
+            self.non_static_atoms.net_client_list_stacking,
+            xcb::ATOM_WINDOW,
+            0,
+            4096,
+        )
+        .get_reply()
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                "xcb_get_property(client_list_stacking): failure",
+            )
+        })?;
+        if reply.type_() == xcb::ATOM_WINDOW && reply.format() == 32 {
+            Ok(reply.value::<xcb::Window>().to_vec())
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Center of a viewable window in root coordinates. None for unmapped windows (other desktop).
+    fn viewable_window_center(&self, window: xcb::Window) -> Option<(i32, i32)> {
+        let attributes = xcb::get_window_attributes(&self.connection, window);
+        let geometry = xcb::get_geometry(&self.connection, window);
+        let position = xcb::translate_coordinates(&self.connection, window, self.root_window, 0, 0);
+        if attributes.get_reply().ok()?.map_state() != xcb::MAP_STATE_VIEWABLE as u8 {
+            return None;
+        }
+        let geometry = geometry.get_reply().ok()?;
+        let position = position.get_reply().ok()?;
+        Some((
+            i32::from(position.dst_x()) + i32::from(geometry.width()) / 2,
+            i32::from(position.dst_y()) + i32::from(geometry.height()) / 2,
+        ))
+    }
+}
+
+/** Counter of user input events using XInput2 raw events, for the activity level.
+ * Only the numbers of key presses and mouse events are kept, never which keys were pressed.
+ * Uses its own connection to the X server, independent from the ActiveWindowChanges stream.
+ */
+struct InputCounter {
+    connection: xcb::Connection,
+    xinput_opcode: u8,
+}
+
+impl InputCounter {
+    fn new() -> io::Result<Self> {
+        let (conn, screen_num) = connect()?;
+        let root_window = {
+            let setup = conn.get_setup();
+            let screen = setup.roots().nth(screen_num as usize).unwrap();
+            screen.root()
+        };
+        let xinput_opcode = enable_raw_input_notifications(
+            &conn,
+            root_window,
+            xcb::xinput::XI_EVENT_MASK_RAW_KEY_PRESS
+                | xcb::xinput::XI_EVENT_MASK_RAW_BUTTON_PRESS
+                | xcb::xinput::XI_EVENT_MASK_RAW_MOTION,
+        )?;
+        conn.flush();
+        conn.has_error().map_err(conn_to_io_error)?;
+        Ok(InputCounter {
+            connection: conn,
+            xinput_opcode: xinput_opcode,
+        })
+    }
+
+    /// Count all pending input events.
+    fn count_events(&self) -> io::Result<InputActivity> {
+        let mut activity = InputActivity::default();
+        while let Some(event) = self.connection.poll_for_event() {
+            if event.response_type() != xcb::GE_GENERIC {
+                continue;
+            }
+            let event: &xcb::GeGenericEvent = unsafe { xcb::cast_event(&event) };
+            if event.extension() != self.xinput_opcode {
+                continue;
+            }
+            let event_type = event.event_type();
+            if event_type == u16::from(xcb::xinput::RAW_KEY_PRESS) {
+                activity.keys += 1
+            } else if event_type == u16::from(xcb::xinput::RAW_BUTTON_PRESS)
+                || event_type == u16::from(xcb::xinput::RAW_MOTION)
+            {
+                activity.mouse += 1
+            }
+        }
+        // No more events may also mean that the connection was lost (X server restart).
+        self.connection.has_error().map_err(conn_to_io_error)?;
+        Ok(activity)
+    }
+}
+
+/// Polling support for the counter: just use the underlying file descriptor.
+impl mio::Evented for InputCounter {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        mio::unix::EventedFd(&self.connection.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        mio::unix::EventedFd(&self.connection.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        mio::unix::EventedFd(&self.connection.as_raw_fd()).deregister(poll)
+    }
+}
+
+/// Asynchronous stream producing the numbers of input events received since the last item.
+pub struct InputEvents {
+    inner: PollEvented<InputCounter>,
+}
+
+impl InputEvents {
+    pub fn new() -> io::Result<Self> {
+        Ok(InputEvents {
+            inner: PollEvented::new(InputCounter::new()?),
+        })
+    }
+}
+
+impl Stream for InputEvents {
+    type Item = InputActivity;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        match self.inner.poll_read_ready(mio::Ready::readable()) {
+            Ok(Async::Ready(_)) => (),
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+        }
+        let activity = self.inner.get_ref().count_events()?;
+        self.inner.clear_read_ready(mio::Ready::readable())?;
+        if activity.is_zero() {
+            Ok(Async::NotReady)
+        } else {
+            Ok(Async::Ready(Some(activity)))
+        }
+    }
+}
+
+/// Errors sooner than this after the previous one are repeated errors, see ErrorThrottle.
+const REPEATED_ERROR_INTERVAL: time::Duration = time::Duration::from_secs(1);
+/// Delay before returning the first repeated error, doubled for each following one.
+const ERROR_THROTTLE_INITIAL_DELAY: time::Duration = time::Duration::from_millis(10);
+/// Maximum delay before returning a repeated error.
+const ERROR_THROTTLE_MAX_DELAY: time::Duration = time::Duration::from_secs(1);
+
+/** Delay errors of a stream which fail again immediately.
+ * A stream polled again after an error may fail the same way at once (broken connection),
+ * which would spin the event loop at full CPU. Repeated errors are returned after a timer:
+ * meanwhile the stream is not ready, and other tasks of the event loop keep running.
+ */
+struct ErrorThrottle {
+    last_error: Option<time::Instant>,
+    delay: time::Duration,
+    delayed_error: Option<(io::Error, Delay)>,
+}
+
+impl ErrorThrottle {
+    fn new() -> Self {
+        ErrorThrottle {
+            last_error: None,
+            delay: ERROR_THROTTLE_INITIAL_DELAY,
+            delayed_error: None,
+        }
+    }
+
+    /// Poll the stream with poll_inner, unless a repeated error is waiting for its timer.
+    fn poll<T, F>(&mut self, poll_inner: F) -> Poll<T, io::Error>
+    where
+        F: FnOnce() -> Poll<T, io::Error>,
+    {
+        let (e, mut timer) = match self.delayed_error.take() {
+            Some(delayed_error) => delayed_error,
+            None => match poll_inner() {
+                Err(e) => self.delay_if_repeated(e)?,
+                result => return result,
+            },
+        };
+        let elapsed = timer
+            .poll()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+            .is_ready();
+        if elapsed {
+            self.last_error = Some(time::Instant::now());
+            Err(e)
+        } else {
+            self.delayed_error = Some((e, timer));
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Return a repeated error with its timer, or other errors directly as Err.
+    fn delay_if_repeated(&mut self, e: io::Error) -> Result<(io::Error, Delay), io::Error> {
+        match self.last_error {
+            Some(last_error) if last_error.elapsed() < REPEATED_ERROR_INTERVAL => {
+                debug!(
+                    "Repeated window listener error, waiting {}ms: {}",
+                    self.delay.as_millis(),
+                    e
+                );
+                let timer = Runtime::delay(time::Instant::now() + self.delay);
+                self.delay = cmp::min(self.delay * 2, ERROR_THROTTLE_MAX_DELAY);
+                Ok((e, timer))
+            }
+            _ => {
+                self.delay = ERROR_THROTTLE_INITIAL_DELAY;
+                self.last_error = Some(time::Instant::now());
+                Err(e)
+            }
+        }
+    }
+}
+
 /// Asynchronous stream producing ActiveWindowMetadata when active window changes.
 pub struct ActiveWindowChanges {
     inner: PollEvented<Stalker>,
+    error_throttle: ErrorThrottle,
 }
 
 impl ActiveWindowChanges {
-    /// Create a new stream.
+    /// Create a new stream. If require_input is set, focus changes need user input to be reported.
     /// No tokio reactor is specified, so the Stalker will be registered lazily at first use.
-    pub fn new() -> io::Result<Self> {
+    pub fn new(require_input: bool) -> io::Result<Self> {
         Ok(ActiveWindowChanges {
-            inner: PollEvented::new(Stalker::new()?),
+            inner: PollEvented::new(Stalker::new(require_input)?),
+            error_throttle: ErrorThrottle::new(),
         })
     }
 
@@ -319,33 +1075,130 @@ impl ActiveWindowChanges {
     pub fn get_current_metadata(&self) -> io::Result<(ActiveWindowMetadata, time::Instant)> {
         self.inner.get_ref().get_active_window_metadata()
     }
-}
 
-/// Asynchronous Stream implementation.
-impl Stream for ActiveWindowChanges {
-    type Item = (ActiveWindowMetadata, time::Instant);
-    type Error = io::Error;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+    /// Poll the listener for a change of the active window metadata.
+    fn poll_changes(
+        inner: &mut PollEvented<Stalker>,
+    ) -> Poll<Option<(ActiveWindowMetadata, time::Instant)>, io::Error> {
         // Check if there is inbound data (xcb events to process)
-        match self.inner.poll_read_ready(mio::Ready::readable()) {
+        match inner.poll_read_ready(mio::Ready::readable()) {
             Ok(Async::Ready(_)) => (),
             Ok(Async::NotReady) => return Ok(Async::NotReady),
             Err(e) => return Err(e),
         }
         // Read all events
-        let active_window_changed = self.inner.get_mut().process_events()?;
+        let active_window_changed = inner.get_mut().process_events()?;
 
         // Reset read flag, will be set again if data arrives on socket
-        self.inner.clear_read_ready(mio::Ready::readable())?;
+        inner.clear_read_ready(mio::Ready::readable())?;
 
         if active_window_changed {
             // get_active_window_metadata requests replies are all consumed
             Ok(Async::Ready(Some(
-                self.inner.get_ref().get_active_window_metadata()?,
+                inner.get_ref().get_active_window_metadata()?,
             )))
         } else {
             Ok(Async::NotReady)
         }
     }
 }
+
+/// Asynchronous Stream implementation.
+impl Stream for ActiveWindowChanges {
+    type Item = (ActiveWindowMetadata, time::Instant);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, io::Error> {
+        let inner = &mut self.inner;
+        self.error_throttle
+            .poll(|| ActiveWindowChanges::poll_changes(inner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_errors_are_delayed() {
+        let mut throttle = ErrorThrottle::new();
+        let mut nb_polls = 0;
+        let mut nb_errors = 0;
+        let start = time::Instant::now();
+        // Broken connection: fails each time it is polled
+        let errors = future::poll_fn(|| {
+            while nb_errors < 5 {
+                let result = throttle.poll(|| -> Poll<(), io::Error> {
+                    nb_polls += 1;
+                    Err(io::Error::new(io::ErrorKind::Other, "broken connection"))
+                });
+                match result {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(())) => panic!("failing stream produced an item"),
+                    Err(_) => nb_errors += 1,
+                }
+            }
+            Ok(Async::Ready(()))
+        });
+        Runtime::new().unwrap().run(errors).unwrap();
+
+        // First error at once, then after 10, 20, 40 and 80ms
+        assert!(start.elapsed() >= time::Duration::from_millis(150));
+        assert_eq!(throttle.delay, time::Duration::from_millis(160));
+        assert_eq!(nb_polls, 5, "Stream polled again while an error is delayed");
+    }
+
+    #[test]
+    fn single_error_is_not_delayed() {
+        let mut throttle = ErrorThrottle::new();
+        let result = throttle.poll(|| -> Poll<(), io::Error> {
+            Err(io::Error::new(io::ErrorKind::Other, "broken connection"))
+        });
+        assert!(result.is_err());
+        assert_eq!(throttle.delay, ERROR_THROTTLE_INITIAL_DELAY);
+        assert!(throttle.delayed_error.is_none());
+    }
+
+    // Arbitrary atom values: they are only compared.
+    const FULLSCREEN: xcb::Atom = 300;
+    const MAXIMIZED_VERT: xcb::Atom = 301;
+    const MAXIMIZED_HORZ: xcb::Atom = 302;
+    const DEMANDS_ATTENTION: xcb::Atom = 303;
+    const FOCUSED: xcb::Atom = 304;
+
+    #[test]
+    fn unrelated_state_changes_are_ignored() {
+        let maximized = [MAXIMIZED_VERT, MAXIMIZED_HORZ];
+        assert_eq!(fullscreen_change(false, &maximized, FULLSCREEN), None);
+        assert_eq!(
+            fullscreen_change(false, &[DEMANDS_ATTENTION], FULLSCREEN),
+            None
+        );
+        assert_eq!(fullscreen_change(false, &[], FULLSCREEN), None);
+        let urgent = [FULLSCREEN, DEMANDS_ATTENTION];
+        assert_eq!(fullscreen_change(true, &urgent, FULLSCREEN), None);
+        assert_eq!(
+            fullscreen_change(true, &[FOCUSED, FULLSCREEN], FULLSCREEN),
+            None
+        );
+    }
+
+    #[test]
+    fn fullscreen_changes_are_reported() {
+        let fullscreen = [MAXIMIZED_VERT, FULLSCREEN];
+        assert_eq!(
+            fullscreen_change(false, &fullscreen, FULLSCREEN),
+            Some(true)
+        );
+        assert_eq!(
+            fullscreen_change(true, &[MAXIMIZED_VERT], FULLSCREEN),
+            Some(false)
+        );
+        assert_eq!(fullscreen_change(true, &[], FULLSCREEN), Some(false));
+        // Window manager without fullscreen support
+        assert_eq!(
+            fullscreen_change(false, &[xcb::ATOM_NONE], xcb::ATOM_NONE),
+            None
+        );
+    }
+}